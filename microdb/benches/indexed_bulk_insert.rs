@@ -0,0 +1,85 @@
+// Bulk insert into an indexed table: Table::extend updates the indexes once for the whole batch, while add updates them per entity; an unindexed extend is the baseline
+
+use microdb::prelude::*;
+use microdb_derive::{Database, DatabaseFactory};
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Flight
+{
+    pub flight_number: String,
+    pub seats: usize
+}
+
+#[derive(Database, DatabaseFactory)]
+pub struct BenchDatabase
+{
+    pub flights: Table::<Flight>
+}
+
+const N: usize = 200000;
+const RUNS: usize = 5;
+
+// Create an empty database, optionally with a hash and an ordered index on the flights
+fn create_database(indexed: bool) -> (BenchDatabase, Arc<Mutex<TransactionManager>>)
+{
+    let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+    let mut db = BenchDatabase::create_database(transaction_manager_ref.clone());
+    if indexed
+    {
+        db.flights.add_index("flight_number", |flight| flight.flight_number.clone());
+        db.flights.add_ordered_index("seats", |flight| flight.seats);
+    }
+    (db, transaction_manager_ref)
+}
+
+fn flights() -> impl Iterator<Item = Box<Flight>>
+{
+    (0..N).map(|index| Box::new(Flight { flight_number: format!("MA{}", index), seats: index % 300 }))
+}
+
+fn main()
+{
+    for _ in 0..RUNS
+    {
+        let (db, transaction_manager_ref) = create_database(true);
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let start = Instant::now();
+        for flight in flights()
+        {
+            db.flights.add(flight);
+        }
+        let add_duration = start.elapsed();
+        let start = Instant::now();
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        let add_rollback_duration = start.elapsed();
+
+        let (db, transaction_manager_ref) = create_database(true);
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let start = Instant::now();
+        db.flights.extend(flights());
+        let extend_duration = start.elapsed();
+        let start = Instant::now();
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        let extend_rollback_duration = start.elapsed();
+
+        // Baseline without indexes
+        let (db, transaction_manager_ref) = create_database(false);
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let start = Instant::now();
+        db.flights.extend(flights());
+        let unindexed_extend_duration = start.elapsed();
+
+        println!("{} flights: add {} ms (rollback {} ms), extend {} ms (rollback {} ms)", N, add_duration.as_millis(), add_rollback_duration.as_millis(),
+            extend_duration.as_millis(), extend_rollback_duration.as_millis());
+        println!("{} flights without indexes: extend {} ms", N, unindexed_extend_duration.as_millis());
+    }
+}
@@ -0,0 +1,66 @@
+// Replay of a command with large String parameters: running it from the borrowed record buffer (run_serialized) compared to
+// copying the buffer and creating a command object (create_from_serialized)
+
+use microdb::prelude::*;
+use microdb_derive::{Database, DatabaseFactory};
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Document
+{
+    pub length: usize
+}
+
+#[derive(Database, DatabaseFactory)]
+pub struct BenchDatabase
+{
+    pub documents: Table::<Document>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DocumentParameters
+{
+    pub title: String,
+    pub body: String
+}
+
+const N: usize = 20000;
+const BODY_LENGTH: usize = 64 * 1024;
+const RUNS: usize = 5;
+
+fn add_document(db: &mut BenchDatabase, parameters: &DocumentParameters) -> Result<(), String>
+{
+    db.documents.add(Box::new(Document { length: parameters.title.len() + parameters.body.len() }));
+    Ok(())
+}
+
+fn main()
+{
+    let definition = CommandDefinition::<BenchDatabase, DocumentParameters>::new("add_document", add_document);
+    let records: Vec<Vec<u8>> = (0..N)
+        .map(|index| bincode::serialize(&DocumentParameters { title: format!("Document {}", index), body: "x".repeat(BODY_LENGTH) }).unwrap())
+        .collect();
+
+    for _ in 0..RUNS
+    {
+        let mut db = BenchDatabase::create_database(Arc::new(Mutex::new(TransactionManager::new())));
+        let start = Instant::now();
+        for record in &records
+        {
+            definition.create_from_serialized(Box::new(record.clone())).run(&mut db).unwrap();
+        }
+        let owned_duration = start.elapsed();
+
+        let mut db = BenchDatabase::create_database(Arc::new(Mutex::new(TransactionManager::new())));
+        let start = Instant::now();
+        for record in &records
+        {
+            definition.run_serialized(&mut db, record).unwrap();
+        }
+        let borrowed_duration = start.elapsed();
+
+        println!("{} commands with {} KiB parameters: owned {} ms, borrowed {} ms", N, BODY_LENGTH / 1024, owned_duration.as_millis(), borrowed_duration.as_millis());
+    }
+}
@@ -0,0 +1,50 @@
+// Aggregate over a large table: Table::par_filter_map scanning with all cores compared to the sequential Table::iter
+
+use microdb::prelude::*;
+use microdb_derive::{Database, DatabaseFactory};
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Flight
+{
+    pub flight_number: String,
+    pub seats: usize
+}
+
+#[derive(Database, DatabaseFactory)]
+pub struct BenchDatabase
+{
+    pub flights: Table::<Flight>
+}
+
+const N: usize = 1000000;
+const RUNS: usize = 5;
+
+// Aggregate computed for each flight, what is expensive enough to be worth parallelizing
+fn checksum(flight: &Flight) -> u64
+{
+    flight.flight_number.bytes().fold(flight.seats as u64, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u64))
+}
+
+fn main()
+{
+    let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+    let mut db = BenchDatabase::create_database(transaction_manager_ref.clone());
+    db.flights.extend((0..N).map(|index| Box::new(Flight { flight_number: format!("MA{}", index), seats: index % 300 })));
+
+    for _ in 0..RUNS
+    {
+        let start = Instant::now();
+        let sequential: u64 = db.flights.iter().filter(|flight| flight.seats > 100).map(|flight| checksum(flight)).fold(0, u64::wrapping_add);
+        let iter_duration = start.elapsed();
+
+        let start = Instant::now();
+        let parallel: u64 = db.flights.par_filter_map(|flight| if flight.seats > 100 { Some(checksum(flight)) } else { None }).into_iter().fold(0, u64::wrapping_add);
+        let par_iter_duration = start.elapsed();
+
+        assert_eq!(sequential, parallel);
+        println!("{} flights: iter {} ms, par_filter_map {} ms", N, iter_duration.as_millis(), par_iter_duration.as_millis());
+    }
+}
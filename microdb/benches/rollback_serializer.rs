@@ -0,0 +1,65 @@
+// Mutation heavy transaction: capturing the rollback state of each changed entity by the bincode (default) and the cloning
+// rollback serializers, and restoring it on rollback
+
+use microdb::prelude::*;
+use microdb_derive::{Database, DatabaseFactory};
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Flight
+{
+    pub flight_number: String,
+    pub seats: usize,
+    pub fares: [u32; 16]
+}
+
+#[derive(Database, DatabaseFactory)]
+pub struct BenchDatabase
+{
+    pub flights: Table::<Flight>
+}
+
+const N: usize = 200000;
+const RUNS: usize = 5;
+
+fn create_database(rollback_serializer: RollbackSerializer<Box<Flight>>) -> (BenchDatabase, Arc<Mutex<TransactionManager>>)
+{
+    let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+    let mut db = BenchDatabase::create_database(transaction_manager_ref.clone());
+    db.flights.set_rollback_serializer(rollback_serializer);
+    db.flights.extend((0..N).map(|index| Box::new(Flight { flight_number: format!("MA{}", index), seats: index % 300, fares: [index as u32; 16] })));
+    (db, transaction_manager_ref)
+}
+
+// Change every flight in a transaction and roll it back, returning the duration of the changes and of the rollback
+fn run(rollback_serializer: RollbackSerializer<Box<Flight>>) -> (u128, u128)
+{
+    let (db, transaction_manager_ref) = create_database(rollback_serializer);
+    let db_lock = RwLock::new(db);
+    let mut db = db_lock.write().unwrap();
+    transaction_manager_ref.lock().unwrap().begin_transaction();
+    let start = Instant::now();
+    for flight in db.flights.iter_mut()
+    {
+        flight.seats += 1;
+        flight.fares[0] += 1;
+    }
+    let change_duration = start.elapsed();
+    let start = Instant::now();
+    transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+    let rollback_duration = start.elapsed();
+    assert!(db.flights.iter().all(|flight| flight.fares[0] == flight.fares[1]));
+    (change_duration.as_millis(), rollback_duration.as_millis())
+}
+
+fn main()
+{
+    for _ in 0..RUNS
+    {
+        let (bincode_change, bincode_rollback) = run(RollbackSerializer::bincode());
+        let (cloning_change, cloning_rollback) = run(RollbackSerializer::cloning());
+        println!("{} changed flights: bincode {} ms (rollback {} ms), cloning {} ms (rollback {} ms)", N, bincode_change, bincode_rollback, cloning_change, cloning_rollback);
+    }
+}
@@ -1,6 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{self, Data, Fields, DeriveInput, PathArguments, Type };
+use syn::{self, Data, Fields, DeriveInput, FnArg, GenericArgument, ImplItemMethod, Lit, LitStr, Meta, MetaNameValue, PathArguments, ReturnType, Type };
 
 #[proc_macro_derive(DatabaseFactory)]
 pub fn databasefactory_derive(input: TokenStream) -> TokenStream
@@ -48,7 +48,7 @@ pub fn databasefactory_derive(input: TokenStream) -> TokenStream
         panic!("Only structs are supported by DatabaseFactory implementation");
     } 
 
-    return expression.into();
+    expression.into()
 }
 
 #[proc_macro_derive(Database)]
@@ -71,19 +71,49 @@ pub fn database_derive(input: TokenStream) -> TokenStream
                     // Get field name and type to use in the quote tamplte
                     let field_name = &field.ident;
 
-                    // Generate expression for one field                    
-                    quote! { if table_id == self.#field_name.get_id() { return &mut self.#field_name }; }
+                    // Generate expression for one field (a field may hold multiple tables, like a sharded table)
+                    quote! { if let Some(table) = microdb::table::TableSet::find_table_mut(&mut self.#field_name, table_id) { return Some(table) }; }
                 }
             );            
 
+            let field_expressions_immutable = fields.named.iter().map(|field|
+                {
+                    let field_name = &field.ident;
+                    quote! { if let Some(table) = microdb::table::TableSet::find_table(&self.#field_name, table_id) { return Some(table) }; }
+                }
+            );
+
+            let field_names = fields.named.iter().map(|field| &field.ident);
+            let field_names_mutable = fields.named.iter().map(|field| &field.ident);
+
             // Generate the expressions 
             expression = quote! {
                 impl Database for #struct_name
                 {
-                    fn get_table_mut(&mut self, table_id: u64) -> &mut dyn microdb::table::TableBase
+                    fn try_get_table(&self, table_id: u64) -> Option<&dyn microdb::table::TableBase>
+                    {
+                        #(#field_expressions_immutable)*
+                        None
+                    }
+
+                    fn get_tables(&self) -> Vec<&dyn microdb::table::TableBase>
+                    {
+                        let mut tables = Vec::new();
+                        #(tables.extend(microdb::table::TableSet::get_tables(&self.#field_names));)*
+                        tables
+                    }
+
+                    fn get_tables_mut(&mut self) -> Vec<&mut dyn microdb::table::TableBase>
+                    {
+                        let mut tables = Vec::new();
+                        #(tables.extend(microdb::table::TableSet::get_tables_mut(&mut self.#field_names_mutable));)*
+                        tables
+                    }
+
+                    fn try_get_table_mut(&mut self, table_id: u64) -> Option<&mut dyn microdb::table::TableBase>
                     {                               
                         #(#field_expressions)*
-                        panic!("Unknown table");
+                        None
                     }
                 }
             };            
@@ -94,10 +124,10 @@ pub fn database_derive(input: TokenStream) -> TokenStream
         panic!("Only structs are supported by DatabaseFactory implementation");
     } 
 
-    return expression.into();
+    expression.into()
 }
 
-#[proc_macro_derive(CommandDirectory)]
+#[proc_macro_derive(CommandDirectory, attributes(command_schemas))]
 pub fn commanddefinitions_derive(input: TokenStream) -> TokenStream
 {
     // Build an expression tree from the tokens   
@@ -114,40 +144,64 @@ pub fn commanddefinitions_derive(input: TokenStream) -> TokenStream
             let field = fields.named.first().unwrap();
             let mut database_type = None;
 
-            match &field.ty
-                    {
-                        Type::Path(path) => {
-                             let arguments = &path.path.segments[0].arguments;
-                             if let PathArguments::AngleBracketed(args) = arguments
-                             {                       
-                                database_type = Some(args.args.first().unwrap());                                
-                             }
-                            },
-                        _ => {}
-                    }
+            if let Type::Path(path) = &field.ty
+            {
+                let arguments = &path.path.segments[0].arguments;
+                if let PathArguments::AngleBracketed(args) = arguments
+                {
+                    database_type = Some(args.args.first().unwrap());
+                }
+            }
 
             // Generate the expression for all fields
             let field_expressions = fields.named.iter().map(|field|
                 { 
-                    // Get field name and type to use in the quote tamplte
+                    // Get field name to use in the quote tamplte
                     let field_name = &field.ident;
-                    let field_type = &field.ty;                   
 
-                    // Generate expression for one field
-                    quote! { std::stringify!(#field_name) => Box::new(#field_type::new(self.#field_name.get_name(), self.#field_name.get_cmd()))}
+                    // Generate expression for one field (commands are dispatched by the name registered in their definitions)
+                    quote! { _name if _name == self.#field_name.get_name() => Some(Box::new(self.#field_name.clone())) }
                 }
             );            
 
+            let field_names = fields.named.iter().map(|field| &field.ident);
+
+            // Schemas of the parameters are generated only on request, because they require the parameters to implement JsonSchema
+            let schemas_expression = if tokens.attrs.iter().any(|attribute| attribute.path.is_ident("command_schemas"))
+            {
+                let field_names = fields.named.iter().map(|field| &field.ident);
+                quote! {
+                    impl microdb::command::CommandSchemas for #struct_name
+                    {
+                        fn command_schemas(&self) -> Vec<(&'static str, microdb::schemars::Schema)>
+                        {
+                            vec![#((self.#field_names.get_name(), self.#field_names.get_parameters_schema())),*]
+                        }
+                    }
+                }
+            }
+            else
+            {
+                quote! {}
+            };
+
             // Generate the expressions 
             expression = quote! {
+                #schemas_expression
+
                 impl CommandDirectory<#database_type> for #struct_name
                 {
-                    fn get(&self, name: &str) -> Box<dyn microdb::command::CommandDefinitionBase<#database_type>>
+                    fn list_commands(&self) -> Vec<&'static str>
+                    {
+                        vec![#(self.#field_names.get_name()),*]
+                    }
+
+                    fn try_get(&self, name: &str) -> Option<Box<dyn microdb::command::CommandDefinitionBase<#database_type>>>
                     {
                         match name
                         {                               
                             #(#field_expressions),*,
-                            _s => panic!("Unknown command {}", _s)                    
+                            _ => None
                         }
                     }
                 }
@@ -159,10 +213,10 @@ pub fn commanddefinitions_derive(input: TokenStream) -> TokenStream
         panic!("Only structs are supported by DatabaseFactory implementation");
     } 
 
-    return expression.into();    
+    expression.into()
 }
 
-#[proc_macro_derive(CommandDirectoryFactory)]
+#[proc_macro_derive(CommandDirectoryFactory, attributes(command_name, non_durable))]
 pub fn commanddirectoryfactory_derive(input: TokenStream) -> TokenStream
 {
     // Build an expression tree from the tokens   
@@ -183,8 +237,22 @@ pub fn commanddirectoryfactory_derive(input: TokenStream) -> TokenStream
                     let field_name = &field.ident;
                     //let field_type = &field.ty;
 
+                    // The command is registered with the field name, unless it is overridden by a #[command_name = "..."] attribute
+                    let command_name = field.attrs.iter().find(|attribute| attribute.path.is_ident("command_name")).map(|attribute|
+                        {
+                            match attribute.parse_meta()
+                            {
+                                Ok(Meta::NameValue(MetaNameValue { lit: Lit::Str(name), .. })) => quote! { #name },
+                                _ => panic!("Command name must be given as #[command_name = \"...\"]")
+                            }
+                        }
+                    ).unwrap_or(quote! { std::stringify!(#field_name) });
+
+                    // Commands marked by a #[non_durable] attribute are not written to the transaction storage
+                    let non_durable = if field.attrs.iter().any(|attribute| attribute.path.is_ident("non_durable")) { quote! { .non_durable() } } else { quote! {} };
+
                     // Generate expression for one field
-                    quote! { #field_name: microdb::command::CommandDefinition::new(std::stringify!(#field_name), #struct_name::#field_name) }
+                    quote! { #field_name: microdb::command::CommandDefinition::new(#command_name, #struct_name::#field_name)#non_durable }
                 }
             );            
 
@@ -208,5 +276,150 @@ pub fn commanddirectoryfactory_derive(input: TokenStream) -> TokenStream
         panic!("Only structs are supported by DatabaseFactory implementation");
     } 
 
-    return expression.into();
-}
\ No newline at end of file
+    expression.into()
+}
+
+#[proc_macro_attribute]
+pub fn command(attr: TokenStream, item: TokenStream) -> TokenStream
+{
+    // The only argument of the attribute is the name the command is registered with
+    let name: LitStr = syn::parse(attr).expect("Command name must be a string literal, like #[command(\"add_item\")]");
+    let method: ImplItemMethod = syn::parse(item).expect("Only associated functions are supported by the command attribute");
+
+    // Get the database and parameter types from the signature of the command function: fn(&mut D, &P) -> Result<(), String>
+    let argument_types: Vec<&Type> = method.sig.inputs.iter().map(|input|
+        {
+            match input
+            {
+                FnArg::Typed(pattern_type) => match &*pattern_type.ty
+                {
+                    Type::Reference(reference) => &*reference.elem,
+                    _ => panic!("Arguments of a command function must be references")
+                },
+                FnArg::Receiver(_) => panic!("Command functions can not have a self argument")
+            }
+        }
+    ).collect();
+    if argument_types.len() != 2
+    {
+        panic!("Command functions must have a database and a parameters argument");
+    }
+    let database_type = argument_types[0];
+    let parameters_type = argument_types[1];
+
+    // Get the result type of successful runs and the error type from the return type of the command function: Result<R, E>
+    let result_arguments: Vec<GenericArgument> = match &method.sig.output
+    {
+        ReturnType::Type(_, return_type) => match &**return_type
+        {
+            Type::Path(path) => match &path.path.segments.last().expect("Command functions must return a Result").arguments
+            {
+                PathArguments::AngleBracketed(args) => Some(args.args.iter().cloned().collect()),
+                _ => None
+            },
+            _ => None
+        },
+        ReturnType::Default => None
+    }.expect("Command functions must return a Result");
+    let result_type = result_arguments.first().expect("Command functions must return a Result");
+    // The error type is String, unless the command function returns a typed error
+    let error_type = match result_arguments.get(1)
+    {
+        Some(error_type) => quote! { #error_type },
+        None => quote! { String }
+    };
+
+    // Generate a function creating the command definition with the registered name
+    let function_name = &method.sig.ident;
+    let definition_function_name = syn::Ident::new(&format!("{}_definition", function_name), function_name.span());
+    let expression = quote! {
+        #method
+
+        pub fn #definition_function_name() -> microdb::command::CommandDefinition<#database_type, #parameters_type, #result_type, #error_type>
+        {
+            microdb::command::CommandDefinition::new(#name, Self::#function_name)
+        }
+    };
+
+    expression.into()
+}
+
+#[proc_macro_derive(CommandParams)]
+pub fn commandparams_derive(input: TokenStream) -> TokenStream
+{
+    // Build an expression tree from the tokens
+    let tokens: DeriveInput = syn::parse(input).unwrap();
+    if !tokens.generics.params.is_empty()
+    {
+        panic!("Generic structs are not supported by CommandParams implementation");
+    }
+
+    let fields = match &tokens.data
+    {
+        Data::Struct(ds) => match &ds.fields
+        {
+            Fields::Named(fields) => fields,
+            _ => panic!("Only structs with named fields are supported by CommandParams implementation")
+        },
+        _ => panic!("Only structs are supported by CommandParams implementation")
+    };
+
+    let struct_name = &tokens.ident;
+    let visibility = &tokens.vis;
+    let builder_name = syn::Ident::new(&format!("{}Builder", struct_name), struct_name.span());
+    let field_names: Vec<&syn::Ident> = fields.named.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+    let field_types: Vec<&Type> = fields.named.iter().map(|field| &field.ty).collect();
+    // Each field of the builder has a type parameter, what is Unset until the field is set, then Set<T>
+    let type_parameters: Vec<syn::Ident> = (0..field_names.len()).map(|index| syn::Ident::new(&format!("__F{}", index), struct_name.span())).collect();
+
+    // Generate a setter for each field, what is available only while the field is unset
+    let setters = field_names.iter().zip(field_types.iter()).enumerate().map(|(index, (field_name, field_type))|
+        {
+            let other_type_parameters = type_parameters.iter().enumerate().filter(|(other_index, _)| *other_index != index).map(|(_, type_parameter)| type_parameter);
+            let unset_type_parameters = type_parameters.iter().enumerate().map(|(other_index, type_parameter)| if other_index == index { quote! { microdb::command::Unset } } else { quote! { #type_parameter } });
+            let set_type_parameters = type_parameters.iter().enumerate().map(|(other_index, type_parameter)| if other_index == index { quote! { microdb::command::Set<#field_type> } } else { quote! { #type_parameter } });
+            let field_values = field_names.iter().map(|other_field_name| if other_field_name == field_name { quote! { #field_name: microdb::command::Set(#field_name) } } else { quote! { #other_field_name: self.#other_field_name } });
+
+            quote! {
+                impl<#(#other_type_parameters),*> #builder_name<#(#unset_type_parameters),*>
+                {
+                    pub fn #field_name(self, #field_name: #field_type) -> #builder_name<#(#set_type_parameters),*>
+                    {
+                        #builder_name { #(#field_values),* }
+                    }
+                }
+            }
+        }
+    );
+
+    let unset_types = type_parameters.iter().map(|_| quote! { microdb::command::Unset });
+
+    // Generate the expressions
+    let expression = quote! {
+        #visibility struct #builder_name<#(#type_parameters),*>
+        {
+            #(#field_names: #type_parameters),*
+        }
+
+        impl #struct_name
+        {
+            // Create a builder of the parameters, what can be built only after all fields are set
+            pub fn builder() -> #builder_name<#(#unset_types),*>
+            {
+                #builder_name { #(#field_names: microdb::command::Unset),* }
+            }
+        }
+
+        #(#setters)*
+
+        impl #builder_name<#(microdb::command::Set<#field_types>),*>
+        {
+            pub fn build(self) -> #struct_name
+            {
+                #struct_name { #(#field_names: self.#field_names.0),* }
+            }
+        }
+    };
+
+    expression.into()
+}
@@ -0,0 +1,118 @@
+use crate::{CommandEngine, Database, EngineError, check_not_in_command};
+use crate::command::{CommandDefinition, CommandDirectory, CommandOutcome};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, MutexGuard};
+use serde::{Serialize, de::DeserializeOwned};
+
+// Command engine shared by the methods of a service: it locks the engine for each call, so commands can be pushed by a single line
+pub struct CommandClient<D, C> where D: Database + Sync + Send, C: CommandDirectory<D>
+{
+    command_engine_mutex: Mutex<CommandEngine<D, C>>
+}
+
+impl<D, C> CommandClient<D, C> where D: Database + Sync + Send + 'static, C: CommandDirectory<D>
+{
+    pub fn new(command_engine: CommandEngine<D, C>) -> Self
+    {
+        Self { command_engine_mutex: Mutex::new(command_engine) }
+    }
+
+    // Push a command of the definition selected from the command directory with the parameters and get its transaction identifier
+    pub fn dispatch<P, R, E>(&self, definition: impl FnOnce(&C) -> &CommandDefinition<D, P, R, E>, parameters: P) -> Result<usize, EngineError>
+        where P: Serialize + DeserializeOwned + Send + Sync + 'static, R: Into<CommandOutcome> + 'static, E: Display + Send + Sync + 'static
+    {
+        // A synchronous command dispatched by the client holds the lock of the command engine while it runs, so it is checked before locking
+        check_not_in_command()?;
+        let mut command_engine = self.lock()?;
+        let command_definitions = command_engine.get_command_definitions();
+        let cmd = definition(&command_definitions).create(parameters);
+        command_engine.push_command(Arc::new(cmd))
+    }
+
+    // Wait until a transaction is processed (see CommandEngine::wait_for_transaction)
+    pub fn wait_for_transaction(&self, transaction_id: usize) -> Result<(), EngineError>
+    {
+        check_not_in_command()?;
+        return self.lock()?.wait_for_transaction(transaction_id);
+    }
+
+    // Lock the command engine for the less frequently used operations
+    pub fn lock(&self) -> Result<MutexGuard<'_, CommandEngine<D, C>>, EngineError>
+    {
+        self.command_engine_mutex.lock().map_err(|_| EngineError::LockPoisoned("command engine"))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{Engine, TransactionStatus};
+    use crate::command::CommandDirectoryFactory;
+    use crate::test_fixtures::*;
+    use crate::transaction_storage::MemoryTransactionStorage;
+    use microdb_derive::{CommandDirectory, CommandDirectoryFactory};
+    use std::thread;
+
+    #[test]
+    fn command_client_dispatches_commands_from_many_threads()
+    {
+        let (query_engine, command_engine) = create_engine(MemoryTransactionStorage::new());
+        let command_client = Arc::new(CommandClient::new(command_engine));
+
+        let threads: Vec<_> = (0..4).map(|thread_number|
+        {
+            let command_client = command_client.clone();
+            thread::spawn(move || (0..10).map(|number| command_client.dispatch(|commands| &commands.add_flight, flight(&format!("MA{}{}", thread_number, number), 10)).unwrap()).max().unwrap())
+        }).collect();
+        let last_transaction_id = threads.into_iter().map(|thread| thread.join().unwrap()).max().unwrap();
+
+        command_client.wait_for_transaction(last_transaction_id).unwrap();
+        assert_eq!(query_engine.query(|db| db.flights.len()), 40);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct ReentrantCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+        // Adds the flight, then tries to push add_flight by the command client of REENTRANT_CLIENT
+        add_flight_and_dispatch: CommandDefinition::<AirlineDatabase, Flight>
+    }
+
+    // Command client used by ReentrantCommands::add_flight_and_dispatch (only by the reentrancy test)
+    static REENTRANT_CLIENT: std::sync::OnceLock<CommandClient<AirlineDatabase, ReentrantCommands>> = std::sync::OnceLock::new();
+
+    impl ReentrantCommands
+    {
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            Ok(())
+        }
+
+        fn add_flight_and_dispatch(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            let command_client = REENTRANT_CLIENT.get().unwrap();
+            match (command_client.dispatch(|commands| &commands.add_flight, flight.clone()), command_client.wait_for_transaction(1))
+            {
+                (Err(EngineError::ReentrantCommand), Err(EngineError::ReentrantCommand)) => Ok(()),
+                results => Err(format!("Unexpected results: {:?}", results))
+            }
+        }
+    }
+
+    #[test]
+    fn command_client_rejects_commands_dispatched_by_a_running_command_instead_of_deadlocking()
+    {
+        let (query_engine, command_engine) = Engine::builder(ReentrantCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+        let command_client = REENTRANT_CLIENT.get_or_init(|| CommandClient::new(command_engine));
+
+        let transaction_id = command_client.dispatch(|commands| &commands.add_flight_and_dispatch, flight("MA100", 10)).unwrap();
+        assert_eq!(command_client.lock().unwrap().get_transaction_status(transaction_id), Ok(TransactionStatus::Completed));
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+        // The client is still usable after the rejected dispatch
+        command_client.dispatch(|commands| &commands.add_flight, flight("MA200", 10)).unwrap();
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+    }
+}
@@ -0,0 +1,551 @@
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::id_allocator::{IdAllocator, SequentialAllocator};
+use crate::table::{AccessCounters, OrphanedReferences, TableAccess, TableBase, TableDiff, TableSet};
+#[cfg(feature = "table-stats")]
+use crate::table::TableAccessStats;
+use crate::transaction::{RollbackState, TransactionEntry, TransactionManager};
+
+// Number of cold tables created by the process, so the files of tables with the same name do not collide
+static COLD_TABLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A table for large, rarely accessed data, what keeps the serialized structs in a scratch file instead of the memory
+pub struct ColdTable<T> where T: Serialize + DeserializeOwned
+{
+    // Name of the table
+    name: &'static str,
+    // Unique identifier of table
+    id: u64,
+    // Position and length of the serialized structs of the entities in the file by their identifiers
+    positions: HashMap<usize, (u64, usize)>,
+    // Allocates the unique identifiers of new entities
+    id_allocator: SequentialAllocator,
+    // Highest identifier allocated or reserved by the table
+    max_used_id: usize,
+    // File and cache of the structs (locked, because reading an entity changes the cache)
+    storage: Mutex<ColdStorage<T>>,
+    // Transaction manager
+    transaction_manager: Arc<Mutex<TransactionManager>>,
+    // Number of accesses by kind (see TableAccessStats)
+    access_counters: AccessCounters
+}
+
+// File of a cold table and the cache of its recently accessed structs
+struct ColdStorage<T>
+{
+    path: PathBuf,
+    // Opened when the first entity is written
+    file: Option<File>,
+    // Length of the file (structs are always appended)
+    len: u64,
+    // Maximum number of cached structs
+    cache_capacity: usize,
+    // Cached structs with the time of their last access by identifiers
+    cache: HashMap<usize, (Arc<T>, u64)>,
+    // Identifiers of the cached structs by the time of their last access, so the least recently used one can be evicted
+    last_accesses: BTreeMap<u64, usize>,
+    // Incremented on every access of the cache
+    time: u64
+}
+
+impl<T> ColdStorage<T> where T: Serialize + DeserializeOwned
+{
+    // Write a serialized struct to the end of the file and get its position
+    fn append(&mut self, serialized_item: &[u8]) -> u64
+    {
+        let file = match &mut self.file
+        {
+            Some(file) => file,
+            None => self.file.insert(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&self.path).unwrap())
+        };
+        let position = self.len;
+        file.seek(SeekFrom::Start(position)).unwrap();
+        file.write_all(serialized_item).unwrap();
+        self.len += serialized_item.len() as u64;
+        position
+    }
+
+    // Read a serialized struct from the file
+    fn read(&mut self, (position, len): (u64, usize)) -> Vec<u8>
+    {
+        let file = self.file.as_mut().expect("File of the cold table is not created yet");
+        let mut serialized_item = vec![0u8; len];
+        file.seek(SeekFrom::Start(position)).unwrap();
+        file.read_exact(&mut serialized_item).unwrap();
+        serialized_item
+    }
+
+    // Get a struct from the cache, or read it from the file and add it to the cache
+    fn get(&mut self, id: usize, position: (u64, usize)) -> Arc<T>
+    {
+        self.time += 1;
+        let time = self.time;
+        if let Some((item, last_access)) = self.cache.get_mut(&id)
+        {
+            self.last_accesses.remove(last_access);
+            *last_access = time;
+            self.last_accesses.insert(time, id);
+            return item.clone();
+        }
+
+        let item = Arc::new(bincode::deserialize::<T>(&self.read(position)).expect("Deserializing an entity of a cold table failed"));
+        if self.cache_capacity > 0
+        {
+            if self.cache.len() >= self.cache_capacity
+            {
+                if let Some((_, evicted_id)) = self.last_accesses.pop_first()
+                {
+                    self.cache.remove(&evicted_id);
+                }
+            }
+            self.cache.insert(id, (item.clone(), time));
+            self.last_accesses.insert(time, id);
+        }
+        item
+    }
+
+    // Remove a changed or removed struct from the cache
+    fn invalidate(&mut self, id: usize)
+    {
+        if let Some((_, last_access)) = self.cache.remove(&id)
+        {
+            self.last_accesses.remove(&last_access);
+        }
+    }
+}
+
+impl<T> Drop for ColdStorage<T>
+{
+    fn drop(&mut self)
+    {
+        if self.file.take().is_some()
+        {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl<T> ColdTable<T> where T: Serialize + DeserializeOwned
+{
+    // Create a new cold table storing its file in the temporary directory and caching 1000 structs
+    pub fn new(name: &'static str, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
+    {
+        // Unique identifier of table is a hash generated from its name (like in normal tables)
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let id = hasher.finish();
+
+        let storage = ColdStorage { path: Self::get_file_path(std::env::temp_dir(), name), file: None, len: 0, cache_capacity: 1000, cache: HashMap::new(), last_accesses: BTreeMap::new(), time: 0 };
+        Self { name, id, positions: HashMap::new(), id_allocator: SequentialAllocator::default(), max_used_id: 0, storage: Mutex::new(storage), transaction_manager, access_counters: AccessCounters::default() }
+    }
+
+    fn get_file_path(directory: PathBuf, name: &str) -> PathBuf
+    {
+        directory.join(format!("microdb-{}-{}-{}.cold", std::process::id(), COLD_TABLE_COUNT.fetch_add(1, Ordering::Relaxed), name))
+    }
+
+    // Set the directory of the file of the table (the temporary directory by default)
+    pub fn set_directory(&mut self, directory: &str)
+    {
+        let storage = self.storage.get_mut().unwrap();
+        assert!(storage.file.is_none(), "Directory of cold table {} can not be changed after entities are added", self.name);
+        storage.path = Self::get_file_path(PathBuf::from(directory), self.name);
+    }
+
+    // Set the maximum number of structs cached in the memory (0 disables the cache)
+    pub fn set_cache_capacity(&mut self, cache_capacity: usize)
+    {
+        let storage = self.storage.get_mut().unwrap();
+        storage.cache_capacity = cache_capacity;
+        storage.cache.clear();
+        storage.last_accesses.clear();
+    }
+
+    // Returns the unique identifier of table
+    pub fn get_id(&self) -> u64
+    {
+        self.id
+    }
+
+    // Get a struct from the table by identifier. It is read from the file, unless it was accessed recently.
+    pub fn get(&self, id: usize) -> Option<Arc<T>>
+    {
+        self.access_counters.count(TableAccess::Read, 1);
+        let position = *self.positions.get(&id)?;
+        Some(self.storage.lock().unwrap().get(id, position))
+    }
+
+    // Returns true if the table contains a (not removed) entity with the identifier
+    pub fn contains(&self, id: usize) -> bool
+    {
+        self.positions.contains_key(&id)
+    }
+
+    // Get the unique identifiers of all entities in an arbitrary order (without reading their structs)
+    pub fn ids(&self) -> impl Iterator<Item = usize> + '_
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.positions.keys().copied()
+    }
+
+    // Add a struct to the table as a new entity
+    pub fn add(&mut self, item: Box<T>) -> usize
+    {
+        // Capture the state of the identifier allocator before the first allocation of the running transaction
+        {
+            let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+            if locked_transaction_manager.capture_id_allocation(self.id)
+            {
+                locked_transaction_manager.add_entry(TransactionEntry::IdAllocation(self.id, Box::new((self.id_allocator.clone(), self.max_used_id))));
+            }
+        }
+        let id = self.id_allocator.allocate();
+        self.max_used_id = self.max_used_id.max(id);
+        self.write(id, &bincode::serialize(&item).unwrap());
+        self.access_counters.count(TableAccess::Insert, 1);
+
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+        if locked_transaction_manager.is_transaction_running()
+        {
+            debug!("Add transaction entry for a new entity (Table: {}, Id: {})", self.name, id);
+            locked_transaction_manager.add_entry(TransactionEntry::NotExisting(self.id, id));
+        }
+        id
+    }
+
+    // Replace the struct of an entity (returns false if there is no entity with the identifier)
+    pub fn update(&mut self, id: usize, item: Box<T>) -> bool
+    {
+        let Some(position) = self.positions.get(&id).copied() else { return false; };
+        self.log_existing(id, position);
+        self.write(id, &bincode::serialize(&item).unwrap());
+        self.access_counters.count(TableAccess::Update, 1);
+        true
+    }
+
+    // Remove an entity from the table (returns false if there is no entity with the identifier)
+    pub fn remove(&mut self, id: usize) -> bool
+    {
+        let Some(position) = self.positions.remove(&id) else { return false; };
+        self.log_existing(id, position);
+        self.storage.get_mut().unwrap().invalidate(id);
+        self.access_counters.count(TableAccess::Delete, 1);
+        true
+    }
+
+    // Write the serialized struct of an entity to the file
+    fn write(&mut self, id: usize, serialized_item: &[u8])
+    {
+        let storage = self.storage.get_mut().unwrap();
+        let position = storage.append(serialized_item);
+        storage.invalidate(id);
+        self.positions.insert(id, (position, serialized_item.len()));
+    }
+
+    // Log the struct of an existing entity before changing or removing it, so it is restored on rollback
+    fn log_existing(&mut self, id: usize, position: (u64, usize))
+    {
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+        if locked_transaction_manager.is_transaction_running()
+        {
+            debug!("Add transaction entry for an existing entity (Table: {}, Id: {})", self.name, id);
+            // The serialized struct is stored as it is in the file, so rollback does not need to serialize it again
+            let serialized_item = self.storage.get_mut().unwrap().read(position);
+            locked_transaction_manager.add_entry(TransactionEntry::Existing(self.id, id, RollbackState::Serialized(serialized_item)));
+        }
+    }
+
+    // Read the serialized structs of all entities in the order of identifiers
+    fn read_all(&self) -> Vec<(usize, Vec<u8>)>
+    {
+        let mut positions: Vec<(usize, (u64, usize))> = self.positions.iter().map(|(id, position)| (*id, *position)).collect();
+        positions.sort_unstable_by_key(|(id, _)| *id);
+        let mut storage = self.storage.lock().unwrap();
+        positions.into_iter().map(|(id, position)| (id, storage.read(position))).collect()
+    }
+}
+
+impl<T> TableBase for ColdTable<T> where T: Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn rollback_to_existing(&mut self, id: usize, state: RollbackState) -> Result<(), String>
+    {
+        debug!("rollback_to_existing ({}-{})", self.name, id);
+        match state
+        {
+            RollbackState::Serialized(serialized_item) =>
+            {
+                self.write(id, &serialized_item);
+                Ok(())
+            },
+            RollbackState::Cloned(_) => Err(String::from("Cloned rollback state can not be restored by a cold table")),
+            RollbackState::Moved(_) => Err(String::from("Moved rollback state can not be restored by a cold table")),
+            RollbackState::Patch(_) => Err(String::from("Patch rollback state can not be restored by a cold table"))
+        }
+    }
+
+    fn rollback_to_not_existing(&mut self, id: usize)
+    {
+        debug!("rollback_to_not_existing ({}-{})", self.name, id);
+        self.positions.remove(&id);
+        self.storage.get_mut().unwrap().invalidate(id);
+    }
+
+    fn rollback_id_allocation(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>
+    {
+        debug!("rollback_id_allocation ({})", self.name);
+        let (id_allocator, max_used_id) = *state.downcast::<(SequentialAllocator, usize)>().map_err(|_| String::from("Id allocation state has an unexpected type"))?;
+        self.id_allocator = id_allocator;
+        self.max_used_id = max_used_id;
+        Ok(())
+    }
+
+    fn contains(&self, id: usize) -> bool
+    {
+        self.positions.contains_key(&id)
+    }
+
+    // Cold tables have no foreign keys
+    fn get_references(&self, _id: usize) -> Vec<(u64, usize)>
+    {
+        Vec::new()
+    }
+
+    fn get_cascading_references_to(&self, _referenced_table_id: u64, _referenced_id: usize) -> Vec<usize>
+    {
+        Vec::new()
+    }
+
+    fn remove_entity(&mut self, id: usize) -> bool
+    {
+        self.remove(id)
+    }
+
+    fn get_id(&self) -> u64
+    {
+        self.id
+    }
+
+    fn shrink_to_fit(&mut self)
+    {
+        self.positions.shrink_to_fit();
+    }
+
+    fn find_orphaned_references(&self, _exists: &dyn Fn(u64, usize) -> bool) -> Vec<OrphanedReferences>
+    {
+        Vec::new()
+    }
+
+    fn check_entity_size(&self, _id: usize) -> Result<(), String>
+    {
+        Ok(())
+    }
+
+    fn check_unique_constraints(&self, _id: usize) -> Result<(), String>
+    {
+        Ok(())
+    }
+
+    fn serialize_rows(&self) -> Result<Vec<u8>, String>
+    {
+        let rows = self.read_all().into_iter().map(|(id, serialized_item)| bincode::deserialize::<T>(&serialized_item).map(|item| (id, item))).collect::<Result<Vec<(usize, T)>, _>>().map_err(|e| e.to_string())?;
+        bincode::serialize(&rows).map_err(|e| e.to_string())
+    }
+
+    fn load_rows(&mut self, rows: &[u8], max_used_id: usize) -> Result<(), String>
+    {
+        // Loaded entities could not be rolled back
+        if self.transaction_manager.lock().unwrap().is_transaction_running()
+        {
+            return Err(String::from("Rows can not be loaded in a transaction"));
+        }
+        let rows: Vec<(usize, T)> = bincode::deserialize(rows).map_err(|e| e.to_string())?;
+        let storage = self.storage.get_mut().unwrap();
+        storage.cache.clear();
+        storage.last_accesses.clear();
+        self.positions.clear();
+        for (id, item) in rows
+        {
+            self.id_allocator.reserve(id);
+            self.max_used_id = self.max_used_id.max(id);
+            self.write(id, &bincode::serialize(&item).map_err(|e| e.to_string())?);
+        }
+        if max_used_id > 0
+        {
+            self.id_allocator.reserve(max_used_id);
+            self.max_used_id = self.max_used_id.max(max_used_id);
+        }
+        Ok(())
+    }
+
+    fn get_max_used_id(&self) -> usize
+    {
+        self.max_used_id
+    }
+
+    fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>)
+    {
+        self.transaction_manager = transaction_manager;
+    }
+
+    fn get_transaction_manager(&self) -> Arc<Mutex<TransactionManager>>
+    {
+        self.transaction_manager.clone()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    // Structs of cold tables are not loaded to the memory, only the entities are counted
+    fn warm(&self) -> usize
+    {
+        self.positions.len()
+    }
+
+    fn diff(&self, other: &dyn TableBase) -> Option<TableDiff>
+    {
+        let other = other.as_any().downcast_ref::<ColdTable<T>>().expect("Tables of different types can not be compared");
+        let mut diff = TableDiff { table_name: self.name, table_id: self.id, added: Vec::new(), removed: Vec::new(), changed: Vec::new() };
+        let other_rows: HashMap<usize, Vec<u8>> = other.read_all().into_iter().collect();
+        for (id, serialized_item) in self.read_all()
+        {
+            match other_rows.get(&id)
+            {
+                Some(other_serialized_item) => if serialized_item != *other_serialized_item
+                {
+                    diff.changed.push(id);
+                },
+                None => diff.removed.push(id)
+            }
+        }
+        diff.added = other_rows.keys().filter(|id| !self.positions.contains_key(id)).copied().collect();
+        if diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty()
+        {
+            return None;
+        }
+        diff.added.sort_unstable();
+        Some(diff)
+    }
+
+    #[cfg(feature = "table-stats")]
+    fn get_access_stats(&self) -> TableAccessStats
+    {
+        self.access_counters.get_stats(self.name, self.id)
+    }
+}
+
+impl<T> TableSet for ColdTable<T> where T: Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>
+    {
+        if table_id == self.id { Some(self) } else { None }
+    }
+
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>
+    {
+        if table_id == self.id { Some(self) } else { None }
+    }
+
+    fn get_tables(&self) -> Vec<&dyn TableBase>
+    {
+        vec![self]
+    }
+
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>
+    {
+        vec![self]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use microdb_derive::{Database, DatabaseFactory};
+    use crate::{Database, DatabaseFactory};
+    use crate::test_fixtures::*;
+
+    #[derive(Database, DatabaseFactory)]
+    struct ArchiveDatabase
+    {
+        flights: ColdTable::<Flight>
+    }
+
+    fn create_cold_table(cache_capacity: usize) -> ColdTable<Flight>
+    {
+        let mut flights = ColdTable::new("flights", Arc::new(Mutex::new(TransactionManager::new())));
+        flights.set_directory(&create_test_directory("cold_table"));
+        flights.set_cache_capacity(cache_capacity);
+        flights
+    }
+
+    #[test]
+    fn entities_are_read_from_the_file_when_the_table_exceeds_the_cache()
+    {
+        let mut flights = create_cold_table(10);
+        let ids: Vec<usize> = (0..100).map(|seats| flights.add(Box::new(flight(&format!("MA{}", seats), seats)))).collect();
+
+        // Reading all entities twice evicts every entity from the cache before it is read again
+        for _ in 0..2
+        {
+            for (seats, id) in ids.iter().enumerate()
+            {
+                assert_eq!(*flights.get(*id).unwrap(), flight(&format!("MA{}", seats), seats));
+            }
+        }
+        let storage = flights.storage.lock().unwrap();
+        assert_eq!(storage.cache.len(), 10);
+        assert_eq!(storage.last_accesses.len(), 10);
+        assert!(storage.cache.keys().all(|id| ids[90..].contains(id)));
+    }
+
+    #[test]
+    fn changes_are_rolled_back_and_the_cache_is_not_stale()
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let db_lock = RwLock::new(ArchiveDatabase::create_database(transaction_manager_ref.clone()));
+        let mut db = db_lock.write().unwrap();
+        db.flights.set_directory(&create_test_directory("cold_table_rollback"));
+        let updated_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let removed_id = db.flights.add(Box::new(flight("MA200", 20)));
+        db.flights.get(updated_id);
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.update(updated_id, Box::new(flight("MA100", 5)));
+        assert_eq!(db.flights.get(updated_id).unwrap().seats, 5);
+        db.flights.remove(removed_id);
+        let added_id = db.flights.add(Box::new(flight("MA300", 30)));
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(db.flights.get(updated_id).unwrap().seats, 10);
+        assert_eq!(*db.flights.get(removed_id).unwrap(), flight("MA200", 20));
+        assert!(db.flights.get(added_id).is_none());
+    }
+
+    #[test]
+    fn load_rows_fails_in_a_transaction()
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let mut flights = ColdTable::<Flight>::new("flights", transaction_manager_ref.clone());
+        let rows = bincode::serialize(&vec![(1usize, flight("MA100", 10))]).unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        assert_eq!(flights.load_rows(&rows, 1), Err(String::from("Rows can not be loaded in a transaction")));
+        assert!(!flights.contains(1));
+        transaction_manager_ref.lock().unwrap().commit_transaction();
+
+        flights.load_rows(&rows, 1).unwrap();
+        assert_eq!(*flights.get(1).unwrap(), flight("MA100", 10));
+    }
+}
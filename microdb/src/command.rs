@@ -1,4 +1,7 @@
-use crate::{Database};
+use crate::{Database, EngineError, SharedCommand};
+use std::any::Any;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::sync::Arc;
 use serde::{Serialize, de::DeserializeOwned};
 
 // ***************************** Command Definition ***************************** //
@@ -6,30 +9,84 @@ use serde::{Serialize, de::DeserializeOwned};
 pub trait CommandDefinitionBase<D> where D: Database
 {
   fn create_from_serialized(&self, serialized_parameters: Box<Vec<u8>>) -> Box<dyn CommandBase<D> + '_>;  
+
+  // Deserialize parameters from a borrowed buffer and run the command without creating a command object
+  fn run_serialized(&self, db: &mut D, serialized_parameters: &[u8]) -> Result<CommandOutcome, CommandFailure>;
+
+  // Deserialize parameters and create a command, what can be pushed to the engine (see CommandEngine::push_serialized)
+  fn create_shared(&self, serialized_parameters: &[u8]) -> Result<SharedCommand<D>, String>;
+
+  // Deserialize parameters and convert them to JSON (used by the NDJSON export of the transaction log)
+  #[cfg(feature = "ndjson")]
+  fn parameters_to_json(&self, serialized_parameters: &[u8]) -> Result<serde_json::Value, String>;
 }
 
-#[derive(Clone)]
-pub struct CommandDefinition<D, P> where D: Database, P: Serialize + DeserializeOwned
+// Maximum fan-out of a command and the function getting the fan-out from the parameters
+type FanOutLimit<P> = (usize, fn(&P) -> usize);
+
+// The result type R is () and the error type E is String by default
+pub struct CommandDefinition<D, P, R = (), E = String> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
 {
   name: &'static str,
-  cmd: fn (&mut D, &P) -> Result<(), String>  
+  cmd: fn (&mut D, &P) -> Result<R, E>,
+  // Durable commands are written to the transaction storage and replayed on startup
+  durable: bool,
+  // Maximum fan-out of the command and the function getting the fan-out from the parameters (see with_max_fan_out)
+  fan_out_limit: Option<FanOutLimit<P>>
+}
+
+// Implemented manually, because deriving would require D and P to be Clone
+impl<D, P, R, E> Clone for CommandDefinition<D, P, R, E> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
+{
+  fn clone(&self) -> Self
+  {
+    Self {name: self.name, cmd: self.cmd, durable: self.durable, fan_out_limit: self.fan_out_limit}
+  }
 }
 
-impl<D, P> CommandDefinition<D, P> where D: Database, P: Serialize + DeserializeOwned
+impl<D, P, R, E> CommandDefinition<D, P, R, E> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
 {
-  pub fn new(name: &'static str, cmd: fn (&mut D, &P) -> Result<(), String>) -> Self
+  pub fn new(name: &'static str, cmd: fn (&mut D, &P) -> Result<R, E>) -> Self
+  {
+    Self {name, cmd, durable: true, fan_out_limit: None}
+  }
+
+  // Limit the fan-out of the command (lowering it makes the replay fail for commands accepted earlier)
+  pub fn with_max_fan_out(mut self, max_fan_out: usize, fan_out: fn(&P) -> usize) -> Self
+  {
+    self.fan_out_limit = Some((max_fan_out, fan_out));
+    self
+  }
+
+  // Mark the command as non-durable: it is applied, but not written to the transaction storage and not replayed
+  pub fn non_durable(mut self) -> Self
   {
-    Self {name, cmd}
+    self.durable = false;
+    self
   }
 
-  pub fn create(&self, p: P) -> Command<D, P>
+  pub fn is_durable(&self) -> bool
   {
-    return Command { definition: CommandDefinition { name: self.name, cmd: self.cmd }, parameters: p };
+    self.durable
   }
 
-  fn run(&self, db: &mut D, parameters: &P) -> Result<(), String>
+  pub fn create(&self, p: P) -> Command<D, P, R, E>
   {
-    return (self.cmd)(db, parameters);
+    Command { definition: self.clone(), parameters: p }
+  }
+
+  // Errors detected by the engine (like exceeding the fan-out limit) are Strings, not values of the error type of the command
+  fn run(&self, db: &mut D, parameters: &P) -> Result<CommandOutcome, CommandFailure>
+  {
+    if let Some((max_fan_out, fan_out)) = self.fan_out_limit
+    {
+      let fan_out = fan_out(parameters);
+      if fan_out > max_fan_out
+      {
+        return Err(CommandFailure::from(format!("Fan-out of command {} is {}, maximum is {}", self.name, fan_out, max_fan_out)));
+      }
+    }
+    (self.cmd)(db, parameters).map(Into::into).map_err(CommandFailure::new)
   }
 
   pub fn get_name(&self) -> &'static str
@@ -37,64 +94,694 @@ impl<D, P> CommandDefinition<D, P> where D: Database, P: Serialize + Deserialize
     self.name
   }
 
-  pub fn get_cmd(&self) -> fn (&mut D, &P) -> Result<(), String>  
+  pub fn get_cmd(&self) -> fn (&mut D, &P) -> Result<R, E>
   {
     self.cmd
   }
 }
 
-impl<D, P> CommandDefinitionBase<D> for CommandDefinition<D, P> where D: Database, P: Serialize + DeserializeOwned
+#[cfg(feature = "schemars")]
+impl<D, P, R, E> CommandDefinition<D, P, R, E> where D: Database, P: Serialize + DeserializeOwned + schemars::JsonSchema, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
+{
+  // Get the JSON schema of the parameters of the command
+  pub fn get_parameters_schema(&self) -> schemars::Schema
+  {
+    schemars::schema_for!(P)
+  }
+}
+
+// Commands pushed to the engine are shared with the command processing thread, so their parameters must be Send and Sync
+impl<D, P, R, E> CommandDefinitionBase<D> for CommandDefinition<D, P, R, E> where D: Database + 'static, P: Serialize + DeserializeOwned + Send + Sync + 'static, R: Into<CommandOutcome> + 'static, E: Display + Send + Sync + 'static
 {
   fn create_from_serialized(&self, serialized_parameters: Box<Vec<u8>>) -> Box<dyn CommandBase<D> + '_>
   {
     let parameters = bincode::deserialize::<P>(&serialized_parameters[..]).unwrap();
-    return Box::new(Command::<D, P> { definition: CommandDefinition { name: self.name, cmd: self.cmd }, parameters });
+    Box::new(Command::<D, P, R, E> { definition: self.clone(), parameters })
   } 
+
+  fn run_serialized(&self, db: &mut D, serialized_parameters: &[u8]) -> Result<CommandOutcome, CommandFailure>
+  {
+    let parameters = bincode::deserialize::<P>(serialized_parameters).map_err(|e| CommandFailure::from(e.to_string()))?;
+    self.run(db, &parameters)
+  }
+
+  fn create_shared(&self, serialized_parameters: &[u8]) -> Result<SharedCommand<D>, String>
+  {
+    let parameters = bincode::deserialize::<P>(serialized_parameters).map_err(|e| e.to_string())?;
+    Ok(Arc::new(self.create(parameters)))
+  }
+
+  #[cfg(feature = "ndjson")]
+  fn parameters_to_json(&self, serialized_parameters: &[u8]) -> Result<serde_json::Value, String>
+  {
+    let parameters = bincode::deserialize::<P>(serialized_parameters).map_err(|e| e.to_string())?;
+    serde_json::to_value(&parameters).map_err(|e| e.to_string())
+  }
 }
 
 // ********************************** Command *********************************** //
 
 pub trait CommandBase<D> where D: Database
 {
-  fn run(&self, db: &mut D) -> Result<(), String>;
+  fn run(&self, db: &mut D) -> Result<CommandOutcome, CommandFailure>;
 
   fn get_name(&self) -> &'static str;  
+
+  // Returns false if the command must not be written to the transaction storage
+  fn is_durable(&self) -> bool;
   
-  fn get_serialized_parameters(&self) -> Vec<u8>;
+  fn get_serialized_parameters(&self) -> Result<Vec<u8>, String>;
 }
 
-pub struct Command<D, P> where D: Database, P: Serialize + DeserializeOwned
+pub struct Command<D, P, R = (), E = String> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
 {
-  definition: CommandDefinition<D, P>,
+  definition: CommandDefinition<D, P, R, E>,
   parameters: P
 }
 
-impl<D, P> CommandBase<D> for Command<D, P> where D: Database, P: Serialize + DeserializeOwned
+impl<D, P, R, E> Command<D, P, R, E> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
+{
+  // Get the typed parameters of the command (e.g. to inspect a command before pushing it)
+  pub fn get_parameters(&self) -> &P
+  {
+    &self.parameters
+  }
+}
+
+impl<D, P, R, E> CommandBase<D> for Command<D, P, R, E> where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
 {
-  fn run(&self, db: &mut D) -> Result<(), String>
+  fn run(&self, db: &mut D) -> Result<CommandOutcome, CommandFailure>
   {    
-    return self.definition.run(db, &self.parameters);
+    self.definition.run(db, &self.parameters)
+  }
+
+  fn get_name(&self) -> &'static str
+  {
+    self.definition.name
+  }
+
+  fn is_durable(&self) -> bool
+  {
+    self.definition.durable
+  }
+
+  fn get_serialized_parameters(&self) -> Result<Vec<u8>, String>
+  {
+    bincode::serialize(&self.parameters).map_err(|e| e.to_string())
+  }
+}
+
+// ************************** Multi-Command Transaction ************************** //
+
+// Name of the records of multi-command transactions in the transaction log (see CommandEngine::push_transaction)
+pub const TRANSACTION_COMMAND_NAME: &str = "$transaction";
+
+// Commands run in order in a single transaction, what is committed only if all of them succeed
+pub(crate) struct TransactionCommand<D>
+{
+  commands: Vec<SharedCommand<D>>
+}
+
+impl<D> TransactionCommand<D>
+{
+  pub(crate) fn new(commands: Vec<SharedCommand<D>>) -> Self
+  {
+    Self { commands }
+  }
+}
+
+impl<D> CommandBase<D> for TransactionCommand<D> where D: Database
+{
+  // Outcomes of the commands are merged, and the first failure fails the whole transaction
+  fn run(&self, db: &mut D) -> Result<CommandOutcome, CommandFailure>
+  {
+    let mut outcome = CommandOutcome::default();
+    for command in &self.commands
+    {
+      outcome.merge(command.run(db)?);
+    }
+    Ok(outcome)
   }
 
   fn get_name(&self) -> &'static str
   {
-    &self.definition.name
+    TRANSACTION_COMMAND_NAME
+  }
+
+  fn is_durable(&self) -> bool
+  {
+    self.commands.iter().any(|command| command.is_durable())
+  }
+
+  fn get_serialized_parameters(&self) -> Result<Vec<u8>, String>
+  {
+    let mut serialized_commands = Vec::new();
+    for command in self.commands.iter().filter(|command| command.is_durable())
+    {
+      serialized_commands.push((command.get_name(), command.get_serialized_parameters()?));
+    }
+    bincode::serialize(&serialized_commands).map_err(|e| e.to_string())
+  }
+}
+
+// Deserialize the names and the serialized parameters of the commands in the record of a multi-command transaction
+pub(crate) fn deserialize_transaction_commands(serialized_parameters: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String>
+{
+  bincode::deserialize(serialized_parameters).map_err(|e| e.to_string())
+}
+
+// ************************** Command Parameters Builder ************************* //
+
+// State of a field of a parameters builder generated by #[derive(CommandParams)]
+pub struct Unset;
+
+pub struct Set<T>(pub T);
+
+// ******************************* Command Outcome ******************************* //
+
+// Result of a successful command. Warnings are reported to the caller (see CommitHandle), but they do not roll back the transaction.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CommandOutcome
+{
+  pub warnings: Vec<String>,
+  // Commands pushed by the engine after the transaction is committed (see with_follow_up)
+  pub follow_up_commands: Vec<FollowUpCommand>
+}
+
+// A command returned by another command to be pushed after it (like the next step of a multi-step process)
+#[derive(Debug, Clone, PartialEq)]
+pub struct FollowUpCommand
+{
+  pub name: &'static str,
+  pub serialized_parameters: Vec<u8>
+}
+
+impl CommandOutcome
+{
+  // Create an outcome with a single warning
+  pub fn with_warning(warning: impl Into<String>) -> Self
+  {
+    Self { warnings: vec![warning.into()], follow_up_commands: Vec::new() }
+  }
+
+  // Add a command pushed by the engine after the transaction is committed
+  pub fn with_follow_up<D, P, R, E>(mut self, definition: &CommandDefinition<D, P, R, E>, parameters: &P) -> Self where D: Database, P: Serialize + DeserializeOwned, R: Into<CommandOutcome>, E: Display + Send + Sync + 'static
+  {
+    let serialized_parameters = bincode::serialize(parameters).expect("Serializing the parameters of a follow-up command failed");
+    self.follow_up_commands.push(FollowUpCommand { name: definition.name, serialized_parameters });
+    self
+  }
+}
+
+impl CommandOutcome
+{
+  // Append the warnings and the follow-up commands of another outcome (used by multi-command transactions)
+  fn merge(&mut self, other: CommandOutcome)
+  {
+    self.warnings.extend(other.warnings);
+    self.follow_up_commands.extend(other.follow_up_commands);
   }
+}
 
-  fn get_serialized_parameters(&self) -> Vec<u8>
+impl From<()> for CommandOutcome
+{
+  fn from(_: ()) -> Self
   {
-    bincode::serialize(&self.parameters).unwrap()
+    Self::default()
   }
 }
 
+// ******************************* Command Failure ******************************* //
+
+// Error of a failed command (the typed error value is kept, see get_error)
+#[derive(Clone)]
+pub struct CommandFailure
+{
+  pub message: String,
+  error: Arc<dyn Any + Send + Sync>
+}
+
+impl CommandFailure
+{
+  pub fn new<E>(error: E) -> Self where E: Display + Send + Sync + 'static
+  {
+    Self { message: error.to_string(), error: Arc::new(error) }
+  }
+
+  // Get the error value returned by the command, or None if the error is not of type E (like errors detected by the engine, what are Strings)
+  pub fn get_error<E>(&self) -> Option<&E> where E: 'static
+  {
+    self.error.downcast_ref::<E>()
+  }
+}
+
+impl From<String> for CommandFailure
+{
+  fn from(message: String) -> Self
+  {
+    Self::new(message)
+  }
+}
+
+// Implemented manually, because the error value is not Debug
+impl Debug for CommandFailure
+{
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "CommandFailure({:?})", self.message)
+  }
+}
+
+impl Display for CommandFailure
+{
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "{}", self.message)
+  }
+}
+
+// ******************************** Command Guard ******************************** //
+
+// Compute an aggregate of the database in a command and fail the command unless the condition holds
+pub fn guard<D, A>(db: &D, aggregate: impl FnOnce(&D) -> A, condition: impl FnOnce(&A) -> bool, error: impl FnOnce(&A) -> String) -> Result<A, String> where D: Database
+{
+  let value = aggregate(db);
+  if !condition(&value)
+  {
+    return Err(error(&value));
+  }
+  Ok(value)
+}
+
 // ***************************** Command Definitions ***************************** //
 
 pub trait CommandDirectory<D>
 {
-    fn get(&self, name: &str) -> Box<dyn CommandDefinitionBase<D>>;
+    // Get a command definition by its registered name, or None if there is no such command
+    fn try_get(&self, name: &str) -> Option<Box<dyn CommandDefinitionBase<D>>>;
+
+    // Get a command definition by its registered name (panics on unknown command)
+    fn get(&self, name: &str) -> Box<dyn CommandDefinitionBase<D>>
+    {
+        self.try_get(name).unwrap_or_else(|| panic!("Unknown command {}", name))
+    }
+
+    // Get the registered names of all commands (e.g. for generating documentation of the available commands)
+    fn list_commands(&self) -> Vec<&'static str>;
+
+    // Run the command of a record of the transaction log (an engine error for unknown commands and corrupted records)
+    fn run_record(&self, db: &mut D, name: &str, serialized_parameters: &[u8]) -> Result<Result<CommandOutcome, CommandFailure>, EngineError> where D: Database
+    {
+        if name != TRANSACTION_COMMAND_NAME
+        {
+            let command_definition = self.try_get(name).ok_or_else(|| EngineError::UnknownCommand(String::from(name)))?;
+            return Ok(command_definition.run_serialized(db, serialized_parameters));
+        }
+        let mut outcome = CommandOutcome::default();
+        for (name, serialized_parameters) in deserialize_transaction_commands(serialized_parameters).map_err(EngineError::Serialization)?
+        {
+            let command_definition = self.try_get(&name).ok_or(EngineError::UnknownCommand(name))?;
+            match command_definition.run_serialized(db, &serialized_parameters)
+            {
+                Ok(command_outcome) => outcome.merge(command_outcome),
+                Err(failure) => return Ok(Err(failure))
+            }
+        }
+        Ok(Ok(outcome))
+    }
+
+    // Check that the commands of a record of the transaction log are registered, without running them
+    fn check_record(&self, name: &str, serialized_parameters: &[u8]) -> Result<(), EngineError>
+    {
+        let names = match name
+        {
+            TRANSACTION_COMMAND_NAME => deserialize_transaction_commands(serialized_parameters).map_err(EngineError::Serialization)?.into_iter().map(|(name, _)| name).collect(),
+            _ => vec![String::from(name)]
+        };
+        match names.into_iter().find(|name| self.try_get(name).is_none())
+        {
+            Some(name) => Err(EngineError::UnknownCommand(name)),
+            None => Ok(())
+        }
+    }
+}
+
+// JSON schemas of the parameters of the commands (generated for directories marked by #[command_schemas])
+#[cfg(feature = "schemars")]
+pub trait CommandSchemas
+{
+    // Get the registered names of all commands with the JSON schemas of their parameters, in the order of list_commands
+    fn command_schemas(&self) -> Vec<(&'static str, schemars::Schema)>;
 }
 
 pub trait CommandDirectoryFactory
 {
   fn new() -> Self;  
 }
+
+// ******************************** Command Error ******************************** //
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError
+{
+  // The command returned an error and its transaction was rolled back
+  Failed(String),
+  // The command engine stopped before the result of the command was known
+  EngineStopped
+}
+
+impl Display for CommandError
+{
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+  {
+    match self
+    {
+      CommandError::Failed(error) => write!(f, "Command failed: {}", error),
+      CommandError::EngineStopped => write!(f, "Command engine stopped")
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::test_fixtures::*;
+  use crate::{Engine, TransactionStatus, transaction_storage::{MemoryTransactionStorage, TransactionLogReader}};
+  use microdb_derive::{command, CommandDirectory, CommandDirectoryFactory};
+  use futures::executor::block_on;
+
+  #[test]
+  fn run_serialized_runs_the_command_with_the_borrowed_parameters()
+  {
+    let (mut db, _) = create_database();
+    let commands = AirlineCommands::new();
+    let serialized_parameters = bincode::serialize(&flight("MA100", 10)).unwrap();
+
+    assert!(commands.add_flight.run_serialized(&mut db, &serialized_parameters).is_ok());
+    assert_eq!(db.flights.iter().next().unwrap().flight_number, "MA100");
+  }
+
+  #[test]
+  fn run_serialized_fails_on_corrupted_parameters()
+  {
+    let (mut db, _) = create_database();
+    let commands = AirlineCommands::new();
+
+    assert!(commands.add_flight.run_serialized(&mut db, &[0xFF]).is_err());
+    assert!(db.flights.is_empty());
+  }
+
+  #[derive(CommandDirectory)]
+  struct RenamedCommands
+  {
+    add_flight: CommandDefinition::<AirlineDatabase, Flight>
+  }
+
+  impl RenamedCommands
+  {
+    #[command("schedule_flight")]
+    fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+      db.flights.add(Box::new(flight.clone()));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn command_attribute_registers_the_command_with_its_name()
+  {
+    let commands = RenamedCommands { add_flight: RenamedCommands::add_flight_definition() };
+    assert_eq!(commands.add_flight.get_name(), "schedule_flight");
+    assert!(commands.try_get("add_flight").is_none());
+
+    // The directory dispatches by the registered name, so the replay finds the command
+    let (mut db, _) = create_database();
+    let serialized_parameters = bincode::serialize(&flight("MA100", 10)).unwrap();
+    assert!(commands.run_record(&mut db, "schedule_flight", &serialized_parameters).unwrap().is_ok());
+    assert_eq!(db.flights.len(), 1);
+  }
+
+  #[derive(CommandDirectory, CommandDirectoryFactory)]
+  struct LegacyCommands
+  {
+    #[command_name = "create_flight"]
+    add_flight: CommandDefinition::<AirlineDatabase, Flight>
+  }
+
+  impl LegacyCommands
+  {
+    fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+      db.flights.add(Box::new(flight.clone()));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn command_name_attribute_overrides_the_field_name()
+  {
+    let commands = LegacyCommands::new();
+    assert_eq!(commands.add_flight.get_name(), "create_flight");
+    assert_eq!(commands.list_commands(), vec!["create_flight"]);
+    assert!(commands.try_get("create_flight").is_some());
+    assert!(commands.try_get("add_flight").is_none());
+  }
+
+  #[derive(CommandDirectory, CommandDirectoryFactory)]
+  struct BookingCommands
+  {
+    add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+    reserve: CommandDefinition::<AirlineDatabase, Reservation>
+  }
+
+  impl BookingCommands
+  {
+    fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+      db.flights.add(Box::new(flight.clone()));
+      Ok(())
+    }
+
+    // Add the reservation, then fail unless the flight still has enough seats (counting the new reservation too)
+    fn reserve(db: &mut AirlineDatabase, reservation: &Reservation) -> Result<(), String>
+    {
+      db.reservations.add(Box::new(reservation.clone()));
+      let seats = db.flights.get(reservation.flight_id).unwrap().seats;
+      guard(db, |db| db.reservations.iter().filter(|other| other.flight_id == reservation.flight_id).count(),
+        |count| *count <= seats, |count| format!("{} reservations exceed the {} seats", count, seats))?;
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn guard_fails_the_command_when_the_aggregate_breaks_the_condition()
+  {
+    let (query_engine, mut command_engine) = Engine::builder(BookingCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+    let commands = BookingCommands::new();
+    command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 1)))).unwrap();
+    let flight_id = query_engine.query(|db| db.flights.iter_with_ids().next().unwrap().0);
+
+    let first = command_engine.push_command_with_handle(Arc::new(commands.reserve.create(reservation(flight_id, "Alice")))).unwrap();
+    let second = command_engine.push_command_with_handle(Arc::new(commands.reserve.create(reservation(flight_id, "Bob")))).unwrap();
+
+    assert!(block_on(first).is_ok());
+    assert!(matches!(block_on(second), Err(CommandError::Failed(message)) if message == "2 reservations exceed the 1 seats"));
+    assert_eq!(query_engine.query(|db| db.reservations.iter().map(|reservation| reservation.passenger.clone()).collect::<Vec<_>>()), vec!["Alice"]);
+  }
+
+  #[test]
+  fn get_parameters_returns_the_parameters_the_command_was_created_with()
+  {
+    let commands = AirlineCommands::new();
+    let command = commands.add_flight.create(flight("MA100", 10));
+
+    assert_eq!(command.get_parameters(), &flight("MA100", 10));
+  }
+
+  #[test]
+  fn list_commands_returns_the_registered_names_in_the_order_of_the_fields()
+  {
+    assert_eq!(AirlineCommands::new().list_commands(), vec!["add_flight", "add_reservation", "add_temporary_flight", "add_flight_and_fail"]);
+  }
+
+  // Parameters describing their JSON schema for SchemaCommands
+  #[cfg(feature = "schemars")]
+  #[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+  struct Route
+  {
+    from: String,
+    to: String
+  }
+
+  #[cfg(feature = "schemars")]
+  #[derive(CommandDirectory, CommandDirectoryFactory)]
+  #[command_schemas]
+  struct SchemaCommands
+  {
+    add_route: CommandDefinition::<AirlineDatabase, Route>,
+    close_airport: CommandDefinition::<AirlineDatabase, String>
+  }
+
+  #[cfg(feature = "schemars")]
+  impl SchemaCommands
+  {
+    fn add_route(_db: &mut AirlineDatabase, _route: &Route) -> Result<(), String>
+    {
+      Ok(())
+    }
+
+    fn close_airport(_db: &mut AirlineDatabase, _airport: &String) -> Result<(), String>
+    {
+      Ok(())
+    }
+  }
+
+  #[cfg(feature = "schemars")]
+  #[test]
+  fn command_schemas_describe_the_parameters_of_the_commands()
+  {
+    let command_schemas = SchemaCommands::new().command_schemas();
+
+    assert_eq!(command_schemas.iter().map(|(name, _)| *name).collect::<Vec<_>>(), vec!["add_route", "close_airport"]);
+    let route_schema = command_schemas[0].1.as_value();
+    assert_eq!(route_schema["title"], "Route");
+    assert_eq!((route_schema["required"][0].as_str(), route_schema["required"][1].as_str()), (Some("from"), Some("to")));
+    assert_eq!(route_schema["properties"]["from"]["type"], "string");
+    assert_eq!(command_schemas[1].1.as_value()["type"], "string");
+  }
+
+  #[derive(CommandDirectory)]
+  struct BatchCommands
+  {
+    add_flights: CommandDefinition::<AirlineDatabase, Vec<Flight>>
+  }
+
+  impl BatchCommands
+  {
+    fn new() -> Self
+    {
+      Self { add_flights: CommandDefinition::new("add_flights", Self::add_flights).with_max_fan_out(2, |flights| flights.len()) }
+    }
+
+    // Command functions take the parameters by reference, so the Vec can not be a slice
+    #[allow(clippy::ptr_arg)]
+    fn add_flights(db: &mut AirlineDatabase, flights: &Vec<Flight>) -> Result<(), String>
+    {
+      db.flights.extend(flights.iter().cloned().map(Box::new));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn with_max_fan_out_fails_the_command_above_the_limit_before_running_it()
+  {
+    let (mut db, _) = create_database();
+    let commands = BatchCommands::new();
+
+    let allowed = bincode::serialize(&vec![flight("MA100", 10), flight("MA101", 10)]).unwrap();
+    assert!(commands.add_flights.run_serialized(&mut db, &allowed).is_ok());
+    assert_eq!(db.flights.len(), 2);
+
+    let too_many = bincode::serialize(&vec![flight("MA102", 10), flight("MA103", 10), flight("MA104", 10)]).unwrap();
+    let failure = commands.add_flights.run_serialized(&mut db, &too_many).unwrap_err();
+    assert_eq!(failure.message, "Fan-out of command add_flights is 3, maximum is 2");
+    assert!(failure.get_error::<String>().is_some());
+    assert_eq!(db.flights.len(), 2);
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum ReservationError
+  {
+    NoFreeSeat,
+    UnknownFlight(usize)
+  }
+
+  impl Display for ReservationError
+  {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+    {
+      match self
+      {
+        ReservationError::NoFreeSeat => write!(f, "No free seat"),
+        ReservationError::UnknownFlight(flight_id) => write!(f, "Unknown flight {}", flight_id)
+      }
+    }
+  }
+
+  #[derive(CommandDirectory)]
+  struct SeatCommands
+  {
+    add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+    add_reservation: CommandDefinition::<AirlineDatabase, Reservation, (), ReservationError>
+  }
+
+  impl SeatCommands
+  {
+    fn new() -> Self
+    {
+      Self { add_flight: SeatCommands::add_flight_definition(), add_reservation: SeatCommands::add_reservation_definition() }
+    }
+
+    #[command("add_flight")]
+    fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+      db.flights.add(Box::new(flight.clone()));
+      Ok(())
+    }
+
+    // Take a seat of the flight for the reservation
+    #[command("add_reservation")]
+    fn add_reservation(db: &mut AirlineDatabase, reservation: &Reservation) -> Result<(), ReservationError>
+    {
+      let flight = db.flights.get_mut(reservation.flight_id).ok_or(ReservationError::UnknownFlight(reservation.flight_id))?;
+      flight.seats = flight.seats.checked_sub(1).ok_or(ReservationError::NoFreeSeat)?;
+      db.reservations.add(Box::new(reservation.clone()));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn typed_errors_of_failed_commands_are_retrievable()
+  {
+    let (query_engine, mut command_engine) = Engine::builder(SeatCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+    let commands = SeatCommands::new();
+    command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 1)))).unwrap();
+    let flight_id = query_engine.query(|db| db.flights.iter_with_ids().next().unwrap().0);
+
+    let reserved = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+    let full = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Bob")))).unwrap();
+    let unknown = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(99, "Carol")))).unwrap();
+
+    assert!(command_engine.get_transaction_error(reserved).is_none());
+    let failure = command_engine.get_transaction_error(full).unwrap();
+    assert_eq!(failure.get_error::<ReservationError>(), Some(&ReservationError::NoFreeSeat));
+    assert_eq!(failure.message, "No free seat");
+    assert_eq!(command_engine.get_transaction_error(unknown).unwrap().get_error::<ReservationError>(), Some(&ReservationError::UnknownFlight(99)));
+    // The error keeps the type returned by the command
+    assert!(failure.get_error::<String>().is_none());
+    assert_eq!(query_engine.query(|db| db.reservations.len()), 1);
+  }
+
+  #[test]
+  fn commands_of_a_transaction_are_committed_or_rolled_back_together_and_replayed_from_one_record()
+  {
+    let storage = MemoryTransactionStorage::new();
+    let (query_engine, mut command_engine) = create_engine(storage.reopen());
+    let commands = command_engine.get_command_definitions();
+    let failed_id = command_engine.push_transaction(vec![Arc::new(commands.add_flight.create(flight("MA100", 10))), Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))]).unwrap();
+    assert_eq!(command_engine.get_transaction_status(failed_id), Ok(TransactionStatus::Failed));
+    assert_eq!(query_engine.query(|db| db.flights.len()), 0);
+
+    let transaction_id = command_engine.push_transaction(vec![Arc::new(commands.add_flight.create(flight("MA300", 10))), Arc::new(commands.add_reservation.create(reservation(1, "Alice")))]).unwrap();
+    assert_eq!(command_engine.get_transaction_status(transaction_id), Ok(TransactionStatus::Completed));
+    let rows = query_engine.query(get_rows);
+    assert_eq!(rows, vec![("flights", 1, String::from("MA300")), ("reservations", 1, String::from("Alice"))]);
+    drop(command_engine);
+
+    let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).map(|record| (record.transaction_id, record.name)).collect();
+    assert_eq!(records, vec![(failed_id, String::from(TRANSACTION_COMMAND_NAME)), (transaction_id, String::from(TRANSACTION_COMMAND_NAME))]);
+    let (query_engine, _command_engine) = create_engine(storage.reopen());
+    assert_eq!(query_engine.query(get_rows), rows);
+  }
+}
@@ -0,0 +1,240 @@
+use std::ops::{Deref, DerefMut};
+use std::fmt::{Debug, Formatter};
+use std::cell::RefCell;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize, Serializer, Deserializer, de::DeserializeOwned};
+
+// Cipher encrypting the serialized value of Encrypted fields (implemented by the application)
+pub trait FieldCipher: Send + Sync
+{
+    // Encrypt the serialized value of a field
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    // Decrypt a ciphertext produced by encrypt, or return an error if it cannot be decrypted (like a wrong key or a tampered log)
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+thread_local!
+{
+    // Cipher of the Encrypted fields serialized or deserialized by the current thread (see with_field_cipher)
+    static FIELD_CIPHER: RefCell<Option<Arc<dyn FieldCipher>>> = const { RefCell::new(None) };
+}
+
+// Run a function with the cipher of the Encrypted fields it serializes or deserializes on the current thread
+pub fn with_field_cipher<R>(cipher: Option<Arc<dyn FieldCipher>>, f: impl FnOnce() -> R) -> R
+{
+    // Restores the previous cipher even if the function panics (like a panicking command)
+    struct RestoreGuard(Option<Arc<dyn FieldCipher>>);
+
+    impl Drop for RestoreGuard
+    {
+        fn drop(&mut self)
+        {
+            FIELD_CIPHER.with(|field_cipher| *field_cipher.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore_guard = RestoreGuard(FIELD_CIPHER.with(|field_cipher| field_cipher.replace(cipher)));
+    f()
+}
+
+fn get_field_cipher() -> Option<Arc<dyn FieldCipher>>
+{
+    FIELD_CIPHER.with(|field_cipher| field_cipher.borrow().clone())
+}
+
+// Field stored in plaintext in the memory, but serialized as ciphertext by the cipher of with_field_cipher
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Encrypted<T>(T);
+
+impl<T> Encrypted<T>
+{
+    // Create an encrypted field from its plaintext value
+    pub fn new(val: T) -> Self
+    {
+        Encrypted(val)
+    }
+
+    // Get the plaintext value of the field
+    pub fn into_inner(self) -> T
+    {
+        self.0
+    }
+}
+
+impl<T> From<T> for Encrypted<T>
+{
+    fn from(val: T) -> Self
+    {
+        Encrypted(val)
+    }
+}
+
+impl<T> Deref for Encrypted<T>
+{
+    type Target = T;
+
+    // Dereference returns the plaintext value
+    fn deref(&self) -> &Self::Target
+    {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Encrypted<T>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target
+    {
+        &mut self.0
+    }
+}
+
+impl<T> Debug for Encrypted<T>
+{
+    // The value is not printed, so it does not leak to logs by debug formatting
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result
+    {
+        write!(f, "Encrypted(..)")
+    }
+}
+
+impl<T> Serialize for Encrypted<T> where T: Serialize
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer
+    {
+        let cipher = get_field_cipher().ok_or_else(|| serde::ser::Error::custom("No field cipher is set for an encrypted field"))?;
+        let plaintext = bincode::serialize(&self.0).map_err(serde::ser::Error::custom)?;
+        cipher.encrypt(&plaintext).serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Encrypted<T> where T: DeserializeOwned
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de>
+    {
+        let ciphertext = Vec::<u8>::deserialize(deserializer)?;
+        let cipher = get_field_cipher().ok_or_else(|| serde::de::Error::custom("No field cipher is set for an encrypted field"))?;
+        let plaintext = cipher.decrypt(&ciphertext).map_err(serde::de::Error::custom)?;
+        let val = bincode::deserialize(&plaintext).map_err(serde::de::Error::custom)?;
+        Ok(Encrypted(val))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_fixtures::create_test_directory;
+    use microdb_derive::*;
+
+    // Cipher XOR-ing the bytes with a key (for the tests only)
+    struct XorCipher(u8);
+
+    impl FieldCipher for XorCipher
+    {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>
+        {
+            plaintext.iter().map(|byte| byte ^ self.0).collect()
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, String>
+        {
+            Ok(self.encrypt(ciphertext))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    struct Passenger
+    {
+        name: Encrypted<String>,
+        seat: u32
+    }
+
+    #[derive(Database, DatabaseFactory)]
+    struct PassengerDatabase
+    {
+        passengers: Table::<Passenger>
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct PassengerCommands
+    {
+        add_passenger: CommandDefinition::<PassengerDatabase, Passenger>,
+        // Changes the seat of all passengers, then fails, so their serialized rollback states are restored
+        move_passengers_and_fail: CommandDefinition::<PassengerDatabase, u32>
+    }
+
+    impl PassengerCommands
+    {
+        fn add_passenger(db: &mut PassengerDatabase, passenger: &Passenger) -> Result<(), String>
+        {
+            db.passengers.add(Box::new(passenger.clone()));
+            Ok(())
+        }
+
+        fn move_passengers_and_fail(db: &mut PassengerDatabase, seat: &u32) -> Result<(), String>
+        {
+            for passenger in db.passengers.iter_mut()
+            {
+                passenger.seat = *seat;
+            }
+            Err(String::from("Passengers can not be moved"))
+        }
+    }
+
+    fn passenger(name: &str, seat: u32) -> Passenger
+    {
+        Passenger { name: Encrypted::new(String::from(name)), seat }
+    }
+
+    fn contains(bytes: &[u8], value: &str) -> bool
+    {
+        bytes.windows(value.len()).any(|window| window == value.as_bytes())
+    }
+
+    #[test]
+    fn encrypted_fields_are_written_to_the_log_and_the_snapshot_as_ciphertext()
+    {
+        let path = create_test_directory("encrypted");
+        let (_, mut command_engine) = Engine::builder(PassengerCommands::new(), Box::new(FileTransactionStorage::new(&path))).with_field_cipher(XorCipher(0x5A)).build();
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_passenger.create(passenger("Alice Plaintext", 1)))).unwrap();
+        command_engine.push_command(Arc::new(commands.move_passengers_and_fail.create(2))).unwrap();
+        command_engine.snapshot().unwrap();
+        command_engine.push_command(Arc::new(commands.add_passenger.create(passenger("Bob Plaintext", 3)))).unwrap();
+        command_engine.shutdown().unwrap();
+
+        let log = std::fs::read(format!("{}/transactions.bin", path)).unwrap();
+        let snapshot = std::fs::read(format!("{}/snapshot.bin", path)).unwrap();
+        assert!(!contains(&log, "Alice Plaintext") && !contains(&log, "Bob Plaintext"));
+        assert!(!contains(&snapshot, "Alice Plaintext"));
+
+        // The snapshot and the log are decrypted by the cipher of the restarted engine
+        let (query_engine, _) = Engine::builder(PassengerCommands::new(), Box::new(FileTransactionStorage::new(&path))).with_field_cipher(XorCipher(0x5A)).build();
+        let mut passengers = query_engine.query(|db| db.passengers.iter().map(|passenger| ((*passenger.name).clone(), passenger.seat)).collect::<Vec<_>>());
+        passengers.sort();
+        assert_eq!(passengers, vec![(String::from("Alice Plaintext"), 1), (String::from("Bob Plaintext"), 3)]);
+    }
+
+    #[test]
+    fn engines_of_the_same_process_use_their_own_cipher()
+    {
+        let first_storage = MemoryTransactionStorage::new();
+        let second_storage = MemoryTransactionStorage::new();
+        let (_, mut first_engine) = Engine::builder(PassengerCommands::new(), Box::new(first_storage.reopen())).with_field_cipher(XorCipher(0x5A)).build();
+        let (_, mut second_engine) = Engine::builder(PassengerCommands::new(), Box::new(second_storage.reopen())).with_field_cipher(XorCipher(0x3C)).build();
+        let commands = first_engine.get_command_definitions();
+        first_engine.push_command(Arc::new(commands.add_passenger.create(passenger("Alice", 1)))).unwrap();
+        second_engine.push_command(Arc::new(commands.add_passenger.create(passenger("Alice", 1)))).unwrap();
+
+        let first_record = TransactionLogReader::new(Box::new(first_storage.reopen())).next().unwrap();
+        let second_record = TransactionLogReader::new(Box::new(second_storage.reopen())).next().unwrap();
+        assert_ne!(first_record.serialized_parameters, second_record.serialized_parameters);
+        let decrypted = with_field_cipher(Some(Arc::new(XorCipher(0x3C))), || bincode::deserialize::<Passenger>(&second_record.serialized_parameters)).unwrap();
+        assert_eq!(*decrypted.name, "Alice");
+
+        // Encrypted fields can not be serialized outside of the engines
+        assert!(bincode::serialize(&passenger("Alice", 1)).is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use std::any::{Any, TypeId};
 use std::sync::{Arc, Mutex};
 use std::ops::{Deref, DerefMut};
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
 use log::debug;
-use serde::{Serialize, de::DeserializeOwned};
-use crate::transaction::{TransactionManager, TransactionEntry};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::Database;
+use crate::table::Table;
+use crate::transaction::{TransactionManager, TransactionEntry, RollbackState, FieldPatch};
 
 // Entity is a smart pointer to struct stored in a MicroDb table
 pub struct Entity<T> where T : Serialize + DeserializeOwned
@@ -17,7 +21,83 @@ pub struct Entity<T> where T : Serialize + DeserializeOwned
     // Reference to the transaction manager, what handles the transaction log in the memory
     transaction_manager: Arc<Mutex<TransactionManager>>,
     // Identifier of the last transacion the entity was modified in
-    last_modified_transaction_id: usize
+    last_modified_transaction_id: usize,
+    // Generation of the entity in its table, changing whenever the table creates a new entity object for the identifier (e.g. on rollback)
+    generation: u64,
+    // Function capturing the rollback state of the stored struct (see RollbackSerializer)
+    capture_rollback_state: fn(&T) -> RollbackState
+}
+
+// Identifier of an entity together with its generation, so a handle of a removed entity is detected
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EntityHandle
+{
+    pub id: usize,
+    pub generation: u64
+}
+
+// Typed reference to an entity of another table (not checked on commit, unlike foreign keys)
+#[derive(Serialize, Deserialize)]
+pub struct Ref<T>
+{
+    table_id: u64,
+    id: usize,
+    #[serde(skip)]
+    marker: PhantomData<fn() -> T>
+}
+
+impl<T> Ref<T> where T: Serialize + DeserializeOwned + 'static
+{
+    // Create a reference to an entity of the table with the unique identifier
+    pub fn new(table_id: u64, id: usize) -> Self
+    {
+        Ref { table_id, id, marker: PhantomData }
+    }
+
+    // Get the unique identifier of the referenced entity
+    pub fn get_id(&self) -> usize
+    {
+        self.id
+    }
+
+    // Get the unique identifier of the table of the referenced entity
+    pub fn get_table_id(&self) -> u64
+    {
+        self.table_id
+    }
+
+    // Get the referenced entity from the database, or None if it does not exist (or the table does not store structs of type T)
+    pub fn resolve<'a, D>(&self, db: &'a D) -> Option<&'a Entity<Box<T>>> where D: Database
+    {
+        db.try_get_table(self.table_id)?.as_any().downcast_ref::<Table<T>>()?.get(self.id)
+    }
+}
+
+// Implemented manually, because deriving would require T to be Clone
+impl<T> Clone for Ref<T>
+{
+    fn clone(&self) -> Self
+    {
+        *self
+    }
+}
+
+impl<T> Copy for Ref<T> {}
+
+impl<T> PartialEq for Ref<T>
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.table_id == other.table_id && self.id == other.id
+    }
+}
+
+impl<T> Debug for Ref<T>
+{
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result
+    {
+        write!(f, "Ref({}, {})", self.table_id, self.id)
+    }
 }
 
 impl<T> Entity<T> where T : Serialize + DeserializeOwned
@@ -25,7 +105,45 @@ impl<T> Entity<T> where T : Serialize + DeserializeOwned
     // Create a new entity
     pub fn new(id: usize, table_id: u64, val: T, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
     {
-        Entity { id, table_id, val, transaction_manager, last_modified_transaction_id: 0 }
+        Entity { id, table_id, val, transaction_manager, last_modified_transaction_id: 0, generation: 0, capture_rollback_state: |val| RollbackState::Serialized(bincode::serialize(val).unwrap()) }
+    }
+
+    // Set the function capturing the rollback state of a new entity (used by tables)
+    pub(crate) fn with_rollback_state_capture(mut self, capture_rollback_state: fn(&T) -> RollbackState) -> Self
+    {
+        self.capture_rollback_state = capture_rollback_state;
+        self
+    }
+
+    // Replace the transaction manager of the entity (used by tables)
+    pub(crate) fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>)
+    {
+        self.transaction_manager = transaction_manager;
+    }
+
+    // Set the generation of a new entity (used by tables)
+    pub(crate) fn with_generation(mut self, generation: u64) -> Self
+    {
+        self.generation = generation;
+        self
+    }
+
+    // Take the stored struct out of the entity (used by tables)
+    pub(crate) fn into_inner(self) -> T
+    {
+        self.val
+    }
+
+    // Get the generation of the entity in its table
+    pub fn get_generation(&self) -> u64
+    {
+        self.generation
+    }
+
+    // Get a handle of the entity, what can be resolved later by its table
+    pub fn get_handle(&self) -> EntityHandle
+    {
+        EntityHandle { id: self.id, generation: self.generation }
     }
 
     // Get the unique identifier of entity
@@ -33,6 +151,83 @@ impl<T> Entity<T> where T : Serialize + DeserializeOwned
     {
         self.id
     }
+
+    // Get mutable access to the stored struct (the original version is logged when the guard is acquired)
+    pub fn edit(&mut self) -> EntityEditGuard<'_, T>
+    {
+        EntityEditGuard { val: self.deref_mut() }
+    }
+
+    // Change a single field of the stored struct, logging only the original value of the field
+    pub fn update_field<F>(&mut self, field: fn(&mut T) -> &mut F, update: impl FnOnce(&mut F)) where T: 'static, F: Clone + Send + 'static
+    {
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+
+        if locked_transaction_manager.is_transaction_running() && locked_transaction_manager.get_transaction_id() > self.last_modified_transaction_id
+        {
+            debug!("Add transaction entry for a field of an existing entity (Table Id: {}, Entity Id: {})", self.table_id, self.id);
+
+            let original = field(&mut self.val).clone();
+            locked_transaction_manager.add_entry(TransactionEntry::Existing(
+                self.table_id,
+                self.id,
+                RollbackState::Patch(FieldPatch { field: (field as usize, TypeId::of::<F>()), restore: Box::new(move |val: &mut dyn Any| {
+                    let val = val.downcast_mut::<T>().ok_or_else(|| String::from("Patched entity has an unexpected type"))?;
+                    *field(val) = original;
+                    Ok(())
+                })})
+            ));
+        }
+        drop(locked_transaction_manager);
+
+        update(field(&mut self.val));
+    }
+
+    // Get mutable access to the stored struct without logging it (used by tables to roll back a field change)
+    pub(crate) fn get_mut_unlogged(&mut self) -> &mut T
+    {
+        &mut self.val
+    }
+
+    // Produce an owned projection of the stored struct (like a few of its fields). Queries should prefer it to cloning the whole struct.
+    pub fn project<R>(&self, f: impl FnOnce(&T) -> R) -> R
+    {
+        f(&self.val)
+    }
+}
+
+// Mutable access to the struct stored in an entity, what is already logged in the running transaction (see Entity::edit)
+pub struct EntityEditGuard<'a, T>
+{
+    val: &'a mut T
+}
+
+impl<T> Deref for EntityEditGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target
+    {
+        self.val
+    }
+}
+
+impl<T> DerefMut for EntityEditGuard<'_, T>
+{
+    // Changes are not logged, because the original version was logged when the guard was acquired
+    fn deref_mut(&mut self) -> &mut Self::Target
+    {
+        self.val
+    }
+}
+
+impl<T> Clone for Entity<T> where T : Clone + Serialize + DeserializeOwned
+{
+    // The copy shares the transaction manager of the original entity
+    fn clone(&self) -> Self
+    {
+        Entity { id: self.id, table_id: self.table_id, val: self.val.clone(), transaction_manager: self.transaction_manager.clone(), last_modified_transaction_id: self.last_modified_transaction_id, generation: self.generation, capture_rollback_state: self.capture_rollback_state }
+    }
 }
 
 impl<T> Deref for Entity<T> where T : Serialize + DeserializeOwned
@@ -65,8 +260,8 @@ impl<T> DerefMut for Entity<T> where T : Serialize + DeserializeOwned
                 locked_transaction_manager.add_entry(TransactionEntry::Existing(
                     self.table_id,
                     self.id,
-                    // Transaction entry contains the whole entity in serialized form
-                    bincode::serialize(&self.val).unwrap()
+                    // Transaction entry contains the whole entity (serialized or copied by the rollback serializer of the table)
+                    (self.capture_rollback_state)(&self.val)
                 ));
 
                 // Transaction id is stored in the entity, because no other transaction entry is needed in the same transaction
@@ -74,7 +269,7 @@ impl<T> DerefMut for Entity<T> where T : Serialize + DeserializeOwned
             }
         }
 
-        return &mut self.val
+        &mut self.val
     }
 }
 
@@ -85,4 +280,89 @@ impl<T> Display for Entity<T> where T : Display + Serialize + DeserializeOwned
     {     
         self.val.fmt(f)        
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use crate::test_fixtures::*;
+
+    #[test]
+    fn project_returns_an_owned_projection_of_the_struct()
+    {
+        let (mut db, _) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+
+        let (flight_number, seats) = db.flights.get(id).unwrap().project(|flight| (flight.flight_number.clone(), flight.seats));
+        db.flights.remove(id);
+        assert_eq!((flight_number.as_str(), seats), ("MA100", 10));
+    }
+
+    #[test]
+    fn changes_made_through_the_edit_guard_are_rolled_back()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        {
+            let entity = db.flights.get_mut(id).unwrap();
+            let mut edited_flight = entity.edit();
+            edited_flight.flight_number = String::from("MA101");
+            edited_flight.seats = 20;
+        }
+        assert_eq!(transaction_manager_ref.lock().unwrap().get_touched_entities().len(), 1);
+        assert_eq!((db.flights.get(id).unwrap().flight_number.as_str(), db.flights.get(id).unwrap().seats), ("MA101", 20));
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(**db.flights.get(id).unwrap(), Box::new(flight("MA100", 10)));
+    }
+
+    #[test]
+    fn ref_resolves_the_entity_through_the_database()
+    {
+        let (mut db, _) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let flight_ref: Ref<Flight> = Ref::new(db.flights.get_id(), id);
+
+        assert_eq!(flight_ref.resolve(&db).unwrap().flight_number, "MA100");
+        // References survive serialization (like in the parameters of a command)
+        let deserialized_ref: Ref<Flight> = bincode::deserialize(&bincode::serialize(&flight_ref).unwrap()).unwrap();
+        assert_eq!(deserialized_ref, flight_ref);
+
+        // References to removed entities and to tables of other types are not resolved
+        let reservation_ref: Ref<Reservation> = Ref::new(db.flights.get_id(), id);
+        assert!(reservation_ref.resolve(&db).is_none());
+        db.flights.remove(id);
+        assert!(flight_ref.resolve(&db).is_none());
+    }
+
+    #[test]
+    fn field_updates_are_rolled_back_to_the_original_value_of_the_field()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let entity = db.flights.get_mut(id).unwrap();
+        entity.update_field(|flight| &mut flight.seats, |seats| *seats -= 1);
+        entity.update_field(|flight| &mut flight.seats, |seats| *seats -= 1);
+        entity.update_field(|flight| &mut flight.to, |to| *to = String::from("CDG"));
+        assert_eq!((entity.seats, entity.to.as_str()), (8, "CDG"));
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(**db.flights.get(id).unwrap(), Box::new(flight("MA100", 10)));
+
+        // A field update after the whole struct is logged is rolled back by the logged struct
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let entity = db.flights.get_mut(id).unwrap();
+        entity.flight_number = String::from("MA200");
+        entity.update_field(|flight| &mut flight.seats, |seats| *seats = 0);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(**db.flights.get(id).unwrap(), Box::new(flight("MA100", 10)));
+    }
+}
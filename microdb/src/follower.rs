@@ -0,0 +1,124 @@
+use crate::{Database, DatabaseFactory, EngineError, QueryEngine, ReadySignal, replay_record};
+use crate::command::CommandDirectory;
+use crate::snapshot::Snapshot;
+use crate::transaction::TransactionManager;
+use crate::transaction_storage::LogTailer;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use log::warn;
+
+// Read replica of a database applying the records of the transaction log of a leader engine
+pub struct FollowerEngine<D, C> where D: Database, C: CommandDirectory<D>
+{
+    db_lock_arc: Arc<RwLock<D>>,
+    transaction_manager_ref: Arc<Mutex<TransactionManager>>,
+    command_definitions: C,
+    log_tailer: LogTailer,
+    // Identifier of the last transaction of the leader applied by the follower
+    last_applied_transaction_id: usize
+}
+
+impl<D, C> FollowerEngine<D, C> where D: Database + DatabaseFactory, C: CommandDirectory<D>
+{
+    // Create a follower of the leader writing its transaction log to the directory at path
+    pub fn new(command_definitions: C, path: &str, init: impl FnOnce(&mut D)) -> Result<Self, EngineError>
+    {
+        let log_tailer = LogTailer::new(path).map_err(|e| EngineError::StorageIo(e.to_string()))?;
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let mut db = D::create_database(transaction_manager_ref.clone());
+        init(&mut db);
+
+        let mut last_applied_transaction_id = 0;
+        // The leader flushes its log before writing a snapshot (the previous one is used if it is corrupted)
+        let serialized_snapshots = ["snapshot.bin", "snapshot.previous.bin"].into_iter().filter_map(|file_name| std::fs::read(format!("{}/{}", path, file_name)).ok());
+        for serialized_snapshot in serialized_snapshots
+        {
+            match Snapshot::deserialize(&serialized_snapshot).and_then(|snapshot| snapshot.load(&mut db).map(|_| snapshot))
+            {
+                Ok(snapshot) =>
+                {
+                    last_applied_transaction_id = snapshot.last_processed_transaction_id;
+                    break;
+                },
+                Err(error) => warn!("Snapshot is ignored: {}", error)
+            }
+        }
+
+        Ok(Self { db_lock_arc: Arc::new(RwLock::new(db)), transaction_manager_ref, command_definitions, log_tailer, last_applied_transaction_id })
+    }
+
+    // Get a query engine reading the database of the follower
+    pub fn get_query_engine(&self) -> QueryEngine<D>
+    {
+        QueryEngine { db_lock_arc: self.db_lock_arc.clone(), published_snapshot: Arc::default(), ready_signal: Arc::new(ReadySignal::new_ready()) }
+    }
+
+    // Get the identifier of the last transaction of the leader applied by the follower
+    pub fn get_last_applied_transaction_id(&self) -> usize
+    {
+        self.last_applied_transaction_id
+    }
+
+    // Apply the records appended to the log of the leader since the last call and get their number
+    pub fn catch_up(&mut self) -> Result<usize, EngineError>
+    {
+        let failed_transaction_ids: HashSet<usize> = self.log_tailer.get_failed_transaction_ids().map_err(|e| EngineError::StorageIo(e.to_string()))?.into_iter().collect();
+        let mut applied_count = 0;
+        while let Some(serialized_transaction) = self.log_tailer.next_record().map_err(|e| EngineError::StorageIo(e.to_string()))?
+        {
+            // Transactions in the snapshot are already applied
+            if serialized_transaction.transaction_id <= self.last_applied_transaction_id
+            {
+                continue;
+            }
+            if !failed_transaction_ids.contains(&serialized_transaction.transaction_id)
+            {
+                let mut db = self.db_lock_arc.write().map_err(|_| EngineError::LockPoisoned("database"))?;
+                // Failed commands are rolled back by replay_record
+                let _ = replay_record(&mut db, &self.transaction_manager_ref, &self.command_definitions, &serialized_transaction)?;
+                applied_count += 1;
+            }
+            self.last_applied_transaction_id = serialized_transaction.transaction_id;
+        }
+        Ok(applied_count)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::command::CommandDirectoryFactory;
+    use crate::test_fixtures::*;
+    use crate::transaction_storage::FileTransactionStorage;
+
+    #[test]
+    fn follower_converges_to_the_leader_including_failed_transactions()
+    {
+        let path = create_test_directory("follower");
+        let (query_engine, mut command_engine) = create_engine(FileTransactionStorage::new(&path));
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        // Fails by the foreign key check only, the command itself succeeds
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(99, "Bob")))).unwrap();
+        let flight_id = query_engine.query(|db| db.flights.iter_with_ids().next().unwrap().0);
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+        command_engine.shutdown().unwrap();
+        let leader_rows = query_engine.query(get_rows);
+        assert_eq!(leader_rows.len(), 2);
+
+        // The failed transactions persisted by the leader are skipped
+        let mut follower = FollowerEngine::new(AirlineCommands::new(), &path, init).unwrap();
+        assert_eq!(follower.catch_up(), Ok(2));
+        assert_eq!(follower.get_last_applied_transaction_id(), 4);
+        assert_eq!(follower.get_query_engine().query(get_rows), leader_rows);
+        assert_eq!(follower.catch_up(), Ok(0));
+
+        // Without the failed transaction identifiers (like before the leader persists them) the failing transactions are rolled back
+        std::fs::remove_file(format!("{}/failed_transactions.bin", path)).unwrap();
+        let mut follower = FollowerEngine::new(AirlineCommands::new(), &path, init).unwrap();
+        assert_eq!(follower.catch_up(), Ok(4));
+        assert_eq!(follower.get_query_engine().query(get_rows), leader_rows);
+    }
+}
@@ -0,0 +1,130 @@
+// Allocates the unique identifiers of the entities added to a table (it must be deterministic for the replay)
+pub trait IdAllocator: Send + Sync
+{
+    // Allocate the next identifier. Identifiers must be positive and must not have been allocated or reserved before.
+    fn allocate(&mut self) -> usize;
+
+    // Make sure an identifier given by the caller (like a seeded entity) is never allocated
+    fn reserve(&mut self, id: usize);
+
+    // Copy the allocator (used when the table is cloned)
+    fn clone_box(&self) -> Box<dyn IdAllocator>;
+}
+
+// Allocates the identifiers first_id, first_id + increment, first_id + 2 * increment, ... (the default allocator of tables)
+#[derive(Clone)]
+pub struct SequentialAllocator
+{
+    next_id: usize,
+    increment: usize
+}
+
+impl SequentialAllocator
+{
+    pub fn new(first_id: usize, increment: usize) -> Self
+    {
+        assert!(first_id > 0, "Identifier 0 is never used by tables");
+        assert!(increment > 0, "Increment of identifiers must be positive");
+        Self { next_id: first_id, increment }
+    }
+}
+
+impl Default for SequentialAllocator
+{
+    fn default() -> Self
+    {
+        Self::new(1, 1)
+    }
+}
+
+impl IdAllocator for SequentialAllocator
+{
+    fn allocate(&mut self) -> usize
+    {
+        let id = self.next_id;
+        self.next_id += self.increment;
+        id
+    }
+
+    fn reserve(&mut self, id: usize)
+    {
+        if id >= self.next_id
+        {
+            // Only identifiers of the sequence are allocated (see ShardedTable)
+            self.next_id += ((id - self.next_id) / self.increment + 1) * self.increment;
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn IdAllocator>
+    {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use crate::test_fixtures::*;
+
+    // Allocates 1, 2, 4, 8, ..., so the identifiers of a batch have no constant step
+    #[derive(Clone)]
+    struct PowersOfTwoAllocator
+    {
+        next_id: usize
+    }
+
+    impl IdAllocator for PowersOfTwoAllocator
+    {
+        fn allocate(&mut self) -> usize
+        {
+            let id = self.next_id;
+            self.next_id *= 2;
+            id
+        }
+
+        fn reserve(&mut self, id: usize)
+        {
+            while self.next_id <= id
+            {
+                self.next_id *= 2;
+            }
+        }
+
+        fn clone_box(&self) -> Box<dyn IdAllocator>
+        {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn sequential_allocator_skips_the_reserved_identifiers_within_its_sequence()
+    {
+        let mut allocator = SequentialAllocator::new(1, 2);
+        assert_eq!(allocator.allocate(), 1);
+        allocator.reserve(4);
+        assert_eq!(allocator.allocate(), 5);
+        allocator.reserve(3);
+        assert_eq!(allocator.allocate(), 7);
+    }
+
+    #[test]
+    fn extend_with_a_custom_allocator_is_rolled_back_entity_by_entity()
+    {
+        let (mut db, tm) = create_database();
+        db.flights.set_id_allocator(PowersOfTwoAllocator { next_id: 1 });
+        let kept_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        tm.lock().unwrap().begin_transaction();
+        let ids = db.flights.extend(vec![Box::new(flight("MA200", 10)), Box::new(flight("MA300", 10)), Box::new(flight("MA400", 10))]);
+        assert_eq!(ids, vec![2, 4, 8]);
+        tm.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(db.flights.iter_with_ids().map(|(id, _)| id).collect::<Vec<_>>(), vec![kept_id]);
+        // The allocator is rolled back too, so the identifiers of the rolled back entities are allocated again
+        assert_eq!(db.flights.add(Box::new(flight("MA500", 10))), 2);
+    }
+}
@@ -1,21 +1,59 @@
 pub mod entity;
 pub mod table;
+pub mod sharded_table;
+pub mod link_table;
+pub mod ordered_table;
+pub mod cold_table;
+pub mod id_allocator;
 pub mod command;
 pub mod transaction;
 pub mod transaction_storage;
+pub mod encrypted;
+mod follower;
+mod client;
+mod snapshot;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+#[cfg(feature = "ndjson")]
+pub mod ndjson;
+#[cfg(test)]
+mod test_fixtures;
+
+pub use follower::FollowerEngine;
+pub use client::CommandClient;
+
+// Referred to by the code generated for #[command_schemas]
+#[cfg(feature = "schemars")]
+pub use schemars;
+
+// The derive macros refer to the crate by its name, so its own tests can use them too
+#[cfg(test)]
+extern crate self as microdb;
 
 pub mod prelude
 {
-    pub use crate::{*, command::*, entity::*, table::*, transaction::*, transaction_storage::*};
+    pub use crate::{*, command::*, entity::*, table::*, sharded_table::*, link_table::*, ordered_table::*, cold_table::*, id_allocator::*, transaction::*, transaction_storage::*, encrypted::*};
 }
 
-use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
 use std::thread;
-use tokio::sync::{mpsc, Notify};
-use command::{ CommandBase, CommandDirectory };
-use transaction::TransactionManager;
-use transaction_storage::TransactionStorage;
-use table::TableBase;
+use std::time::{Duration, Instant};
+use log::{error, warn};
+use tokio::sync::{mpsc, oneshot, Notify};
+use command::{ CommandBase, CommandDirectory, CommandError, CommandFailure, CommandOutcome, FollowUpCommand, TransactionCommand };
+use transaction::{TransactionManager, RollbackFailurePolicy};
+use transaction_storage::{TransactionStorage, TransactionLogReader, NullTransactionStorage, SerializedTransaction, get_record_size};
+use encrypted::FieldCipher;
+use snapshot::Snapshot;
+use table::{Table, TableBase, TableDiff, OrphanedReferences};
+use serde::{Serialize, de::DeserializeOwned};
 use futures::executor::block_on;
 
 pub trait DatabaseFactory
@@ -25,225 +63,2732 @@ pub trait DatabaseFactory
 
 pub trait Database
 {
-    fn get_table_mut(&mut self, table_id: u64) -> &mut dyn TableBase;
+    // Get a table by its unique identifier, or None if there is no such table in the database
+    fn try_get_table(&self, table_id: u64) -> Option<&dyn TableBase>;
+
+    // Get a table by its unique identifier, or None if there is no such table in the database
+    fn try_get_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>;
+
+    // Get a table by its unique identifier (panics on unknown table)
+    fn get_table_mut(&mut self, table_id: u64) -> &mut dyn TableBase
+    {
+        self.try_get_table_mut(table_id).expect("Unknown table")
+    }
+
+    // Get all tables of the database
+    fn get_tables(&self) -> Vec<&dyn TableBase>;
+
+    // Get all tables of the database as mutable
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>;
+
+    // Compact the rollback log of the running transaction and get the number of removed entries
+    fn checkpoint_transaction(&self) -> usize
+    {
+        self.get_tables().first().map(|table| table.get_transaction_manager().lock().unwrap().checkpoint()).unwrap_or(0)
+    }
+
+    // Shrink the memory allocated by all tables (e.g. after removing lots of entities). It must be called outside of commands.
+    fn shrink_all(&mut self)
+    {
+        for table in self.get_tables_mut()
+        {
+            table.shrink_to_fit();
+        }
+    }
+
+    // Remove an entity and recursively all entities referencing it by cascading foreign keys, returns the number of removed entities
+    fn remove_cascade(&mut self, table_id: u64, id: usize) -> usize
+    {
+        let mut removed_count = 0;
+        let mut entities_to_remove = vec![(table_id, id)];
+        while let Some((table_id, id)) = entities_to_remove.pop()
+        {
+            for table in self.get_tables()
+            {
+                for referencing_id in table.get_cascading_references_to(table_id, id)
+                {
+                    entities_to_remove.push((table.get_id(), referencing_id));
+                }
+            }
+            if self.get_table_mut(table_id).remove_entity(id)
+            {
+                removed_count += 1;
+            }
+        }
+        removed_count
+    }
+
+    // Check that the entities referenced by the foreign keys of the listed (table identifier, entity identifier) pairs exist
+    fn check_foreign_keys(&self, entities: &[(u64, usize)]) -> Result<(), String>
+    {
+        for (table_id, id) in entities
+        {
+            let Some(table) = self.try_get_table(*table_id) else { continue; };
+            for (referenced_table_id, referenced_id) in table.get_references(*id)
+            {
+                let exists = self.try_get_table(referenced_table_id).map(|referenced_table| referenced_table.contains(referenced_id)).unwrap_or(false);
+                if !exists
+                {
+                    return Err(format!("Entity {} of table {} references not existing entity {} of table {}", id, table_id, referenced_id, referenced_table_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check that the listed (table identifier, entity identifier) pairs do not exceed the maximum entity size of their tables
+    fn check_entity_sizes(&self, entities: &[(u64, usize)]) -> Result<(), String>
+    {
+        for (table_id, id) in entities
+        {
+            if let Some(table) = self.try_get_table(*table_id)
+            {
+                table.check_entity_size(*id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Check that the listed (table identifier, entity identifier) pairs do not violate the unique constraints of their tables
+    fn check_unique_constraints(&self, entities: &[(u64, usize)]) -> Result<(), String>
+    {
+        for (table_id, id) in entities
+        {
+            if let Some(table) = self.try_get_table(*table_id)
+            {
+                table.check_unique_constraints(*id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Check the foreign keys, sizes and unique keys of the listed (table identifier, entity identifier) pairs (the checks of a commit)
+    fn check_touched_entities(&self, entities: &[(u64, usize)]) -> Result<(), String>
+    {
+        self.check_foreign_keys(entities)?;
+        self.check_entity_sizes(entities)?;
+        self.check_unique_constraints(entities)
+    }
+
+    // Find all entities referencing not existing entities by their foreign keys (scans all tables)
+    fn check_consistency(&self) -> Vec<OrphanedReferences>
+    {
+        let exists = |table_id: u64, id: usize| self.try_get_table(table_id).map(|table| table.contains(id)).unwrap_or(false);
+        self.get_tables().iter().flat_map(|table| table.find_orphaned_references(&exists)).collect()
+    }
+
+    // Get the number of accesses of all tables in the order of get_tables (e.g. to find hot tables worth indexing or sharding)
+    #[cfg(feature = "table-stats")]
+    fn table_access_stats(&self) -> Vec<table::TableAccessStats>
+    {
+        self.get_tables().iter().map(|table| table.get_access_stats()).collect()
+    }
+
+    // Compare the database to another state of it and list the tables with differences
+    fn diff(&self, other: &Self) -> Vec<TableDiff> where Self: Sized
+    {
+        self.get_tables().iter().filter_map(|table| table.diff(other.try_get_table(table.get_id()).expect("Unknown table"))).collect()
+    }
 }
 
+// Queries see only committed states, because commands hold the write lock until their commit or rollback
 pub struct QueryEngine<D> where D: Database
 {
-    db_lock_arc: Arc<RwLock<D>>
+    db_lock_arc: Arc<RwLock<D>>,
+    published_snapshot: PublishedSnapshot<D>,
+    ready_signal: Arc<ReadySignal>
+}
+
+// Signals that the replay of the transaction log completed, so queries can see the fully recovered database
+#[derive(Default)]
+struct ReadySignal
+{
+    // Checked first, so queries after the startup do not lock the mutex
+    ready_flag: AtomicBool,
+    ready: Mutex<bool>,
+    condvar: Condvar
+}
+
+impl ReadySignal
+{
+    fn new_ready() -> Self
+    {
+        Self { ready_flag: AtomicBool::new(true), ready: Mutex::new(true), condvar: Condvar::new() }
+    }
+
+    fn set_ready(&self)
+    {
+        *self.ready.lock().unwrap() = true;
+        self.ready_flag.store(true, Ordering::Release);
+        self.condvar.notify_all();
+    }
+
+    // Wait until ready or the timeout elapses (None waits forever) and return true if ready
+    fn wait(&self, timeout: Option<Duration>) -> bool
+    {
+        if self.ready_flag.load(Ordering::Acquire)
+        {
+            return true;
+        }
+        let ready = self.ready.lock().unwrap();
+        match timeout
+        {
+            Some(timeout) => *self.condvar.wait_timeout_while(ready, timeout, |ready| !*ready).unwrap().0,
+            None => *self.condvar.wait_while(ready, |ready| !*ready).unwrap()
+        }
+    }
+}
+
+// Copies share the database, so read access can be given to multiple threads (QueryEngine is Send and Sync if D is)
+impl<D> Clone for QueryEngine<D> where D: Database
+{
+    fn clone(&self) -> Self
+    {
+        Self { db_lock_arc: self.db_lock_arc.clone(), published_snapshot: self.published_snapshot.clone(), ready_signal: self.ready_signal.clone() }
+    }
 }
 
 impl<D> QueryEngine<D> where D: Database
 {
+    // Get read access to the database (it blocks until the replay completes)
     pub fn get_db(&self) -> RwLockReadGuard<'_, D>
     {
-        return self.db_lock_arc.read().unwrap();
+        self.ready_signal.wait(None);
+        self.db_lock_arc.read().unwrap()
+    }
+
+    // Returns true if the replay of the transaction log completed, so queries do not block (e.g. for a readiness probe)
+    pub fn is_ready(&self) -> bool
+    {
+        self.ready_signal.wait(Some(Duration::ZERO))
+    }
+
+    // Wait until the replay of the transaction log completes or the timeout elapses. Returns true if the replay completed.
+    pub fn wait_until_ready(&self, timeout: Duration) -> bool
+    {
+        self.ready_signal.wait(Some(timeout))
+    }
+
+    // Run a query on one consistent committed state of the database
+    pub fn query<R>(&self, f: impl FnOnce(&D) -> R) -> R
+    {
+        return f(&self.get_db());
+    }
+
+    // Resolve a path of entity identifiers to copies of the structs in the order of the path
+    pub fn resolve_path<T>(&self, table: impl Fn(&D) -> &Table<T>, ids: &[usize]) -> Result<Vec<T>, String> where T: Clone + Serialize + DeserializeOwned
+    {
+        self.query(|db| {
+            let table = table(db);
+            ids.iter().map(|id| table.get(*id).map(|entity| (***entity).clone()).ok_or_else(|| format!("Entity {} does not exist in table {}", id, table.get_id()))).collect()
+        })
+    }
+
+    // Read all entities of all tables after startup and get the number of entities read
+    pub fn warm(&self) -> usize
+    {
+        self.query(|db| db.get_tables().iter().map(|table| table.warm()).sum())
+    }
+
+    // Get the latest published copy of the database without waiting for the database lock
+    pub fn stale_read(&self) -> Option<Arc<D>>
+    {
+        return self.published_snapshot.read().unwrap().clone();
     }
 }
 
-#[derive(PartialEq)]
-pub enum CommandExecutionType { Synchronous, Asynchronous }
+// A command shared between the caller and the command processing thread
+pub type SharedCommand<D> = Arc<dyn CommandBase<D> + Sync + Send>;
 
-#[derive(PartialEq)]
-pub enum TransactionStatus { Completed, Failed, NotExecuted }
+// Sender half of the channel used to report the result of a transaction to its commit handle
+type CommitSender = oneshot::Sender<Result<CommandOutcome, CommandError>>;
 
-pub struct CommandEngine<D, C> where D: Database + Sync + Send, C: CommandDirectory<D>
+// A command waiting in the queue of the command processing thread
+struct QueuedCommand<D>
 {
-    db_lock_arc: Arc<RwLock<D>>,
-    command_definitions: Arc<C>,
-    transaction_storage: Box<dyn TransactionStorage>,
-    last_pushed_transaction_id: usize,
-    last_processed_transaction_id_lock: Arc<RwLock<usize>>,
-    transaction_manager_ref: Arc<Mutex<TransactionManager>>,
-    failed_transaction_ids_lock: Arc<RwLock<Vec<usize>>>,
-    command_execution_type: CommandExecutionType,
-    command_sender: Option<mpsc::Sender<Arc<dyn CommandBase<D> + Sync + Send>>>,
-    processed_transaction_id_notify: Option<Arc<Notify>>
+    // Identifier of the transaction assigned when the command was accepted
+    transaction_id: usize,
+    command: SharedCommand<D>,
+    commit_sender: Option<CommitSender>,
+    // Record of the command, what is written to the log only if the transaction changes the database (see PendingRecord)
+    pending_record: Option<PendingRecord>
 }
 
-impl<D, C> CommandEngine<D, C> where D: Database + Sync + Send + 'static, C: CommandDirectory<D>
+// Record of a durable command written to the transaction log after the command ran
+struct PendingRecord
 {
-    pub fn new(
-        db_lock_arc: Arc<RwLock<D>>,
-        command_definitions: C,
-        mut transaction_storage: Box<dyn TransactionStorage>,
-        transaction_manager_ref: Arc<Mutex<TransactionManager>>,
-        command_execution_type: CommandExecutionType
-        ) -> Self
-    {
-        let mut last_processed_transaction_id: usize = 0;
-        loop {
-            let serialized_transaction = transaction_storage.get();            
-            if serialized_transaction.is_some()
-             {                
-                let serialized_transaction = serialized_transaction.unwrap();
-                let command_definition = command_definitions.get(&serialized_transaction.name);
-                let command = command_definition.create_from_serialized(serialized_transaction.serialized_parameters);
-                let db_lock = db_lock_arc.clone();
-                let mut db = db_lock.write().unwrap();                
-                last_processed_transaction_id += 1;
-                // TODO: Store falied transaction ids on the disk to skip them when database is loaded
-                command.run(&mut *(db)).expect("Transaction failed, what was succesful earlier");
-             }
-             else {
-                 break;                
-             }    
-        }         
+    name: String,
+    serialized_parameters: Vec<u8>,
+    metadata: HashMap<String, String>
+}
 
-        let mut command_engine = Self {
-             db_lock_arc: db_lock_arc.clone(),
-             command_definitions: Arc::new(command_definitions),
-             transaction_storage,
-             last_pushed_transaction_id: last_processed_transaction_id,
-             last_processed_transaction_id_lock: Arc::new(RwLock::new(last_processed_transaction_id)),
-             transaction_manager_ref: transaction_manager_ref.clone(),
-             failed_transaction_ids_lock: Arc::new(RwLock::new(Vec::new())),
-             command_execution_type,
-             command_sender: None,
-             processed_transaction_id_notify : None
-             };
+// Asynchronous execution returns before the command runs (use push_command_and_wait to read your writes)
+#[derive(PartialEq)]
+pub enum CommandExecutionType { Synchronous, Asynchronous }
 
-        if command_engine.command_execution_type == CommandExecutionType::Asynchronous
-        {
-            let (command_sender, mut command_receiver): (mpsc::Sender<Arc<dyn CommandBase<D> + Sync + Send>>, mpsc::Receiver<Arc<dyn CommandBase<D> + Sync + Send>>) = mpsc::channel(100);
-            command_engine.command_sender = Some(command_sender);
+// Handling of log records, what do not follow the previous record during replay
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum ReplayOrderPolicy
+{
+    // Stop the replay by panicking, so a corrupted log never produces a database state
+    #[default]
+    Abort,
+    // Log a warning and skip the record
+    WarnAndSkip
+}
 
-            let transactioprocessed_transaction_id_notify = Arc::new(Notify::new());
-            command_engine.processed_transaction_id_notify = Some(transactioprocessed_transaction_id_notify.clone());
+// Options of the command engine set by the engine builder
+struct EngineOptions<D>
+{
+    replay_order_policy: ReplayOrderPolicy,
+    snapshot_publisher: Option<SnapshotPublisher<D>>,
+    // Pause of the replay after every batch of records as (batch size, pause)
+    replay_throttle: Option<(usize, Duration)>,
+    // Maximum number of commands queued for the command processing thread (asynchronous execution only)
+    channel_capacity: usize,
+    // Transactions not changing the database are not written to the transaction log
+    skip_empty_transactions: bool,
+    // Cipher of the Encrypted fields serialized or deserialized by the engine
+    field_cipher: Option<Arc<dyn FieldCipher>>
+}
 
-            let db_lock_arc = command_engine.db_lock_arc.clone();
-            let transaction_manager_ref =  command_engine.transaction_manager_ref.clone();
-            let last_processed_transaction_id_arc = command_engine.last_processed_transaction_id_lock.clone();
-            let failed_transaction_ids_lock = command_engine.failed_transaction_ids_lock.clone();
-            thread::spawn(move ||
-                {
-                    loop
-                    {
-                        let command = block_on(command_receiver.recv());
+impl<D> Default for EngineOptions<D>
+{
+    fn default() -> Self
+    {
+        Self { replay_order_policy: ReplayOrderPolicy::default(), snapshot_publisher: None, replay_throttle: None, channel_capacity: 100, skip_empty_transactions: false, field_cipher: None }
+    }
+}
 
-                        // If the channel is closed by the other thread
-                        if command.is_none()
-                        {
-                            break;
-                        }
+// The latest published snapshot of the database shared with the query engine
+type PublishedSnapshot<D> = Arc<RwLock<Option<Arc<D>>>>;
 
-                        let command = command.unwrap();
+// Metadata key of the records of follow-up commands, storing the identifier of the transaction returning them
+const FOLLOW_UP_METADATA_KEY: &str = "follow_up_of";
 
-                        transaction_manager_ref.lock().unwrap().begin_transaction();
-                        let mut last_processed_transaction_id = last_processed_transaction_id_arc.write().unwrap();
-                        *last_processed_transaction_id += 1;
-                        let mut db = db_lock_arc.write().unwrap();
-                        let transaction_result = command.run(&mut *(db));
-                        match transaction_result
-                        {
-                            Ok(_) => {
-                            transaction_manager_ref.lock().unwrap().commit_transaction();
-                        }
-                        Err(_) => {                                
-                            transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db);
-                            let mut failed_transaction_ids = failed_transaction_ids_lock.write().unwrap();
-                            failed_transaction_ids.push(*last_processed_transaction_id);
-                            }
-                        }
-                    
-                        transactioprocessed_transaction_id_notify.notify_waiters();
-                    }
-                }
-            );
-        }
+// Publishes an immutable copy of the database after every N committed transactions for stale reads
+struct SnapshotPublisher<D>
+{
+    clone_fn: fn(&D) -> D,
+    // Number of committed transactions between two snapshots
+    interval: usize,
+    // Number of committed transactions since the last snapshot
+    commit_count: Mutex<usize>,
+    published_snapshot: PublishedSnapshot<D>
+}
 
-        command_engine
+impl<D> SnapshotPublisher<D>
+{
+    fn publish(&self, db: &D)
+    {
+        let snapshot = Arc::new((self.clone_fn)(db));
+        *self.published_snapshot.write().unwrap() = Some(snapshot);
+        *self.commit_count.lock().unwrap() = 0;
     }
 
-    pub fn push_command(&mut self, cmd: Arc<dyn CommandBase<D> + Sync + Send>) -> usize
+    fn on_commit(&self, db: &D)
     {
-        let serialized_parameters = cmd.get_serialized_parameters();
-        let name = String::from(cmd.get_name());
-        self.transaction_storage.add(name, Box::new(serialized_parameters));
-        self.last_pushed_transaction_id += 1;
-        
-
-        if self.command_execution_type == CommandExecutionType::Synchronous
+        let mut commit_count = self.commit_count.lock().unwrap();
+        *commit_count += 1;
+        if *commit_count >= self.interval
         {
-            let db_lock = self.db_lock_arc.clone();
-            let mut db = db_lock.write().unwrap();
-
-            self.transaction_manager_ref.lock().unwrap().begin_transaction();
-            let mut last_processed_transaction_id = self.last_processed_transaction_id_lock.write().unwrap();
-            *last_processed_transaction_id += 1;
-            let transaction_result = cmd.run(&mut *(db));
-            match transaction_result
-            {
-                Ok(_) => {
-                     self.transaction_manager_ref.lock().unwrap().commit_transaction();
-                }
-                Err(_) => {                                
-                     self.transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db);
-                    let mut failed_transaction_ids = self.failed_transaction_ids_lock.write().unwrap();
-                    failed_transaction_ids.push(*last_processed_transaction_id);
-                }
-            }            
-        }
-        else
-        {            
-            let _ = block_on(self.command_sender.as_ref().unwrap().send(cmd));
+            drop(commit_count);
+            self.publish(db);
         }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum TransactionStatus { Completed, Failed, NotExecuted }
+
+// Errors of the engine itself (as opposed to the errors of commands)
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError
+{
+    // A lock was poisoned by a panic (like a panicking command), so the state it protects may be inconsistent
+    LockPoisoned(&'static str),
+    // The command processing thread stopped, so queued and new commands are never processed
+    WorkerStopped,
+    // Writing the transaction storage failed
+    StorageIo(String),
+    // Serializing the parameters or the metadata of a command failed
+    Serialization(String),
+    // The command was rejected, because its serialized parameters are larger than the configured maximum
+    ParametersTooLarge { size: usize, max_size: usize },
+    // The command was rejected, because writing it would make the transaction log larger than the configured maximum
+    LogFull { size: usize, max_size: usize },
+    // No command is registered with the name in the command directory
+    UnknownCommand(String),
+    // The name of the command is empty, so it could not be found in the command directory on replay
+    EmptyCommandName,
+    // The command was disabled by CommandEngine::disable_command
+    CommandDisabled(String),
+    // The engine was shut down by CommandEngine::shutdown, so it does not accept commands anymore
+    ShuttingDown,
+    // A command was pushed from a running command (commands should return follow-up commands instead, see CommandOutcome::with_follow_up)
+    ReentrantCommand,
+    // A record of the transaction log could not be replayed (like an unknown command or an out of order transaction identifier)
+    ReplayFailed(String)
+}
 
-        self.last_pushed_transaction_id
+impl Display for EngineError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            EngineError::LockPoisoned(lock_name) => write!(f, "Lock of the {} is poisoned", lock_name),
+            EngineError::WorkerStopped => write!(f, "Command processing thread stopped"),
+            EngineError::StorageIo(error) => write!(f, "Transaction storage error: {}", error),
+            EngineError::Serialization(error) => write!(f, "Serialization error: {}", error),
+            EngineError::ParametersTooLarge { size, max_size } => write!(f, "Command parameters are too large ({} bytes, maximum is {} bytes)", size, max_size),
+            EngineError::LogFull { size, max_size } => write!(f, "Transaction log is full ({} bytes, maximum is {} bytes)", size, max_size),
+            EngineError::UnknownCommand(name) => write!(f, "Unknown command: {}", name),
+            EngineError::EmptyCommandName => write!(f, "Command name is empty"),
+            EngineError::CommandDisabled(name) => write!(f, "Command {} is disabled", name),
+            EngineError::ShuttingDown => write!(f, "Command engine is shut down"),
+            EngineError::ReentrantCommand => write!(f, "A command can not be pushed by a running command (return it as a follow-up command instead)"),
+            EngineError::ReplayFailed(error) => write!(f, "Replay failed: {}", error)
+        }
     }
+}
 
-    pub fn get_command_definitions(&self) -> Arc<C>
+// Sets the stopped flag of the command processing thread and wakes up the waiting callers when the thread exits (even by a panic)
+struct WorkerStoppedGuard<D> where D: Database
+{
+    transaction_processor: Arc<TransactionProcessor<D>>
+}
+
+impl<D> Drop for WorkerStoppedGuard<D> where D: Database
+{
+    fn drop(&mut self)
     {
-        return self.command_definitions.clone();
+        self.transaction_processor.worker_stopped.store(true, Ordering::SeqCst);
+        self.transaction_processor.processed_transaction_id_notify.notify_waiters();
     }
+}
+
+// Future resolving when the transaction of a pushed command is committed (with the outcome of the command) or rolled back
+pub struct CommitHandle
+{
+    transaction_id: usize,
+    receiver: oneshot::Receiver<Result<CommandOutcome, CommandError>>
+}
 
-    pub fn get_transaction_status(&self, transaction_id: usize) -> TransactionStatus
+impl CommitHandle
+{
+    // Get the identifier of the transaction the handle belongs to
+    pub fn get_transaction_id(&self) -> usize
     {
-        let last_processed_transaction_id = *self.last_processed_transaction_id_lock.read().unwrap();
-        let failed_transaction_ids = self.failed_transaction_ids_lock.read().unwrap();
+        self.transaction_id
+    }
+}
 
-        if transaction_id > last_processed_transaction_id
-            { return TransactionStatus::NotExecuted; }
-        else if failed_transaction_ids.contains(&transaction_id)
-            { return TransactionStatus::Failed; }
-        else {
-            { return TransactionStatus::Completed; }
-        }
+impl Future for CommitHandle
+{
+    type Output = Result<CommandOutcome, CommandError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output>
+    {
+        // If the sender is dropped without sending a result, then the command processing thread stopped
+        Pin::new(&mut self.receiver).poll(cx).map(|result| result.unwrap_or(Err(CommandError::EngineStopped)))
     }
+}
+
+// Details of a rolled back transaction passed to the rollback observer
+pub struct RollbackEvent<'a>
+{
+    pub transaction_id: usize,
+    pub command_name: &'a str,
+    // Error returned by the command (or by the foreign key, entity size and unique constraint checks)
+    pub error: &'a str,
+    // Entities reverted by the rollback as (table identifier, entity identifier) pairs
+    pub reverted_entities: Vec<(u64, usize)>,
+    // Entries failed to roll back (see RollbackFailurePolicy)
+    pub rollback_errors: Vec<String>
+}
+
+// Callback fired after a transaction is rolled back. It is called while the database is locked, so it must not use the query engine.
+pub type RollbackObserver = Box<dyn Fn(&RollbackEvent) + Send + Sync>;
+
+// Details of a command running longer than the threshold of the slow command observer
+pub struct SlowCommandEvent<'a>
+{
+    pub transaction_id: usize,
+    pub command_name: &'a str,
+    // Time of running the command (including the foreign key, entity size and unique constraint checks, excluding the commit or the rollback)
+    pub duration: Duration
+}
+
+// Callback fired after a slow command is committed or rolled back (it must not use the query engine)
+pub type SlowCommandObserver = Box<dyn Fn(&SlowCommandEvent) + Send + Sync>;
+
+// Callback fired after a transaction is committed while the database is locked (it must not use the query engine)
+pub type PostCommitHook<D> = Box<dyn Fn(&D, usize) + Send + Sync>;
+
+// Function checking a condition of the database, what must hold after every transaction
+pub type InvariantCheck<D> = Box<dyn Fn(&D) -> Result<(), String> + Send + Sync>;
+
+// An invariant registered by CommandEngine::add_invariant
+struct Invariant<D>
+{
+    name: String,
+    check: InvariantCheck<D>
+}
+
+// A violated invariant found by CommandEngine::check_invariants
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation
+{
+    pub invariant: String,
+    pub error: String
+}
 
-    pub fn wait_for_transaction(&mut self, transaction_id: usize)
+thread_local!
+{
+    // Set while a command runs on the thread, so a command pushing another command is rejected
+    static IN_COMMAND: Cell<bool> = const { Cell::new(false) };
+}
+
+// Marks the thread as running a command until it is dropped (even if the command panics)
+struct InCommandGuard;
+
+impl InCommandGuard
+{
+    fn new() -> Self
     {
-        let mut last_processed_transaction_id = *self.last_processed_transaction_id_lock.read().unwrap();        
+        IN_COMMAND.with(|in_command| in_command.set(true));
+        InCommandGuard
+    }
+}
 
-        loop {            
+impl Drop for InCommandGuard
+{
+    fn drop(&mut self)
+    {
+        IN_COMMAND.with(|in_command| in_command.set(false));
+    }
+}
 
-            if transaction_id <= last_processed_transaction_id            
-            {
-                break;
-            }
-            
-            block_on(self.processed_transaction_id_notify.as_ref().unwrap().notified());
-            
-            last_processed_transaction_id = *self.last_processed_transaction_id_lock.read().unwrap();
-            
-        }
+// Returns an error if the thread runs a command (a command pushed by it could never be processed)
+fn check_not_in_command() -> Result<(), EngineError>
+{
+    if IN_COMMAND.with(Cell::get)
+    {
+        return Err(EngineError::ReentrantCommand);
     }
+    Ok(())
 }
 
-pub struct Engine
+// Runs commands in transactions (shared by the synchronous path and the command processing thread)
+struct TransactionProcessor<D> where D: Database
 {
+    db_lock_arc: Arc<RwLock<D>>,
+    transaction_manager_ref: Arc<Mutex<TransactionManager>>,
+    last_processed_transaction_id_lock: Arc<RwLock<usize>>,
+    failed_transaction_ids_lock: Arc<RwLock<Vec<usize>>>,
+    // Errors of the transactions failed since the engine was started (errors of failed transactions replayed from the log are not known)
+    failed_transaction_errors_lock: RwLock<HashMap<usize, CommandFailure>>,
+    processed_transaction_id_notify: Arc<Notify>,
+    transaction_storage: Arc<Mutex<Box<dyn TransactionStorage>>>,
+    // Number of processed records of the transaction log (non-durable commands have no record)
+    processed_record_count: Mutex<usize>,
+    // Size of the transaction log in bytes (see CommandEngine::set_max_log_size)
+    log_size: AtomicUsize,
+    rollback_observer: RwLock<Option<RollbackObserver>>,
+    post_commit_hook: RwLock<Option<PostCommitHook<D>>>,
+    // Threshold of running time and the observer of slower commands
+    slow_command_observer: RwLock<Option<(Duration, SlowCommandObserver)>>,
+    snapshot_publisher: Option<SnapshotPublisher<D>>,
+    // Set when the command processing thread exits
+    worker_stopped: AtomicBool,
+    invariants: RwLock<Vec<Invariant<D>>>,
+    // Run the invariants after every committed transaction (debug builds only)
+    check_invariants_after_commit: AtomicBool,
+    // Follow-up commands of committed transactions not pushed yet, with the identifiers of the transactions returning them
+    follow_up_commands: Mutex<VecDeque<(usize, FollowUpCommand)>>,
+    // Cipher of the Encrypted fields (see encrypted::with_field_cipher)
+    field_cipher: Option<Arc<dyn FieldCipher>>
 }
 
-impl Engine
+impl<D> TransactionProcessor<D> where D: Database
 {
-    pub fn new<D, C>(command_definitions: C, transaction_storage: Box<dyn TransactionStorage>, command_execution_type: CommandExecutionType, init: &'static dyn Fn(&mut D)) -> (QueryEngine<D>, CommandEngine<D, C>) where D: Database + DatabaseFactory + Send + Sync, C: CommandDirectory<D>
+    // Queue the follow-up commands of a committed transaction, so the engine pushes them (see CommandEngine::push_follow_up_commands)
+    fn add_follow_up_commands(&self, transaction_id: usize, outcome: &CommandOutcome)
     {
-        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
-        let mut db = D::create_database(transaction_manager_ref.clone());        
-        init(&mut db);
-        let db_lock_arc = Arc::new(RwLock::new(db));
-        let query_engine = QueryEngine { db_lock_arc: db_lock_arc.clone() };
-        let command_engine = CommandEngine::new( db_lock_arc.clone(), command_definitions, transaction_storage, transaction_manager_ref.clone(), command_execution_type );
-        return (query_engine, command_engine);
+        if !outcome.follow_up_commands.is_empty()
+        {
+            self.follow_up_commands.lock().unwrap().extend(outcome.follow_up_commands.iter().map(|follow_up_command| (transaction_id, follow_up_command.clone())));
+        }
     }
-}
\ No newline at end of file
+
+    // Remove a follow-up command from the queue, because it is already in the transaction log (found during replay)
+    fn remove_follow_up_command(&self, parent_transaction_id: usize, name: &str)
+    {
+        let mut follow_up_commands = self.follow_up_commands.lock().unwrap();
+        match follow_up_commands.iter().position(|(transaction_id, follow_up_command)| *transaction_id == parent_transaction_id && follow_up_command.name == name)
+        {
+            Some(position) => { follow_up_commands.remove(position); },
+            None => warn!("Follow-up command {} of transaction {} is in the log, but it was not returned by the transaction", name, parent_transaction_id)
+        }
+    }
+
+    // Run all registered invariants on the database and collect the violated ones
+    fn check_invariants(&self, db: &D) -> Vec<InvariantViolation>
+    {
+        self.invariants.read().unwrap().iter()
+            .filter_map(|invariant| (invariant.check)(db).err().map(|error| InvariantViolation { invariant: invariant.name.clone(), error }))
+            .collect()
+    }
+
+    // Run a command in a new transaction, then commit it on success or roll it back on failure
+    fn process(&self, transaction_id: usize, command: &dyn CommandBase<D>, commit_sender: Option<CommitSender>, pending_record: Option<PendingRecord>) -> Result<(), EngineError>
+    {
+        // On an engine error the commit sender is dropped, so the commit handle reports that the engine stopped
+        // Parameters and rollback states of Encrypted fields are serialized by the command
+        let transaction_result = encrypted::with_field_cipher(self.field_cipher.clone(), || self.run_in_transaction(transaction_id, command.get_name(), command.is_durable(), pending_record, |db| command.run(db)))?;
+
+        if let Some(commit_sender) = commit_sender
+        {
+            // The commit handle may have been dropped by the caller, what is not an error
+            let _ = commit_sender.send(transaction_result.map_err(|failure| CommandError::Failed(failure.message)));
+        }
+
+        self.processed_transaction_id_notify.notify_waiters();
+        Ok(())
+    }
+
+    // Returns the result of the command, or an engine error if the transaction could not be run at all
+    fn run_in_transaction<F>(&self, transaction_id: usize, command_name: &str, durable: bool, pending_record: Option<PendingRecord>, f: F) -> Result<Result<CommandOutcome, CommandFailure>, EngineError> where F: FnOnce(&mut D) -> Result<CommandOutcome, CommandFailure>
+    {
+        // A poisoned database lock means that a command panicked while changing the database
+        let mut db = self.db_lock_arc.write().map_err(|_| EngineError::LockPoisoned("database"))?;
+
+        self.transaction_manager_ref.lock().unwrap().begin_transaction();
+        // The last processed transaction identifier is always valid, so it is recovered from a poisoned lock
+        let mut last_processed_transaction_id = self.last_processed_transaction_id_lock.write().unwrap_or_else(PoisonError::into_inner);
+        // Transactions must be processed in the order their identifiers were assigned
+        assert_eq!(*last_processed_transaction_id + 1, transaction_id, "Transaction processed out of order");
+        // Foreign keys, sizes and unique keys of the inserted and modified entities are checked before commit
+        let start = Instant::now();
+        let mut empty = false;
+        let mut storage_error = None;
+        let in_command_guard = InCommandGuard::new();
+        let transaction_result = f(&mut *(db)).and_then(|outcome| {
+            let touched_entities = self.transaction_manager_ref.lock().unwrap().get_touched_entities();
+            db.check_touched_entities(&touched_entities)?;
+            empty = touched_entities.is_empty();
+            Ok(outcome)
+        });
+        drop(in_command_guard);
+        // A pending record is written for committed transactions changing the database or returning follow-up commands
+        let (transaction_result, durable) = match (transaction_result, pending_record)
+        {
+            (Ok(outcome), Some(pending_record)) if !empty || !outcome.follow_up_commands.is_empty() =>
+            {
+                let mut transaction_storage = self.transaction_storage.lock().unwrap();
+                let record_size = get_record_size(&pending_record.name, pending_record.serialized_parameters.len(), &pending_record.metadata);
+                match transaction_storage.add(transaction_id, pending_record.name, Box::new(pending_record.serialized_parameters), &pending_record.metadata)
+                {
+                    Ok(()) =>
+                    {
+                        self.log_size.fetch_add(record_size, Ordering::Relaxed);
+                        (Ok(outcome), true)
+                    },
+                    Err(error) => (Err(CommandFailure::from(format!("Writing the transaction log failed: {}", error))), false)
+                }
+            },
+            (transaction_result, Some(_)) => (transaction_result, false),
+            (transaction_result, None) => (transaction_result, durable)
+        };
+        let duration = start.elapsed();
+        match &transaction_result
+        {
+            Ok(outcome) => {
+                self.transaction_manager_ref.lock().unwrap().commit_transaction();
+                self.add_follow_up_commands(transaction_id, outcome);
+                if let Some(snapshot_publisher) = &self.snapshot_publisher
+                {
+                    snapshot_publisher.on_commit(&db);
+                }
+                if let Some(post_commit_hook) = self.post_commit_hook.read().unwrap().as_ref()
+                {
+                    post_commit_hook(&db, transaction_id);
+                }
+                if cfg!(debug_assertions) && self.check_invariants_after_commit.load(Ordering::Relaxed)
+                {
+                    if let Some(violation) = self.check_invariants(&db).first()
+                    {
+                        panic!("Invariant '{}' violated after transaction {} (command '{}'): {}", violation.invariant, transaction_id, command_name, violation.error);
+                    }
+                }
+            }
+            Err(error) => {
+                let mut transaction_manager = self.transaction_manager_ref.lock().unwrap();
+                let reverted_entities = transaction_manager.get_touched_entities();
+                // Entries failed to roll back are already handled by the rollback failure policy
+                let rollback_errors = transaction_manager.rollback_transaction(&mut db).err().unwrap_or_default();
+                drop(transaction_manager);
+                let mut failed_transaction_ids = self.failed_transaction_ids_lock.write().unwrap();
+                failed_transaction_ids.push(transaction_id);
+                self.failed_transaction_errors_lock.write().unwrap().insert(transaction_id, error.clone());
+                // Failed transactions are persisted, so the replay skips them
+                if durable
+                {
+                    if let Err(error) = self.transaction_storage.lock().unwrap().add_failed_transaction_id(transaction_id)
+                    {
+                        storage_error = Some(EngineError::StorageIo(format!("Writing failed transaction {} failed: {}", transaction_id, error)));
+                    }
+                }
+
+                if let Some(rollback_observer) = self.rollback_observer.read().unwrap().as_ref()
+                {
+                    rollback_observer(&RollbackEvent { transaction_id, command_name, error: &error.message, reverted_entities, rollback_errors });
+                }
+            }
+        }
+        if let Some((threshold, slow_command_observer)) = self.slow_command_observer.read().unwrap().as_ref()
+        {
+            if duration > *threshold
+            {
+                slow_command_observer(&SlowCommandEvent { transaction_id, command_name, duration });
+            }
+        }
+        // The transaction is processed only after it is committed or rolled back (a panicking command leaves it not executed)
+        *last_processed_transaction_id = transaction_id;
+        if durable
+        {
+            let mut processed_record_count = self.processed_record_count.lock().unwrap();
+            *processed_record_count += 1;
+            // The transaction is already committed, and a checkpoint behind the processed records is safe (see set_checkpoint)
+            if let Err(error) = self.transaction_storage.lock().unwrap().set_checkpoint(*processed_record_count)
+            {
+                error!("Writing the checkpoint after transaction {} failed: {}", transaction_id, error);
+            }
+        }
+
+        match storage_error
+        {
+            Some(engine_error) => Err(engine_error),
+            None => Ok(transaction_result)
+        }
+    }
+}
+
+pub struct CommandEngine<D, C> where D: Database + Sync + Send, C: CommandDirectory<D>
+{
+    command_definitions: Arc<C>,
+    last_pushed_transaction_id: usize,
+    transaction_processor: Arc<TransactionProcessor<D>>,
+    command_execution_type: CommandExecutionType,
+    command_sender: Option<mpsc::Sender<QueuedCommand<D>>>,
+    // Command processing thread of asynchronous execution (joined by shutdown)
+    worker_handle: Option<thread::JoinHandle<()>>,
+    // Records of durable commands are written after running them, and only if they changed the database
+    skip_empty_transactions: bool,
+    // Set by shutdown, so no more commands are accepted
+    shut_down: bool,
+    // Maximum size of serialized command parameters accepted by push_command
+    max_parameters_size: Option<usize>,
+    // Maximum size of the transaction log accepted by push_command
+    max_log_size: Option<usize>,
+    // Names of the commands rejected by push_command
+    disabled_commands: HashSet<String>,
+    scheduled_commands: Vec<ScheduledCommand<D>>
+}
+
+// A command run periodically by CommandEngine::run_scheduled_commands
+struct ScheduledCommand<D>
+{
+    // Logical time between two runs
+    interval: u64,
+    // Logical time of the next run
+    next_run: u64,
+    // Creates the command of a run from the logical time of the run
+    create: Box<dyn Fn(u64) -> SharedCommand<D> + Send>
+}
+
+// Replay a record of the transaction log in a transaction (used by the replay, replay_range and FollowerEngine)
+fn replay_record<D, C>(db: &mut RwLockWriteGuard<'_, D>, transaction_manager_ref: &Mutex<TransactionManager>, command_definitions: &C,
+    serialized_transaction: &SerializedTransaction) -> Result<Result<CommandOutcome, CommandFailure>, EngineError> where D: Database, C: CommandDirectory<D>
+{
+    transaction_manager_ref.lock().unwrap().begin_transaction();
+    let transaction_result = command_definitions.run_record(db, &serialized_transaction.name, &serialized_transaction.serialized_parameters)
+        .map(|command_result| command_result.and_then(|outcome| {
+            let touched_entities = transaction_manager_ref.lock().unwrap().get_touched_entities();
+            db.check_touched_entities(&touched_entities)?;
+            Ok(outcome)
+        }));
+
+    match &transaction_result
+    {
+        Ok(Ok(_)) => transaction_manager_ref.lock().unwrap().commit_transaction(),
+        // Entries failed to roll back are already handled by the rollback failure policy
+        _ => { let _ = transaction_manager_ref.lock().unwrap().rollback_transaction(db); }
+    }
+    transaction_result
+}
+
+impl<D, C> CommandEngine<D, C> where D: Database + Sync + Send + 'static, C: CommandDirectory<D>
+{
+    pub fn new(
+        db_lock_arc: Arc<RwLock<D>>,
+        command_definitions: C,
+        transaction_storage: Box<dyn TransactionStorage>,
+        transaction_manager_ref: Arc<Mutex<TransactionManager>>,
+        command_execution_type: CommandExecutionType
+        ) -> Self
+    {
+        Self::new_with_options(db_lock_arc, Arc::new(command_definitions), transaction_storage, transaction_manager_ref, command_execution_type, EngineOptions::default())
+            .unwrap_or_else(|engine_error| panic!("{}", engine_error))
+    }
+
+    fn new_with_options(
+        db_lock_arc: Arc<RwLock<D>>,
+        command_definitions: Arc<C>,
+        mut transaction_storage: Box<dyn TransactionStorage>,
+        transaction_manager_ref: Arc<Mutex<TransactionManager>>,
+        command_execution_type: CommandExecutionType,
+        options: EngineOptions<D>
+        ) -> Result<Self, EngineError>
+    {
+        // Transactions after the checkpoint were accepted, but not processed before the restart
+        let checkpoint = transaction_storage.get_checkpoint().map_err(|error| EngineError::StorageIo(error.to_string()))?;
+
+        let transaction_processor = Arc::new(TransactionProcessor {
+            db_lock_arc,
+            transaction_manager_ref,
+            last_processed_transaction_id_lock: Arc::new(RwLock::new(0)),
+            failed_transaction_ids_lock: Arc::new(RwLock::new(Vec::new())),
+            failed_transaction_errors_lock: RwLock::new(HashMap::new()),
+            processed_transaction_id_notify: Arc::new(Notify::new()),
+            transaction_storage: Arc::new(Mutex::new(transaction_storage)),
+            processed_record_count: Mutex::new(0),
+            log_size: AtomicUsize::new(0),
+            rollback_observer: RwLock::new(None),
+            post_commit_hook: RwLock::new(None),
+            slow_command_observer: RwLock::new(None),
+            snapshot_publisher: options.snapshot_publisher,
+            worker_stopped: AtomicBool::new(false),
+            invariants: RwLock::new(Vec::new()),
+            check_invariants_after_commit: AtomicBool::new(false),
+            follow_up_commands: Mutex::new(VecDeque::new()),
+            field_cipher: options.field_cipher
+            });
+
+        let failed_transaction_ids: HashSet<usize> = transaction_processor.transaction_storage.lock().unwrap().get_failed_transaction_ids()
+            .map_err(|error| EngineError::StorageIo(error.to_string()))?.into_iter().collect();
+        // The snapshot is loaded only if the log contains all transactions in it (the previous one if it is corrupted)
+        let deserialize = |serialized_snapshot: Vec<u8>| Snapshot::deserialize(&serialized_snapshot).inspect_err(|error| warn!("Snapshot is ignored: {}", error)).ok();
+        let mut transaction_storage = transaction_processor.transaction_storage.lock().unwrap();
+        let mut pending_snapshot = transaction_storage.get_snapshot().and_then(deserialize).or_else(|| transaction_storage.get_previous_snapshot().and_then(deserialize));
+        drop(transaction_storage);
+        let mut skipped_transactions: Vec<Box<SerializedTransaction>> = Vec::new();
+        let mut replayed_transactions: VecDeque<Box<SerializedTransaction>> = VecDeque::new();
+        let mut last_processed_transaction_id: usize = 0;
+        let mut record_count: usize = 0;
+        let mut log_size: usize = 0;
+        loop
+        {
+            if let Some(snapshot) = pending_snapshot.take_if(|snapshot| snapshot.processed_record_count == record_count)
+            {
+                let mut db = transaction_processor.db_lock_arc.write().unwrap();
+                match snapshot.load(&mut *db)
+                {
+                    Ok(()) =>
+                    {
+                        last_processed_transaction_id = snapshot.last_processed_transaction_id;
+                        *transaction_processor.last_processed_transaction_id_lock.write().unwrap() = last_processed_transaction_id;
+                        *transaction_processor.processed_record_count.lock().unwrap() = record_count;
+                        let mut failed_ids_in_snapshot: Vec<usize> = failed_transaction_ids.iter().copied().filter(|id| *id <= last_processed_transaction_id).collect();
+                        failed_ids_in_snapshot.sort_unstable();
+                        transaction_processor.failed_transaction_ids_lock.write().unwrap().extend(failed_ids_in_snapshot);
+                        skipped_transactions.clear();
+                    },
+                    Err(error) =>
+                    {
+                        warn!("Snapshot is ignored, the whole log is replayed: {}", error);
+                        replayed_transactions.extend(skipped_transactions.drain(..));
+                        record_count = 0;
+                        log_size = 0;
+                    }
+                }
+            }
+
+            let serialized_transaction = match replayed_transactions.pop_front()
+            {
+                Some(serialized_transaction) => Some(serialized_transaction),
+                None => transaction_processor.transaction_storage.lock().unwrap().get()
+            };
+            let Some(serialized_transaction) = serialized_transaction else
+            {
+                // The log ends before the last transaction of the snapshot, so the snapshot is newer than the log
+                if pending_snapshot.take().is_some()
+                {
+                    warn!("Snapshot is ignored, the whole log is replayed: the log contains only {} of its records", record_count);
+                    replayed_transactions.extend(skipped_transactions.drain(..));
+                    record_count = 0;
+                    log_size = 0;
+                    continue;
+                }
+                break;
+            };
+            record_count += 1;
+            log_size += get_record_size(&serialized_transaction.name, serialized_transaction.serialized_parameters.len(), &serialized_transaction.metadata);
+
+            if let Some(snapshot) = &pending_snapshot
+            {
+                if serialized_transaction.transaction_id <= snapshot.last_processed_transaction_id
+                {
+                    skipped_transactions.push(serialized_transaction);
+                    continue;
+                }
+                // A transaction after the snapshot precedes some records of the snapshot, so the log and the snapshot do not match
+                warn!("Snapshot is ignored, the whole log is replayed: transaction {} of record {} is not in the snapshot", serialized_transaction.transaction_id, record_count);
+                pending_snapshot = None;
+                replayed_transactions.extend(skipped_transactions.drain(..));
+                replayed_transactions.push_back(serialized_transaction);
+                record_count = 0;
+                log_size = 0;
+                continue;
+            }
+
+            // Transaction identifiers of the records must be strictly increasing (identifiers of non-durable commands are missing)
+            let transaction_id = serialized_transaction.transaction_id;
+            if transaction_id <= last_processed_transaction_id
+            {
+                match options.replay_order_policy
+                {
+                    ReplayOrderPolicy::Abort => return Err(EngineError::ReplayFailed(format!("Transaction {} of record {} of the log does not follow transaction {}", transaction_id, record_count, last_processed_transaction_id))),
+                    ReplayOrderPolicy::WarnAndSkip =>
+                    {
+                        warn!("Record {} of the log is skipped, because transaction {} does not follow transaction {}", record_count, transaction_id, last_processed_transaction_id);
+                        *transaction_processor.processed_record_count.lock().unwrap() = record_count;
+                        continue;
+                    }
+                }
+            }
+            last_processed_transaction_id = transaction_id;
+            // Follow-up commands found in the log are not pushed again after the replay
+            if let Some(parent_transaction_id) = serialized_transaction.metadata.get(FOLLOW_UP_METADATA_KEY).and_then(|value| value.parse().ok())
+            {
+                transaction_processor.remove_follow_up_command(parent_transaction_id, &serialized_transaction.name);
+            }
+            // Failed transactions were rolled back, so they are not run again (only their status is restored)
+            if failed_transaction_ids.contains(&transaction_id)
+            {
+                transaction_processor.failed_transaction_ids_lock.write().unwrap().push(transaction_id);
+                *transaction_processor.last_processed_transaction_id_lock.write().unwrap() = transaction_id;
+                *transaction_processor.processed_record_count.lock().unwrap() = record_count;
+                continue;
+            }
+            // Empty names are rejected by push_command, so a record with an empty name is corrupted
+            if serialized_transaction.name.is_empty()
+            {
+                return Err(EngineError::ReplayFailed(format!("Record {} of the log (transaction {}) has an empty command name", record_count, transaction_id)));
+            }
+            // Unknown commands and corrupted records of multi-command transactions stop the replay
+            let replay_error = |error: &dyn Display| EngineError::ReplayFailed(format!("Record {} of the log (transaction {}) can not be replayed: {}", record_count, transaction_id, error));
+            command_definitions.check_record(&serialized_transaction.name, &serialized_transaction.serialized_parameters).map_err(|engine_error| replay_error(&engine_error))?;
+            let run_record = |db: &mut D| command_definitions.run_record(db, &serialized_transaction.name, &serialized_transaction.serialized_parameters)
+                .unwrap_or_else(|engine_error| Err(CommandFailure::from(engine_error.to_string())));
+
+            match checkpoint
+            {
+                Some(checkpoint) if record_count > checkpoint =>
+                {
+                    // Pending transactions are processed in the same way as new ones, so they may fail
+                    *transaction_processor.last_processed_transaction_id_lock.write().unwrap() = transaction_id - 1;
+                    let _ = transaction_processor.run_in_transaction(transaction_id, &serialized_transaction.name, true, None, run_record)?;
+                },
+                _ =>
+                {
+                    let mut db = transaction_processor.db_lock_arc.write().unwrap();
+                    *transaction_processor.last_processed_transaction_id_lock.write().unwrap() = transaction_id;
+                    *transaction_processor.processed_record_count.lock().unwrap() = record_count;
+                    // Parameters are deserialized directly from the buffer read from the storage
+                    let outcome = replay_record(&mut db, &transaction_processor.transaction_manager_ref, &*command_definitions, &serialized_transaction)
+                        .map_err(|engine_error| replay_error(&engine_error))?
+                        .map_err(|failure| replay_error(&format!("transaction failed, what was succesful earlier: {}", failure.message)))?;
+                    transaction_processor.add_follow_up_commands(transaction_id, &outcome);
+                }
+            }
+
+            if let Some((batch_size, pause)) = options.replay_throttle
+            {
+                if record_count.is_multiple_of(batch_size)
+                {
+                    thread::sleep(pause);
+                }
+            }
+        }
+
+        transaction_processor.log_size.store(log_size, Ordering::Relaxed);
+
+        // The first snapshot contains the replayed state
+        if let Some(snapshot_publisher) = &transaction_processor.snapshot_publisher
+        {
+            snapshot_publisher.publish(&transaction_processor.db_lock_arc.read().unwrap());
+        }
+
+        let mut command_engine = Self {
+             command_definitions,
+             last_pushed_transaction_id: last_processed_transaction_id,
+             transaction_processor,
+             command_execution_type,
+             command_sender: None,
+             max_parameters_size: None,
+             max_log_size: None,
+             disabled_commands: HashSet::new(),
+             scheduled_commands: Vec::new(),
+             worker_handle: None,
+             skip_empty_transactions: options.skip_empty_transactions,
+             shut_down: false
+             };
+
+        if command_engine.command_execution_type == CommandExecutionType::Asynchronous
+        {
+            let (command_sender, mut command_receiver) = mpsc::channel::<QueuedCommand<D>>(options.channel_capacity);
+            command_engine.command_sender = Some(command_sender);
+
+            let transaction_processor = command_engine.transaction_processor.clone();
+            command_engine.worker_handle = Some(thread::spawn(move ||
+                {
+                    let _worker_stopped_guard = WorkerStoppedGuard { transaction_processor: transaction_processor.clone() };
+                    loop
+                    {
+                        let command = block_on(command_receiver.recv());
+
+                        // If the channel is closed by the other thread
+                        if command.is_none()
+                        {
+                            break;
+                        }
+
+                        let queued_command = command.unwrap();
+
+                        if let Err(engine_error) = transaction_processor.process(queued_command.transaction_id, queued_command.command.as_ref(), queued_command.commit_sender, queued_command.pending_record)
+                        {
+                            error!("Command processing thread stopped: {}", engine_error);
+                            break;
+                        }
+                    }
+                }
+            ));
+        }
+
+        // Follow-up commands returned during the replay, but missing from the log are pushed now
+        if let Err(engine_error) = command_engine.push_follow_up_commands()
+        {
+            error!("Pushing the follow-up commands after the replay failed: {}", engine_error);
+        }
+
+        Ok(command_engine)
+    }
+
+    // Push a command and get the identifier of its transaction (commands are applied in the order they are pushed)
+    pub fn push_command(&mut self, cmd: SharedCommand<D>) -> Result<usize, EngineError>
+    {
+        self.submit_command(cmd, None, HashMap::new())
+    }
+
+    // Push a command with metadata (like a correlation identifier) stored alongside its record in the transaction log
+    pub fn push_command_with_metadata(&mut self, cmd: SharedCommand<D>, metadata: HashMap<String, String>) -> Result<usize, EngineError>
+    {
+        self.submit_command(cmd, None, metadata)
+    }
+
+    // Push a command and wait until its transaction is processed, so queries started afterwards see its effect in both execution types
+    pub fn push_command_and_wait(&mut self, cmd: SharedCommand<D>) -> Result<usize, EngineError>
+    {
+        let transaction_id = self.push_command(cmd)?;
+        self.wait_for_transaction(transaction_id)?;
+        Ok(transaction_id)
+    }
+
+    // Push a command and get a future resolving when its transaction is committed or rolled back
+    pub fn push_command_with_handle(&mut self, cmd: SharedCommand<D>) -> Result<CommitHandle, EngineError>
+    {
+        let (commit_sender, receiver) = oneshot::channel();
+        let transaction_id = self.submit_command(cmd, Some(commit_sender), HashMap::new())?;
+        Ok(CommitHandle { transaction_id, receiver })
+    }
+
+    // Push multiple commands run in order in a single transaction written as one record
+    pub fn push_transaction(&mut self, cmds: Vec<SharedCommand<D>>) -> Result<usize, EngineError>
+    {
+        for cmd in &cmds
+        {
+            if cmd.get_name().is_empty()
+            {
+                return Err(EngineError::EmptyCommandName);
+            }
+            if self.disabled_commands.contains(cmd.get_name())
+            {
+                return Err(EngineError::CommandDisabled(String::from(cmd.get_name())));
+            }
+        }
+        self.submit_command(Arc::new(TransactionCommand::new(cmds)), None, HashMap::new())
+    }
+
+    // Set the maximum size of serialized command parameters (None means unlimited). Larger commands are rejected by push_command.
+    pub fn set_max_parameters_size(&mut self, max_parameters_size: Option<usize>)
+    {
+        self.max_parameters_size = max_parameters_size;
+    }
+
+    // Set the maximum size of the transaction log in bytes (None means unlimited)
+    pub fn set_max_log_size(&mut self, max_log_size: Option<usize>)
+    {
+        self.max_log_size = max_log_size;
+    }
+
+    // Reject the command with the name from now on (it is not persisted)
+    pub fn disable_command(&mut self, name: &str)
+    {
+        self.disabled_commands.insert(String::from(name));
+    }
+
+    // Accept the command with the name again
+    pub fn enable_command(&mut self, name: &str)
+    {
+        self.disabled_commands.remove(name);
+    }
+
+    // Push a command given by its registered name and serialized parameters
+    pub fn push_serialized(&mut self, name: &str, serialized_parameters: Vec<u8>) -> Result<usize, EngineError>
+    {
+        let command_definition = self.command_definitions.try_get(name).ok_or_else(|| EngineError::UnknownCommand(String::from(name)))?;
+        let cmd = encrypted::with_field_cipher(self.transaction_processor.field_cipher.clone(), || command_definition.create_shared(&serialized_parameters)).map_err(EngineError::Serialization)?;
+        self.push_command(cmd)
+    }
+
+    // Push the follow-up commands returned by the committed transactions and get their transaction identifiers
+    pub fn push_follow_up_commands(&mut self) -> Result<Vec<usize>, EngineError>
+    {
+        let mut transaction_ids = Vec::new();
+        // Follow-up commands of synchronous follow-up commands are added to the queue while it is processed
+        while let Some((cmd, metadata)) = self.pop_follow_up_command()?
+        {
+            transaction_ids.push(self.submit_single_command(cmd, None, metadata)?);
+        }
+        Ok(transaction_ids)
+    }
+
+    // Take the next follow-up command from the queue as a command and its metadata
+    #[allow(clippy::type_complexity)]
+    fn pop_follow_up_command(&mut self) -> Result<Option<(SharedCommand<D>, HashMap<String, String>)>, EngineError>
+    {
+        let follow_up_command = self.transaction_processor.follow_up_commands.lock().map_err(|_| EngineError::LockPoisoned("follow-up commands"))?.pop_front();
+        let Some((parent_transaction_id, follow_up_command)) = follow_up_command else { return Ok(None); };
+        let command_definition = self.command_definitions.try_get(follow_up_command.name).ok_or_else(|| EngineError::UnknownCommand(String::from(follow_up_command.name)))?;
+        let cmd = encrypted::with_field_cipher(self.transaction_processor.field_cipher.clone(), || command_definition.create_shared(&follow_up_command.serialized_parameters)).map_err(EngineError::Serialization)?;
+        let metadata = HashMap::from([(String::from(FOLLOW_UP_METADATA_KEY), parent_transaction_id.to_string())]);
+        Ok(Some((cmd, metadata)))
+    }
+
+    fn submit_command(&mut self, cmd: SharedCommand<D>, commit_sender: Option<CommitSender>, metadata: HashMap<String, String>) -> Result<usize, EngineError>
+    {
+        let transaction_id = self.submit_single_command(cmd, commit_sender, metadata)?;
+        // The command is accepted, so failing to push a follow-up command is not an error of the push
+        if let Err(engine_error) = self.push_follow_up_commands()
+        {
+            error!("Pushing a follow-up command failed: {}", engine_error);
+        }
+        Ok(transaction_id)
+    }
+
+    fn submit_single_command(&mut self, cmd: SharedCommand<D>, commit_sender: Option<CommitSender>, metadata: HashMap<String, String>) -> Result<usize, EngineError>
+    {
+        let queued_command = self.accept_command(cmd, commit_sender, metadata)?;
+        let transaction_id = queued_command.transaction_id;
+
+        if self.command_execution_type == CommandExecutionType::Synchronous
+        {
+            self.transaction_processor.process(transaction_id, queued_command.command.as_ref(), queued_command.commit_sender, queued_command.pending_record)?;
+        }
+        else
+        {
+            // Sending fails only if the command processing thread stopped. Blocking is needed only if the queue is full.
+            let command_sender = self.command_sender.as_ref().unwrap();
+            match command_sender.try_send(queued_command)
+            {
+                Ok(()) => {},
+                Err(mpsc::error::TrySendError::Full(queued_command)) => block_on(command_sender.send(queued_command)).map_err(|_| EngineError::WorkerStopped)?,
+                Err(mpsc::error::TrySendError::Closed(_)) => return Err(EngineError::WorkerStopped)
+            }
+        }
+
+        Ok(transaction_id)
+    }
+
+    // Push a command without blocking the calling thread while the queue is full
+    pub async fn push_command_async(&mut self, cmd: SharedCommand<D>) -> Result<usize, EngineError>
+    {
+        let transaction_id = self.submit_single_command_async(cmd, HashMap::new()).await?;
+        // The command is accepted, so failing to push a follow-up command is not an error of the push
+        loop
+        {
+            let follow_up_command = match self.pop_follow_up_command()
+            {
+                Ok(follow_up_command) => follow_up_command,
+                Err(engine_error) => { error!("Pushing a follow-up command failed: {}", engine_error); break; }
+            };
+            let Some((cmd, metadata)) = follow_up_command else { break; };
+            if let Err(engine_error) = self.submit_single_command_async(cmd, metadata).await
+            {
+                error!("Pushing a follow-up command failed: {}", engine_error);
+                break;
+            }
+        }
+        Ok(transaction_id)
+    }
+
+    async fn submit_single_command_async(&mut self, cmd: SharedCommand<D>, metadata: HashMap<String, String>) -> Result<usize, EngineError>
+    {
+        if self.command_execution_type == CommandExecutionType::Synchronous
+        {
+            let queued_command = self.accept_command(cmd, None, metadata)?;
+            self.transaction_processor.process(queued_command.transaction_id, queued_command.command.as_ref(), queued_command.commit_sender, queued_command.pending_record)?;
+            return Ok(queued_command.transaction_id);
+        }
+
+        // A place in the queue is reserved first, so dropping the future never leaves an accepted command behind
+        check_not_in_command()?;
+        self.check_running()?;
+        let command_sender = self.command_sender.clone().ok_or(EngineError::WorkerStopped)?;
+        // Reserving fails only if the command processing thread stopped
+        let permit = command_sender.reserve().await.map_err(|_| EngineError::WorkerStopped)?;
+        let queued_command = self.accept_command(cmd, None, metadata)?;
+        let transaction_id = queued_command.transaction_id;
+        permit.send(queued_command);
+
+        Ok(transaction_id)
+    }
+
+    // Check a command, write it to the transaction storage (unless its record is deferred) and assign its transaction identifier
+    fn accept_command(&mut self, cmd: SharedCommand<D>, commit_sender: Option<CommitSender>, metadata: HashMap<String, String>) -> Result<QueuedCommand<D>, EngineError>
+    {
+        check_not_in_command()?;
+        // Commands are not accepted if they could never be processed
+        self.check_running()?;
+        if cmd.get_name().is_empty()
+        {
+            return Err(EngineError::EmptyCommandName);
+        }
+        if self.disabled_commands.contains(cmd.get_name())
+        {
+            return Err(EngineError::CommandDisabled(String::from(cmd.get_name())));
+        }
+
+        // Parameters are serialized only if they are written to the storage or their size must be checked
+        let mut pending_record = None;
+        if cmd.is_durable() || self.max_parameters_size.is_some()
+        {
+            let serialized_parameters = encrypted::with_field_cipher(self.transaction_processor.field_cipher.clone(), || cmd.get_serialized_parameters()).map_err(EngineError::Serialization)?;
+            if let Some(max_parameters_size) = self.max_parameters_size
+            {
+                if serialized_parameters.len() > max_parameters_size
+                {
+                    return Err(EngineError::ParametersTooLarge { size: serialized_parameters.len(), max_size: max_parameters_size });
+                }
+            }
+            if cmd.is_durable()
+            {
+                let name = String::from(cmd.get_name());
+                let record_size = get_record_size(&name, serialized_parameters.len(), &metadata);
+                if let Some(max_log_size) = self.max_log_size
+                {
+                    let log_size = self.transaction_processor.log_size.load(Ordering::Relaxed);
+                    if log_size + record_size > max_log_size
+                    {
+                        return Err(EngineError::LogFull { size: log_size, max_size: max_log_size });
+                    }
+                }
+                if self.skip_empty_transactions
+                {
+                    pending_record = Some(PendingRecord { name, serialized_parameters, metadata });
+                }
+                else
+                {
+                    let mut transaction_storage = self.transaction_processor.transaction_storage.lock().map_err(|_| EngineError::LockPoisoned("transaction storage"))?;
+                    transaction_storage.add(self.last_pushed_transaction_id + 1, name, Box::new(serialized_parameters), &metadata).map_err(|e| EngineError::StorageIo(e.to_string()))?;
+                    self.transaction_processor.log_size.fetch_add(record_size, Ordering::Relaxed);
+                }
+            }
+        }
+        self.last_pushed_transaction_id += 1;
+
+        Ok(QueuedCommand { transaction_id: self.last_pushed_transaction_id, command: cmd, commit_sender, pending_record })
+    }
+
+    // Register a command run periodically by run_scheduled_commands (schedules are not persisted)
+    pub fn schedule_command(&mut self, first_run: u64, interval: u64, create: impl Fn(u64) -> SharedCommand<D> + Send + 'static)
+    {
+        assert!(interval > 0, "Interval of a scheduled command must be positive");
+        self.scheduled_commands.push(ScheduledCommand { interval, next_run: first_run, create: Box::new(create) });
+    }
+
+    // Push the scheduled commands due at the given logical time and get their transaction identifiers
+    pub fn run_scheduled_commands(&mut self, now: u64) -> Result<Vec<usize>, EngineError>
+    {
+        let mut due_commands = Vec::new();
+        for scheduled_command in self.scheduled_commands.iter_mut()
+        {
+            while scheduled_command.next_run <= now
+            {
+                due_commands.push((scheduled_command.next_run, (scheduled_command.create)(scheduled_command.next_run)));
+                scheduled_command.next_run += scheduled_command.interval;
+            }
+        }
+        // Runs of different commands are pushed in the order of their times
+        due_commands.sort_by_key(|(run_time, _)| *run_time);
+        due_commands.into_iter().map(|(_, command)| self.push_command(command)).collect()
+    }
+
+    // Register an invariant: a condition of the database, what must hold after every transaction
+    pub fn add_invariant(&mut self, name: &str, check: impl Fn(&D) -> Result<(), String> + Send + Sync + 'static)
+    {
+        self.transaction_processor.invariants.write().unwrap().push(Invariant { name: String::from(name), check: Box::new(check) });
+    }
+
+    // Run all registered invariants on the current state of the database (e.g. periodically or in tests)
+    pub fn check_invariants(&self) -> Result<(), Vec<InvariantViolation>>
+    {
+        let db = self.transaction_processor.db_lock_arc.read().unwrap();
+        let violations = self.transaction_processor.check_invariants(&db);
+        if violations.is_empty() { Ok(()) } else { Err(violations) }
+    }
+
+    // Check the invariants after every committed transaction and panic on a violation (debug builds only)
+    pub fn set_invariant_checks(&mut self, enabled: bool)
+    {
+        self.transaction_processor.check_invariants_after_commit.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_rollback_failure_policy(&mut self, rollback_failure_policy: RollbackFailurePolicy)
+    {
+        self.transaction_processor.transaction_manager_ref.lock().unwrap().set_rollback_failure_policy(rollback_failure_policy);
+    }
+
+    // Register a callback fired after a transaction is rolled back (replaces the previously registered one)
+    pub fn set_rollback_observer(&mut self, rollback_observer: RollbackObserver)
+    {
+        *self.transaction_processor.rollback_observer.write().unwrap() = Some(rollback_observer);
+    }
+
+    // Register a callback fired after every command running longer than the threshold (replaces the previously registered one)
+    pub fn set_slow_command_observer(&mut self, threshold: Duration, slow_command_observer: SlowCommandObserver)
+    {
+        *self.transaction_processor.slow_command_observer.write().unwrap() = Some((threshold, slow_command_observer));
+    }
+
+    // Register a callback fired after every committed transaction (replaces the previous one)
+    pub fn set_post_commit_hook(&mut self, post_commit_hook: PostCommitHook<D>)
+    {
+        *self.transaction_processor.post_commit_hook.write().unwrap() = Some(post_commit_hook);
+    }
+
+    // Persist the state of all tables, so a restart replays only the transactions after it
+    pub fn snapshot(&self) -> Result<(), EngineError>
+    {
+        let db = self.transaction_processor.db_lock_arc.read().map_err(|_| EngineError::LockPoisoned("database"))?;
+        // No transaction is running while the database is locked, so the snapshot contains exactly the processed transactions
+        let last_processed_transaction_id = *self.transaction_processor.last_processed_transaction_id_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let processed_record_count = *self.transaction_processor.processed_record_count.lock().map_err(|_| EngineError::LockPoisoned("processed record count"))?;
+        let mut tables = Vec::new();
+        for table in db.get_tables()
+        {
+            let rows = encrypted::with_field_cipher(self.transaction_processor.field_cipher.clone(), || table.serialize_rows()).map_err(EngineError::Serialization)?;
+            tables.push((table.get_id(), table.get_max_used_id(), rows));
+        }
+        drop(db);
+
+        let snapshot = Snapshot { last_processed_transaction_id, processed_record_count, tables };
+        let serialized_snapshot = snapshot.serialize().map_err(EngineError::Serialization)?;
+        let mut transaction_storage = self.transaction_processor.transaction_storage.lock().map_err(|_| EngineError::LockPoisoned("transaction storage"))?;
+        transaction_storage.set_snapshot(&serialized_snapshot).map_err(|e| EngineError::StorageIo(e.to_string()))
+    }
+
+    // Shrink the memory allocated by all tables between two transactions
+    pub fn shrink_all(&mut self)
+    {
+        self.transaction_processor.db_lock_arc.write().unwrap().shrink_all();
+    }
+
+    // Create a copy of the committed database with a separate synchronous engine (clones all tables, not copy-on-write)
+    pub fn clone_fork(&self) -> (QueryEngine<D>, CommandEngine<D, C>) where D: Clone
+    {
+        let db = self.transaction_processor.db_lock_arc.read().unwrap();
+        // No transaction is running while the database is locked, so the fork continues the transactions of the original
+        let transaction_manager_ref = Arc::new(Mutex::new(self.transaction_processor.transaction_manager_ref.lock().unwrap().fork()));
+        let mut forked_db = db.clone();
+        drop(db);
+        for table in forked_db.get_tables_mut()
+        {
+            table.set_transaction_manager(transaction_manager_ref.clone());
+        }
+
+        let db_lock_arc = Arc::new(RwLock::new(forked_db));
+        let query_engine = QueryEngine { db_lock_arc: db_lock_arc.clone(), published_snapshot: Arc::default(), ready_signal: Arc::new(ReadySignal::new_ready()) };
+        let command_engine = CommandEngine::new_with_options(db_lock_arc, self.command_definitions.clone(), Box::new(NullTransactionStorage::new()), transaction_manager_ref, CommandExecutionType::Synchronous, EngineOptions::default())
+            .unwrap_or_else(|engine_error| panic!("{}", engine_error));
+        (query_engine, command_engine)
+    }
+
+    pub fn get_command_definitions(&self) -> Arc<C>
+    {
+        self.command_definitions.clone()
+    }
+
+    // Get the status of a transaction. Returns an error for a not executed transaction, what will never be executed, because the engine stopped.
+    pub fn get_transaction_status(&self, transaction_id: usize) -> Result<TransactionStatus, EngineError>
+    {
+        // Transaction identifiers are always valid, so they are recovered from poisoned locks
+        let last_processed_transaction_id = *self.transaction_processor.last_processed_transaction_id_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let failed_transaction_ids = self.transaction_processor.failed_transaction_ids_lock.read().unwrap_or_else(PoisonError::into_inner);
+
+        if transaction_id > last_processed_transaction_id
+            { self.check_running()?; Ok(TransactionStatus::NotExecuted) }
+        else if failed_transaction_ids.contains(&transaction_id)
+            { Ok(TransactionStatus::Failed) }
+        else
+            { Ok(TransactionStatus::Completed) }
+    }
+
+    // Get the error of a transaction failed since the engine was started
+    pub fn get_transaction_error(&self, transaction_id: usize) -> Option<CommandFailure>
+    {
+        self.transaction_processor.failed_transaction_errors_lock.read().unwrap_or_else(PoisonError::into_inner).get(&transaction_id).cloned()
+    }
+
+    // Returns an error if commands can not be processed anymore
+    fn check_running(&self) -> Result<(), EngineError>
+    {
+        if self.shut_down
+        {
+            return Err(EngineError::ShuttingDown);
+        }
+        if self.transaction_processor.db_lock_arc.is_poisoned()
+        {
+            return Err(EngineError::LockPoisoned("database"));
+        }
+        if self.transaction_processor.worker_stopped.load(Ordering::SeqCst)
+        {
+            return Err(EngineError::WorkerStopped);
+        }
+        Ok(())
+    }
+
+    // Stop the engine after processing and persisting all pushed commands (later commands are rejected)
+    pub fn shutdown(&mut self) -> Result<(), EngineError>
+    {
+        // Follow-up commands are known only after the commands returning them are processed
+        loop
+        {
+            self.wait_for_transaction(self.last_pushed_transaction_id)?;
+            if self.push_follow_up_commands()?.is_empty()
+            {
+                break;
+            }
+        }
+        // Commands are rejected from now on, because the command processing thread exits
+        self.shut_down = true;
+        // Closing the channel makes the command processing thread exit, what happens after the queue is empty
+        self.command_sender = None;
+        if let Some(worker_handle) = self.worker_handle.take()
+        {
+            worker_handle.join().map_err(|_| EngineError::WorkerStopped)?;
+        }
+        let mut transaction_storage = self.transaction_processor.transaction_storage.lock().map_err(|_| EngineError::LockPoisoned("transaction storage"))?;
+        transaction_storage.flush().map_err(|e| EngineError::StorageIo(e.to_string()))
+    }
+
+    // Wait until a transaction is processed without blocking the calling thread (see wait_for_transaction)
+    pub async fn wait_for_transaction_async(&self, transaction_id: usize) -> Result<(), EngineError>
+    {
+        let processed_transaction_id_notify = self.transaction_processor.processed_transaction_id_notify.clone();
+
+        loop
+        {
+            // Register for the notification before checking the condition to not to miss a notification between them
+            let notified = processed_transaction_id_notify.notified();
+            futures::pin_mut!(notified);
+            notified.as_mut().enable();
+
+            let last_processed_transaction_id = *self.transaction_processor.last_processed_transaction_id_lock.read().unwrap_or_else(PoisonError::into_inner);
+            if transaction_id <= last_processed_transaction_id
+            {
+                return Ok(());
+            }
+            self.check_running()?;
+
+            notified.await;
+        }
+    }
+
+    // Wait until a transaction is processed. Returns an error if the engine stopped before processing it.
+    pub fn wait_for_transaction(&mut self, transaction_id: usize) -> Result<(), EngineError>
+    {
+        block_on(self.wait_for_transaction_async(transaction_id))
+    }
+}
+
+pub struct Engine
+{
+}
+
+impl Engine
+{
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<D, C>(command_definitions: C, transaction_storage: Box<dyn TransactionStorage>, command_execution_type: CommandExecutionType, init: &'static dyn Fn(&mut D)) -> (QueryEngine<D>, CommandEngine<D, C>) where D: Database + DatabaseFactory + Send + Sync + 'static, C: CommandDirectory<D>
+    {
+        Self::builder(command_definitions, transaction_storage).with_command_execution_type(command_execution_type).with_init(init).build()
+    }
+
+    // Create a builder for the less frequently used options of the engine
+    pub fn builder<D, C>(command_definitions: C, transaction_storage: Box<dyn TransactionStorage>) -> EngineBuilder<D, C> where D: Database + DatabaseFactory + Send + Sync + 'static, C: CommandDirectory<D>
+    {
+        EngineBuilder { command_definitions, transaction_storage, command_execution_type: CommandExecutionType::Synchronous, init: None, on_startup: None, options: EngineOptions::default() }
+    }
+
+    // Reconstruct the database from the transactions of the log with identifiers in [start_id, end_id] only
+    pub fn replay_range<D, C>(mut transaction_storage: Box<dyn TransactionStorage>, command_definitions: &C, init: impl FnOnce(&mut D), start_id: usize, end_id: usize) -> D where D: Database + DatabaseFactory, C: CommandDirectory<D>
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let mut db = D::create_database(transaction_manager_ref.clone());
+        init(&mut db);
+        let db_lock = RwLock::new(db);
+        let failed_transaction_ids: HashSet<usize> = transaction_storage.get_failed_transaction_ids()
+            .unwrap_or_else(|error| panic!("Reading the failed transaction identifiers failed: {}", error)).into_iter().collect();
+
+        // Transaction identifiers of the records are increasing, so the records after the range are not read
+        let serialized_transactions = TransactionLogReader::new(transaction_storage)
+            .skip_while(|serialized_transaction| serialized_transaction.transaction_id < start_id)
+            .take_while(|serialized_transaction| serialized_transaction.transaction_id <= end_id)
+            .filter(|serialized_transaction| !failed_transaction_ids.contains(&serialized_transaction.transaction_id));
+        for serialized_transaction in serialized_transactions
+        {
+            let mut db = db_lock.write().unwrap();
+            let _ = replay_record(&mut db, &transaction_manager_ref, command_definitions, &serialized_transaction).unwrap_or_else(|engine_error| panic!("{}", engine_error));
+        }
+
+        db_lock.into_inner().unwrap()
+    }
+}
+
+// Function initializing the empty database before the replay
+type InitFunction<D> = Box<dyn FnOnce(&mut D)>;
+
+pub struct EngineBuilder<D, C> where D: Database + DatabaseFactory + Send + Sync + 'static, C: CommandDirectory<D>
+{
+    command_definitions: C,
+    transaction_storage: Box<dyn TransactionStorage>,
+    command_execution_type: CommandExecutionType,
+    // Called on the empty database before the replay (like registering foreign keys)
+    init: Option<InitFunction<D>>,
+    // Called with the query engine before the replay
+    on_startup: Option<Box<dyn FnOnce(QueryEngine<D>)>>,
+    options: EngineOptions<D>
+}
+
+impl<D, C> EngineBuilder<D, C> where D: Database + DatabaseFactory + Send + Sync + 'static, C: CommandDirectory<D>
+{
+    // Set the execution type of commands (Synchronous by default)
+    pub fn with_command_execution_type(mut self, command_execution_type: CommandExecutionType) -> Self
+    {
+        self.command_execution_type = command_execution_type;
+        self
+    }
+
+    // Set the maximum number of commands queued for the command processing thread (100 by default)
+    pub fn with_channel_capacity(mut self, channel_capacity: usize) -> Self
+    {
+        assert!(channel_capacity > 0, "Channel capacity must be positive");
+        self.options.channel_capacity = channel_capacity;
+        self
+    }
+
+    // Set the function called on the empty database before the replay
+    pub fn with_init(mut self, init: impl FnOnce(&mut D) + 'static) -> Self
+    {
+        self.init = Some(Box::new(init));
+        self
+    }
+
+    // Pass a copy of the query engine to on_startup before the replay starts
+    pub fn with_startup_query_engine(mut self, on_startup: impl FnOnce(QueryEngine<D>) + 'static) -> Self
+    {
+        self.on_startup = Some(Box::new(on_startup));
+        self
+    }
+
+    // Do not write the records of transactions, what did not change the database (records are written after the commands ran)
+    pub fn with_empty_transactions_skipped(mut self) -> Self
+    {
+        self.options.skip_empty_transactions = true;
+        self
+    }
+
+    // Set the cipher of the Encrypted fields serialized or deserialized by the engine
+    pub fn with_field_cipher(mut self, field_cipher: impl FieldCipher + 'static) -> Self
+    {
+        self.options.field_cipher = Some(Arc::new(field_cipher));
+        self
+    }
+
+    // Set the handling of log records with out of order transaction identifiers during replay (Abort by default)
+    pub fn with_replay_order_policy(mut self, replay_order_policy: ReplayOrderPolicy) -> Self
+    {
+        self.options.replay_order_policy = replay_order_policy;
+        self
+    }
+
+    // Pause the replay for the given duration after every batch_size records
+    pub fn with_replay_throttle(mut self, batch_size: usize, pause: Duration) -> Self
+    {
+        assert!(batch_size > 0, "Replay batch size must be positive");
+        self.options.replay_throttle = Some((batch_size, pause));
+        self
+    }
+
+    // Publish a copy of the database after every interval committed transactions (see QueryEngine::stale_read)
+    pub fn with_published_snapshots(mut self, interval: usize) -> Self where D: Clone
+    {
+        assert!(interval > 0, "Snapshot interval must be positive");
+        let published_snapshot = Arc::new(RwLock::new(None));
+        self.options.snapshot_publisher = Some(SnapshotPublisher { clone_fn: D::clone, interval, commit_count: Mutex::new(0), published_snapshot });
+        self
+    }
+
+    // Create the database, replay the transaction log and start the engine. Panics if the log can not be replayed (see try_build).
+    pub fn build(self) -> (QueryEngine<D>, CommandEngine<D, C>)
+    {
+        self.try_build().unwrap_or_else(|engine_error| panic!("{}", engine_error))
+    }
+
+    // Create the database, replay the transaction log and start the engine, or return the error of the replay
+    pub fn try_build(self) -> Result<(QueryEngine<D>, CommandEngine<D, C>), EngineError>
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let mut db = D::create_database(transaction_manager_ref.clone());
+        if let Some(init) = self.init
+        {
+            init(&mut db);
+        }
+        let db_lock_arc = Arc::new(RwLock::new(db));
+        let published_snapshot = self.options.snapshot_publisher.as_ref().map(|snapshot_publisher| snapshot_publisher.published_snapshot.clone()).unwrap_or_default();
+        let query_engine = QueryEngine { db_lock_arc: db_lock_arc.clone(), published_snapshot, ready_signal: Arc::new(ReadySignal::default()) };
+        if let Some(on_startup) = self.on_startup
+        {
+            on_startup(query_engine.clone());
+        }
+        // The snapshot and the records of the log are deserialized by the replay
+        let field_cipher = self.options.field_cipher.clone();
+        let command_engine = encrypted::with_field_cipher(field_cipher, || CommandEngine::new_with_options(db_lock_arc, Arc::new(self.command_definitions), self.transaction_storage, transaction_manager_ref, self.command_execution_type, self.options));
+        query_engine.ready_signal.set_ready();
+        Ok((query_engine, command_engine?))
+    }
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::command::{CommandDefinition, CommandDirectoryFactory};
+    use crate::test_fixtures::*;
+    use crate::transaction_storage::{FileTransactionStorage, MemoryTransactionStorage};
+    use microdb_derive::{CommandDirectory, CommandDirectoryFactory};
+
+    #[test]
+    fn commit_handle_resolves_with_the_result_of_the_command()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+
+        let committed = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let rolled_back = command_engine.push_command_with_handle(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        assert_eq!(rolled_back.get_transaction_id(), committed.get_transaction_id() + 1);
+
+        assert!(block_on(committed).is_ok());
+        assert!(matches!(block_on(rolled_back), Err(CommandError::Failed(message)) if message == "Flight MA200 is not allowed"));
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    #[test]
+    fn commit_handle_resolves_after_the_asynchronous_commit()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let commands = command_engine.get_command_definitions();
+
+        let commit_handle = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+
+        assert!(block_on(commit_handle).is_ok());
+        // The transaction is committed, when the handle resolves
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    #[test]
+    fn checkpoint_is_kept_over_a_restart()
+    {
+        let path = create_test_directory("checkpoint_restart");
+        let (_, mut command_engine) = create_engine(FileTransactionStorage::new(&path).with_checkpoint().unwrap());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))).unwrap();
+        command_engine.shutdown().unwrap();
+        drop(command_engine);
+
+        assert_eq!(FileTransactionStorage::new(&path).with_checkpoint().unwrap().get_checkpoint().unwrap(), Some(3));
+        let (query_engine, command_engine) = create_engine(FileTransactionStorage::new(&path).with_checkpoint().unwrap());
+        let mut flight_numbers = query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>());
+        flight_numbers.sort();
+        assert_eq!(flight_numbers, vec!["MA100", "MA300"]);
+        assert_eq!(command_engine.get_transaction_status(2).unwrap(), TransactionStatus::Failed);
+    }
+
+    #[test]
+    fn commands_accepted_but_not_processed_before_a_restart_are_processed_on_replay()
+    {
+        let path = create_test_directory("checkpoint_pending");
+        let (_, mut command_engine) = create_engine(FileTransactionStorage::new(&path).with_checkpoint().unwrap());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.shutdown().unwrap();
+        drop(command_engine);
+        // Records written to the log, but not processed (like after a crash with a full command queue)
+        let mut storage = FileTransactionStorage::new(&path).with_checkpoint().unwrap();
+        storage.add(2, String::from("add_flight_and_fail"), Box::new(bincode::serialize(&flight("MA200", 10)).unwrap()), &HashMap::new()).unwrap();
+        storage.add(3, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA300", 10)).unwrap()), &HashMap::new()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let (query_engine, command_engine) = create_engine(FileTransactionStorage::new(&path).with_checkpoint().unwrap());
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+        assert_eq!(command_engine.get_transaction_status(2).unwrap(), TransactionStatus::Failed);
+        assert_eq!(command_engine.get_transaction_status(3).unwrap(), TransactionStatus::Completed);
+    }
+
+    #[test]
+    fn remove_cascade_removes_the_referencing_entities_and_is_rolled_back()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let flights_table_id = db.flights.get_id();
+        db.reservations.add_cascading_foreign_key(|reservation| reservation.flight_id, flights_table_id);
+        let flight_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let other_flight_id = db.flights.add(Box::new(flight("MA200", 10)));
+        db.reservations.add(Box::new(reservation(flight_id, "Alice")));
+        db.reservations.add(Box::new(reservation(flight_id, "Bob")));
+        let kept_id = db.reservations.add(Box::new(reservation(other_flight_id, "Carol")));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        assert_eq!(db.remove_cascade(flights_table_id, flight_id), 3);
+        assert_eq!(db.reservations.iter().map(|reservation| reservation.get_id()).collect::<Vec<_>>(), vec![kept_id]);
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert!(db.flights.contains(flight_id));
+        assert_eq!(db.reservations.len(), 3);
+    }
+
+    #[test]
+    fn commands_pushed_by_concurrent_producers_are_applied_in_the_order_of_acceptance()
+    {
+        let (query_engine, command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let command_engine = Arc::new(Mutex::new(command_engine));
+
+        let producers: Vec<_> = (0..4).map(|producer| {
+            let command_engine = command_engine.clone();
+            thread::spawn(move || (0..500).map(|index| {
+                let flight_number = format!("MA{}-{}", producer, index);
+                let mut command_engine = command_engine.lock().unwrap();
+                let cmd = command_engine.get_command_definitions().add_flight.create(flight(&flight_number, 10));
+                (command_engine.push_command(Arc::new(cmd)).unwrap(), flight_number)
+            }).collect::<Vec<_>>())
+        }).collect();
+        let pushed: Vec<(usize, String)> = producers.into_iter().flat_map(|producer| producer.join().unwrap()).collect();
+        command_engine.lock().unwrap().wait_for_transaction(2000).unwrap();
+
+        // Every command added its flight at the identifier equal to its transaction identifier
+        query_engine.query(|db| for (transaction_id, flight_number) in &pushed
+        {
+            assert_eq!(&db.flights.get(*transaction_id).unwrap().flight_number, flight_number);
+        });
+    }
+
+    #[test]
+    fn query_after_push_command_and_wait_sees_the_command_in_asynchronous_mode()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let commands = command_engine.get_command_definitions();
+
+        for index in 0..100
+        {
+            let transaction_id = command_engine.push_command_and_wait(Arc::new(commands.add_flight.create(flight("MA100", index)))).unwrap();
+            assert_eq!(query_engine.query(|db| db.flights.get(transaction_id).map(|flight| flight.seats)), Some(index));
+        }
+    }
+
+    #[test]
+    fn non_durable_commands_are_applied_but_not_replayed()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_temporary_flight.create(flight("MA200", 10)))).unwrap();
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+
+        assert_eq!(TransactionLogReader::new(Box::new(storage.reopen())).count(), 1);
+        let (query_engine, _) = create_engine(storage.reopen());
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+    }
+
+    #[test]
+    fn rollback_observer_is_called_with_the_details_of_the_rolled_back_transaction()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed_events = events.clone();
+        command_engine.set_rollback_observer(Box::new(move |event| observed_events.lock().unwrap().push(
+            (event.transaction_id, event.command_name.to_string(), event.error.to_string(), event.reverted_entities.clone(), event.rollback_errors.len()))));
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let transaction_id = command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+
+        let flights_table_id = query_engine.query(|db| db.flights.get_id());
+        assert_eq!(*events.lock().unwrap(), vec![(transaction_id, String::from("add_flight_and_fail"), String::from("Flight MA200 is not allowed"), vec![(flights_table_id, 2)], 0)]);
+    }
+
+    #[test]
+    fn commands_with_too_large_parameters_are_rejected()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        command_engine.set_max_parameters_size(Some(100));
+
+        let large_flight = Flight { flight_number: "M".repeat(200), ..flight("MA100", 10) };
+        assert!(matches!(command_engine.push_command(Arc::new(commands.add_flight.create(large_flight))),
+            Err(EngineError::ParametersTooLarge { max_size: 100, .. })));
+        // The rejected command does not get a transaction identifier and it is not written to the log
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap(), 1);
+        assert_eq!(TransactionLogReader::new(Box::new(storage.reopen())).count(), 1);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    #[test]
+    fn check_consistency_reports_reservations_of_removed_flights()
+    {
+        let (mut db, _) = create_database();
+        init(&mut db);
+        let removed_flight_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let kept_flight_id = db.flights.add(Box::new(flight("MA200", 10)));
+        let orphaned_id = db.reservations.add(Box::new(reservation(removed_flight_id, "Alice")));
+        db.reservations.add(Box::new(reservation(kept_flight_id, "Bob")));
+        assert!(db.check_consistency().is_empty());
+
+        db.flights.remove(removed_flight_id);
+
+        let orphaned_references = db.check_consistency();
+        assert_eq!(orphaned_references.len(), 1);
+        assert_eq!(orphaned_references[0].table_id, db.reservations.get_id());
+        assert_eq!(orphaned_references[0].referenced_table_id, db.flights.get_id());
+        assert_eq!(orphaned_references[0].entities, vec![(orphaned_id, removed_flight_id)]);
+    }
+
+    #[test]
+    fn metadata_of_a_command_is_stored_with_its_record()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        let metadata = HashMap::from([(String::from("request_id"), String::from("42"))]);
+        command_engine.push_command_with_metadata(Arc::new(commands.add_flight.create(flight("MA100", 10))), metadata.clone()).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).collect();
+        assert_eq!(records.iter().map(|record| (record.transaction_id, record.name.as_str())).collect::<Vec<_>>(), vec![(1, "add_flight"), (2, "add_flight")]);
+        assert_eq!(records[0].metadata, metadata);
+        assert!(records[1].metadata.is_empty());
+    }
+
+    // Log with a duplicate transaction identifier (like after a botched compaction)
+    fn create_inconsistent_log() -> MemoryTransactionStorage
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        for (transaction_id, flight_number) in [(1, "MA100"), (2, "MA200"), (2, "MA300"), (3, "MA400")]
+        {
+            storage.add(transaction_id, String::from("add_flight"), Box::new(bincode::serialize(&flight(flight_number, 10)).unwrap()), &HashMap::new()).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn replay_skips_records_out_of_order_by_the_warn_and_skip_policy()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(create_inconsistent_log()))
+            .with_replay_order_policy(ReplayOrderPolicy::WarnAndSkip).build();
+
+        let mut flight_numbers = query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>());
+        flight_numbers.sort();
+        assert_eq!(flight_numbers, vec!["MA100", "MA200", "MA400"]);
+        let commands = command_engine.get_command_definitions();
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA500", 10)))).unwrap(), 4);
+    }
+
+    #[test]
+    fn replay_aborts_on_records_out_of_order_by_default()
+    {
+        let engine_error = Engine::builder(AirlineCommands::new(), Box::new(create_inconsistent_log())).try_build().err().unwrap();
+        assert_eq!(engine_error, EngineError::ReplayFailed(String::from("Transaction 2 of record 3 of the log does not follow transaction 2")));
+    }
+
+    #[test]
+    fn stale_read_returns_the_snapshot_published_after_the_interval()
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        storage.add(1, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", 10)).unwrap()), &HashMap::new()).unwrap();
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(storage)).with_published_snapshots(2).build();
+        let commands = command_engine.get_command_definitions();
+
+        // The first snapshot contains the replayed state
+        assert_eq!(query_engine.stale_read().unwrap().flights.len(), 1);
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        assert_eq!(query_engine.stale_read().unwrap().flights.len(), 1);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))).unwrap();
+        assert_eq!(query_engine.stale_read().unwrap().flights.len(), 3);
+    }
+
+    #[test]
+    fn stale_read_returns_none_without_published_snapshots()
+    {
+        let (query_engine, _) = create_engine(MemoryTransactionStorage::new());
+        assert!(query_engine.stale_read().is_none());
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct PanickingCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>
+    }
+
+    impl PanickingCommands
+    {
+        // Adds the flight, then panics while the database is locked
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            panic!("Flight {} crashed the command", flight.flight_number);
+        }
+    }
+
+    #[test]
+    fn panicking_command_makes_later_commands_fail_with_a_poisoned_lock()
+    {
+        let (_, mut command_engine) = Engine::builder(PanickingCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+        let commands = command_engine.get_command_definitions();
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10))))));
+        assert!(panicked.is_err());
+
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))), Err(EngineError::LockPoisoned("database")));
+        assert_eq!(command_engine.get_transaction_status(1), Err(EngineError::LockPoisoned("database")));
+    }
+
+    #[test]
+    fn stopped_worker_wakes_up_the_waiting_callers_with_an_error()
+    {
+        let (_, mut command_engine) = Engine::builder(PanickingCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let commands = command_engine.get_command_definitions();
+
+        let transaction_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+
+        // The worker stopped by the panic of the command, so the caller is not left waiting for the transaction forever
+        assert_eq!(command_engine.wait_for_transaction(transaction_id), Err(EngineError::LockPoisoned("database")));
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))), Err(EngineError::LockPoisoned("database")));
+    }
+
+    #[test]
+    fn query_sees_the_tables_in_one_committed_state()
+    {
+        // No foreign key is registered, so a reservation can be added without its flight
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let commands = command_engine.get_command_definitions();
+
+        // Each transaction adds a flight and a reservation, so they are equal in every committed state
+        let mut last_transaction_id = 0;
+        for index in 0..500
+        {
+            last_transaction_id = command_engine.push_transaction(vec![Arc::new(commands.add_flight.create(flight("MA100", index))),
+                Arc::new(commands.add_reservation.create(reservation(index, "Alice")))]).unwrap();
+        }
+
+        let reader = query_engine.clone();
+        let reader_thread = thread::spawn(move || {
+            let mut counts = Vec::new();
+            while counts.last() != Some(&500)
+            {
+                counts.push(reader.query(|db| { assert_eq!(db.flights.len(), db.reservations.len()); db.flights.len() }));
+            }
+            counts
+        });
+
+        command_engine.wait_for_transaction(last_transaction_id).unwrap();
+        let counts = reader_thread.join().unwrap();
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn replay_throttle_pauses_after_every_batch_and_gives_the_same_state()
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        for transaction_id in 1..=10
+        {
+            storage.add(transaction_id, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", transaction_id)).unwrap()), &HashMap::new()).unwrap();
+        }
+
+        let started = Instant::now();
+        let (throttled_query_engine, _) = Engine::builder(AirlineCommands::new(), Box::new(storage.reopen()))
+            .with_replay_throttle(2, Duration::from_millis(20)).build();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let (query_engine, _) = create_engine(storage.reopen());
+        let seats = |db: &AirlineDatabase| { let mut seats: Vec<usize> = db.flights.iter().map(|flight| flight.seats).collect(); seats.sort(); seats };
+        assert_eq!(throttled_query_engine.query(seats), query_engine.query(seats));
+        assert_eq!(query_engine.query(seats), (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scheduled_commands_are_pushed_for_each_due_run_in_time_order()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        command_engine.schedule_command(10, 10, move |now| Arc::new(commands.add_flight.create(flight("MA100", now as usize))));
+        let commands = command_engine.get_command_definitions();
+        command_engine.schedule_command(15, 20, move |now| Arc::new(commands.add_flight.create(flight("MA200", now as usize))));
+
+        assert!(command_engine.run_scheduled_commands(5).unwrap().is_empty());
+        assert_eq!(command_engine.run_scheduled_commands(30).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(command_engine.run_scheduled_commands(35).unwrap(), vec![5]);
+
+        let mut flights = query_engine.query(|db| db.flights.iter_with_ids().map(|(id, flight)| (id, flight.flight_number.clone(), flight.seats)).collect::<Vec<_>>());
+        flights.sort();
+        let flights: Vec<(&str, usize)> = flights.iter().map(|(_, flight_number, seats)| (flight_number.as_str(), *seats)).collect();
+        assert_eq!(flights, vec![("MA100", 10), ("MA200", 15), ("MA100", 20), ("MA100", 30), ("MA200", 35)]);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct WarningCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight, CommandOutcome>
+    }
+
+    impl WarningCommands
+    {
+        // Adds the flight, and warns about flights without seats
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<CommandOutcome, String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            Ok(if flight.seats == 0 { CommandOutcome::with_warning(format!("Flight {} has no seats", flight.flight_number)) } else { CommandOutcome::default() })
+        }
+    }
+
+    #[test]
+    fn commit_handle_reports_the_warnings_of_a_committed_command()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(WarningCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+        let commands = command_engine.get_command_definitions();
+
+        let warned = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 0)))).unwrap();
+        let not_warned = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+
+        assert_eq!(block_on(warned), Ok(CommandOutcome::with_warning("Flight MA100 has no seats")));
+        assert_eq!(block_on(not_warned), Ok(CommandOutcome::default()));
+        // Warnings do not roll back the transaction
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+    }
+
+    // Every flight must have seats
+    fn add_seats_invariant(command_engine: &mut CommandEngine<AirlineDatabase, AirlineCommands>)
+    {
+        command_engine.add_invariant("flights have seats", |db| match db.flights.iter().find(|flight| flight.seats == 0)
+        {
+            Some(flight) => Err(format!("Flight {} has no seats", flight.flight_number)),
+            None => Ok(())
+        });
+    }
+
+    #[test]
+    fn check_invariants_reports_the_violated_invariants()
+    {
+        let (_, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        add_seats_invariant(&mut command_engine);
+        command_engine.add_invariant("flights exist", |db| if db.flights.is_empty() { Err(String::from("No flights")) } else { Ok(()) });
+        let commands = command_engine.get_command_definitions();
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        assert_eq!(command_engine.check_invariants(), Ok(()));
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 0)))).unwrap();
+        assert_eq!(command_engine.check_invariants(), Err(vec![InvariantViolation { invariant: String::from("flights have seats"), error: String::from("Flight MA200 has no seats") }]));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Invariant 'flights have seats' violated after transaction 2 (command 'add_flight'): Flight MA200 has no seats")]
+    fn invariant_checks_after_commit_panic_at_the_violating_transaction()
+    {
+        let (_, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        add_seats_invariant(&mut command_engine);
+        command_engine.set_invariant_checks(true);
+        let commands = command_engine.get_command_definitions();
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let _ = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 0))));
+    }
+
+    #[test]
+    fn replay_range_of_the_whole_log_gives_the_live_state()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        let flight_id = query_engine.query(|db| db.flights.iter_with_ids().next().unwrap().0);
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+        // Rolled back by the foreign key check
+        let last_transaction_id = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id + 1000, "Bob")))).unwrap();
+        assert_eq!(command_engine.get_transaction_status(last_transaction_id), Ok(TransactionStatus::Failed));
+
+        let db: AirlineDatabase = Engine::replay_range(Box::new(storage.reopen()), &AirlineCommands::new(), init, 1, last_transaction_id);
+        assert_eq!(get_rows(&db), query_engine.query(get_rows));
+        assert_eq!(get_rows(&db), vec![("flights", flight_id, String::from("MA100")), ("reservations", 1, String::from("Alice"))]);
+
+        // The reservation of a flight added before the range fails the foreign key check
+        let db: AirlineDatabase = Engine::replay_range(Box::new(storage.reopen()), &AirlineCommands::new(), init, 2, last_transaction_id);
+        assert!(get_rows(&db).is_empty());
+    }
+
+    #[test]
+    fn push_serialized_pushes_the_command_by_its_name()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = create_engine(storage.reopen());
+
+        let transaction_id = command_engine.push_serialized("add_flight", bincode::serialize(&flight("MA100", 10)).unwrap()).unwrap();
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+        let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).collect();
+        assert_eq!((records[0].transaction_id, records[0].name.as_str()), (transaction_id, "add_flight"));
+
+        assert_eq!(command_engine.push_serialized("remove_flight", Vec::new()), Err(EngineError::UnknownCommand(String::from("remove_flight"))));
+        assert!(matches!(command_engine.push_serialized("add_flight", vec![0xFF]), Err(EngineError::Serialization(_))));
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    #[test]
+    fn commands_pushed_to_a_full_queue_are_processed_in_order()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).with_channel_capacity(2).build();
+        let commands = command_engine.get_command_definitions();
+
+        let transaction_ids: Vec<usize> = (0..50).map(|seats| command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", seats)))).unwrap()).collect();
+        assert_eq!(transaction_ids, (1..=50).collect::<Vec<_>>());
+
+        command_engine.wait_for_transaction(50).unwrap();
+        let mut flights = query_engine.query(|db| db.flights.iter_with_ids().map(|(id, flight)| (id, flight.seats)).collect::<Vec<_>>());
+        flights.sort();
+        assert_eq!(flights.into_iter().map(|(_, seats)| seats).collect::<Vec<_>>(), (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn post_commit_hook_sees_each_committed_transaction()
+    {
+        let (_, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let hook_commits = commits.clone();
+        command_engine.set_post_commit_hook(Box::new(move |db, transaction_id| hook_commits.lock().unwrap().push((transaction_id, db.flights.len()))));
+        let commands = command_engine.get_command_definitions();
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))).unwrap();
+
+        // The rolled back transaction is not reported
+        assert_eq!(*commits.lock().unwrap(), vec![(1, 1), (3, 2)]);
+    }
+
+    #[test]
+    fn resolve_path_returns_the_entities_in_the_order_of_the_path()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        for flight_number in ["MA100", "MA200", "MA300"]
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(flight_number, 10)))).unwrap();
+        }
+        let mut ids = query_engine.query(|db| db.flights.iter_with_ids().map(|(id, _)| id).collect::<Vec<_>>());
+        ids.sort();
+
+        let path = query_engine.resolve_path(|db| &db.flights, &[ids[2], ids[0]]).unwrap();
+        assert_eq!(path, vec![flight("MA300", 10), flight("MA100", 10)]);
+        assert!(query_engine.resolve_path(|db| &db.flights, &[ids[0], ids[2] + 1000]).unwrap_err().starts_with(&format!("Entity {} does not exist", ids[2] + 1000)));
+    }
+
+    #[test]
+    fn durable_commands_are_rejected_once_the_log_reaches_its_maximum_size()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        let record_size = get_record_size("add_flight", bincode::serialize(&flight("MA100", 10)).unwrap().len(), &HashMap::new());
+        command_engine.set_max_log_size(Some(2 * record_size));
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))), Err(EngineError::LogFull { size: 2 * record_size, max_size: 2 * record_size }));
+        // Non-durable commands are not written to the log
+        assert!(command_engine.push_command(Arc::new(commands.add_temporary_flight.create(flight("MA400", 10)))).is_ok());
+        drop(command_engine);
+
+        // The size of the log is counted by the replay
+        let (_, mut command_engine) = create_engine(storage.reopen());
+        command_engine.set_max_log_size(Some(2 * record_size));
+        assert!(matches!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))), Err(EngineError::LogFull { .. })));
+    }
+
+    #[test]
+    fn skipped_records_do_not_count_in_the_maximum_log_size()
+    {
+        let (_, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new())).with_init(init).with_empty_transactions_skipped().build();
+        let commands = command_engine.get_command_definitions();
+        let record_size = get_record_size("add_flight", bincode::serialize(&flight("MA100", 10)).unwrap().len(), &HashMap::new());
+        command_engine.set_max_log_size(Some(2 * record_size));
+
+        // Failed transactions are not written to the log
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight(&format!("MA{}", number), 10)))).unwrap();
+        }
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))), Err(EngineError::LogFull { size: 2 * record_size, max_size: 2 * record_size }));
+    }
+
+    #[test]
+    fn commands_of_a_fork_do_not_change_the_original_database()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+
+        let (forked_query_engine, mut forked_command_engine) = command_engine.clone_fork();
+        forked_command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        // Rollbacks in the fork use the transaction manager of the fork
+        forked_command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA300", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA400", 10)))).unwrap();
+
+        let flight_numbers = |db: &AirlineDatabase| { let mut flight_numbers: Vec<String> = db.flights.iter().map(|flight| flight.flight_number.clone()).collect(); flight_numbers.sort(); flight_numbers };
+        assert_eq!(forked_query_engine.query(flight_numbers), vec!["MA100", "MA200"]);
+        assert_eq!(query_engine.query(flight_numbers), vec!["MA100"]);
+        assert_eq!(TransactionLogReader::new(Box::new(storage.reopen())).count(), 2);
+    }
+
+    #[test]
+    fn command_with_an_empty_name_is_rejected()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let unnamed_command = CommandDefinition::<AirlineDatabase, Flight>::new("", |db, flight| { db.flights.add(Box::new(flight.clone())); Ok(()) });
+
+        assert_eq!(command_engine.push_command(Arc::new(unnamed_command.create(flight("MA100", 10)))), Err(EngineError::EmptyCommandName));
+        assert!(query_engine.query(|db| db.flights.is_empty()));
+    }
+
+    #[test]
+    fn replay_stops_at_a_record_with_an_empty_command_name()
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        storage.add(1, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", 10)).unwrap()), &HashMap::new()).unwrap();
+        storage.add(2, String::new(), Box::new(bincode::serialize(&flight("MA200", 10)).unwrap()), &HashMap::new()).unwrap();
+
+        let engine_error = Engine::builder(AirlineCommands::new(), Box::new(storage)).try_build().err().unwrap();
+        assert_eq!(engine_error, EngineError::ReplayFailed(String::from("Record 2 of the log (transaction 2) has an empty command name")));
+    }
+
+    #[test]
+    fn replay_stops_at_a_record_of_an_unknown_command()
+    {
+        let path = create_test_directory("unknown_command_replay");
+        let mut storage = FileTransactionStorage::new(&path);
+        storage.add(1, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", 10)).unwrap()), &HashMap::new()).unwrap();
+        storage.add(2, String::from("remove_flight"), Box::new(bincode::serialize(&1usize).unwrap()), &HashMap::new()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let engine_error = Engine::builder(AirlineCommands::new(), Box::new(FileTransactionStorage::new(&path))).try_build().err().unwrap();
+        assert_eq!(engine_error.to_string(), "Replay failed: Record 2 of the log (transaction 2) can not be replayed: Unknown command: remove_flight");
+        // Pending transactions after the checkpoint are checked before they run, so the unknown command is not persisted as failed
+        assert!(Engine::builder(AirlineCommands::new(), Box::new(FileTransactionStorage::new(&path).with_checkpoint().unwrap())).try_build().is_err());
+        assert!(FileTransactionStorage::new(&path).get_failed_transaction_ids().unwrap().is_empty());
+    }
+
+    // Memory storage taking a long time to write snapshots, like a storage rewriting a large log
+    struct SlowSnapshotStorage
+    {
+        transaction_storage: MemoryTransactionStorage,
+        snapshot_delay: Duration
+    }
+
+    impl TransactionStorage for SlowSnapshotStorage
+    {
+        fn read(&mut self, buf: &mut [u8]) -> usize
+        {
+            self.transaction_storage.read(buf)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+        {
+            self.transaction_storage.write(buf)
+        }
+
+        fn set_snapshot(&mut self, snapshot: &[u8]) -> std::io::Result<()>
+        {
+            thread::sleep(self.snapshot_delay);
+            self.transaction_storage.set_snapshot(snapshot)
+        }
+    }
+
+    #[test]
+    fn queries_are_not_blocked_while_the_storage_writes_a_snapshot()
+    {
+        let (query_engine, mut command_engine) = create_engine(SlowSnapshotStorage { transaction_storage: MemoryTransactionStorage::new(), snapshot_delay: Duration::from_millis(300) });
+        let commands = command_engine.get_command_definitions();
+        for number in 0..100
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("MA{}", number), 10)))).unwrap();
+        }
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let query_thread =
+        {
+            let query_engine = query_engine.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move ||
+            {
+                let mut slowest_query = Duration::ZERO;
+                let mut query_count = 0;
+                while !stopped.load(Ordering::Acquire)
+                {
+                    let started_at = Instant::now();
+                    let (count, seats) = query_engine.query(|db| (db.flights.len(), db.flights.iter().map(|flight| flight.seats).sum::<usize>()));
+                    slowest_query = slowest_query.max(started_at.elapsed());
+                    assert_eq!((count, seats), (100, 1000));
+                    query_count += 1;
+                }
+                (slowest_query, query_count)
+            })
+        };
+
+        command_engine.snapshot().unwrap();
+        stopped.store(true, Ordering::Release);
+        let (slowest_query, query_count) = query_thread.join().unwrap();
+
+        assert!(query_count > 1);
+        assert!(slowest_query < Duration::from_millis(100), "A query took {:?}", slowest_query);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct SleepingCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+        // Sleeps for the milliseconds
+        sleep: CommandDefinition::<AirlineDatabase, u64>
+    }
+
+    impl SleepingCommands
+    {
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            Ok(())
+        }
+
+        fn sleep(_db: &mut AirlineDatabase, milliseconds: &u64) -> Result<(), String>
+        {
+            thread::sleep(Duration::from_millis(*milliseconds));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_command_observer_is_fired_only_above_the_threshold()
+    {
+        let (_, mut command_engine) = Engine::builder(SleepingCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+        let commands = command_engine.get_command_definitions();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed_events = events.clone();
+        command_engine.set_slow_command_observer(Duration::from_millis(50), Box::new(move |event|
+            observed_events.lock().unwrap().push((event.transaction_id, event.command_name.to_string(), event.duration))));
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let slow_transaction_id = command_engine.push_command(Arc::new(commands.sleep.create(100))).unwrap();
+        command_engine.push_command(Arc::new(commands.sleep.create(0))).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].0, events[0].1.as_str()), (slow_transaction_id, "sleep"));
+        assert!(events[0].2 >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn warm_reads_every_entity_of_every_table()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("MA{}", number), 10)))).unwrap();
+        }
+        let flight_id = query_engine.query(|db| db.flights.iter_with_ids().next().unwrap().0);
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+
+        assert_eq!(query_engine.warm(), 4);
+    }
+
+    #[test]
+    fn disabled_commands_are_rejected_until_enabled_again()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+
+        command_engine.disable_command("add_flight");
+        assert!(matches!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))), Err(EngineError::CommandDisabled(name)) if name == "add_flight"));
+        // Other commands are still accepted
+        command_engine.push_command(Arc::new(commands.add_temporary_flight.create(flight("MA200", 10)))).unwrap();
+
+        command_engine.enable_command("add_flight");
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))).unwrap();
+        let mut flight_numbers = query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>());
+        flight_numbers.sort();
+        assert_eq!(flight_numbers, vec!["MA200", "MA300"]);
+    }
+
+    #[test]
+    fn clones_of_the_query_engine_see_the_commits_of_the_shared_database()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        let cloned_query_engine = query_engine.clone();
+
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+
+        let count = thread::spawn(move || cloned_query_engine.query(|db| db.flights.len())).join().unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct SlowlyFailingCommands
+    {
+        // Adds the flight, waits, then fails, so the flight is rolled back
+        add_flight_and_fail_slowly: CommandDefinition::<AirlineDatabase, Flight>
+    }
+
+    impl SlowlyFailingCommands
+    {
+        fn add_flight_and_fail_slowly(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            thread::sleep(Duration::from_millis(100));
+            Err(format!("Flight {} is not allowed", flight.flight_number))
+        }
+    }
+
+    #[test]
+    fn queries_never_observe_the_changes_of_a_rolled_back_transaction()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(SlowlyFailingCommands::new(), Box::new(MemoryTransactionStorage::new())).build();
+        let commands = command_engine.get_command_definitions();
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let query_thread =
+        {
+            let query_engine = query_engine.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move ||
+            {
+                let mut observed_flights = 0;
+                while !stopped.load(Ordering::Acquire)
+                {
+                    observed_flights = observed_flights.max(query_engine.query(|db| db.flights.len()));
+                }
+                observed_flights
+            })
+        };
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight_and_fail_slowly.create(flight(&format!("MA{}", number), 10)))).unwrap();
+        }
+        stopped.store(true, Ordering::Release);
+
+        assert_eq!(query_thread.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn failed_transactions_are_skipped_by_the_replay_after_a_restart()
+    {
+        let path = create_test_directory("failed_transaction_ids");
+        let (_, mut command_engine) = create_engine(FileTransactionStorage::new(&path));
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let failed_id = command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10)))).unwrap();
+        let skipped_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA300", 10)))).unwrap();
+        drop(command_engine);
+        assert_eq!(FileTransactionStorage::new(&path).get_failed_transaction_ids().unwrap(), vec![failed_id]);
+        // A transaction recorded as failed is not run by the replay, even if its command would succeed
+        FileTransactionStorage::new(&path).add_failed_transaction_id(skipped_id).unwrap();
+
+        let (query_engine, command_engine) = create_engine(FileTransactionStorage::new(&path));
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+        assert_eq!(command_engine.get_transaction_status(failed_id), Ok(TransactionStatus::Failed));
+        assert_eq!(command_engine.get_transaction_status(skipped_id), Ok(TransactionStatus::Failed));
+    }
+
+    #[test]
+    fn failed_transactions_allocate_no_identifiers_so_a_restart_keeps_the_identifiers()
+    {
+        let path = create_test_directory("failed_transaction_identifiers");
+        let (query_engine, mut command_engine) = create_engine(FileTransactionStorage::new(&path));
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight_and_fail.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        let flight_id = query_engine.query(|db| db.flights.iter_with_ids().map(|(id, _)| id).next().unwrap());
+        // The reservation refers to the flight by its identifier, so the replay has to allocate the same identifier for it
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+        let rows = query_engine.query(get_rows);
+        assert_eq!(flight_id, 1);
+        drop(command_engine);
+
+        let (query_engine, _command_engine) = create_engine(FileTransactionStorage::new(&path));
+        assert_eq!(query_engine.query(get_rows), rows);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct SagaCommands
+    {
+        // Adds the flight and returns a follow-up command reserving a seat for the crew
+        add_crewed_flight: CommandDefinition::<AirlineDatabase, Flight, CommandOutcome>,
+        add_reservation: CommandDefinition::<AirlineDatabase, Reservation>
+    }
+
+    impl SagaCommands
+    {
+        fn add_crewed_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<CommandOutcome, String>
+        {
+            let flight_id = db.flights.add(Box::new(flight.clone()));
+            Ok(CommandOutcome::default().with_follow_up(&SagaCommands::new().add_reservation, &reservation(flight_id, "Crew")))
+        }
+
+        fn add_reservation(db: &mut AirlineDatabase, reservation: &Reservation) -> Result<(), String>
+        {
+            db.reservations.add(Box::new(reservation.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn follow_up_commands_are_logged_after_their_command_and_not_pushed_again_by_the_replay()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(SagaCommands::new(), Box::new(storage.reopen())).with_init(init).build();
+        let commands = command_engine.get_command_definitions();
+        let transaction_id = command_engine.push_command(Arc::new(commands.add_crewed_flight.create(flight("MA100", 10)))).unwrap();
+        drop(command_engine);
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).collect();
+        assert_eq!(records.iter().map(|record| (record.transaction_id, record.name.as_str())).collect::<Vec<_>>(),
+            vec![(transaction_id, "add_crewed_flight"), (transaction_id + 1, "add_reservation")]);
+        assert_eq!(records[1].metadata.get(FOLLOW_UP_METADATA_KEY), Some(&transaction_id.to_string()));
+
+        let (query_engine, command_engine) = Engine::builder(SagaCommands::new(), Box::new(storage.reopen())).with_init(init).build();
+        assert_eq!(TransactionLogReader::new(Box::new(storage.reopen())).count(), 2);
+        let (flight_id, passengers) = query_engine.query(|db| (db.flights.iter_with_ids().next().unwrap().0, db.reservations.iter().map(|reservation| (reservation.flight_id, reservation.passenger.clone())).collect::<Vec<_>>()));
+        assert_eq!(passengers, vec![(flight_id, String::from("Crew"))]);
+        assert_eq!(command_engine.get_transaction_status(transaction_id + 1), Ok(TransactionStatus::Completed));
+    }
+
+    #[test]
+    fn queries_started_during_the_replay_see_the_fully_recovered_database()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(SleepingCommands::new(), Box::new(storage.reopen())).build();
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.push_command(Arc::new(commands.sleep.create(100))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        drop(command_engine);
+
+        let reader = Arc::new(Mutex::new(None));
+        let started_reader = reader.clone();
+        let (query_engine, _command_engine) = Engine::builder(SleepingCommands::new(), Box::new(storage.reopen())).with_startup_query_engine(move |query_engine|
+        {
+            // The replay has not started yet
+            assert!(!query_engine.is_ready());
+            *started_reader.lock().unwrap() = Some(thread::spawn(move || query_engine.query(|db| db.flights.len())));
+        }).build();
+
+        assert!(query_engine.is_ready());
+        let reader = reader.lock().unwrap().take().unwrap();
+        assert_eq!(reader.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn push_command_blocks_while_the_queue_of_the_configured_capacity_is_full()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(SleepingCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).with_channel_capacity(1).build();
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.sleep.create(300))).unwrap();
+        // Let the command processing thread take the sleeping command, then fill the queue
+        thread::sleep(Duration::from_millis(50));
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+
+        let start = Instant::now();
+        let transaction_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        command_engine.wait_for_transaction(transaction_id).unwrap();
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Channel capacity must be positive")]
+    fn channel_capacity_must_be_positive()
+    {
+        let _ = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new())).with_channel_capacity(0);
+    }
+
+    #[test]
+    fn shutdown_processes_and_persists_the_pushed_commands_and_their_follow_up_commands()
+    {
+        let path = create_test_directory("shutdown");
+        let (query_engine, mut command_engine) = Engine::builder(SagaCommands::new(), Box::new(FileTransactionStorage::new(&path)))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).with_init(init).build();
+        let commands = command_engine.get_command_definitions();
+        for flight_number in ["MA100", "MA200", "MA300"]
+        {
+            command_engine.push_command(Arc::new(commands.add_crewed_flight.create(flight(flight_number, 10)))).unwrap();
+        }
+        command_engine.shutdown().unwrap();
+
+        // The follow-up commands are applied and persisted by the time shutdown returns
+        assert_eq!(query_engine.query(|db| (db.flights.len(), db.reservations.len())), (3, 3));
+        let names: Vec<String> = TransactionLogReader::new(Box::new(FileTransactionStorage::new(&path))).map(|record| record.name).collect();
+        assert_eq!(names.iter().filter(|name| *name == "add_crewed_flight").count(), 3);
+        assert_eq!(names.iter().filter(|name| *name == "add_reservation").count(), 3);
+    }
+
+    #[test]
+    fn transactions_not_changing_the_database_are_not_written_when_empty_transactions_are_skipped()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(SleepingCommands::new(), Box::new(storage.reopen())).with_empty_transactions_skipped().build();
+        let commands = command_engine.get_command_definitions();
+        let first_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let empty_id = command_engine.push_command(Arc::new(commands.sleep.create(0))).unwrap();
+        let last_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))).unwrap();
+        drop(command_engine);
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).map(|record| (record.transaction_id, record.name)).collect();
+        assert_eq!(records, vec![(first_id, String::from("add_flight")), (last_id, String::from("add_flight"))]);
+
+        let (query_engine, command_engine) = Engine::builder(SleepingCommands::new(), Box::new(storage.reopen())).with_empty_transactions_skipped().build();
+        assert_eq!(query_engine.query(|db| db.flights.len()), 2);
+        assert_eq!(command_engine.get_transaction_status(empty_id), Ok(TransactionStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn dropping_a_push_waiting_for_a_place_in_the_queue_does_not_accept_the_command()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(SleepingCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).with_channel_capacity(1).build();
+        let commands = command_engine.get_command_definitions();
+        let first_id = command_engine.push_command_async(Arc::new(commands.sleep.create(300))).await.unwrap();
+        // Let the command processing thread take the sleeping command, then fill the queue
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        command_engine.push_command_async(Arc::new(commands.add_flight.create(flight("MA100", 10)))).await.unwrap();
+
+        let dropped = command_engine.push_command_async(Arc::new(commands.add_flight.create(flight("MA200", 10))));
+        assert!(tokio::time::timeout(Duration::from_millis(50), dropped).await.is_err());
+
+        // The dropped push took no transaction identifier, so the next command is processed in order and committed
+        let transaction_id = command_engine.push_command_async(Arc::new(commands.add_flight.create(flight("MA300", 10)))).await.unwrap();
+        assert_eq!(transaction_id, first_id + 2);
+        command_engine.wait_for_transaction_async(transaction_id).await.unwrap();
+        assert_eq!(command_engine.get_transaction_status(transaction_id), Ok(TransactionStatus::Completed));
+        let mut flight_numbers = query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>());
+        flight_numbers.sort();
+        assert_eq!(flight_numbers, vec!["MA100", "MA300"]);
+    }
+
+    #[test]
+    fn commands_pushed_after_shutdown_are_rejected_while_queries_keep_working()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new()))
+            .with_command_execution_type(CommandExecutionType::Asynchronous).build();
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.shutdown().unwrap();
+
+        assert_eq!(command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 10)))), Err(EngineError::ShuttingDown));
+        assert_eq!(command_engine.push_transaction(vec![Arc::new(commands.add_flight.create(flight("MA300", 10)))]), Err(EngineError::ShuttingDown));
+        assert_eq!(command_engine.push_serialized("add_flight", bincode::serialize(&flight("MA400", 10)).unwrap()), Err(EngineError::ShuttingDown));
+        assert_eq!(block_on(command_engine.push_command_async(Arc::new(commands.add_flight.create(flight("MA500", 10))))), Err(EngineError::ShuttingDown));
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+
+    // Memory storage failing to read its checkpoint, like a storage on a failing disk
+    struct FailingCheckpointStorage
+    {
+        transaction_storage: MemoryTransactionStorage
+    }
+
+    impl TransactionStorage for FailingCheckpointStorage
+    {
+        fn read(&mut self, buf: &mut [u8]) -> usize
+        {
+            self.transaction_storage.read(buf)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+        {
+            self.transaction_storage.write(buf)
+        }
+
+        fn get_checkpoint(&mut self) -> std::io::Result<Option<usize>>
+        {
+            Err(std::io::Error::other("checkpoint is not readable"))
+        }
+    }
+
+    #[test]
+    fn checkpoint_read_error_is_returned_by_try_build()
+    {
+        let storage = FailingCheckpointStorage { transaction_storage: MemoryTransactionStorage::new() };
+
+        let engine_error = Engine::builder(AirlineCommands::new(), Box::new(storage)).try_build().err().unwrap();
+        assert_eq!(engine_error, EngineError::StorageIo(String::from("checkpoint is not readable")));
+    }
+}
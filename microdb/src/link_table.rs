@@ -0,0 +1,337 @@
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::table::{AccessCounters, OrphanedReferences, Table, TableAccess, TableBase, TableDiff, TableSet};
+#[cfg(feature = "table-stats")]
+use crate::table::TableAccessStats;
+use crate::transaction::{RollbackState, TransactionManager};
+
+// A link between an entity of the left and an entity of the right table of a many-to-many relationship
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Link
+{
+    pub left_id: usize,
+    pub right_id: usize
+}
+
+// A table storing the links of a many-to-many relationship, indexed by both sides
+#[derive(Clone)]
+pub struct LinkTable
+{
+    links: Table<Link>,
+    // Identifiers of the link entities by left identifier and right identifier
+    left_index: HashMap<usize, HashMap<usize, usize>>,
+    // Identifiers of the link entities by right identifier and left identifier
+    right_index: HashMap<usize, HashMap<usize, usize>>,
+    // Links are counted by the link table, not by the table of the links, what is also accessed by the indexes
+    access_counters: AccessCounters
+}
+
+impl LinkTable
+{
+    // Create a new link table
+    pub fn new(name: &'static str, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
+    {
+        Self { links: Table::new(name, transaction_manager), left_index: HashMap::new(), right_index: HashMap::new(), access_counters: AccessCounters::default() }
+    }
+
+    // Returns the unique identifier of table
+    pub fn get_id(&self) -> u64
+    {
+        self.links.get_id()
+    }
+
+    // Link two entities. Returns false if they are already linked.
+    pub fn link(&mut self, left_id: usize, right_id: usize) -> bool
+    {
+        if self.left_index.get(&left_id).is_some_and(|right_ids| right_ids.contains_key(&right_id))
+        {
+            return false;
+        }
+        let id = self.links.add(Box::new(Link { left_id, right_id }));
+        self.add_to_indexes(id);
+        self.access_counters.count(TableAccess::Insert, 1);
+        true
+    }
+
+    // Remove the link of two entities. Returns false if they are not linked.
+    pub fn unlink(&mut self, left_id: usize, right_id: usize) -> bool
+    {
+        let Some(id) = self.left_index.get(&left_id).and_then(|right_ids| right_ids.get(&right_id)).copied() else { return false; };
+        self.remove_entity(id)
+    }
+
+    // Returns true if the two entities are linked
+    pub fn contains(&self, left_id: usize, right_id: usize) -> bool
+    {
+        self.access_counters.count(TableAccess::Read, 1);
+        self.left_index.get(&left_id).is_some_and(|right_ids| right_ids.contains_key(&right_id))
+    }
+
+    // Get the identifiers of the left entities linked to a right entity
+    pub fn left_of(&self, right_id: usize) -> impl Iterator<Item = usize> + '_
+    {
+        self.access_counters.count(TableAccess::Read, 1);
+        self.right_index.get(&right_id).into_iter().flat_map(|left_ids| left_ids.keys().copied())
+    }
+
+    // Get the identifiers of the right entities linked to a left entity
+    pub fn right_of(&self, left_id: usize) -> impl Iterator<Item = usize> + '_
+    {
+        self.access_counters.count(TableAccess::Read, 1);
+        self.left_index.get(&left_id).into_iter().flat_map(|right_ids| right_ids.keys().copied())
+    }
+
+    // Get an iterator for all links
+    pub fn iter(&self) -> impl Iterator<Item = &Link>
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.links.iter().map(|entity| &***entity)
+    }
+
+    fn add_to_indexes(&mut self, id: usize)
+    {
+        let link = ***self.links.get(id).unwrap();
+        self.left_index.entry(link.left_id).or_default().insert(link.right_id, id);
+        self.right_index.entry(link.right_id).or_default().insert(link.left_id, id);
+    }
+
+    fn remove_from_indexes(&mut self, link: Link)
+    {
+        Self::remove_from_index(&mut self.left_index, link.left_id, link.right_id);
+        Self::remove_from_index(&mut self.right_index, link.right_id, link.left_id);
+    }
+
+    fn remove_from_index(index: &mut HashMap<usize, HashMap<usize, usize>>, id: usize, linked_id: usize)
+    {
+        if let Some(linked_ids) = index.get_mut(&id)
+        {
+            linked_ids.remove(&linked_id);
+            if linked_ids.is_empty()
+            {
+                index.remove(&id);
+            }
+        }
+    }
+}
+
+impl TableBase for LinkTable
+{
+    fn rollback_to_existing(&mut self, id: usize, state: RollbackState) -> Result<(), String>
+    {
+        // Links are never changed, so an existing link is restored only after it was removed
+        if let Some(entity) = self.links.get(id)
+        {
+            let link = ***entity;
+            self.remove_from_indexes(link);
+        }
+        self.links.rollback_to_existing(id, state)?;
+        self.add_to_indexes(id);
+        Ok(())
+    }
+
+    fn rollback_to_not_existing(&mut self, id: usize)
+    {
+        if let Some(entity) = self.links.get(id)
+        {
+            let link = ***entity;
+            self.remove_from_indexes(link);
+        }
+        self.links.rollback_to_not_existing(id);
+    }
+
+    fn rollback_id_allocation(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>
+    {
+        self.links.rollback_id_allocation(state)
+    }
+
+    fn contains(&self, id: usize) -> bool
+    {
+        self.links.contains(id)
+    }
+
+    fn get_references(&self, id: usize) -> Vec<(u64, usize)>
+    {
+        self.links.get_references(id)
+    }
+
+    fn check_entity_size(&self, id: usize) -> Result<(), String>
+    {
+        self.links.check_entity_size(id)
+    }
+
+    fn check_unique_constraints(&self, id: usize) -> Result<(), String>
+    {
+        self.links.check_unique_constraints(id)
+    }
+
+    fn get_cascading_references_to(&self, referenced_table_id: u64, referenced_id: usize) -> Vec<usize>
+    {
+        self.links.get_cascading_references_to(referenced_table_id, referenced_id)
+    }
+
+    fn remove_entity(&mut self, id: usize) -> bool
+    {
+        let Some(link) = self.links.get(id).map(|entity| ***entity) else { return false; };
+        self.remove_from_indexes(link);
+        self.access_counters.count(TableAccess::Delete, 1);
+        self.links.remove_entity(id)
+    }
+
+    fn get_id(&self) -> u64
+    {
+        self.links.get_id()
+    }
+
+    fn shrink_to_fit(&mut self)
+    {
+        self.links.shrink_to_fit();
+    }
+
+    fn find_orphaned_references(&self, exists: &dyn Fn(u64, usize) -> bool) -> Vec<OrphanedReferences>
+    {
+        self.links.find_orphaned_references(exists)
+    }
+
+    fn serialize_rows(&self) -> Result<Vec<u8>, String>
+    {
+        self.links.serialize_rows()
+    }
+
+    fn load_rows(&mut self, rows: &[u8], max_used_id: usize) -> Result<(), String>
+    {
+        self.links.load_rows(rows, max_used_id)?;
+        self.left_index.clear();
+        self.right_index.clear();
+        let ids: Vec<usize> = self.links.iter_with_ids().map(|(id, _)| id).collect();
+        for id in ids
+        {
+            self.add_to_indexes(id);
+        }
+        Ok(())
+    }
+
+    fn get_max_used_id(&self) -> usize
+    {
+        self.links.get_max_used_id()
+    }
+
+    fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>)
+    {
+        self.links.set_transaction_manager(transaction_manager);
+    }
+
+    fn get_transaction_manager(&self) -> Arc<Mutex<TransactionManager>>
+    {
+        self.links.get_transaction_manager()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn warm(&self) -> usize
+    {
+        self.links.warm()
+    }
+
+    fn diff(&self, other: &dyn TableBase) -> Option<TableDiff>
+    {
+        let other = other.as_any().downcast_ref::<LinkTable>().expect("Tables of different types can not be compared");
+        self.links.diff(&other.links)
+    }
+
+    #[cfg(feature = "table-stats")]
+    fn get_access_stats(&self) -> TableAccessStats
+    {
+        self.access_counters.get_stats(self.links.get_name(), self.get_id())
+    }
+}
+
+impl TableSet for LinkTable
+{
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>
+    {
+        if table_id == self.get_id() { Some(self) } else { None }
+    }
+
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>
+    {
+        if table_id == self.get_id() { Some(self) } else { None }
+    }
+
+    fn get_tables(&self) -> Vec<&dyn TableBase>
+    {
+        vec![self]
+    }
+
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>
+    {
+        vec![self]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use microdb_derive::{Database, DatabaseFactory};
+    use crate::{Database, DatabaseFactory};
+
+    #[derive(Database, DatabaseFactory)]
+    struct CrewDatabase
+    {
+        // Links of pilots (left) and flights (right)
+        assignments: LinkTable
+    }
+
+    fn sorted(ids: impl Iterator<Item = usize>) -> Vec<usize>
+    {
+        let mut ids: Vec<usize> = ids.collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn links_are_found_from_both_sides()
+    {
+        let mut assignments = LinkTable::new("assignments", Arc::new(Mutex::new(TransactionManager::new())));
+        assert!(assignments.link(1, 10));
+        assert!(assignments.link(1, 20));
+        assert!(assignments.link(2, 10));
+        assert!(!assignments.link(1, 10));
+
+        assert_eq!(sorted(assignments.right_of(1)), vec![10, 20]);
+        assert_eq!(sorted(assignments.left_of(10)), vec![1, 2]);
+        assert!(assignments.unlink(1, 10));
+        assert!(!assignments.unlink(1, 10));
+        assert!(!assignments.contains(1, 10));
+        assert_eq!(sorted(assignments.left_of(10)), vec![2]);
+        assert_eq!(assignments.iter().count(), 2);
+    }
+
+    #[test]
+    fn rollback_restores_the_links_and_both_indexes()
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let db_lock = RwLock::new(CrewDatabase::create_database(transaction_manager_ref.clone()));
+        let mut db = db_lock.write().unwrap();
+        db.assignments.link(1, 10);
+        db.assignments.link(2, 10);
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.assignments.unlink(1, 10);
+        db.assignments.link(1, 20);
+        db.assignments.link(3, 10);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(sorted(db.assignments.left_of(10)), vec![1, 2]);
+        assert_eq!(sorted(db.assignments.right_of(1)), vec![10]);
+        assert_eq!(db.assignments.left_of(20).count(), 0);
+        assert_eq!(db.assignments.right_of(3).count(), 0);
+        assert_eq!(db.assignments.iter().count(), 2);
+    }
+}
@@ -0,0 +1,82 @@
+use std::io::{self, Write};
+use serde_json::json;
+use crate::Database;
+use crate::command::{CommandDirectory, TRANSACTION_COMMAND_NAME, deserialize_transaction_commands};
+use crate::transaction_storage::TransactionLogReader;
+
+// Write the records of a transaction log as newline-delimited JSON and get the number of written lines
+pub fn export_ndjson<D, C, W>(transaction_log_reader: TransactionLogReader, command_directory: &C, writer: &mut W) -> io::Result<usize> where D: Database, C: CommandDirectory<D>, W: Write
+{
+    let mut line_count = 0;
+    for serialized_transaction in transaction_log_reader
+    {
+        // Parameters of a multi-command transaction are written as an array of its commands with their parameters
+        let parameters = if serialized_transaction.name == TRANSACTION_COMMAND_NAME
+        {
+            deserialize_transaction_commands(&serialized_transaction.serialized_parameters).and_then(|commands| commands.iter()
+                .map(|(name, serialized_parameters)| parameters_to_json(command_directory, name, serialized_parameters).map(|parameters| json!({ "command": name, "parameters": parameters })))
+                .collect::<Result<Vec<_>, String>>())
+                .map(serde_json::Value::Array)
+        }
+        else
+        {
+            parameters_to_json(command_directory, &serialized_transaction.name, &serialized_transaction.serialized_parameters)
+        };
+        let line = match parameters
+        {
+            Ok(parameters) => json!({
+                "transaction_id": serialized_transaction.transaction_id,
+                "command": serialized_transaction.name,
+                "parameters": parameters,
+                "metadata": serialized_transaction.metadata
+            }),
+            Err(error) => json!({
+                "transaction_id": serialized_transaction.transaction_id,
+                "command": serialized_transaction.name,
+                "error": error,
+                "metadata": serialized_transaction.metadata
+            })
+        };
+        serde_json::to_writer(&mut *writer, &line)?;
+        writer.write_all(b"\n")?;
+        line_count += 1;
+    }
+    Ok(line_count)
+}
+
+// Decode the serialized parameters of a command by its registered name
+fn parameters_to_json<D, C>(command_directory: &C, name: &str, serialized_parameters: &[u8]) -> Result<serde_json::Value, String> where D: Database, C: CommandDirectory<D>
+{
+    match command_directory.try_get(name)
+    {
+        Some(command_definition) => command_definition.parameters_to_json(serialized_parameters),
+        None => Err(format!("Unknown command {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::collections::HashMap;
+    use crate::command::CommandDirectoryFactory;
+    use crate::transaction_storage::{MemoryTransactionStorage, TransactionStorage};
+    use crate::test_fixtures::*;
+
+    #[test]
+    fn export_writes_a_line_with_the_decoded_parameters_for_each_record()
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        let metadata = HashMap::from([(String::from("request_id"), String::from("42"))]);
+        storage.add(1, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", 10)).unwrap()), &metadata).unwrap();
+        storage.add(2, String::from("unknown"), Box::new(vec![1, 2, 3]), &HashMap::new()).unwrap();
+
+        let mut output = Vec::new();
+        let line_count = export_ndjson(TransactionLogReader::new(Box::new(storage.reopen())), &AirlineCommands::new(), &mut output).unwrap();
+
+        assert_eq!(line_count, 2);
+        let lines: Vec<serde_json::Value> = String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines[0], json!({ "transaction_id": 1, "command": "add_flight", "parameters": flight("MA100", 10), "metadata": { "request_id": "42" } }));
+        assert_eq!(lines[1], json!({ "transaction_id": 2, "command": "unknown", "error": "Unknown command unknown", "metadata": {} }));
+    }
+}
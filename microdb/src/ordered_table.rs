@@ -0,0 +1,322 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::any::Any;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+use crate::entity::Entity;
+use crate::table::{OrphanedReferences, Table, TableBase, TableDiff, TableSet};
+#[cfg(feature = "table-stats")]
+use crate::table::TableAccessStats;
+use crate::transaction::{RollbackState, TransactionManager};
+
+// Name of the ordered index of the table storing the entities of an ordered table
+const ORDER_INDEX_NAME: &str = "$order";
+
+// Key of the structs stored by an ordered table (like the departure time of a flight). Keys do not need to be unique.
+pub trait OrderKey<K>
+{
+    // Compute the key of the struct
+    fn order_key(&self) -> K;
+}
+
+// A table iterating its entities in the order of their keys (see OrderKey)
+pub struct OrderedTable<K, T> where T: Serialize + DeserializeOwned
+{
+    table: Table<T>,
+    marker: PhantomData<fn() -> K>
+}
+
+// Implemented manually, because deriving would require K to be Clone
+impl<K, T> Clone for OrderedTable<K, T> where T: Clone + Serialize + DeserializeOwned
+{
+    fn clone(&self) -> Self
+    {
+        Self { table: self.table.clone(), marker: PhantomData }
+    }
+}
+
+impl<K, T> OrderedTable<K, T> where T: Serialize + DeserializeOwned + OrderKey<K> + 'static, K: Ord + Clone + Send + Sync + 'static
+{
+    // Create a new ordered table
+    pub fn new(name: &'static str, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
+    {
+        let mut table = Table::new(name, transaction_manager);
+        table.add_ordered_index(ORDER_INDEX_NAME, T::order_key);
+        Self { table, marker: PhantomData }
+    }
+
+    // Returns the unique identifier of table
+    pub fn get_id(&self) -> u64
+    {
+        self.table.get_id()
+    }
+
+    // Returns the name of the table
+    pub fn get_name(&self) -> &'static str
+    {
+        self.table.get_name()
+    }
+
+    // Get an entity by identifier
+    pub fn get(&self, id: usize) -> Option<&Entity<Box<T>>>
+    {
+        self.table.get(id)
+    }
+
+    // Get an entity by identifier as mutable. The ordered index is updated, if the key of the struct is changed.
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Entity<Box<T>>>
+    {
+        self.table.get_mut(id)
+    }
+
+    // Returns true if the table contains an entity with the identifier
+    pub fn contains(&self, id: usize) -> bool
+    {
+        self.table.contains(id)
+    }
+
+    // Returns the number of entities in the table
+    pub fn len(&self) -> usize
+    {
+        self.table.len()
+    }
+
+    // Returns true if the table has no entities
+    pub fn is_empty(&self) -> bool
+    {
+        self.table.is_empty()
+    }
+
+    // Add a new struct to the table. Returns the identifier of the new entity.
+    pub fn add(&mut self, item: Box<T>) -> usize
+    {
+        self.table.add(item)
+    }
+
+    // Remove an entity by identifier
+    pub fn remove(&mut self, id: usize)
+    {
+        self.table.remove(id);
+    }
+
+    // Get all entities ordered by key (entities with the same key by identifier)
+    pub fn iter(&self) -> impl Iterator<Item = &Entity<Box<T>>>
+    {
+        self.table.range_by_index::<K, _>(ORDER_INDEX_NAME, ..).into_iter()
+    }
+
+    // Get the entities with keys in the range (like start..end) ordered by key (entities with the same key by identifier)
+    pub fn range<R>(&self, range: R) -> Vec<&Entity<Box<T>>> where R: RangeBounds<K>
+    {
+        self.table.range_by_index(ORDER_INDEX_NAME, range)
+    }
+
+    // Get the table storing the entities (like for adding foreign keys or hash indexes)
+    pub fn get_table_mut(&mut self) -> &mut Table<T>
+    {
+        &mut self.table
+    }
+}
+
+impl<K, T> TableBase for OrderedTable<K, T> where T: Serialize + DeserializeOwned + OrderKey<K> + 'static, K: Ord + Clone + Send + Sync + 'static
+{
+    fn rollback_to_existing(&mut self, id: usize, state: RollbackState) -> Result<(), String>
+    {
+        self.table.rollback_to_existing(id, state)
+    }
+
+    fn rollback_to_not_existing(&mut self, id: usize)
+    {
+        self.table.rollback_to_not_existing(id);
+    }
+
+    fn rollback_id_allocation(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>
+    {
+        self.table.rollback_id_allocation(state)
+    }
+
+    fn contains(&self, id: usize) -> bool
+    {
+        self.table.contains(id)
+    }
+
+    fn get_references(&self, id: usize) -> Vec<(u64, usize)>
+    {
+        self.table.get_references(id)
+    }
+
+    fn check_entity_size(&self, id: usize) -> Result<(), String>
+    {
+        self.table.check_entity_size(id)
+    }
+
+    fn check_unique_constraints(&self, id: usize) -> Result<(), String>
+    {
+        self.table.check_unique_constraints(id)
+    }
+
+    fn get_cascading_references_to(&self, referenced_table_id: u64, referenced_id: usize) -> Vec<usize>
+    {
+        self.table.get_cascading_references_to(referenced_table_id, referenced_id)
+    }
+
+    fn remove_entity(&mut self, id: usize) -> bool
+    {
+        self.table.remove_entity(id)
+    }
+
+    fn get_id(&self) -> u64
+    {
+        self.table.get_id()
+    }
+
+    fn shrink_to_fit(&mut self)
+    {
+        self.table.shrink_to_fit();
+    }
+
+    fn find_orphaned_references(&self, exists: &dyn Fn(u64, usize) -> bool) -> Vec<OrphanedReferences>
+    {
+        self.table.find_orphaned_references(exists)
+    }
+
+    fn serialize_rows(&self) -> Result<Vec<u8>, String>
+    {
+        self.table.serialize_rows()
+    }
+
+    fn load_rows(&mut self, rows: &[u8], max_used_id: usize) -> Result<(), String>
+    {
+        self.table.load_rows(rows, max_used_id)
+    }
+
+    fn get_max_used_id(&self) -> usize
+    {
+        self.table.get_max_used_id()
+    }
+
+    fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>)
+    {
+        self.table.set_transaction_manager(transaction_manager);
+    }
+
+    fn get_transaction_manager(&self) -> Arc<Mutex<TransactionManager>>
+    {
+        self.table.get_transaction_manager()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn warm(&self) -> usize
+    {
+        self.table.warm()
+    }
+
+    fn diff(&self, other: &dyn TableBase) -> Option<TableDiff>
+    {
+        let other = other.as_any().downcast_ref::<OrderedTable<K, T>>().expect("Tables of different types can not be compared");
+        self.table.diff(&other.table)
+    }
+
+    #[cfg(feature = "table-stats")]
+    fn get_access_stats(&self) -> TableAccessStats
+    {
+        self.table.get_access_stats()
+    }
+}
+
+impl<K, T> TableSet for OrderedTable<K, T> where T: Serialize + DeserializeOwned + OrderKey<K> + 'static, K: Ord + Clone + Send + Sync + 'static
+{
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>
+    {
+        if table_id == self.get_id() { Some(self) } else { None }
+    }
+
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>
+    {
+        if table_id == self.get_id() { Some(self) } else { None }
+    }
+
+    fn get_tables(&self) -> Vec<&dyn TableBase>
+    {
+        vec![self]
+    }
+
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>
+    {
+        vec![self]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use microdb_derive::{Database, DatabaseFactory};
+    use crate::{Database, DatabaseFactory};
+    use crate::test_fixtures::{Flight, flight};
+
+    // Flights ordered by their free seats
+    impl OrderKey<usize> for Flight
+    {
+        fn order_key(&self) -> usize
+        {
+            self.seats
+        }
+    }
+
+    #[derive(Database, DatabaseFactory)]
+    struct TimetableDatabase
+    {
+        flights: OrderedTable::<usize, Flight>
+    }
+
+    fn flight_numbers<'a>(entities: impl IntoIterator<Item = &'a Entity<Box<Flight>>>) -> Vec<String>
+    {
+        entities.into_iter().map(|entity| entity.flight_number.clone()).collect()
+    }
+
+    #[test]
+    fn entities_are_iterated_and_found_by_the_order_of_their_keys()
+    {
+        let mut flights = OrderedTable::<usize, Flight>::new("flights", Arc::new(Mutex::new(TransactionManager::new())));
+        flights.add(Box::new(flight("MA300", 30)));
+        let id = flights.add(Box::new(flight("MA100", 10)));
+        flights.add(Box::new(flight("MA200", 20)));
+        flights.add(Box::new(flight("MA101", 10)));
+
+        assert_eq!(flight_numbers(flights.iter()), vec!["MA100", "MA101", "MA200", "MA300"]);
+        assert_eq!(flight_numbers(flights.range(10..25)), vec!["MA100", "MA101", "MA200"]);
+        assert!(flights.range(31..).is_empty());
+
+        // Changing the key moves the entity
+        flights.get_mut(id).unwrap().seats = 40;
+        assert_eq!(flight_numbers(flights.iter()), vec!["MA101", "MA200", "MA300", "MA100"]);
+        flights.remove(id);
+        assert_eq!(flight_numbers(flights.range(..)), vec!["MA101", "MA200", "MA300"]);
+    }
+
+    #[test]
+    fn order_is_restored_by_the_rollback()
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let mut db = TimetableDatabase::create_database(transaction_manager_ref.clone());
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        db.flights.add(Box::new(flight("MA200", 20)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(id).unwrap().seats = 30;
+        db.flights.add(Box::new(flight("MA050", 5)));
+        assert_eq!(flight_numbers(db.flights.iter()), vec!["MA050", "MA200", "MA100"]);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(flight_numbers(db.flights.iter()), vec!["MA100", "MA200"]);
+        assert_eq!(flight_numbers(db.flights.range(..=10)), vec!["MA100"]);
+    }
+}
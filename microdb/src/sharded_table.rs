@@ -0,0 +1,188 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::sync::{Arc, Mutex};
+use crate::entity::Entity;
+use crate::table::{Table, TableBase, TableSet};
+use crate::transaction::TransactionManager;
+
+// Trait of structs stored in a sharded table, defining the key used to select the shard of an entity
+pub trait ShardKey
+{
+    type Key: Hash;
+
+    fn shard_key(&self) -> Self::Key;
+}
+
+// A logical table distributing its entities between N shards by the hash of their shard keys
+#[derive(Clone)]
+pub struct ShardedTable<T, const N: usize> where T : Serialize + DeserializeOwned + ShardKey
+{
+    shards: Vec<Table<T>>
+}
+
+impl<T, const N: usize> ShardedTable<T, N> where T : Serialize + DeserializeOwned + ShardKey
+{
+    // Create a new sharded table
+    pub fn new(name: &'static str, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
+    {
+        assert!(N > 0, "A sharded table must have at least one shard");
+
+        let shards = (0..N).map(|shard_index|
+            {
+                // Unique identifier of a shard is a hash generated from the name of the table and the index of the shard
+                let mut hasher = DefaultHasher::new();
+                name.hash(&mut hasher);
+                shard_index.hash(&mut hasher);
+                Table::new_with_id(name, hasher.finish(), shard_index + 1, N, transaction_manager.clone())
+            }
+        ).collect();
+
+        Self { shards }
+    }
+
+    // Get the shards of the table
+    pub fn get_shards(&self) -> &[Table<T>]
+    {
+        &self.shards
+    }
+
+    // Gets an item from the table by identifier
+    pub fn get(&self, id: usize) -> Option<&Entity<Box<T>>>
+    {
+        self.shards[Self::shard_index_of_id(id)?].get(id)
+    }
+
+    // Get an item from the table as mutable by identifier
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Entity<Box<T>>>
+    {
+        self.shards[Self::shard_index_of_id(id)?].get_mut(id)
+    }
+
+    // Returns true if the table contains a (not removed) entity with the identifier
+    pub fn contains(&self, id: usize) -> bool
+    {
+        self.get(id).is_some()
+    }
+
+    // Add a struct to the shard selected by its shard key
+    pub fn add(&mut self, item: Box<T>) -> usize
+    {
+        let mut hasher = DefaultHasher::new();
+        item.shard_key().hash(&mut hasher);
+        let shard_index = (hasher.finish() % N as u64) as usize;
+        self.shards[shard_index].add(item)
+    }
+
+    // Remove an entity from the table
+    pub fn remove(&mut self, id: usize)
+    {
+        if let Some(shard_index) = Self::shard_index_of_id(id)
+        {
+            self.shards[shard_index].remove(id);
+        }
+    }
+
+    // Get an iterator for the entities stored in all shards
+    pub fn iter(&self) -> impl Iterator<Item = &Entity<Box<T>>>
+    {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    // Get a mutable iterator for the entities stored in all shards
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Entity<Box<T>>>
+    {
+        self.shards.iter_mut().flat_map(|shard| shard.iter_mut())
+    }
+
+    fn shard_index_of_id(id: usize) -> Option<usize>
+    {
+        if id == 0 { None } else { Some((id - 1) % N) }
+    }
+}
+
+impl<T, const N: usize> TableSet for ShardedTable<T, N> where T : Serialize + DeserializeOwned + ShardKey + 'static
+{
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>
+    {
+        self.shards.iter().find(|shard| shard.get_id() == table_id).map(|shard| shard as &dyn TableBase)
+    }
+
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>
+    {
+        self.shards.iter_mut().find(|shard| shard.get_id() == table_id).map(|shard| shard as &mut dyn TableBase)
+    }
+
+    fn get_tables(&self) -> Vec<&dyn TableBase>
+    {
+        self.shards.iter().map(|shard| shard as &dyn TableBase).collect()
+    }
+
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>
+    {
+        self.shards.iter_mut().map(|shard| shard as &mut dyn TableBase).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::RwLock;
+    use microdb_derive::{Database, DatabaseFactory};
+    use crate::{Database, DatabaseFactory};
+    use crate::test_fixtures::*;
+
+    impl ShardKey for Flight
+    {
+        type Key = String;
+
+        fn shard_key(&self) -> Self::Key
+        {
+            self.flight_number.clone()
+        }
+    }
+
+    #[derive(Database, DatabaseFactory)]
+    struct ShardedDatabase
+    {
+        flights: ShardedTable::<Flight, 4>
+    }
+
+    #[test]
+    fn entities_with_the_same_key_are_stored_in_the_same_shard()
+    {
+        let mut flights = ShardedTable::<Flight, 4>::new("flights", Arc::new(Mutex::new(TransactionManager::new())));
+        let ids: Vec<usize> = (0..100).map(|index| flights.add(Box::new(flight(&format!("MA{}", index % 10), index)))).collect();
+
+        for (index, id) in ids.iter().enumerate()
+        {
+            assert_eq!(flights.get(*id).unwrap().seats, index);
+            // The shard of an entity is computed from its identifier
+            let shard_index = (id - 1) % 4;
+            assert!(flights.get_shards()[shard_index].contains(*id));
+            assert_eq!(shard_index, (ids[index % 10] - 1) % 4);
+        }
+        assert_eq!(flights.iter().count(), 100);
+    }
+
+    #[test]
+    fn changes_of_all_shards_are_rolled_back()
+    {
+        let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+        let db_lock = RwLock::new(ShardedDatabase::create_database(transaction_manager_ref.clone()));
+        let mut db = db_lock.write().unwrap();
+        let kept_id = db.flights.add(Box::new(flight("MA100", 10)));
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(kept_id).unwrap().seats = 5;
+        for index in 0..10
+        {
+            db.flights.add(Box::new(flight(&format!("MA{}", index), 10)));
+        }
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(db.flights.iter().count(), 1);
+        assert_eq!(db.flights.get(kept_id).unwrap().seats, 10);
+    }
+}
@@ -0,0 +1,356 @@
+// Snapshots of all tables persisted by CommandEngine::snapshot, so a restart replays only the transactions after it
+
+use crate::Database;
+use serde::{Serialize, Deserialize};
+
+// Version of the format of snapshots (earlier versions are migrated, later ones are refused)
+pub(crate) const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+// Format version, length and checksum of the contents
+const SNAPSHOT_HEADER_LEN: usize = 4 + 8 + 8;
+
+// Migration of the contents of a snapshot of a format version to the contents of the next version
+pub(crate) type SnapshotMigration = fn(Vec<u8>) -> Result<Vec<u8>, String>;
+
+// Migrations of the snapshot contents by the version they migrate from
+const SNAPSHOT_MIGRATIONS: &[(u32, SnapshotMigration)] = &[(1, migrate_from_version_1)];
+
+// Contents of version 1 snapshots are the same, only the length and the checksum are missing from them
+fn migrate_from_version_1(contents: Vec<u8>) -> Result<Vec<u8>, String>
+{
+    Ok(contents)
+}
+
+// FNV-1a hash of the contents of a snapshot
+fn checksum(contents: &[u8]) -> u64
+{
+    contents.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// State of all tables at a transaction. It is stored after the header of the snapshot (see SNAPSHOT_HEADER_LEN).
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot
+{
+    // Identifier of the last transaction processed before the snapshot
+    pub(crate) last_processed_transaction_id: usize,
+    // Number of records of the transaction log processed before the snapshot
+    pub(crate) processed_record_count: usize,
+    // Identifier, highest used entity identifier and serialized rows of all tables
+    pub(crate) tables: Vec<(u64, usize, Vec<u8>)>
+}
+
+impl Snapshot
+{
+    // Serialize the snapshot with the current format version
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>, String>
+    {
+        let contents = bincode::serialize(self).map_err(|e| e.to_string())?;
+        let mut serialized_snapshot = Vec::with_capacity(SNAPSHOT_HEADER_LEN + contents.len());
+        serialized_snapshot.extend_from_slice(&SNAPSHOT_FORMAT_VERSION.to_le_bytes());
+        serialized_snapshot.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        serialized_snapshot.extend_from_slice(&checksum(&contents).to_le_bytes());
+        serialized_snapshot.extend_from_slice(&contents);
+        Ok(serialized_snapshot)
+    }
+
+    // Deserialize a snapshot read from the transaction storage, migrating it from an earlier format version
+    pub(crate) fn deserialize(serialized_snapshot: &[u8]) -> Result<Self, String>
+    {
+        Self::deserialize_with_migrations(serialized_snapshot, SNAPSHOT_FORMAT_VERSION, SNAPSHOT_MIGRATIONS)
+    }
+
+    fn deserialize_with_migrations(serialized_snapshot: &[u8], format_version: u32, migrations: &[(u32, SnapshotMigration)]) -> Result<Self, String>
+    {
+        let Some((version_bytes, contents)) = serialized_snapshot.split_first_chunk::<4>() else { return Err(String::from("Snapshot has no format version")); };
+        let version = u32::from_le_bytes(*version_bytes);
+        if version > format_version
+        {
+            return Err(format!("Snapshot format version {} is newer than the supported version {}", version, format_version));
+        }
+        // Snapshots before version 2 have no length and checksum
+        let mut contents = match version
+        {
+            0 | 1 => contents.to_vec(),
+            _ => Self::get_checked_contents(contents)?.to_vec()
+        };
+        for version in version..format_version
+        {
+            let Some((_, migrate)) = migrations.iter().find(|(from_version, _)| *from_version == version) else
+            {
+                return Err(format!("Snapshot format version {} can not be migrated to version {}", version, format_version));
+            };
+            contents = migrate(contents).map_err(|error| format!("Migrating the snapshot from format version {} failed: {}", version, error))?;
+        }
+        bincode::deserialize(&contents).map_err(|e| e.to_string())
+    }
+
+    // Get the contents after the format version, checking their length and checksum
+    fn get_checked_contents(serialized_snapshot: &[u8]) -> Result<&[u8], String>
+    {
+        let Some((length_bytes, rest)) = serialized_snapshot.split_first_chunk::<8>() else { return Err(String::from("Snapshot header is truncated")); };
+        let Some((checksum_bytes, contents)) = rest.split_first_chunk::<8>() else { return Err(String::from("Snapshot header is truncated")); };
+        let length = u64::from_le_bytes(*length_bytes);
+        if contents.len() as u64 != length
+        {
+            return Err(format!("Snapshot is {} bytes long instead of {} bytes", contents.len(), length));
+        }
+        if checksum(contents) != u64::from_le_bytes(*checksum_bytes)
+        {
+            return Err(String::from("Snapshot checksum does not match its contents"));
+        }
+        Ok(contents)
+    }
+
+    // Replace the rows of the tables of the database by the rows in the snapshot. Tables missing from the snapshot keep their rows.
+    pub(crate) fn load<D>(&self, db: &mut D) -> Result<(), String> where D: Database
+    {
+        if let Some((table_id, _, _)) = self.tables.iter().find(|(table_id, _, _)| db.try_get_table(*table_id).is_none())
+        {
+            return Err(format!("Table {} of the snapshot does not exist in the database", table_id));
+        }
+        // Rows of the tables before loading are kept, so a snapshot failed to load does not leave a partially loaded database behind
+        let mut original_tables = Vec::new();
+        for (table_id, _, _) in &self.tables
+        {
+            let table = db.try_get_table(*table_id).unwrap();
+            original_tables.push((*table_id, table.get_max_used_id(), table.serialize_rows()?));
+        }
+        for (table_id, max_used_id, rows) in &self.tables
+        {
+            if let Err(error) = db.try_get_table_mut(*table_id).unwrap().load_rows(rows, *max_used_id)
+            {
+                for (table_id, max_used_id, rows) in &original_tables
+                {
+                    db.try_get_table_mut(*table_id).unwrap().load_rows(rows, *max_used_id).expect("Restoring a table failed");
+                }
+                return Err(format!("Loading table {} failed: {}", table_id, error));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_fixtures::*;
+    use microdb_derive::*;
+    use std::sync::{Arc, Mutex};
+
+    // Contents of a snapshot of format version 0 in the tests, what did not store the number of processed records
+    #[derive(Serialize)]
+    struct SnapshotVersion0
+    {
+        last_processed_transaction_id: usize,
+        tables: Vec<(u64, usize, Vec<u8>)>
+    }
+
+    fn serialize_version_0(last_processed_transaction_id: usize) -> Vec<u8>
+    {
+        let mut serialized_snapshot = 0u32.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut serialized_snapshot, &SnapshotVersion0 { last_processed_transaction_id, tables: vec![(1, 2, vec![3])] }).unwrap();
+        serialized_snapshot
+    }
+
+    // Migration from version 0 in the tests, counting the processed records from the transaction identifier
+    fn migrate_from_version_0(contents: Vec<u8>) -> Result<Vec<u8>, String>
+    {
+        let (last_processed_transaction_id, tables): (usize, Vec<(u64, usize, Vec<u8>)>) = bincode::deserialize(&contents).map_err(|e| e.to_string())?;
+        bincode::serialize(&Snapshot { last_processed_transaction_id, processed_record_count: last_processed_transaction_id, tables }).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn snapshot_is_read_back_with_the_current_format_version()
+    {
+        let serialized_snapshot = Snapshot { last_processed_transaction_id: 5, processed_record_count: 4, tables: vec![(1, 2, vec![3])] }.serialize().unwrap();
+        assert_eq!(serialized_snapshot[..4], SNAPSHOT_FORMAT_VERSION.to_le_bytes());
+
+        let snapshot = Snapshot::deserialize(&serialized_snapshot).unwrap();
+        assert_eq!((snapshot.last_processed_transaction_id, snapshot.processed_record_count, snapshot.tables), (5, 4, vec![(1, 2, vec![3])]));
+    }
+
+    #[test]
+    fn snapshot_of_a_future_format_version_is_refused()
+    {
+        let mut serialized_snapshot = Snapshot { last_processed_transaction_id: 5, processed_record_count: 4, tables: Vec::new() }.serialize().unwrap();
+        serialized_snapshot[..4].copy_from_slice(&(SNAPSHOT_FORMAT_VERSION + 1).to_le_bytes());
+
+        let error = Snapshot::deserialize(&serialized_snapshot).err().unwrap();
+        assert_eq!(error, format!("Snapshot format version {} is newer than the supported version {}", SNAPSHOT_FORMAT_VERSION + 1, SNAPSHOT_FORMAT_VERSION));
+        assert_eq!(Snapshot::deserialize(&[1, 0]).err().unwrap(), "Snapshot has no format version");
+    }
+
+    #[test]
+    fn snapshot_of_an_earlier_format_version_is_migrated()
+    {
+        let snapshot = Snapshot::deserialize_with_migrations(&serialize_version_0(7), 1, &[(0, migrate_from_version_0)]).unwrap();
+        assert_eq!((snapshot.last_processed_transaction_id, snapshot.processed_record_count, snapshot.tables), (7, 7, vec![(1, 2, vec![3])]));
+
+        let error = Snapshot::deserialize_with_migrations(&serialize_version_0(7), 1, &[]).err().unwrap();
+        assert_eq!(error, "Snapshot format version 0 can not be migrated to version 1");
+    }
+
+    #[test]
+    fn engine_replays_the_whole_log_instead_of_a_snapshot_of_a_future_format_version()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = create_engine(storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        command_engine.snapshot().unwrap();
+        drop(command_engine);
+        let mut serialized_snapshot = storage.reopen().get_snapshot().unwrap();
+        serialized_snapshot[..4].copy_from_slice(&(SNAPSHOT_FORMAT_VERSION + 1).to_le_bytes());
+        storage.reopen().set_snapshot(&serialized_snapshot).unwrap();
+
+        let (query_engine, _command_engine) = create_engine(storage.reopen());
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+    }
+
+    #[test]
+    fn torn_or_corrupted_snapshot_is_refused()
+    {
+        let serialized_snapshot = Snapshot { last_processed_transaction_id: 5, processed_record_count: 4, tables: vec![(1, 2, vec![3])] }.serialize().unwrap();
+
+        let error = Snapshot::deserialize(&serialized_snapshot[..serialized_snapshot.len() - 1]).err().unwrap();
+        assert_eq!(error, format!("Snapshot is {} bytes long instead of {} bytes", serialized_snapshot.len() - 21, serialized_snapshot.len() - 20));
+        let mut corrupted_snapshot = serialized_snapshot.clone();
+        *corrupted_snapshot.last_mut().unwrap() ^= 1;
+        assert_eq!(Snapshot::deserialize(&corrupted_snapshot).err().unwrap(), "Snapshot checksum does not match its contents");
+        assert_eq!(Snapshot::deserialize(&serialized_snapshot[..10]).err().unwrap(), "Snapshot header is truncated");
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct RecordingCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>
+    }
+
+    // Flight numbers of the runs of RecordingCommands::add_flight, so a test can tell which records were replayed
+    static ADDED_FLIGHTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl RecordingCommands
+    {
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            ADDED_FLIGHTS.lock().unwrap().push(flight.flight_number.clone());
+            db.flights.add(Box::new(flight.clone()));
+            Ok(())
+        }
+    }
+
+    // Build an engine replaying the storage and get the number of replayed records adding flights with the prefix
+    fn count_replayed_flights(storage: &MemoryTransactionStorage, prefix: &str) -> (QueryEngine<AirlineDatabase>, usize)
+    {
+        ADDED_FLIGHTS.lock().unwrap().retain(|flight_number| !flight_number.starts_with(prefix));
+        let (query_engine, _command_engine) = Engine::builder(RecordingCommands::new(), Box::new(storage.reopen())).build();
+        let replayed_flights = ADDED_FLIGHTS.lock().unwrap().iter().filter(|flight_number| flight_number.starts_with(prefix)).count();
+        (query_engine, replayed_flights)
+    }
+
+    #[test]
+    fn engine_migrates_a_snapshot_of_format_version_1()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(RecordingCommands::new(), Box::new(storage.reopen())).build();
+        let commands = command_engine.get_command_definitions();
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("V1-{}", number), 10)))).unwrap();
+        }
+        command_engine.snapshot().unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("V1-3", 10)))).unwrap();
+        drop(command_engine);
+        // Version 1 snapshots are the contents after the format version
+        let serialized_snapshot = storage.reopen().get_snapshot().unwrap();
+        let mut version_1_snapshot = 1u32.to_le_bytes().to_vec();
+        version_1_snapshot.extend_from_slice(&serialized_snapshot[20..]);
+        storage.reopen().set_snapshot(&version_1_snapshot).unwrap();
+
+        let (query_engine, replayed_flights) = count_replayed_flights(&storage, "V1-");
+        assert_eq!(replayed_flights, 1);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 4);
+    }
+
+    #[test]
+    fn engine_falls_back_to_the_previous_snapshot_and_to_the_whole_log_if_the_snapshots_are_corrupted()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(RecordingCommands::new(), Box::new(storage.reopen())).build();
+        let commands = command_engine.get_command_definitions();
+        for number in 0..4
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("CS-{}", number), 10)))).unwrap();
+            if number == 1 || number == 2
+            {
+                command_engine.snapshot().unwrap();
+            }
+        }
+        drop(command_engine);
+        let corrupt = |serialized_snapshot: Option<Vec<u8>>| { let mut serialized_snapshot = serialized_snapshot.unwrap(); *serialized_snapshot.last_mut().unwrap() ^= 1; serialized_snapshot };
+        let (previous_snapshot, latest_snapshot) = (storage.reopen().get_previous_snapshot(), storage.reopen().get_snapshot());
+
+        // The latest snapshot is torn, so the records after the previous one are replayed
+        storage.reopen().set_snapshot(&previous_snapshot.clone().unwrap()).unwrap();
+        storage.reopen().set_snapshot(&latest_snapshot.unwrap()[..50]).unwrap();
+        let (query_engine, replayed_flights) = count_replayed_flights(&storage, "CS-");
+        assert_eq!(replayed_flights, 2);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 4);
+
+        // Both snapshots are corrupted, so the whole log is replayed
+        storage.reopen().set_snapshot(&corrupt(previous_snapshot)).unwrap();
+        storage.reopen().set_snapshot(&[0, 1]).unwrap();
+        let (query_engine, replayed_flights) = count_replayed_flights(&storage, "CS-");
+        assert_eq!(replayed_flights, 4);
+        assert_eq!(query_engine.query(|db| db.flights.len()), 4);
+    }
+
+    #[test]
+    fn restart_loads_the_snapshot_and_replays_only_the_later_transactions()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = Engine::builder(RecordingCommands::new(), Box::new(storage.reopen())).build();
+        let commands = command_engine.get_command_definitions();
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("RS-{}", number), 10)))).unwrap();
+        }
+        command_engine.snapshot().unwrap();
+        let last_transaction_id = command_engine.push_command(Arc::new(commands.add_flight.create(flight("RS-3", 10)))).unwrap();
+        drop(command_engine);
+
+        let (query_engine, replayed_flights) = count_replayed_flights(&storage, "RS-");
+        assert_eq!(replayed_flights, 1);
+        let mut flight_numbers = query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>());
+        flight_numbers.sort();
+        assert_eq!(flight_numbers, vec!["RS-0", "RS-1", "RS-2", "RS-3"]);
+        let (_, command_engine) = Engine::builder(RecordingCommands::new(), Box::new(storage.reopen())).build();
+        assert_eq!(command_engine.get_transaction_status(last_transaction_id), Ok(TransactionStatus::Completed));
+    }
+
+    #[test]
+    fn snapshot_newer_than_the_log_is_ignored_and_the_whole_log_is_replayed()
+    {
+        let snapshot_storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = create_engine(snapshot_storage.reopen());
+        let commands = command_engine.get_command_definitions();
+        for number in 0..3
+        {
+            command_engine.push_command(Arc::new(commands.add_flight.create(flight(&format!("MA{}", number), 10)))).unwrap();
+        }
+        command_engine.snapshot().unwrap();
+        drop(command_engine);
+
+        // The log of the other storage has fewer records than the snapshot
+        let storage = MemoryTransactionStorage::new();
+        let (_, mut command_engine) = create_engine(storage.reopen());
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        drop(command_engine);
+        storage.reopen().set_snapshot(&snapshot_storage.reopen().get_snapshot().unwrap()).unwrap();
+
+        let (query_engine, _command_engine) = create_engine(storage.reopen());
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+    }
+}
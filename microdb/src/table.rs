@@ -1,23 +1,200 @@
-use log::debug;
+use log::{debug, warn};
 use serde::{Serialize, de::DeserializeOwned};
-use std::collections::{HashMap, hash_map::Values, hash_map::ValuesMut};
+use std::collections::{BTreeSet, HashMap, HashSet, hash_map::Values, hash_map::ValuesMut};
 use std::hash::{Hash, Hasher};
+use std::ops::{Bound, RangeBounds};
 use std::collections::hash_map::DefaultHasher;
+use std::any::Any;
 use std::sync::{Arc, Mutex};
-use crate::entity::Entity;
-use crate::transaction::{TransactionManager, TransactionEntry};
+#[cfg(feature = "table-stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use crate::entity::{Entity, EntityHandle};
+use crate::id_allocator::{IdAllocator, SequentialAllocator};
+use crate::transaction::{TransactionManager, TransactionEntry, RollbackSerializer, RollbackState};
 
 // Trait defining rollback related functions for tables (used by the transaction manager)
 pub trait TableBase
 {
     // Revert an entity to its original state, what already existed before the transaction
-    fn rollback_to_existing(&mut self, id: usize, state: &Vec<u8>);
+    fn rollback_to_existing(&mut self, id: usize, state: RollbackState) -> Result<(), String>;
 
     // Remove and entity what did not exist before thre transaction
     fn rollback_to_not_existing(&mut self, id: usize);
+
+    // Remove a batch of entities, what did not exist before the transaction (like the ones added by Table::extend)
+    fn rollback_batch_to_not_existing(&mut self, ids: &[usize])
+    {
+        for id in ids
+        {
+            self.rollback_to_not_existing(*id);
+        }
+    }
+
+    // Restore the identifier allocator to its state captured before the first allocation of the transaction
+    fn rollback_id_allocation(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>;
+
+    // Returns true if the table contains an entity with the identifier
+    fn contains(&self, id: usize) -> bool;
+
+    // Get the entities referenced by the foreign keys of an entity as (table identifier, entity identifier) pairs
+    fn get_references(&self, id: usize) -> Vec<(u64, usize)>;
+
+    // Get the identifiers of entities referencing an entity of another table by a cascading foreign key
+    fn get_cascading_references_to(&self, referenced_table_id: u64, referenced_id: usize) -> Vec<usize>;
+
+    // Remove an entity in a way, what can be rolled back (returns false if there is no entity with the identifier)
+    fn remove_entity(&mut self, id: usize) -> bool;
+
+    // Returns the unique identifier of table
+    fn get_id(&self) -> u64;
+
+    // Shrink the memory allocated for the entities as much as possible (skipped while a transaction is running)
+    fn shrink_to_fit(&mut self);
+
+    // Find the entities referencing not existing entities by their foreign keys (exists tells if a table contains an entity)
+    fn find_orphaned_references(&self, exists: &dyn Fn(u64, usize) -> bool) -> Vec<OrphanedReferences>;
+
+    // Check that the serialized size of an entity does not exceed the maximum entity size of the table (if any)
+    fn check_entity_size(&self, id: usize) -> Result<(), String>;
+
+    // Check that an entity has no key of a unique constraint what another entity of the table also has
+    fn check_unique_constraints(&self, id: usize) -> Result<(), String>;
+
+    // Serialize the identifiers and the structs of all entities in the order of identifiers (e.g. to compare states of a table)
+    fn serialize_rows(&self) -> Result<Vec<u8>, String>;
+
+    // Replace all entities of the table by serialized rows without transaction logging (used to load a snapshot)
+    fn load_rows(&mut self, rows: &[u8], max_used_id: usize) -> Result<(), String>;
+
+    // Get the highest identifier allocated or reserved by the table (0 if none)
+    fn get_max_used_id(&self) -> usize;
+
+    // Replace the transaction manager of the table and its entities (used by CommandEngine::clone_fork)
+    fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>);
+
+    // Get the transaction manager of the table (shared by all tables of the database)
+    fn get_transaction_manager(&self) -> Arc<Mutex<TransactionManager>>;
+
+    // Get the table as Any, so it can be downcast to its typed table (see Ref::resolve)
+    fn as_any(&self) -> &dyn Any;
+
+    // Read all entities of the table to load their memory (see QueryEngine::warm) and get the number of entities
+    fn warm(&self) -> usize;
+
+    // Compare the entities of the table to the same table of another state (None if they are equal)
+    fn diff(&self, other: &dyn TableBase) -> Option<TableDiff>;
+
+    // Get the number of accesses of the table since it was created (see Database::table_access_stats)
+    #[cfg(feature = "table-stats")]
+    fn get_access_stats(&self) -> TableAccessStats;
+}
+
+// Number of accesses of a table since it was created
+#[cfg(feature = "table-stats")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableAccessStats
+{
+    pub table_name: &'static str,
+    pub table_id: u64,
+    // Entities read by identifier, handle or index
+    pub reads: u64,
+    // Iterations over all entities (like iter or cloned)
+    pub scans: u64,
+    pub inserts: u64,
+    // Entities borrowed as mutable (counted even if the entity is not changed through the borrow)
+    pub updates: u64,
+    pub deletes: u64
+}
+
+// Kind of an access counted by AccessCounters
+#[derive(Clone, Copy)]
+pub(crate) enum TableAccess { Read, Scan, Insert, Update, Delete }
+
+// Access counters of a table (nothing is stored without the table-stats feature)
+#[derive(Default)]
+pub(crate) struct AccessCounters
+{
+    #[cfg(feature = "table-stats")]
+    counts: [AtomicU64; 5]
 }
 
-// A table, what can store specific type of entities
+impl AccessCounters
+{
+    pub(crate) fn count(&self, access: TableAccess, count: usize)
+    {
+        #[cfg(feature = "table-stats")]
+        self.counts[access as usize].fetch_add(count as u64, Ordering::Relaxed);
+        #[cfg(not(feature = "table-stats"))]
+        let _ = (access, count);
+    }
+
+    #[cfg(feature = "table-stats")]
+    pub(crate) fn get_stats(&self, table_name: &'static str, table_id: u64) -> TableAccessStats
+    {
+        let get = |access: TableAccess| self.counts[access as usize].load(Ordering::Relaxed);
+        TableAccessStats { table_name, table_id, reads: get(TableAccess::Read), scans: get(TableAccess::Scan), inserts: get(TableAccess::Insert), updates: get(TableAccess::Update), deletes: get(TableAccess::Delete) }
+    }
+}
+
+// A copy of a table continues counting from the counts of the original
+impl Clone for AccessCounters
+{
+    fn clone(&self) -> Self
+    {
+        Self
+        {
+            #[cfg(feature = "table-stats")]
+            counts: std::array::from_fn(|index| AtomicU64::new(self.counts[index].load(Ordering::Relaxed)))
+        }
+    }
+}
+
+// Differences of a table between two states of a database by entity identifiers (found by Database::diff)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDiff
+{
+    pub table_name: &'static str,
+    pub table_id: u64,
+    // Entities existing only in the other state
+    pub added: Vec<usize>,
+    // Entities existing only in this state
+    pub removed: Vec<usize>,
+    // Entities existing in both states with different (serialized) structs
+    pub changed: Vec<usize>
+}
+
+// Entities of a table referencing not existing entities by a foreign key (found by Database::check_consistency)
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrphanedReferences
+{
+    pub table_name: &'static str,
+    pub table_id: u64,
+    // Index of the foreign key in the order the foreign keys were registered on the table
+    pub foreign_key_index: usize,
+    pub referenced_table_id: u64,
+    // Orphaned entities as (entity identifier, identifier of the not existing referenced entity) pairs
+    pub entities: Vec<(usize, usize)>
+}
+
+// Trait of database fields holding one or more tables (used by the Database derive)
+pub trait TableSet
+{
+    // Get a table of the set by its unique identifier
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>;
+
+    // Get a table of the set by its unique identifier as mutable
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>;
+
+    // Get all tables of the set
+    fn get_tables(&self) -> Vec<&dyn TableBase>;
+
+    // Get all tables of the set as mutable
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>;
+}
+
+// A table, what can store specific type of entities (identifiers of removed entities are never reused)
 pub struct Table<T> where T : Serialize + DeserializeOwned
 {
     // Name of the table
@@ -26,10 +203,196 @@ pub struct Table<T> where T : Serialize + DeserializeOwned
     id: u64,
     // Hash map to store all entities by their unique identifiers
     rows: HashMap<usize, Entity<Box<T>>>,
-    // First free unique identifier in the table
-    first_free_id: usize,
+    // Allocates the unique identifiers of new entities
+    id_allocator: Box<dyn IdAllocator>,
+    // Generation of the last entity object created by the table
+    last_generation: u64,
+    // Captures and restores the state of entities for rollback
+    rollback_serializer: RollbackSerializer<Box<T>>,
     // Transaction manager
-    transaction_manager: Arc<Mutex<TransactionManager>>
+    transaction_manager: Arc<Mutex<TransactionManager>>,
+    // Foreign keys referencing entities of other tables
+    foreign_keys: Vec<ForeignKey<T>>,
+    // Maximum serialized size of an entity in bytes (None means unlimited)
+    max_entity_size: Option<usize>,
+    // Highest identifier allocated or reserved by the table
+    max_used_id: usize,
+    // Hash indexes of the table by their names
+    indexes: HashMap<&'static str, Box<dyn TableIndex<T>>>,
+    // Names of the indexes with unique keys, and the functions checking an entity by them
+    unique_constraints: Vec<(&'static str, UniqueCheck<T>)>,
+    // Entities borrowed as mutable since the indexes were last updated, so their keys may have changed
+    changed_ids: HashSet<usize>,
+    // Number of accesses by kind (see TableAccessStats)
+    access_counters: AccessCounters
+}
+
+// Function checking that no other entity has the key of a struct in a unique index
+type UniqueCheck<T> = fn(&Table<T>, &'static str, Option<usize>, &T) -> Result<(), String>;
+
+// A foreign key of a table
+struct ForeignKey<T>
+{
+    // Function returning the identifier of the referenced entity
+    field_fn: fn(&T) -> usize,
+    // Unique identifier of the referenced table
+    referenced_table_id: u64,
+    // Removing the referenced entity removes the referencing entities as well
+    cascade_delete: bool
+}
+
+// An index of a table by a key computed from the stored structs
+trait TableIndex<T>: Send + Sync
+{
+    // Update the key of an entity in the index (None removes the entity from the index)
+    fn update(&mut self, id: usize, item: Option<&T>);
+
+    // Add a batch of new entities (not in the index yet) to the index (see Table::extend)
+    fn insert_batch(&mut self, items: &[(usize, &T)])
+    {
+        for (id, item) in items
+        {
+            self.update(*id, Some(item));
+        }
+    }
+
+    // Remove a batch of entities from the index (see TableBase::rollback_batch_to_not_existing)
+    fn remove_batch(&mut self, ids: &[usize])
+    {
+        for id in ids
+        {
+            self.update(*id, None);
+        }
+    }
+
+    // Get the index as Any, so it can be downcast to its typed index
+    fn as_any(&self) -> &dyn Any;
+
+    // Copy the index (used when the table is cloned)
+    fn clone_box(&self) -> Box<dyn TableIndex<T>>;
+}
+
+// A hash index finding the entities by a key in O(1). Keys do not need to be unique.
+struct HashIndex<T, K>
+{
+    // Function computing the key of a struct
+    key_fn: fn(&T) -> K,
+    // Identifiers of the entities by key (ordered, so the same entity is found for a key after a replay)
+    ids_by_key: HashMap<K, BTreeSet<usize>>,
+    // Keys of the entities by identifier, so an entity can be removed from the index after its struct is changed
+    keys_by_id: HashMap<usize, K>
+}
+
+impl<T, K> TableIndex<T> for HashIndex<T, K> where T: 'static, K: Hash + Eq + Clone + Send + Sync + 'static
+{
+    fn update(&mut self, id: usize, item: Option<&T>)
+    {
+        if let Some(key) = self.keys_by_id.remove(&id)
+        {
+            if let Some(ids) = self.ids_by_key.get_mut(&key)
+            {
+                ids.remove(&id);
+                if ids.is_empty()
+                {
+                    self.ids_by_key.remove(&key);
+                }
+            }
+        }
+        if let Some(item) = item
+        {
+            let key = (self.key_fn)(item);
+            self.ids_by_key.entry(key.clone()).or_default().insert(id);
+            self.keys_by_id.insert(id, key);
+        }
+    }
+
+    // The maps are grown once for the whole batch instead of rehashing repeatedly while the entities are added
+    fn insert_batch(&mut self, items: &[(usize, &T)])
+    {
+        self.keys_by_id.reserve(items.len());
+        self.ids_by_key.reserve(items.len());
+        for (id, item) in items
+        {
+            let key = (self.key_fn)(item);
+            self.ids_by_key.entry(key.clone()).or_default().insert(*id);
+            self.keys_by_id.insert(*id, key);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn TableIndex<T>>
+    {
+        Box::new(HashIndex { key_fn: self.key_fn, ids_by_key: self.ids_by_key.clone(), keys_by_id: self.keys_by_id.clone() })
+    }
+}
+
+// An ordered index finding the entities in a range of keys in O(log n) (see Table::range_by_index). Keys do not need to be unique.
+struct OrderedIndex<T, K>
+{
+    // Function computing the key of a struct
+    key_fn: fn(&T) -> K,
+    // Keys and identifiers of the entities ordered by key, then by identifier
+    ids_by_key: BTreeSet<(K, usize)>,
+    // Keys of the entities by identifier, so an entity can be removed from the index after its struct is changed
+    keys_by_id: HashMap<usize, K>
+}
+
+impl<T, K> TableIndex<T> for OrderedIndex<T, K> where T: 'static, K: Ord + Clone + Send + Sync + 'static
+{
+    fn update(&mut self, id: usize, item: Option<&T>)
+    {
+        if let Some(key) = self.keys_by_id.remove(&id)
+        {
+            self.ids_by_key.remove(&(key, id));
+        }
+        if let Some(item) = item
+        {
+            let key = (self.key_fn)(item);
+            self.ids_by_key.insert((key.clone(), id));
+            self.keys_by_id.insert(id, key);
+        }
+    }
+
+    // The keys of the batch are sorted and built into a tree at once, then merged into the index in a single pass
+    fn insert_batch(&mut self, items: &[(usize, &T)])
+    {
+        self.keys_by_id.reserve(items.len());
+        let mut batch: BTreeSet<(K, usize)> = items.iter().map(|(id, item)| ((self.key_fn)(item), *id)).collect();
+        self.keys_by_id.extend(batch.iter().map(|(key, id)| (*id, key.clone())));
+        self.ids_by_key.append(&mut batch);
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn TableIndex<T>>
+    {
+        Box::new(OrderedIndex { key_fn: self.key_fn, ids_by_key: self.ids_by_key.clone(), keys_by_id: self.keys_by_id.clone() })
+    }
+}
+
+// Implemented manually, because deriving would require T to be Clone
+impl<T> Clone for ForeignKey<T>
+{
+    fn clone(&self) -> Self
+    {
+        Self { field_fn: self.field_fn, referenced_table_id: self.referenced_table_id, cascade_delete: self.cascade_delete }
+    }
+}
+
+impl<T> Clone for Table<T> where T : Clone + Serialize + DeserializeOwned
+{
+    // The copy shares the transaction manager of the original table, so it must not be changed outside of the engine
+    fn clone(&self) -> Self
+    {
+        Self { name: self.name, id: self.id, rows: self.rows.clone(), id_allocator: self.id_allocator.clone_box(), last_generation: self.last_generation, rollback_serializer: self.rollback_serializer.clone(), transaction_manager: self.transaction_manager.clone(), foreign_keys: self.foreign_keys.clone(), max_entity_size: self.max_entity_size, max_used_id: self.max_used_id, indexes: self.indexes.iter().map(|(name, index)| (*name, index.clone_box())).collect(), unique_constraints: self.unique_constraints.clone(), changed_ids: self.changed_ids.clone(), access_counters: self.access_counters.clone() }
+    }
 }
 
 impl<T> Table<T> where T : Serialize + DeserializeOwned
@@ -42,7 +405,13 @@ impl<T> Table<T> where T : Serialize + DeserializeOwned
         name.hash(&mut hasher);
         let id = hasher.finish();
 
-        return Self {name, id, rows: HashMap::new(), first_free_id: 1, transaction_manager };
+        Self::new_with_id(name, id, 1, 1, transaction_manager)
+    }
+
+    // Create a new table with a given unique identifier, allocating entity identifiers first_free_id, first_free_id + id_increment, ...
+    pub(crate) fn new_with_id(name: &'static str, id: u64, first_free_id: usize, id_increment: usize, transaction_manager: Arc<Mutex<TransactionManager>>) -> Self
+    {
+        Self {name, id, rows: HashMap::new(), id_allocator: Box::new(SequentialAllocator::new(first_free_id, id_increment)), last_generation: 0, rollback_serializer: RollbackSerializer::bincode(), transaction_manager, foreign_keys: Vec::new(), max_entity_size: None, max_used_id: 0, indexes: HashMap::new(), unique_constraints: Vec::new(), changed_ids: HashSet::new(), access_counters: AccessCounters::default() }
     }
     
     // Returns the unique identifier of table
@@ -51,30 +420,265 @@ impl<T> Table<T> where T : Serialize + DeserializeOwned
         self.id
     }
 
+    // Returns the name of table
+    pub fn get_name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    // Register a foreign key. Transactions inserting or updating an entity referencing a not existing entity in the referenced table fail.
+    pub fn add_foreign_key(&mut self, field_fn: fn(&T) -> usize, referenced_table_id: u64)
+    {
+        self.foreign_keys.push(ForeignKey { field_fn, referenced_table_id, cascade_delete: false });
+    }
+
+    // Register a foreign key, what also removes the referencing entities when the referenced entity is removed by Database::remove_cascade
+    pub fn add_cascading_foreign_key(&mut self, field_fn: fn(&T) -> usize, referenced_table_id: u64)
+    {
+        self.foreign_keys.push(ForeignKey { field_fn, referenced_table_id, cascade_delete: true });
+    }
+
+    // Limit the serialized size of entities (transactions storing a larger entity fail)
+    pub fn set_max_entity_size(&mut self, max_entity_size: usize)
+    {
+        self.max_entity_size = Some(max_entity_size);
+    }
+
+    // Add a hash index by a key computed from the stored structs (see find_by_index)
+    pub fn add_index<K>(&mut self, name: &'static str, key_fn: fn(&T) -> K) where T: 'static, K: Hash + Eq + Clone + Send + Sync + 'static
+    {
+        let mut index = HashIndex { key_fn, ids_by_key: HashMap::new(), keys_by_id: HashMap::new() };
+        for (id, entity) in &self.rows
+        {
+            index.update(*id, Some(&***entity));
+        }
+        self.indexes.insert(name, Box::new(index));
+    }
+
+    // Find an entity by the key of an index (the one with the lowest identifier if there are more)
+    pub fn find_by_index<K>(&self, name: &str, key: &K) -> Option<&Entity<Box<T>>> where T: 'static, K: Hash + Eq + Clone + Send + Sync + 'static
+    {
+        let index = self.indexes.get(name).and_then(|index| index.as_any().downcast_ref::<HashIndex<T, K>>())
+            .unwrap_or_else(|| panic!("Table {} has no index {} with the requested key type", self.name, name));
+        // Keys of the entities borrowed as mutable since the last update of the index are computed again
+        let indexed_ids = index.ids_by_key.get(key).into_iter().flatten().copied().filter(|id| !self.changed_ids.contains(id));
+        let changed_ids = self.changed_ids.iter().copied().filter(|id| self.rows.get(id).is_some_and(|entity| (index.key_fn)(entity) == *key));
+        self.access_counters.count(TableAccess::Read, 1);
+        indexed_ids.chain(changed_ids).min().and_then(|id| self.rows.get(&id))
+    }
+
+    // Add a unique constraint by a key computed from the stored structs (checked before commit)
+    pub fn add_unique_constraint<K>(&mut self, name: &'static str, key_fn: fn(&T) -> K) where T: 'static, K: Hash + Eq + Clone + Send + Sync + 'static
+    {
+        self.add_index(name, key_fn);
+        self.unique_constraints.push((name, Self::check_unique_key::<K>));
+    }
+
+    // Check that no entity other than the one with the identifier (if any) has the key of the struct in the unique index
+    fn check_unique_key<K>(&self, name: &'static str, id: Option<usize>, item: &T) -> Result<(), String> where T: 'static, K: Hash + Eq + Clone + Send + Sync + 'static
+    {
+        let index = self.indexes.get(name).and_then(|index| index.as_any().downcast_ref::<HashIndex<T, K>>()).expect("Unique constraint without index");
+        let key = (index.key_fn)(item);
+        // Keys of the entities borrowed as mutable since the last update of the index are computed again
+        let indexed_ids = index.ids_by_key.get(&key).into_iter().flatten().copied().filter(|id| !self.changed_ids.contains(id));
+        let changed_ids = self.changed_ids.iter().copied().filter(|id| self.rows.get(id).is_some_and(|entity| (index.key_fn)(entity) == key));
+        match (indexed_ids.chain(changed_ids).filter(|other_id| Some(*other_id) != id).min(), id)
+        {
+            (Some(other_id), Some(id)) => Err(format!("Entity {} of table {} has the same key as entity {} by unique constraint {}", id, self.name, other_id, name)),
+            (Some(other_id), None) => Err(format!("Entity {} of table {} already has the key by unique constraint {}", other_id, self.name, name)),
+            (None, _) => Ok(())
+        }
+    }
+
+    // Add an ordered index by a key computed from the stored structs (see range_by_index)
+    pub fn add_ordered_index<K>(&mut self, name: &'static str, key_fn: fn(&T) -> K) where T: 'static, K: Ord + Clone + Send + Sync + 'static
+    {
+        let mut index = OrderedIndex { key_fn, ids_by_key: BTreeSet::new(), keys_by_id: HashMap::new() };
+        for (id, entity) in &self.rows
+        {
+            index.update(*id, Some(&***entity));
+        }
+        self.indexes.insert(name, Box::new(index));
+    }
+
+    // Get the entities with keys of an ordered index in the range, ordered by key
+    pub fn range_by_index<K, R>(&self, name: &str, range: R) -> Vec<&Entity<Box<T>>> where T: 'static, K: Ord + Clone + Send + Sync + 'static, R: RangeBounds<K>
+    {
+        let index = self.indexes.get(name).and_then(|index| index.as_any().downcast_ref::<OrderedIndex<T, K>>())
+            .unwrap_or_else(|| panic!("Table {} has no ordered index {} with the requested key type", self.name, name));
+        // Entities with a key are ordered by identifier, so the bounds of the key range are extended by the lowest or highest identifier
+        let start = match range.start_bound()
+        {
+            Bound::Included(key) => Bound::Included((key.clone(), usize::MIN)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), usize::MAX)),
+            Bound::Unbounded => Bound::Unbounded
+        };
+        let end = match range.end_bound()
+        {
+            Bound::Included(key) => Bound::Included((key.clone(), usize::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), usize::MIN)),
+            Bound::Unbounded => Bound::Unbounded
+        };
+        self.access_counters.count(TableAccess::Scan, 1);
+        // Ranges of BTreeSet panic if the start is after the end, but an empty range is returned like by RangeBounds::contains
+        if let (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) = (&start, &end)
+        {
+            if start > end
+            {
+                return Vec::new();
+            }
+        }
+        let mut keys_and_ids: Vec<(K, usize)> = index.ids_by_key.range((start, end)).filter(|(_, id)| !self.changed_ids.contains(id)).cloned().collect();
+        // Keys of the entities borrowed as mutable since the last update of the index are computed again
+        if !self.changed_ids.is_empty()
+        {
+            keys_and_ids.extend(self.changed_ids.iter().filter_map(|id| self.rows.get(id).map(|entity| ((index.key_fn)(entity), *id))).filter(|(key, _)| range.contains(key)));
+            keys_and_ids.sort_unstable();
+        }
+        keys_and_ids.into_iter().filter_map(|(_, id)| self.rows.get(&id)).collect()
+    }
+
+    // Update the indexes for an entity added, removed or replaced by the table
+    fn update_indexes(&mut self, id: usize)
+    {
+        let item = self.rows.get(&id).map(|entity| &***entity);
+        for index in self.indexes.values_mut()
+        {
+            index.update(id, item);
+        }
+        self.changed_ids.remove(&id);
+    }
+
+    // Add a batch of entities added by the table to the indexes at once
+    fn insert_batch_into_indexes(&mut self, ids: &[usize])
+    {
+        if self.indexes.is_empty()
+        {
+            return;
+        }
+        let items: Vec<(usize, &T)> = ids.iter().filter_map(|id| self.rows.get(id).map(|entity| (*id, &***entity))).collect();
+        for index in self.indexes.values_mut()
+        {
+            index.insert_batch(&items);
+        }
+    }
+
+    // Update the indexes for the entities borrowed as mutable earlier (their borrows ended, when the table is borrowed again)
+    fn update_changed_indexes(&mut self)
+    {
+        for id in std::mem::take(&mut self.changed_ids)
+        {
+            self.update_indexes(id);
+        }
+    }
+
+    // Record that an entity is borrowed as mutable, so its key may change
+    fn mark_changed(&mut self, id: usize)
+    {
+        if !self.indexes.is_empty()
+        {
+            self.changed_ids.insert(id);
+        }
+    }
+
+    // Create a new entity object of the table with the next generation
+    fn create_entity(&mut self, id: usize, item: Box<T>) -> Entity<Box<T>>
+    {
+        self.last_generation += 1;
+        Entity::new(id, self.id, item, Arc::clone(&self.transaction_manager)).with_generation(self.last_generation).with_rollback_state_capture(self.rollback_serializer.capture)
+    }
+
+    // Set how the state of entities is stored for rollback (bincode by default, set it before adding entities)
+    pub fn set_rollback_serializer(&mut self, rollback_serializer: RollbackSerializer<Box<T>>)
+    {
+        self.rollback_serializer = rollback_serializer;
+    }
+
+    // Get an item from the table by a handle (None if the entity was removed or recreated since)
+    pub fn get_by_handle(&self, handle: EntityHandle) -> Option<&Entity<Box<T>>>
+    {
+        self.access_counters.count(TableAccess::Read, 1);
+        let entity = self.rows.get(&handle.id).filter(|entity| entity.get_generation() == handle.generation);
+        debug_assert!(entity.is_some(), "Stale handle of entity {} (generation {}) in table {}", handle.id, handle.generation, self.name);
+        entity
+    }
+
+    // Get an item from the table as mutable by a handle got earlier (see get_by_handle)
+    pub fn get_mut_by_handle(&mut self, handle: EntityHandle) -> Option<&mut Entity<Box<T>>>
+    {
+        self.access_counters.count(TableAccess::Update, 1);
+        self.update_changed_indexes();
+        self.mark_changed(handle.id);
+        let entity = self.rows.get_mut(&handle.id).filter(|entity| entity.get_generation() == handle.generation);
+        debug_assert!(entity.is_some(), "Stale handle of entity {} (generation {}) in table {}", handle.id, handle.generation, self.name);
+        entity
+    }
+
     // Gets an item from the table by identifier
     pub fn get(&self, id: usize) -> Option<&Entity<Box<T>>>
     {
+        self.access_counters.count(TableAccess::Read, 1);
         self.rows.get(&id)
     }
 
+    // Returns true if the table contains a (not removed) entity with the identifier
+    pub fn contains(&self, id: usize) -> bool
+    {
+        self.rows.contains_key(&id)
+    }
+
+    // Returns the number of entities in the table (including the ones added by the running command)
+    pub fn len(&self) -> usize
+    {
+        self.rows.len()
+    }
+
+    // Returns true if the table contains no entities
+    pub fn is_empty(&self) -> bool
+    {
+        self.rows.is_empty()
+    }
+
     // Get an item from the table as mutable byidentifirt
     pub fn get_mut(&mut self, id: usize) -> Option<&mut Entity<Box<T>>>
     {
+        self.access_counters.count(TableAccess::Update, 1);
+        self.update_changed_indexes();
+        self.mark_changed(id);
         self.rows.get_mut(&id)
     }
 
+    // Get two different items from the table as mutable at once (None if any is missing or the identifiers are equal)
+    #[allow(clippy::type_complexity)]
+    pub fn get_disjoint_mut(&mut self, id_a: usize, id_b: usize) -> Option<(&mut Entity<Box<T>>, &mut Entity<Box<T>>)>
+    {
+        if id_a == id_b
+        {
+            return None;
+        }
+        self.access_counters.count(TableAccess::Update, 2);
+        self.update_changed_indexes();
+        self.mark_changed(id_a);
+        self.mark_changed(id_b);
+        match self.rows.get_disjoint_mut([&id_a, &id_b])
+        {
+            [Some(entity_a), Some(entity_b)] => Some((entity_a, entity_b)),
+            _ => None
+        }
+    }
+
     // Add a struct to the table as a new entity
     pub fn add(&mut self, item: Box<T>) -> usize
     {
-        // Use the first free identifier for the new entity
-        let id = self.first_free_id;
-        self.first_free_id += 1;
+        let id = self.allocate_id();
 
         // Create the new entity        
-        let entity = Entity::new(id, self.id, item, Arc::clone(&self.transaction_manager));
+        let entity = self.create_entity(id, item);
         
         // Add the new entity to the hash map
         self.rows.insert(id, entity);
+        self.update_indexes(id);
+        self.access_counters.count(TableAccess::Insert, 1);
         
         let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
         
@@ -88,50 +692,1016 @@ impl<T> Table<T> where T : Serialize + DeserializeOwned
             ));        
         }
 
-        return id;
+        id
     }
 
-    // Remove an entity from the table
-    pub fn remove(&mut self, id: usize)
+    // Add a struct to the table like add, but return an error if its key is taken by a unique constraint
+    pub fn try_add(&mut self, item: Box<T>) -> Result<usize, String>
     {
-        self.rows.remove(&id);
+        for (name, check_fn) in &self.unique_constraints
+        {
+            check_fn(self, name, None, &item)?;
+        }
+        Ok(self.add(item))
     }
 
-    // Get an iterator for the entities stored in the table
-    pub fn iter(&self) -> Values<usize, Entity<Box<T>>>
-    {            
-        self.rows.values()
+    // Add a struct to the table with a given identifier without transaction logging (used by the test support helpers)
+    #[cfg(feature = "test-support")]
+    pub(crate) fn insert_with_id(&mut self, id: usize, item: Box<T>)
+    {
+        // Seeded entities could not be rolled back
+        assert!(!self.transaction_manager.lock().unwrap().is_transaction_running(), "Entities can not be seeded in a transaction");
+        self.reserve_id(id);
+        let entity = self.create_entity(id, item);
+        self.rows.insert(id, entity);
+        self.update_indexes(id);
+        self.access_counters.count(TableAccess::Insert, 1);
     }
-    
-    // Get a mutable iterator for the entities stored in the table
-    pub fn iter_mut(&mut self) -> ValuesMut<usize, Entity<Box<T>>>
-    {            
-        self.rows.values_mut()
-    }  
-
-}
 
-impl<T> TableBase for Table<T> where T: Serialize + DeserializeOwned
-{
-    // Revert an entity to its original state, what already existed before the transaction
-    fn rollback_to_existing(&mut self, id: usize, state: &Vec<u8>)
+    // Capture the state of the identifier allocator before the first allocation of the running transaction
+    fn capture_id_allocation(&self)
     {
-        debug!("rollback_to_existing ({}-{})", self.name, id);
-        // Remove the modified version of entity if it is still in the table
-        self.rows.remove(&id);        
-        // Deserialize the original version of struct stored the entity
-        let item = bincode::deserialize::<Box<T>>(&state[..]).unwrap();
-        // Create a new entity (containing original version of the stored struct)
-        let new_entity = Entity::<Box<T>>::new(id, self.id, item, self.transaction_manager.clone());
-        // Add the new entity to the hash map
-        self.rows.insert(id, new_entity);
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+        if locked_transaction_manager.capture_id_allocation(self.id)
+        {
+            let state: (Box<dyn IdAllocator>, usize) = (self.id_allocator.clone_box(), self.max_used_id);
+            locked_transaction_manager.add_entry(TransactionEntry::IdAllocation(self.id, Box::new(state)));
+        }
     }
 
-    // Remove and entity what did not exist before thre transaction
-    fn rollback_to_not_existing(&mut self, id: usize)
+    // Make sure an identifier given by the caller is never allocated for another entity
+    fn reserve_id(&mut self, id: usize)
     {
-        debug!("rollback_to_not_existing ({}-{})", self.name, id);
-        // Remove entity from hash map
-        self.rows.remove(&id);
+        self.capture_id_allocation();
+        self.id_allocator.reserve(id);
+        self.max_used_id = self.max_used_id.max(id);
     }
-}
\ No newline at end of file
+
+    // Allocate the identifier of a new entity
+    fn allocate_id(&mut self) -> usize
+    {
+        self.capture_id_allocation();
+        let id = self.id_allocator.allocate();
+        debug_assert!(id > 0 && !self.rows.contains_key(&id), "Identifier {} allocated for table {} is already used", id, self.name);
+        self.max_used_id = self.max_used_id.max(id);
+        id
+    }
+
+    // Replace the allocator of the unique identifiers (set it in the init function of the engine)
+    pub fn set_id_allocator(&mut self, id_allocator: impl IdAllocator + 'static)
+    {
+        self.id_allocator = Box::new(id_allocator);
+    }
+
+    // Get an entity as mutable, or add one with the default value of the struct if it does not exist
+    pub fn get_or_default(&mut self, id: usize) -> &mut Entity<Box<T>> where T: Default
+    {
+        self.update_changed_indexes();
+        self.mark_changed(id);
+        if !self.rows.contains_key(&id)
+        {
+            self.access_counters.count(TableAccess::Insert, 1);
+            self.reserve_id(id);
+            let entity = self.create_entity(id, Box::default());
+            self.rows.insert(id, entity);
+
+            let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+            if locked_transaction_manager.is_transaction_running()
+            {
+                debug!("Add transaction entry for a new entity (Table: {}, Id: {})", self.name, id);
+                locked_transaction_manager.add_entry(TransactionEntry::NotExisting(self.id, id));
+            }
+        }
+        else
+        {
+            self.access_counters.count(TableAccess::Update, 1);
+        }
+
+        self.rows.get_mut(&id).unwrap()
+    }
+
+    // Add structs from an iterator to the table as new entities and get their identifiers
+    pub fn extend<I>(&mut self, items: I) -> Vec<usize> where I: IntoIterator<Item = Box<T>>
+    {
+        let mut ids = Vec::new();
+        for item in items
+        {
+            let id = self.allocate_id();
+            let entity = self.create_entity(id, item);
+            self.rows.insert(id, entity);
+            ids.push(id);
+        }
+        self.insert_batch_into_indexes(&ids);
+        self.access_counters.count(TableAccess::Insert, ids.len());
+
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+
+        if !ids.is_empty() && locked_transaction_manager.is_transaction_running()
+        {
+            // Identifiers allocated with a constant step are recorded as a range, others one by one
+            let step = if ids.len() > 1 { ids[1].wrapping_sub(ids[0]) } else { 1 };
+            if ids.windows(2).all(|pair| pair[0] < pair[1] && pair[1] - pair[0] == step)
+            {
+                debug!("Add transaction entry for {} new entities (Table: {}, First Id: {})", ids.len(), self.name, ids[0]);
+                locked_transaction_manager.add_entry(TransactionEntry::NotExistingRange(self.id, ids[0], ids.len(), step));
+            }
+            else
+            {
+                for id in ids.iter()
+                {
+                    locked_transaction_manager.add_entry(TransactionEntry::NotExisting(self.id, *id));
+                }
+            }
+        }
+
+        ids
+    }
+
+    // Remove an entity from the table
+    pub fn remove(&mut self, id: usize)
+    {
+        self.remove_and_log(id);
+    }
+
+    // Remove an entity and log it for rollback (false if there is no entity with the identifier)
+    fn remove_and_log(&mut self, id: usize) -> bool
+    {
+        let Some(entity) = self.rows.remove(&id) else { return false; };
+        self.update_indexes(id);
+        self.access_counters.count(TableAccess::Delete, 1);
+
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+
+        if locked_transaction_manager.is_transaction_running()
+        {
+            // Add an "Existing" transaction entry, so the entity is added back on rollback
+            debug!("Add transaction entry for a removed entity (Table: {}, Id: {})", self.name, id);
+            locked_transaction_manager.add_entry(TransactionEntry::Existing(
+                self.id,
+                id,
+                (self.rollback_serializer.capture)(&entity)
+            ));
+        }
+        true
+    }
+
+    // Remove all entities from the table (they are moved to the transaction log, not serialized)
+    pub fn clear(&mut self) where T: Send + 'static
+    {
+        let rows = std::mem::take(&mut self.rows);
+        self.access_counters.count(TableAccess::Delete, rows.len());
+        for id in rows.keys()
+        {
+            self.update_indexes(*id);
+        }
+
+        let mut locked_transaction_manager = self.transaction_manager.lock().unwrap();
+
+        if locked_transaction_manager.is_transaction_running()
+        {
+            // Add an "Existing" transaction entry for each entity, so all of them are added back on rollback
+            debug!("Add transaction entries for {} removed entities (Table: {})", rows.len(), self.name);
+            for (id, entity) in rows
+            {
+                locked_transaction_manager.add_entry(TransactionEntry::Existing(self.id, id, RollbackState::Moved(Box::new(entity.into_inner()))));
+            }
+        }
+    }
+
+    // Get an iterator for the entities stored in the table
+    pub fn iter(&self) -> Values<'_, usize, Entity<Box<T>>>
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.values()
+    }
+    
+    // Get an iterator for the unique identifiers and the structs stored in the table
+    pub fn iter_with_ids(&self) -> impl Iterator<Item = (usize, &T)>
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.iter().map(|(id, entity)| (*id, &***entity))
+    }
+
+    // Get a copy of the unique identifiers and the structs stored in the table (e.g. to return the whole table from a query)
+    pub fn cloned(&self) -> Vec<(usize, T)> where T: Clone
+    {
+        self.iter_with_ids().map(|(id, val)| (id, val.clone())).collect()
+    }
+
+    // Get a copy of the structs stored in the table, what match the predicate (e.g. to return the result of a filtering query)
+    pub fn find_all<F>(&self, predicate: F) -> Vec<T> where T: Clone, F: Fn(&T) -> bool
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.values().map(|entity| &***entity).filter(|val| predicate(val)).cloned().collect()
+    }
+
+    // Get the distinct keys computed from the stored structs (like the days of week having flights), without copying the structs
+    pub fn distinct_by<K, F>(&self, f: F) -> HashSet<K> where K: Hash + Eq, F: Fn(&T) -> K
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.values().map(|entity| f(entity)).collect()
+    }
+
+    // Get a parallel iterator for the entities stored in the table (the read lock of the database is held while it is used)
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Entity<Box<T>>> where T: Sync
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.par_iter().map(|(_, entity)| entity)
+    }
+
+    // Filter and map the structs stored in the table in parallel
+    #[cfg(feature = "parallel")]
+    pub fn par_filter_map<R, F>(&self, f: F) -> Vec<R> where T: Sync, R: Send, F: Fn(&T) -> Option<R> + Sync + Send
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        self.rows.par_iter().filter_map(|(_, entity)| f(entity)).collect()
+    }
+
+    // Returns the number of entities the table can store without reallocating memory
+    pub fn capacity(&self) -> usize
+    {
+        self.rows.capacity()
+    }
+
+    // Get a mutable iterator for the entities stored in the table (counted as a scan, not as updates of all entities)
+    pub fn iter_mut(&mut self) -> ValuesMut<'_, usize, Entity<Box<T>>>
+    {
+        self.access_counters.count(TableAccess::Scan, 1);
+        if !self.indexes.is_empty()
+        {
+            self.changed_ids.extend(self.rows.keys());
+        }
+        self.rows.values_mut()
+    }  
+
+}
+
+impl<T> TableBase for Table<T> where T: Serialize + DeserializeOwned + 'static
+{
+    // Revert an entity to its original state, what already existed before the transaction
+    fn rollback_to_existing(&mut self, id: usize, state: RollbackState) -> Result<(), String>
+    {
+        debug!("rollback_to_existing ({}-{})", self.name, id);
+        // A field change is rolled back in place (later changes of the entity are already rolled back, because entries are rolled back in reverse order)
+        if let RollbackState::Patch(patch) = state
+        {
+            let entity = self.rows.get_mut(&id).ok_or_else(|| format!("Entity {} with a changed field does not exist", id))?;
+            (patch.restore)(entity.get_mut_unlogged() as &mut dyn Any)?;
+            self.update_indexes(id);
+            return Ok(());
+        }
+        // Restore the original version of struct stored the entity (the modified version is kept if it fails)
+        let item = match state
+        {
+            RollbackState::Moved(item) => *item.downcast::<Box<T>>().map_err(|_| String::from("Moved rollback state has an unexpected type"))?,
+            state => (self.rollback_serializer.restore)(state)?
+        };
+        // Remove the modified version of entity if it is still in the table
+        self.rows.remove(&id);
+        // Create a new entity (containing original version of the stored struct)
+        let new_entity = self.create_entity(id, item);
+        // Add the new entity to the hash map
+        self.rows.insert(id, new_entity);
+        self.update_indexes(id);
+        Ok(())
+    }
+
+    // Remove and entity what did not exist before thre transaction
+    fn rollback_to_not_existing(&mut self, id: usize)
+    {
+        debug!("rollback_to_not_existing ({}-{})", self.name, id);
+        // Remove entity from hash map
+        self.rows.remove(&id);
+        self.update_indexes(id);
+    }
+
+    fn rollback_id_allocation(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>
+    {
+        debug!("rollback_id_allocation ({})", self.name);
+        let (id_allocator, max_used_id) = *state.downcast::<(Box<dyn IdAllocator>, usize)>().map_err(|_| String::from("Id allocation state has an unexpected type"))?;
+        self.id_allocator = id_allocator;
+        self.max_used_id = max_used_id;
+        Ok(())
+    }
+
+    fn rollback_batch_to_not_existing(&mut self, ids: &[usize])
+    {
+        debug!("rollback_batch_to_not_existing ({}, {} entities)", self.name, ids.len());
+        for id in ids
+        {
+            self.rows.remove(id);
+            self.changed_ids.remove(id);
+        }
+        for index in self.indexes.values_mut()
+        {
+            index.remove_batch(ids);
+        }
+    }
+
+    fn contains(&self, id: usize) -> bool
+    {
+        self.rows.contains_key(&id)
+    }
+
+    fn get_cascading_references_to(&self, referenced_table_id: u64, referenced_id: usize) -> Vec<usize>
+    {
+        let cascading_foreign_keys: Vec<&ForeignKey<T>> = self.foreign_keys.iter().filter(|foreign_key| foreign_key.cascade_delete && foreign_key.referenced_table_id == referenced_table_id).collect();
+        if cascading_foreign_keys.is_empty()
+        {
+            return Vec::new();
+        }
+        self.rows.iter()
+            .filter(|(_, entity)| cascading_foreign_keys.iter().any(|foreign_key| (foreign_key.field_fn)(entity) == referenced_id))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn remove_entity(&mut self, id: usize) -> bool
+    {
+        self.remove_and_log(id)
+    }
+
+    fn get_id(&self) -> u64
+    {
+        self.id
+    }
+
+    fn shrink_to_fit(&mut self)
+    {
+        // Shrinking is a maintenance operation, what should not be mixed with the changes of a command
+        if self.transaction_manager.lock().unwrap().is_transaction_running()
+        {
+            warn!("Shrinking table {} is skipped, because a transaction is running", self.name);
+            return;
+        }
+        self.rows.shrink_to_fit();
+    }
+
+    fn get_references(&self, id: usize) -> Vec<(u64, usize)>
+    {
+        match self.rows.get(&id)
+        {
+            Some(entity) => self.foreign_keys.iter().map(|foreign_key| (foreign_key.referenced_table_id, (foreign_key.field_fn)(entity))).collect(),
+            None => Vec::new()
+        }
+    }
+
+    fn check_entity_size(&self, id: usize) -> Result<(), String>
+    {
+        let (Some(max_entity_size), Some(entity)) = (self.max_entity_size, self.rows.get(&id)) else { return Ok(()); };
+        let size = bincode::serialized_size(&**entity).map_err(|e| e.to_string())? as usize;
+        if size > max_entity_size
+        {
+            return Err(format!("Entity {} of table {} is {} bytes, maximum is {} bytes", id, self.name, size, max_entity_size));
+        }
+        Ok(())
+    }
+
+    fn check_unique_constraints(&self, id: usize) -> Result<(), String>
+    {
+        let Some(entity) = self.rows.get(&id) else { return Ok(()); };
+        for (name, check_fn) in &self.unique_constraints
+        {
+            check_fn(self, name, Some(id), entity)?;
+        }
+        Ok(())
+    }
+
+    fn find_orphaned_references(&self, exists: &dyn Fn(u64, usize) -> bool) -> Vec<OrphanedReferences>
+    {
+        self.foreign_keys.iter().enumerate().filter_map(|(foreign_key_index, foreign_key)|
+            {
+                let mut entities: Vec<(usize, usize)> = self.rows.iter()
+                    .map(|(id, entity)| (*id, (foreign_key.field_fn)(entity)))
+                    .filter(|(_, referenced_id)| !exists(foreign_key.referenced_table_id, *referenced_id))
+                    .collect();
+                if entities.is_empty()
+                {
+                    return None;
+                }
+                entities.sort_unstable();
+                Some(OrphanedReferences { table_name: self.name, table_id: self.id, foreign_key_index, referenced_table_id: foreign_key.referenced_table_id, entities })
+            }
+        ).collect()
+    }
+
+    fn serialize_rows(&self) -> Result<Vec<u8>, String>
+    {
+        let mut rows: Vec<(usize, &T)> = self.rows.iter().map(|(id, entity)| (*id, &***entity)).collect();
+        rows.sort_unstable_by_key(|(id, _)| *id);
+        bincode::serialize(&rows).map_err(|e| e.to_string())
+    }
+
+    fn load_rows(&mut self, rows: &[u8], max_used_id: usize) -> Result<(), String>
+    {
+        // Loaded entities could not be rolled back
+        if self.transaction_manager.lock().unwrap().is_transaction_running()
+        {
+            return Err(String::from("Rows can not be loaded in a transaction"));
+        }
+        let rows: Vec<(usize, T)> = bincode::deserialize(rows).map_err(|e| e.to_string())?;
+        let removed_ids: Vec<usize> = self.rows.keys().copied().collect();
+        self.rows.clear();
+        for id in removed_ids
+        {
+            self.update_indexes(id);
+        }
+        for (id, item) in rows
+        {
+            self.reserve_id(id);
+            let entity = self.create_entity(id, Box::new(item));
+            self.rows.insert(id, entity);
+            self.update_indexes(id);
+        }
+        if max_used_id > 0
+        {
+            self.reserve_id(max_used_id);
+        }
+        Ok(())
+    }
+
+    fn get_max_used_id(&self) -> usize
+    {
+        self.max_used_id
+    }
+
+    fn set_transaction_manager(&mut self, transaction_manager: Arc<Mutex<TransactionManager>>)
+    {
+        for entity in self.rows.values_mut()
+        {
+            entity.set_transaction_manager(transaction_manager.clone());
+        }
+        self.transaction_manager = transaction_manager;
+    }
+
+    fn get_transaction_manager(&self) -> Arc<Mutex<TransactionManager>>
+    {
+        self.transaction_manager.clone()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn warm(&self) -> usize
+    {
+        // The entities are passed to black_box, so the iteration is not optimized away
+        let mut count = 0;
+        for entity in self.rows.values()
+        {
+            std::hint::black_box(&***entity);
+            count += 1;
+        }
+        count
+    }
+
+    fn diff(&self, other: &dyn TableBase) -> Option<TableDiff>
+    {
+        let other = other.as_any().downcast_ref::<Table<T>>().expect("Tables of different types can not be compared");
+        let mut diff = TableDiff { table_name: self.name, table_id: self.id, added: Vec::new(), removed: Vec::new(), changed: Vec::new() };
+        for (id, entity) in self.rows.iter()
+        {
+            match other.rows.get(id)
+            {
+                // Structs are compared serialized, so T does not have to implement PartialEq
+                Some(other_entity) => if bincode::serialize(&***entity).ok() != bincode::serialize(&***other_entity).ok()
+                {
+                    diff.changed.push(*id);
+                },
+                None => diff.removed.push(*id)
+            }
+        }
+        diff.added = other.rows.keys().filter(|id| !self.rows.contains_key(id)).copied().collect();
+        if diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty()
+        {
+            return None;
+        }
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.changed.sort_unstable();
+        Some(diff)
+    }
+
+    #[cfg(feature = "table-stats")]
+    fn get_access_stats(&self) -> TableAccessStats
+    {
+        self.access_counters.get_stats(self.name, self.id)
+    }
+}
+
+impl<T> TableSet for Table<T> where T: Serialize + DeserializeOwned + 'static
+{
+    fn find_table(&self, table_id: u64) -> Option<&dyn TableBase>
+    {
+        if table_id == self.id { Some(self) } else { None }
+    }
+
+    fn find_table_mut(&mut self, table_id: u64) -> Option<&mut dyn TableBase>
+    {
+        if table_id == self.id { Some(self) } else { None }
+    }
+
+    fn get_tables(&self) -> Vec<&dyn TableBase>
+    {
+        vec![self]
+    }
+
+    fn get_tables_mut(&mut self) -> Vec<&mut dyn TableBase>
+    {
+        vec![self]
+    }
+}
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+    use std::sync::{Arc, RwLock};
+    use crate::{Database, Engine, TransactionStatus};
+    use crate::command::{CommandDirectoryFactory, CommandError};
+    use crate::test_fixtures::*;
+    use crate::transaction_storage::MemoryTransactionStorage;
+    use futures::executor::block_on;
+
+    #[test]
+    fn iter_with_ids_yields_the_identifiers_and_the_structs()
+    {
+        let (mut db, _) = create_database();
+        let first_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let second_id = db.flights.add(Box::new(flight("MA200", 20)));
+
+        let mut flights: Vec<(usize, &str)> = db.flights.iter_with_ids().map(|(id, flight)| (id, flight.flight_number.as_str())).collect();
+        flights.sort();
+        assert_eq!(flights, vec![(first_id, "MA100"), (second_id, "MA200")]);
+    }
+
+    #[test]
+    fn identifiers_of_removed_entities_are_not_reused()
+    {
+        let (mut db, _) = create_database();
+        let removed_id = db.flights.add(Box::new(flight("MA100", 10)));
+        db.flights.remove(removed_id);
+
+        let added_id = db.flights.add(Box::new(flight("MA200", 10)));
+        assert_ne!(added_id, removed_id);
+        assert!(!db.flights.contains(removed_id));
+        assert!(db.flights.contains(added_id));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_iteration_gives_the_same_result_as_the_sequential_one()
+    {
+        use rayon::iter::ParallelIterator;
+
+        let (mut db, _) = create_database();
+        for seats in 0..1000
+        {
+            db.flights.add(Box::new(flight("MA100", seats)));
+        }
+
+        let sequential: usize = db.flights.iter().map(|flight| flight.seats).sum();
+        assert_eq!(db.flights.par_iter().map(|flight| flight.seats).sum::<usize>(), sequential);
+        let mut large_flights = db.flights.par_filter_map(|flight| (flight.seats >= 990).then_some(flight.seats));
+        large_flights.sort();
+        assert_eq!(large_flights, (990..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shrink_to_fit_releases_the_capacity_of_removed_entities_outside_of_transactions()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let ids = db.flights.extend((0..1000).map(|seats| Box::new(flight("MA100", seats))));
+        for id in &ids[10..]
+        {
+            db.flights.remove(*id);
+        }
+        let capacity = db.flights.capacity();
+
+        // Shrinking is skipped while a transaction is running
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.shrink_all();
+        assert_eq!(db.flights.capacity(), capacity);
+        transaction_manager_ref.lock().unwrap().commit_transaction();
+
+        db.shrink_all();
+        assert!(db.flights.capacity() < capacity);
+        assert_eq!(db.flights.len(), 10);
+    }
+
+    #[test]
+    fn extend_is_rolled_back_together_with_the_transaction()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let existing_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let flights_table_id = db.flights.get_id();
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let ids = db.flights.extend(["MA200", "MA300", "MA400"].into_iter().map(|flight_number| Box::new(flight(flight_number, 10))));
+        assert_eq!(ids.len(), 3);
+        assert_eq!(db.flights.len(), 4);
+        assert_eq!(transaction_manager_ref.lock().unwrap().get_touched_entities(), ids.iter().map(|id| (flights_table_id, *id)).collect::<Vec<_>>());
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert!(ids.iter().all(|id| !db.flights.contains(*id)));
+        assert!(db.flights.contains(existing_id));
+        assert_eq!(db.flights.len(), 1);
+    }
+
+    #[test]
+    fn handle_resolves_the_entity_it_was_got_from()
+    {
+        let (mut db, _) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let handle = db.flights.get(id).unwrap().get_handle();
+
+        db.flights.get_mut_by_handle(handle).unwrap().seats = 20;
+        assert_eq!(db.flights.get_by_handle(handle).unwrap().seats, 20);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stale handle of entity")]
+    fn handle_of_an_entity_recreated_by_a_rollback_is_stale()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(id).unwrap().seats = 20;
+        let handle = db.flights.get(id).unwrap().get_handle();
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(db.flights.get(id).unwrap().seats, 10);
+        db.flights.get_by_handle(handle);
+    }
+
+    #[test]
+    fn get_disjoint_mut_changes_of_both_entities_are_rolled_back()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let from_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let to_id = db.flights.add(Box::new(flight("MA200", 20)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let (from, to) = db.flights.get_disjoint_mut(from_id, to_id).unwrap();
+        from.seats -= 5;
+        to.seats += 5;
+        assert_eq!((db.flights.get(from_id).unwrap().seats, db.flights.get(to_id).unwrap().seats), (5, 25));
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!((db.flights.get(from_id).unwrap().seats, db.flights.get(to_id).unwrap().seats), (10, 20));
+    }
+
+    #[test]
+    fn get_disjoint_mut_returns_none_for_equal_or_missing_identifiers()
+    {
+        let (mut db, _) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+
+        assert!(db.flights.get_disjoint_mut(id, id).is_none());
+        assert!(db.flights.get_disjoint_mut(id, id + 1000).is_none());
+    }
+
+    #[test]
+    fn cloned_returns_owned_copies_of_the_entities()
+    {
+        let (mut db, _) = create_database();
+        let first_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let second_id = db.flights.add(Box::new(flight("MA200", 20)));
+
+        let mut flights = db.flights.cloned();
+        db.flights.remove(first_id);
+        flights.sort_by_key(|(id, _)| *id);
+        assert_eq!(flights, vec![(first_id, flight("MA100", 10)), (second_id, flight("MA200", 20))]);
+    }
+
+    #[test]
+    fn get_or_default_adds_the_entity_once_and_is_rolled_back()
+    {
+        let (db, transaction_manager_ref) = create_database();
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_or_default(5).seats += 1;
+        db.flights.get_or_default(5).seats += 1;
+        assert_eq!(db.flights.get(5).unwrap().seats, 2);
+        // The identifier is not allocated for another entity
+        assert!(db.flights.add(Box::new(flight("MA100", 10))) > 5);
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert!(db.flights.is_empty());
+    }
+
+    // Index the flights by flight number and seats
+    fn add_flight_indexes(db: &mut AirlineDatabase)
+    {
+        db.flights.add_index("flight_number", |flight| flight.flight_number.clone());
+        db.flights.add_ordered_index("seats", |flight| flight.seats);
+    }
+
+    #[test]
+    fn indexes_are_consistent_after_a_bulk_insert_and_its_rollback()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        add_flight_indexes(&mut db);
+        let existing_id = db.flights.add(Box::new(flight("MA000", 50)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        let ids = db.flights.extend((0..100).map(|index| Box::new(flight(&format!("MA{:03}", index + 1), index))));
+        for (index, id) in ids.iter().enumerate()
+        {
+            assert_eq!(db.flights.find_by_index("flight_number", &format!("MA{:03}", index + 1)).unwrap().get_id(), *id);
+        }
+        let seats: Vec<usize> = db.flights.range_by_index("seats", 45usize..55).iter().map(|flight| flight.seats).collect();
+        assert_eq!(seats, vec![45, 46, 47, 48, 49, 50, 50, 51, 52, 53, 54]);
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert!((1..=100).all(|index| db.flights.find_by_index("flight_number", &format!("MA{:03}", index)).is_none()));
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA000")).unwrap().get_id(), existing_id);
+        assert_eq!(db.flights.range_by_index::<usize, _>("seats", ..).iter().map(|flight| flight.get_id()).collect::<Vec<_>>(), vec![existing_id]);
+
+        // Identifiers of the rolled back batch are indexed again when they are added later
+        let readded_ids = db.flights.extend([Box::new(flight("MA001", 1))]);
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA001")).unwrap().get_id(), readded_ids[0]);
+        assert_eq!(db.flights.range_by_index("seats", ..10usize).len(), 1);
+    }
+
+    #[test]
+    fn diff_lists_the_added_removed_and_changed_entities_of_the_tables_with_differences()
+    {
+        let (mut db, _) = create_database();
+        let kept_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let changed_id = db.flights.add(Box::new(flight("MA200", 10)));
+        let removed_id = db.flights.add(Box::new(flight("MA300", 10)));
+        db.reservations.add(Box::new(reservation(kept_id, "Alice")));
+        let mut other = db.clone();
+        assert!(db.diff(&other).is_empty());
+
+        other.flights.get_mut(changed_id).unwrap().seats = 5;
+        other.flights.remove(removed_id);
+        let added_id = other.flights.add(Box::new(flight("MA400", 10)));
+
+        let diffs = db.diff(&other);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].table_id, db.flights.get_id());
+        assert_eq!(diffs[0].added, vec![added_id]);
+        assert_eq!(diffs[0].removed, vec![removed_id]);
+        assert_eq!(diffs[0].changed, vec![changed_id]);
+    }
+
+    #[test]
+    fn removed_entities_are_restored_with_their_fields_on_rollback()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        add_flight_indexes(&mut db);
+        let removed_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let kept_id = db.flights.add(Box::new(flight("MA200", 20)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.remove(removed_id);
+        assert!(!db.flights.contains(removed_id));
+        assert!(db.flights.find_by_index("flight_number", &String::from("MA100")).is_none());
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(***db.flights.get(removed_id).unwrap(), flight("MA100", 10));
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA100")).unwrap().get_id(), removed_id);
+        assert_eq!(db.flights.range_by_index::<usize, _>("seats", ..).iter().map(|flight| flight.get_id()).collect::<Vec<_>>(), vec![removed_id, kept_id]);
+    }
+
+    #[test]
+    fn find_by_index_follows_the_changes_of_the_entities_and_their_rollback()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let first_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let second_id = db.flights.add(Box::new(flight("MA100", 20)));
+        // Existing entities are indexed when the index is added
+        db.flights.add_index("flight_number", |flight| flight.flight_number.clone());
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA100")).unwrap().get_id(), first_id);
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(first_id).unwrap().flight_number = String::from("MA200");
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA100")).unwrap().get_id(), second_id);
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA200")).unwrap().get_id(), first_id);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        assert_eq!(db.flights.find_by_index("flight_number", &String::from("MA100")).unwrap().get_id(), first_id);
+        assert!(db.flights.find_by_index("flight_number", &String::from("MA200")).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Table flights has no index seats with the requested key type")]
+    fn find_by_index_panics_without_the_index()
+    {
+        let (db, _) = create_database();
+        db.flights.find_by_index("seats", &10usize);
+    }
+
+    #[test]
+    fn load_rows_fails_in_a_transaction_and_keeps_the_entities()
+    {
+        use super::TableBase;
+
+        let (mut source, _) = create_database();
+        source.flights.add(Box::new(flight("MA100", 10)));
+        let rows = source.flights.serialize_rows().unwrap();
+        let (mut db, transaction_manager_ref) = create_database();
+        let kept_id = db.flights.add(Box::new(flight("MA200", 20)));
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        assert_eq!(db.flights.load_rows(&rows, 1), Err(String::from("Rows can not be loaded in a transaction")));
+        assert_eq!(db.flights.cloned(), vec![(kept_id, flight("MA200", 20))]);
+        transaction_manager_ref.lock().unwrap().commit_transaction();
+
+        db.flights.load_rows(&rows, 1).unwrap();
+        assert_eq!(db.flights.cloned(), vec![(1, flight("MA100", 10))]);
+    }
+
+    #[test]
+    #[cfg(feature = "table-stats")]
+    fn access_counters_count_the_accesses_of_the_table_but_not_the_rollbacks()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        db.flights.add(Box::new(flight("MA200", 20)));
+        assert!(db.flights.get(id).is_some());
+        db.flights.get_mut(id).unwrap().seats = 11;
+        assert_eq!(db.flights.iter().count(), 2);
+        db.flights.remove(id);
+
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.add(Box::new(flight("MA300", 30)));
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+
+        let stats = db.table_access_stats();
+        let flights = stats.iter().find(|stats| stats.table_name == "flights").unwrap();
+        assert_eq!((flights.reads, flights.scans, flights.inserts, flights.updates, flights.deletes), (1, 1, 3, 1, 1));
+        let reservations = stats.iter().find(|stats| stats.table_name == "reservations").unwrap();
+        assert_eq!((reservations.reads, reservations.scans, reservations.inserts, reservations.updates, reservations.deletes), (0, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn len_counts_the_entities_until_their_transaction_is_rolled_back()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        assert!(db.flights.is_empty());
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        db.flights.add(Box::new(flight("MA200", 20)));
+        db.flights.remove(id);
+        assert_eq!((db.flights.len(), db.flights.is_empty()), (1, false));
+
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.add(Box::new(flight("MA300", 30)));
+        assert_eq!(db.flights.len(), 2);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(db.flights.len(), 1);
+    }
+
+    #[test]
+    fn clear_is_rolled_back_and_keeps_the_identifiers_of_the_removed_entities()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        for flight_number in ["MA100", "MA200", "MA300"]
+        {
+            db.flights.add(Box::new(flight(flight_number, 10)));
+        }
+        let sorted_rows = |db: &AirlineDatabase|
+        {
+            let mut rows: Vec<(usize, String)> = db.flights.iter_with_ids().map(|(id, flight)| (id, flight.flight_number.clone())).collect();
+            rows.sort();
+            rows
+        };
+        let rows = sorted_rows(&db);
+
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.clear();
+        assert!(db.flights.is_empty());
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(sorted_rows(&db), rows);
+
+        db.flights.clear();
+        assert!(db.flights.is_empty());
+        assert_eq!(db.flights.add(Box::new(flight("MA400", 10))), 4);
+    }
+
+    #[test]
+    fn find_all_returns_copies_of_the_matching_structs()
+    {
+        let (mut db, _) = create_database();
+        db.flights.add(Box::new(flight("MA100", 10)));
+        db.flights.add(Box::new(flight("MA200", 0)));
+        db.flights.add(Box::new(flight("MA300", 30)));
+
+        let mut found = db.flights.find_all(|flight| flight.seats > 0);
+        found.sort_by(|a, b| a.flight_number.cmp(&b.flight_number));
+        assert_eq!(found, vec![flight("MA100", 10), flight("MA300", 30)]);
+        // Changing a copy does not change the table
+        found[0].seats = 0;
+        assert_eq!(db.flights.find_all(|flight| flight.seats == 0), vec![flight("MA200", 0)]);
+        assert!(db.flights.find_all(|flight| flight.seats > 100).is_empty());
+    }
+
+    #[test]
+    fn distinct_by_returns_each_key_once()
+    {
+        let (mut db, _) = create_database();
+        assert!(db.flights.distinct_by(|flight| flight.day_of_week).is_empty());
+        for (flight_number, day_of_week) in [("MA100", 1), ("MA200", 3), ("MA300", 1), ("MA400", 5)]
+        {
+            db.flights.add(Box::new(Flight { day_of_week, ..flight(flight_number, 10) }));
+        }
+
+        assert_eq!(db.flights.distinct_by(|flight| flight.day_of_week), HashSet::from([1, 3, 5]));
+        assert_eq!(db.flights.distinct_by(|flight| flight.to.clone()), HashSet::from([String::from("LHR")]));
+    }
+
+    #[test]
+    fn unique_constraint_rejects_added_and_changed_duplicate_keys()
+    {
+        let (mut db, _) = create_database();
+        db.flights.add_unique_constraint("flight_number", |flight| flight.flight_number.clone());
+        let first_id = db.flights.try_add(Box::new(flight("MA100", 10))).unwrap();
+        let second_id = db.flights.try_add(Box::new(flight("MA200", 10))).unwrap();
+
+        assert_eq!(db.flights.try_add(Box::new(flight("MA100", 20))), Err(format!("Entity {} of table flights already has the key by unique constraint flight_number", first_id)));
+        assert_eq!(db.flights.len(), 2);
+
+        db.flights.get_mut(second_id).unwrap().flight_number = String::from("MA100");
+        assert_eq!(db.check_unique_constraints(&[(db.flights.get_id(), second_id)]),
+            Err(format!("Entity {} of table flights has the same key as entity {} by unique constraint flight_number", second_id, first_id)));
+        db.flights.get_mut(second_id).unwrap().flight_number = String::from("MA300");
+        assert_eq!(db.check_unique_constraints(&[(db.flights.get_id(), second_id)]), Ok(()));
+    }
+
+    #[test]
+    fn transaction_referencing_a_not_existing_entity_is_rolled_back()
+    {
+        let (query_engine, mut command_engine) = create_engine(MemoryTransactionStorage::new());
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let flight_id = query_engine.query(|db| db.flights.iter().next().unwrap().get_id());
+
+        let referencing = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id, "Alice")))).unwrap();
+        let dangling = command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_id + 1, "Bob")))).unwrap();
+
+        assert_eq!(command_engine.get_transaction_status(referencing).unwrap(), TransactionStatus::Completed);
+        assert_eq!(command_engine.get_transaction_status(dangling).unwrap(), TransactionStatus::Failed);
+        assert_eq!(query_engine.query(|db| db.reservations.find_all(|reservation| reservation.passenger == "Bob").len()), 0);
+        assert_eq!(query_engine.query(|db| db.reservations.len()), 1);
+    }
+
+    #[test]
+    fn transactions_creating_a_duplicate_unique_key_fail_and_are_rolled_back()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new())).with_init(|db|
+        {
+            init(db);
+            db.flights.add_unique_constraint("flight_number", |flight| flight.flight_number.clone());
+        }).build();
+        let commands = command_engine.get_command_definitions();
+
+        let first = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let duplicate = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 20)))).unwrap();
+
+        assert!(block_on(first).is_ok());
+        assert!(matches!(block_on(duplicate), Err(CommandError::Failed(message)) if message == "Entity 2 of table flights has the same key as entity 1 by unique constraint flight_number"));
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.seats).collect::<Vec<_>>()), vec![10]);
+    }
+
+    #[test]
+    fn transactions_adding_an_entity_larger_than_the_maximum_size_fail()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(MemoryTransactionStorage::new())).with_init(|db|
+        {
+            init(db);
+            db.flights.set_max_entity_size(60);
+        }).build();
+        let commands = command_engine.get_command_definitions();
+
+        let small = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight("MA100", 10)))).unwrap();
+        let large = command_engine.push_command_with_handle(Arc::new(commands.add_flight.create(flight(&format!("MA{}", "0".repeat(100)), 10)))).unwrap();
+
+        assert!(block_on(small).is_ok());
+        assert!(matches!(block_on(large), Err(CommandError::Failed(message)) if message == "Entity 2 of table flights is 141 bytes, maximum is 60 bytes"));
+        assert_eq!(query_engine.query(|db| db.flights.len()), 1);
+    }
+}
@@ -0,0 +1,119 @@
+// Airline database and commands shared by the tests of the modules
+
+use crate::prelude::*;
+use microdb_derive::*;
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Flight
+{
+    pub flight_number: String,
+    pub from: String,
+    pub to: String,
+    pub day_of_week: u8,
+    pub seats: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Reservation
+{
+    pub flight_id: usize,
+    pub passenger: String
+}
+
+#[derive(Database, DatabaseFactory, Clone)]
+pub struct AirlineDatabase
+{
+    pub flights: Table::<Flight>,
+    pub reservations: Table::<Reservation>
+}
+
+#[derive(CommandDirectory, CommandDirectoryFactory)]
+pub struct AirlineCommands
+{
+    pub add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+    pub add_reservation: CommandDefinition::<AirlineDatabase, Reservation>,
+    // Adds the flight without writing the command to the transaction log
+    #[non_durable]
+    pub add_temporary_flight: CommandDefinition::<AirlineDatabase, Flight>,
+    // Adds the flight, then fails, so the flight is rolled back
+    pub add_flight_and_fail: CommandDefinition::<AirlineDatabase, Flight>
+}
+
+impl AirlineCommands
+{
+    fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+        db.flights.add(Box::new(flight.clone()));
+        Ok(())
+    }
+
+    fn add_reservation(db: &mut AirlineDatabase, reservation: &Reservation) -> Result<(), String>
+    {
+        db.reservations.add(Box::new(reservation.clone()));
+        Ok(())
+    }
+
+    fn add_temporary_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+        db.flights.add(Box::new(flight.clone()));
+        Ok(())
+    }
+
+    fn add_flight_and_fail(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+    {
+        db.flights.add(Box::new(flight.clone()));
+        Err(format!("Flight {} is not allowed", flight.flight_number))
+    }
+}
+
+// Create a flight with the number and free seats
+pub fn flight(flight_number: &str, seats: usize) -> Flight
+{
+    Flight { flight_number: String::from(flight_number), from: String::from("BUD"), to: String::from("LHR"), day_of_week: 1, seats }
+}
+
+// Create a reservation of the passenger on the flight
+pub fn reservation(flight_id: usize, passenger: &str) -> Reservation
+{
+    Reservation { flight_id, passenger: String::from(passenger) }
+}
+
+// Create an empty airline database with its own transaction manager (for tests of tables and transactions)
+pub fn create_database() -> (AirlineDatabase, Arc<Mutex<TransactionManager>>)
+{
+    let transaction_manager_ref = Arc::new(Mutex::new(TransactionManager::new()));
+    (AirlineDatabase::create_database(transaction_manager_ref.clone()), transaction_manager_ref)
+}
+
+// Register the foreign key of the reservations (the init function of the engines)
+pub fn init(db: &mut AirlineDatabase)
+{
+    let flights_table_id = db.flights.get_id();
+    db.reservations.add_foreign_key(|reservation| reservation.flight_id, flights_table_id);
+}
+
+// Create a synchronous engine replaying the storage
+pub fn create_engine(transaction_storage: impl TransactionStorage + 'static) -> (QueryEngine<AirlineDatabase>, CommandEngine<AirlineDatabase, AirlineCommands>)
+{
+    Engine::builder(AirlineCommands::new(), Box::new(transaction_storage)).with_init(init).build()
+}
+
+// Create an empty directory for the files of a test
+pub fn create_test_directory(name: &str) -> String
+{
+    let path = std::env::temp_dir().join(format!("microdb-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    String::from(path.to_str().unwrap())
+}
+
+// Flights and reservations of the database as (table, identifier, flight number or passenger) ordered by table and identifier
+pub fn get_rows(db: &AirlineDatabase) -> Vec<(&'static str, usize, String)>
+{
+    let mut rows: Vec<_> = db.flights.iter_with_ids().map(|(id, flight)| ("flights", id, flight.flight_number.clone()))
+        .chain(db.reservations.iter_with_ids().map(|(id, reservation)| ("reservations", id, reservation.passenger.clone()))).collect();
+    rows.sort();
+    rows
+}
@@ -0,0 +1,112 @@
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{Database, DatabaseFactory, Engine, SharedCommand};
+use crate::command::{CommandDirectory, CommandDirectoryFactory};
+use crate::table::Table;
+use crate::transaction_storage::MemoryTransactionStorage;
+
+// Add structs to a table at specific identifiers without running commands and without transaction logging
+pub fn seed_table<T, I>(table: &mut Table<T>, items: I) where T: Serialize + DeserializeOwned, I: IntoIterator<Item = (usize, T)>
+{
+    for (id, item) in items
+    {
+        assert!(id > 0, "Identifier 0 is never used by tables");
+        table.insert_with_id(id, Box::new(item));
+    }
+}
+
+// Serialize all tables of a database as (table identifier, serialized rows) pairs in the order of table identifiers
+fn serialize_tables<D>(db: &D) -> Vec<(u64, Vec<u8>)> where D: Database
+{
+    let mut tables: Vec<(u64, Vec<u8>)> = db.get_tables().iter().map(|table| (table.get_id(), table.serialize_rows().expect("Serializing a table failed"))).collect();
+    tables.sort_unstable_by_key(|(table_id, _)| *table_id);
+    tables
+}
+
+// Assert that replaying the log of the commands created by create_commands reproduces the tables byte by byte
+pub fn assert_replay_deterministic<D, C>(init: fn(&mut D), create_commands: impl FnOnce(&C) -> Vec<SharedCommand<D>>)
+    where D: Database + DatabaseFactory + Send + Sync + 'static, C: CommandDirectory<D> + CommandDirectoryFactory
+{
+    let transaction_storage = MemoryTransactionStorage::new();
+
+    let (query_engine, mut command_engine) = Engine::builder(C::new(), Box::new(transaction_storage.reopen())).with_init(init).build();
+    let command_definitions = command_engine.get_command_definitions();
+    for command in create_commands(&command_definitions)
+    {
+        command_engine.push_command(command).expect("Pushing a command failed");
+    }
+    let original_tables = query_engine.query(serialize_tables);
+    drop(command_engine);
+
+    let (query_engine, _command_engine) = Engine::builder(C::new(), Box::new(transaction_storage)).with_init(init).build();
+    let replayed_tables = query_engine.query(serialize_tables);
+
+    assert_eq!(original_tables.len(), replayed_tables.len(), "Number of tables differs after replay");
+    for ((table_id, original_rows), (_, replayed_rows)) in original_tables.iter().zip(replayed_tables.iter())
+    {
+        assert!(original_rows == replayed_rows, "State of table {} differs after replay", table_id);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::command::{CommandDefinition, CommandDirectoryFactory};
+    use microdb_derive::{CommandDirectory, CommandDirectoryFactory};
+    use crate::transaction_storage::TransactionLogReader;
+    use crate::test_fixtures::*;
+
+    #[test]
+    fn seeded_entities_keep_their_identifiers_and_are_not_logged()
+    {
+        let storage = MemoryTransactionStorage::new();
+        let (query_engine, mut command_engine) = Engine::builder(AirlineCommands::new(), Box::new(storage.reopen()))
+            .with_init(|db: &mut AirlineDatabase| seed_table(&mut db.flights, [(5, flight("MA100", 10)), (10, flight("MA200", 20))])).build();
+        let commands = command_engine.get_command_definitions();
+
+        command_engine.push_command_and_wait(Arc::new(commands.add_flight.create(flight("MA300", 30)))).unwrap();
+        // Entities added later get identifiers after the seeded ones
+        assert_eq!(query_engine.query(|db| db.flights.get(11).unwrap().flight_number.clone()), "MA300");
+        assert_eq!(query_engine.query(|db| db.flights.get(5).unwrap().seats), 10);
+        assert_eq!(TransactionLogReader::new(Box::new(storage.reopen())).count(), 1);
+    }
+
+    #[test]
+    fn replay_of_deterministic_commands_reproduces_the_state()
+    {
+        assert_replay_deterministic::<AirlineDatabase, AirlineCommands>(init, |commands| vec![
+            Arc::new(commands.add_flight.create(flight("MA100", 10))),
+            Arc::new(commands.add_flight_and_fail.create(flight("MA200", 10))),
+            Arc::new(commands.add_reservation.create(reservation(1, "Alice")))
+        ]);
+    }
+
+    // Number of seats of the next flight added by the counting command, what is not stored in the database
+    static NEXT_SEATS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct CountingCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>
+    }
+
+    impl CountingCommands
+    {
+        // Adds the flight with seats taken from a counter outside of the database, so the replay gives a different state
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            let seats = NEXT_SEATS.fetch_add(1, Ordering::SeqCst);
+            db.flights.add(Box::new(Flight { seats, ..flight.clone() }));
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "differs after replay")]
+    fn replay_of_non_deterministic_commands_fails_the_assertion()
+    {
+        assert_replay_deterministic::<AirlineDatabase, CountingCommands>(init, |commands| vec![Arc::new(commands.add_flight.create(flight("MA100", 10)))]);
+    }
+}
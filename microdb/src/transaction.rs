@@ -1,14 +1,112 @@
-use std::{sync::{RwLockWriteGuard}, fmt::{Display, self}};
+use std::{any::{Any, TypeId}, collections::HashSet, sync::{RwLockWriteGuard}, fmt::{Display, self}};
+use serde::{Serialize, de::DeserializeOwned};
 
-use log::debug;
+use log::{debug, error};
 
 use  crate::Database;
 
 
+// State of an entity before the transaction, stored to roll back its changes
+pub enum RollbackState
+{
+    // The entity serialized by bincode
+    Serialized(Vec<u8>),
+    // A copy of the entity
+    Cloned(Box<dyn Any + Send>),
+    // The entity itself moved out of its table (see Table::clear), so it is restored by the table without a rollback serializer
+    Moved(Box<dyn Any + Send>),
+    // Restores a single field of the entity in place (see Entity::update_field)
+    Patch(FieldPatch)
+}
+
+// Restores the original value of a single field of the stored struct
+pub struct FieldPatch
+{
+    // Address of the accessor and type of the field
+    pub(crate) field: (usize, TypeId),
+    pub(crate) restore: RestoreField
+}
+
+// Function restoring a field of the stored struct passed as Any
+pub(crate) type RestoreField = Box<dyn FnOnce(&mut dyn Any) -> Result<(), String> + Send>;
+
+// Captures and restores the rollback state of entities (it never leaves the memory, so it needs no stable format)
+pub struct RollbackSerializer<T>
+{
+    pub(crate) capture: fn(&T) -> RollbackState,
+    pub(crate) restore: fn(RollbackState) -> Result<T, String>
+}
+
+impl<T> Clone for RollbackSerializer<T>
+{
+    fn clone(&self) -> Self
+    {
+        Self { capture: self.capture, restore: self.restore }
+    }
+}
+
+impl<T> RollbackSerializer<T> where T: Serialize + DeserializeOwned
+{
+    // Serialize the entities by bincode (default)
+    pub fn bincode() -> Self
+    {
+        Self { capture: |value| RollbackState::Serialized(bincode::serialize(value).unwrap()), restore: Self::restore_serialized }
+    }
+
+    fn restore_serialized(state: RollbackState) -> Result<T, String>
+    {
+        match state
+        {
+            RollbackState::Serialized(state) => bincode::deserialize::<T>(&state).map_err(|e| e.to_string()),
+            RollbackState::Cloned(_) => Err(String::from("Cloned rollback state can not be restored by the bincode rollback serializer")),
+            RollbackState::Moved(_) => Err(String::from("Moved rollback state can not be restored by a rollback serializer")),
+            RollbackState::Patch(_) => Err(String::from("Patch rollback state can not be restored by a rollback serializer"))
+        }
+    }
+}
+
+impl<T> RollbackSerializer<T> where T: Serialize + DeserializeOwned + Clone + Send + 'static
+{
+    // Keep a copy of the entities instead of serializing them (faster for structs without heap allocated fields)
+    pub fn cloning() -> Self
+    {
+        Self { capture: |value| RollbackState::Cloned(Box::new(value.clone())), restore: Self::restore_cloned }
+    }
+
+    fn restore_cloned(state: RollbackState) -> Result<T, String>
+    {
+        match state
+        {
+            RollbackState::Cloned(state) => state.downcast::<T>().map(|value| *value).map_err(|_| String::from("Cloned rollback state has an unexpected type")),
+            // State captured before the serializer was changed
+            RollbackState::Serialized(_) | RollbackState::Moved(_) | RollbackState::Patch(_) => Self::restore_serialized(state)
+        }
+    }
+}
+
 pub enum TransactionEntry
 {
-    Existing(u64, usize, Vec<u8>),
-    NotExisting(u64, usize)
+    Existing(u64, usize, RollbackState),
+    NotExisting(u64, usize),
+    // Entities added in one batch (table identifier, first identifier, number of entities, identifier increment)
+    NotExistingRange(u64, usize, usize, usize),
+    // State of the identifier allocator of a table before its first allocation in the transaction
+    IdAllocation(u64, Box<dyn Any + Send>)
+}
+
+impl TransactionEntry
+{
+    // Get the entities of the entry as (table identifier, entity identifier) pairs
+    fn get_entities(&self) -> Vec<(u64, usize)>
+    {
+        match self
+        {
+            TransactionEntry::Existing(table_id, id, _) => vec![(*table_id, *id)],
+            TransactionEntry::NotExisting(table_id, id) => vec![(*table_id, *id)],
+            TransactionEntry::NotExistingRange(table_id, first_id, count, id_increment) => (0..*count).map(|index| (*table_id, first_id + index * id_increment)).collect(),
+            TransactionEntry::IdAllocation(..) => Vec::new()
+        }
+    }
 }
 
 impl Display for TransactionEntry
@@ -16,23 +114,47 @@ impl Display for TransactionEntry
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             TransactionEntry::Existing(id, _, _ ) => { write!(f, "Existing ({})", id) },
-            TransactionEntry::NotExisting(id, _ ) => { write!(f, "Not Existing ({})", id) }
+            TransactionEntry::NotExisting(id, _ ) => { write!(f, "Not Existing ({})", id) },
+            TransactionEntry::NotExistingRange(id, _, count, _ ) => { write!(f, "Not Existing Range ({}, {} entities)", id, count) },
+            TransactionEntry::IdAllocation(id, _ ) => { write!(f, "Id Allocation ({})", id) }
         }
     }
 }
 
+// Defines what happens when an entry of the transaction log can not be rolled back
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum RollbackFailurePolicy
+{
+    // Stop the rollback at the first failing entry, remaining entries are not rolled back
+    Abort,
+    // Log the failing entry and continue with the remaining ones
+    #[default]
+    SkipAndLog,
+    // Panic at the first failing entry
+    PanicOnFirst
+}
+
 pub struct TransactionManager
 {    
     transaction_id: usize,    
     entries: Vec<TransactionEntry>,
-    transaction_running: bool
+    transaction_running: bool,
+    // Tables whose identifier allocation is already captured in the running transaction
+    id_allocating_tables: HashSet<u64>,
+    rollback_failure_policy: RollbackFailurePolicy
 }
 
 impl TransactionManager
 {
     pub fn new() -> Self
     {        
-        return Self { transaction_id: 1, entries: Vec::new(), transaction_running: false };
+        Self { transaction_id: 1, entries: Vec::new(), transaction_running: false, id_allocating_tables: HashSet::new(), rollback_failure_policy: RollbackFailurePolicy::default() }
+    }
+
+    // Create a transaction manager for a copy of the database, continuing the transaction identifiers of this one
+    pub(crate) fn fork(&self) -> Self
+    {
+        Self { transaction_id: self.transaction_id, entries: Vec::new(), transaction_running: false, id_allocating_tables: HashSet::new(), rollback_failure_policy: self.rollback_failure_policy }
     }
 
     pub fn is_transaction_running(&self) -> bool
@@ -54,30 +176,138 @@ impl TransactionManager
         debug!("Commit Transaction ({})", self.transaction_id);
 
         self.transaction_running = false;
-        self.entries.clear();        
+        self.entries.clear();
+        self.id_allocating_tables.clear();
     }
 
-    pub fn rollback_transaction<D>(&mut self, db: &mut RwLockWriteGuard<'_, D>) where D: Database
+    pub fn set_rollback_failure_policy(&mut self, rollback_failure_policy: RollbackFailurePolicy)
+    {
+        self.rollback_failure_policy = rollback_failure_policy;
+    }
+
+    pub fn get_rollback_failure_policy(&self) -> RollbackFailurePolicy
+    {
+        self.rollback_failure_policy
+    }
+
+    // Roll back all entries of the transaction log. Entries failed to roll back are handled by the rollback failure policy and returned as errors
+    pub fn rollback_transaction<D>(&mut self, db: &mut RwLockWriteGuard<'_, D>) -> Result<(), Vec<String>> where D: Database
     {
         debug!("Rollback Transaction ({})", self.transaction_id);
+
+        let mut errors = Vec::new();
         
-        for transaction_entry in &self.entries
+        // Entries are rolled back in reverse order, so an entity changed multiple times ends up in its state before the transaction
+        for transaction_entry in std::mem::take(&mut self.entries).into_iter().rev()
         {
-            match transaction_entry
+            let description = transaction_entry.to_string();
+            let result = match transaction_entry
             {
                 TransactionEntry::Existing(table_id, id, state) =>
                 {
-                    let table = db.get_table_mut(*table_id);
-                    table.rollback_to_existing(*id, state);
+                    match db.try_get_table_mut(table_id)
+                    {
+                        Some(table) => table.rollback_to_existing(id, state),
+                        None => Err(format!("Unknown table ({})", table_id))
+                    }
                 },
                 TransactionEntry::NotExisting(table_id, id) =>
                 {
-                    let table = db.get_table_mut(*table_id);
-                    table.rollback_to_not_existing(*id);
+                    match db.try_get_table_mut(table_id)
+                    {
+                        Some(table) => { table.rollback_to_not_existing(id); Ok(()) },
+                        None => Err(format!("Unknown table ({})", table_id))
+                    }
+                },
+                TransactionEntry::NotExistingRange(table_id, _, _, _) =>
+                {
+                    match db.try_get_table_mut(table_id)
+                    {
+                        Some(table) =>
+                        {
+                            let ids: Vec<usize> = transaction_entry.get_entities().into_iter().map(|(_, id)| id).collect();
+                            table.rollback_batch_to_not_existing(&ids);
+                            Ok(())
+                        },
+                        None => Err(format!("Unknown table ({})", table_id))
+                    }
+                },
+                TransactionEntry::IdAllocation(table_id, state) =>
+                {
+                    match db.try_get_table_mut(table_id)
+                    {
+                        Some(table) => table.rollback_id_allocation(state),
+                        None => Err(format!("Unknown table ({})", table_id))
+                    }
+                }
+            };
+
+            if let Err(e) = result
+            {
+                let message = format!("Rollback of entry {} failed in transaction {}: {}", description, self.transaction_id, e);
+                match self.rollback_failure_policy
+                {
+                    RollbackFailurePolicy::Abort =>
+                    {
+                        error!("{}", message);
+                        errors.push(message);
+                        break;
+                    },
+                    RollbackFailurePolicy::SkipAndLog =>
+                    {
+                        error!("{}", message);
+                        errors.push(message);
+                    },
+                    RollbackFailurePolicy::PanicOnFirst => panic!("{}", message)
                 }
             }
         }
         self.entries.clear();
+        self.id_allocating_tables.clear();
+        // The transaction is over even if some of its entries could not be rolled back
+        self.transaction_running = false;
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // Get the entities inserted or modified in the current transaction as (table identifier, entity identifier) pairs
+    pub fn get_touched_entities(&self) -> Vec<(u64, usize)>
+    {
+        self.entries.iter().flat_map(|entry| entry.get_entities()).collect()
+    }
+
+    // Remove the entries of the running transaction, what are not needed to roll it back
+    pub fn checkpoint(&mut self) -> usize
+    {
+        let entry_count = self.entries.len();
+        // Entities restored as a whole by a kept entry, and the fields restored by a kept entry
+        let mut restored_entities = HashSet::new();
+        let mut restored_fields = HashSet::new();
+        self.entries.retain(|entry|
+        {
+            match entry
+            {
+                TransactionEntry::Existing(table_id, id, RollbackState::Patch(patch)) =>
+                {
+                    !restored_entities.contains(&(*table_id, *id)) && restored_fields.insert((*table_id, *id, patch.field))
+                },
+                TransactionEntry::Existing(table_id, id, _) => restored_entities.insert((*table_id, *id)),
+                TransactionEntry::NotExisting(..) | TransactionEntry::NotExistingRange(..) =>
+                {
+                    restored_entities.extend(entry.get_entities());
+                    true
+                },
+                TransactionEntry::IdAllocation(..) => true
+            }
+        });
+        debug!("Checkpoint of transaction {} removed {} of {} entries", self.transaction_id, entry_count - self.entries.len(), entry_count);
+        entry_count - self.entries.len()
+    }
+
+    // Returns true if the table allocates its first identifier in the running transaction
+    pub fn capture_id_allocation(&mut self, table_id: u64) -> bool
+    {
+        self.transaction_running && self.id_allocating_tables.insert(table_id)
     }
 
     pub fn add_entry(&mut self, entry: TransactionEntry)
@@ -90,4 +320,174 @@ impl TransactionManager
         self.transaction_id
     }
 
-}
\ No newline at end of file
+}
+
+impl Default for TransactionManager
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use crate::{Engine, TransactionStatus};
+    use crate::command::{CommandDefinition, CommandDirectory, CommandDirectoryFactory};
+    use crate::test_fixtures::*;
+    use crate::transaction_storage::MemoryTransactionStorage;
+    use microdb_derive::{CommandDirectory, CommandDirectoryFactory};
+
+    #[test]
+    fn rollback_skips_a_failing_entry_and_reverts_the_others()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let first_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let second_id = db.flights.add(Box::new(flight("MA200", 20)));
+        let flights_table_id = db.flights.get_id();
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(first_id).unwrap().seats = 1;
+        // State what can not be deserialized, so the rollback of the entry fails
+        transaction_manager_ref.lock().unwrap().add_entry(TransactionEntry::Existing(flights_table_id, second_id, RollbackState::Serialized(vec![0xFF])));
+        db.flights.get_mut(second_id).unwrap().seats = 2;
+        let third_id = db.flights.add(Box::new(flight("MA300", 30)));
+
+        let errors = transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(db.flights.get(first_id).unwrap().seats, 10);
+        assert_eq!(db.flights.get(second_id).unwrap().seats, 20);
+        assert!(!db.flights.contains(third_id));
+        assert!(!transaction_manager_ref.lock().unwrap().is_transaction_running());
+    }
+
+    #[test]
+    fn rollback_stops_at_a_failing_entry_by_the_abort_policy()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let first_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let flights_table_id = db.flights.get_id();
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().set_rollback_failure_policy(RollbackFailurePolicy::Abort);
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(first_id).unwrap().seats = 1;
+        transaction_manager_ref.lock().unwrap().add_entry(TransactionEntry::NotExisting(flights_table_id + 1000, 1));
+        let second_id = db.flights.add(Box::new(flight("MA200", 20)));
+
+        let errors = transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        // Entries are rolled back in reverse order, so only the entries after the failing one are reverted
+        assert!(!db.flights.contains(second_id));
+        assert_eq!(db.flights.get(first_id).unwrap().seats, 1);
+        assert!(!transaction_manager_ref.lock().unwrap().is_transaction_running());
+    }
+
+    #[test]
+    fn rollback_restores_the_entities_copied_by_the_cloning_serializer()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        db.flights.set_rollback_serializer(RollbackSerializer::cloning());
+        let modified_id = db.flights.add(Box::new(flight("MA100", 10)));
+        let removed_id = db.flights.add(Box::new(flight("MA200", 20)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        db.flights.get_mut(modified_id).unwrap().flight_number = String::from("MA101");
+        db.flights.remove(removed_id);
+
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(**db.flights.get(modified_id).unwrap(), Box::new(flight("MA100", 10)));
+        assert_eq!(**db.flights.get(removed_id).unwrap(), Box::new(flight("MA200", 20)));
+    }
+
+    #[test]
+    fn checkpoint_removes_the_redundant_entries_and_the_rollback_restores_the_same_state()
+    {
+        let (mut db, transaction_manager_ref) = create_database();
+        let id = db.flights.add(Box::new(flight("MA100", 10)));
+        let db_lock = RwLock::new(db);
+        let mut db = db_lock.write().unwrap();
+
+        transaction_manager_ref.lock().unwrap().begin_transaction();
+        for _ in 0..3
+        {
+            db.flights.get_mut(id).unwrap().update_field(|flight| &mut flight.seats, |seats| *seats -= 1);
+        }
+        let new_id = db.flights.add(Box::new(flight("MA200", 20)));
+        db.flights.get_mut(new_id).unwrap().update_field(|flight| &mut flight.seats, |seats| *seats = 0);
+
+        // Only the first change of the seats of the existing flight and the addition of the new flight are needed for the rollback
+        assert_eq!(db.checkpoint_transaction(), 3);
+        assert_eq!(db.checkpoint_transaction(), 0);
+        transaction_manager_ref.lock().unwrap().rollback_transaction(&mut db).unwrap();
+        assert_eq!(db.flights.iter_with_ids().map(|(id, flight)| (id, flight.clone())).collect::<Vec<_>>(), vec![(id, flight("MA100", 10))]);
+        assert_eq!(db.flights.add(Box::new(flight("MA300", 30))), new_id);
+    }
+
+    #[derive(CommandDirectory, CommandDirectoryFactory)]
+    struct TransferCommands
+    {
+        add_flight: CommandDefinition::<AirlineDatabase, Flight>,
+        add_reservation: CommandDefinition::<AirlineDatabase, Reservation>,
+        move_reservation: CommandDefinition::<AirlineDatabase, (usize, usize)>
+    }
+
+    impl TransferCommands
+    {
+        fn add_flight(db: &mut AirlineDatabase, flight: &Flight) -> Result<(), String>
+        {
+            db.flights.add(Box::new(flight.clone()));
+            Ok(())
+        }
+
+        fn add_reservation(db: &mut AirlineDatabase, reservation: &Reservation) -> Result<(), String>
+        {
+            db.reservations.add(Box::new(reservation.clone()));
+            Ok(())
+        }
+
+        // Move the reservation to the flight, taking a seat of it. Entities of the two tables are borrowed as mutable at the same time.
+        fn move_reservation(db: &mut AirlineDatabase, (reservation_id, flight_id): &(usize, usize)) -> Result<(), String>
+        {
+            let reservation = db.reservations.get_mut(*reservation_id).ok_or("Reservation does not exist")?;
+            let flight = db.flights.get_mut(*flight_id).ok_or("Flight does not exist")?;
+            reservation.flight_id = *flight_id;
+            flight.seats = flight.seats.checked_sub(1).ok_or(format!("Flight {} is full", flight.flight_number))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn command_changing_entities_of_two_tables_is_rolled_back_together()
+    {
+        let (query_engine, mut command_engine) = Engine::builder(TransferCommands::new(), Box::new(MemoryTransactionStorage::new())).with_init(init).build();
+        let commands = command_engine.get_command_definitions();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA100", 1)))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_flight.create(flight("MA200", 1)))).unwrap();
+        let mut flight_ids = query_engine.query(|db| db.flights.iter_with_ids().map(|(id, _)| id).collect::<Vec<_>>());
+        flight_ids.sort();
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_ids[0], "Alice")))).unwrap();
+        command_engine.push_command(Arc::new(commands.add_reservation.create(reservation(flight_ids[0], "Bob")))).unwrap();
+        let mut reservation_ids = query_engine.query(|db| db.reservations.iter_with_ids().map(|(id, _)| id).collect::<Vec<_>>());
+        reservation_ids.sort();
+
+        let moved = command_engine.push_command(Arc::new(commands.move_reservation.create((reservation_ids[0], flight_ids[1])))).unwrap();
+        let rolled_back = command_engine.push_command(Arc::new(commands.move_reservation.create((reservation_ids[1], flight_ids[1])))).unwrap();
+
+        assert_eq!(command_engine.get_transaction_status(moved), Ok(TransactionStatus::Completed));
+        assert_eq!(command_engine.get_transaction_status(rolled_back), Ok(TransactionStatus::Failed));
+        query_engine.query(|db| {
+            assert_eq!(db.flights.get(flight_ids[1]).unwrap().seats, 0);
+            assert_eq!(db.reservations.get(reservation_ids[0]).unwrap().flight_id, flight_ids[1]);
+            // The reservation changed before the failure is rolled back together with the flight
+            assert_eq!(db.reservations.get(reservation_ids[1]).unwrap().flight_id, flight_ids[0]);
+        });
+    }
+}
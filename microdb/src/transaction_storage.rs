@@ -1,49 +1,372 @@
+use log::warn;
 use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions };
-use std::io::{Read, Write, BufReader, BufWriter, Seek, SeekFrom };
+use std::io::{self, Read, Write, BufReader, BufWriter, Seek, SeekFrom };
+use std::sync::{Arc, Mutex};
 
 #[derive(Serialize, Deserialize)]
 pub struct SerializedTransaction
 {
+    // Identifier of the transaction assigned when the command was accepted
+    pub transaction_id: usize,
     pub name: String,
-    pub serialized_parameters: Box<Vec<u8>>
+    pub serialized_parameters: Box<Vec<u8>>,
+    // Arbitrary metadata attached to the transaction (like a correlation or request identifier)
+    pub metadata: HashMap<String, String>
 }
 
-pub trait TransactionStorage
+// Transaction logs written to files start with a header of the magic bytes and the version of the record layout
+const LOG_MAGIC: [u8; 4] = *b"MDBL";
+// Version of the record layout (logs without a header are version 0)
+pub const LOG_FORMAT_VERSION: u32 = 1;
+const LOG_HEADER_LEN: usize = LOG_MAGIC.len() + std::mem::size_of::<u32>();
+
+// Get the header written at the start of a new transaction log file
+fn create_log_header() -> [u8; LOG_HEADER_LEN]
+{
+    let mut header = [0u8; LOG_HEADER_LEN];
+    header[..LOG_MAGIC.len()].copy_from_slice(&LOG_MAGIC);
+    header[LOG_MAGIC.len()..].copy_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// Read the format version from the header of a log file (0 for a log without a header)
+fn read_log_format_version(file: &mut File) -> io::Result<u32>
+{
+    let mut header = [0u8; LOG_HEADER_LEN];
+    let read_len = file.read(&mut header)?;
+    if read_len < LOG_HEADER_LEN || header[..LOG_MAGIC.len()] != LOG_MAGIC
+    {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(0);
+    }
+    let version = u32::from_le_bytes(header[LOG_MAGIC.len()..].try_into().unwrap());
+    if version > LOG_FORMAT_VERSION
+    {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Transaction log format version {} is not supported (supported version is {})", version, LOG_FORMAT_VERSION)));
+    }
+    Ok(version)
+}
+
+// Rewrite a log of format version 0 in the current format
+fn upgrade_headerless_log(path: &str) -> io::Result<()>
+{
+    let log_path = format!("{}/transactions.bin", path);
+    let temporary_path = format!("{}/transactions.bin.tmp", path);
+    let mut reader = BufReader::new(File::open(&log_path)?);
+    let mut writer = BufWriter::new(File::create(&temporary_path)?);
+    let metadata_bytes = bincode::serialize(&HashMap::<String, String>::new()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_all(&create_log_header())?;
+    let mut transaction_id: usize = 0;
+    while let (Some(name_bytes), Some(serialized_parameters)) = (read_headerless_part(&mut reader)?, read_headerless_part(&mut reader)?)
+    {
+        transaction_id += 1;
+        writer.write_all(&transaction_id.to_le_bytes())?;
+        for part in [&name_bytes, &serialized_parameters, &metadata_bytes]
+        {
+            writer.write_all(&part.len().to_le_bytes())?;
+            writer.write_all(part)?;
+        }
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(temporary_path, log_path)
+}
+
+// Read a part of a record of format version 0, or None at the end of the log
+fn read_headerless_part(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>>
+{
+    let mut length_buf = [0u8; 8];
+    match reader.read_exact(&mut length_buf)
+    {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?
+    }
+    let length = usize::from_le_bytes(length_buf);
+    let mut buf = Vec::new();
+    reader.take(length as u64).read_to_end(&mut buf)?;
+    Ok((buf.len() == length).then_some(buf))
+}
+
+// Get the number of bytes a record of the transaction log takes in the storage
+pub fn get_record_size(name: &str, serialized_parameters_len: usize, metadata: &HashMap<String, String>) -> usize
+{
+    let metadata_len = bincode::serialized_size(metadata).unwrap_or(0) as usize;
+    4 * std::mem::size_of::<usize>() + name.len() + serialized_parameters_len + metadata_len
+}
+
+pub trait TransactionStorage: Send
 {
     fn read(&mut self, buf: &mut [u8]) -> usize;
 
-    fn write(&mut self, buf: &[u8]) -> usize;
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    // Write the buffered records to the underlying storage (like before a shutdown)
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+
+    // Persist the number of processed records (ignored if checkpoints are not supported, may be skipped for some calls)
+    fn set_checkpoint(&mut self, _processed_record_count: usize) -> io::Result<()>
+    {
+        Ok(())
+    }
+
+    // Get the number of processed records of the transaction log, or None if the storage does not support checkpoints
+    fn get_checkpoint(&mut self) -> io::Result<Option<usize>>
+    {
+        Ok(None)
+    }
+
+    // Persist the identifier of a transaction rolled back by its command, so the replay can skip it (ignored if not supported)
+    fn add_failed_transaction_id(&mut self, _transaction_id: usize) -> io::Result<()>
+    {
+        Ok(())
+    }
+
+    // Get the identifiers of the transactions rolled back by their commands (empty if the storage does not support it)
+    fn get_failed_transaction_ids(&mut self) -> io::Result<Vec<usize>>
+    {
+        Ok(Vec::new())
+    }
+
+    // Persist a snapshot of the database and keep the last one before it as the previous snapshot
+    fn set_snapshot(&mut self, _snapshot: &[u8]) -> io::Result<()>
+    {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Snapshots are not supported by the transaction storage"))
+    }
+
+    // Get the last persisted snapshot of the database, or None if there is no snapshot (or the storage does not support it)
+    fn get_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        None
+    }
+
+    // Get the snapshot persisted before the last one, or None if there is no such snapshot (or the storage does not support it)
+    fn get_previous_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        None
+    }
+
+    // Get the maximum length of the name, the parameters and the metadata of a record read by get (None means unlimited)
+    fn get_max_record_size(&self) -> Option<usize>
+    {
+        None
+    }
+
+    // Check the length of a part of a record before allocating a buffer for it (a corrupted length could allocate any memory)
+    fn check_record_part_length(&self, transaction_id: usize, length: usize)
+    {
+        if let Some(max_record_size) = self.get_max_record_size()
+        {
+            assert!(length <= max_record_size, "Record of transaction {} is larger than the maximum record size ({} bytes, maximum is {} bytes)", transaction_id, length, max_record_size);
+        }
+    }
 
-    fn add(&mut self, name: String, serialized_parameters: Box<Vec<u8>>)
+    fn add(&mut self, transaction_id: usize, name: String, serialized_parameters: Box<Vec<u8>>, metadata: &HashMap<String, String>) -> io::Result<()>
     {
+        let metadata_bytes = bincode::serialize(metadata).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write(&transaction_id.to_le_bytes())?;
         let name_bytes = name.as_bytes();
-        self.write(&name_bytes.len().to_le_bytes());
-        self.write(name_bytes);
-        self.write(&serialized_parameters.len().to_le_bytes());
-        self.write(&serialized_parameters.as_ref());
+        self.write(&name_bytes.len().to_le_bytes())?;
+        self.write(name_bytes)?;
+        self.write(&serialized_parameters.len().to_le_bytes())?;
+        self.write(serialized_parameters.as_ref())?;
+        self.write(&metadata_bytes.len().to_le_bytes())?;
+        self.write(&metadata_bytes)?;
+        Ok(())
     }
 
+    // Truncate the log at the start of the last record, what was read only partially
+    fn truncate_incomplete_record(&mut self, _read_len: usize) -> io::Result<()>
+    {
+        Ok(())
+    }
+
+    // Get the next record of the log, or None at the end of the log. An incomplete or corrupted last record ends the log and is truncated.
     fn get(&mut self) -> Option<Box<SerializedTransaction>>
     {
-        let mut name_length_buf: [u8;8] = [0;8];
-        let count = self.read(&mut name_length_buf);
-        if count == 0
+        let mut read_len = 0;
+        match read_record(self, &mut read_len)
         {
-            return None;
+            Ok(serialized_transaction) => serialized_transaction,
+            Err(error) =>
+            {
+                warn!("Transaction log is truncated at an incomplete record of {} bytes: {}", read_len, error);
+                if let Err(error) = self.truncate_incomplete_record(read_len)
+                {
+                    warn!("Truncating the transaction log failed: {}", error);
+                }
+                None
+            }
         }
-        let name_length = usize::from_le_bytes(name_length_buf);
-        let mut name_buf = vec![0u8; name_length];
-        self.read(&mut name_buf);
-        let name = std::str::from_utf8(&mut name_buf).unwrap();
+    }
+}
 
-        let mut buf: [u8;8] = [0;8];
-        self.read(&mut buf);
-        let length = usize::from_le_bytes(buf);
-        let mut serialized_parameters = vec![0u8; length];
-        self.read(&mut serialized_parameters);
-        Some(Box::new(SerializedTransaction { name: String::from(name), serialized_parameters: Box::new(serialized_parameters) }))
+// Fill the buffer from the storage and count the bytes read, or return an error if the log ends before the buffer is filled
+fn read_exact<S: TransactionStorage + ?Sized>(storage: &mut S, buf: &mut [u8], read_len: &mut usize) -> Result<(), String>
+{
+    let count = storage.read(buf);
+    *read_len += count;
+    if count < buf.len()
+    {
+        return Err(format!("the log ends after {} of {} bytes of a record part", count, buf.len()));
     }
+    Ok(())
+}
+
+fn read_usize<S: TransactionStorage + ?Sized>(storage: &mut S, read_len: &mut usize) -> Result<usize, String>
+{
+    let mut buf: [u8;8] = [0;8];
+    read_exact(storage, &mut buf, read_len)?;
+    Ok(usize::from_le_bytes(buf))
+}
+
+// Read a part of a record stored as its length followed by its bytes
+fn read_part<S: TransactionStorage + ?Sized>(storage: &mut S, transaction_id: usize, read_len: &mut usize) -> Result<Vec<u8>, String>
+{
+    let length = read_usize(storage, read_len)?;
+    storage.check_record_part_length(transaction_id, length);
+    let mut buf = vec![0u8; length];
+    read_exact(storage, &mut buf, read_len)?;
+    Ok(buf)
+}
+
+// Read the next record of the log, or None at the end of the log
+fn read_record<S: TransactionStorage + ?Sized>(storage: &mut S, read_len: &mut usize) -> Result<Option<Box<SerializedTransaction>>, String>
+{
+    let mut transaction_id_buf: [u8;8] = [0;8];
+    match read_exact(storage, &mut transaction_id_buf, read_len)
+    {
+        Err(_) if *read_len == 0 => return Ok(None),
+        result => result?
+    }
+    let transaction_id = usize::from_le_bytes(transaction_id_buf);
+
+    let name_buf = read_part(storage, transaction_id, read_len)?;
+    let name = String::from_utf8(name_buf).map_err(|e| e.to_string())?;
+    let serialized_parameters = read_part(storage, transaction_id, read_len)?;
+    let metadata_buf = read_part(storage, transaction_id, read_len)?;
+    let metadata = bincode::deserialize(&metadata_buf).map_err(|e| e.to_string())?;
+    Ok(Some(Box::new(SerializedTransaction { transaction_id, name, serialized_parameters: Box::new(serialized_parameters), metadata })))
+}
+
+// ***************************** TransactionLogReader ***************************** //
+
+// Iterates the records of a transaction log in the order they were written (e.g. to inspect the log of a stopped engine)
+pub struct TransactionLogReader
+{
+    transaction_storage: Box<dyn TransactionStorage>
+}
+
+impl TransactionLogReader
+{
+    pub fn new(transaction_storage: Box<dyn TransactionStorage>) -> Self
+    {
+        Self { transaction_storage }
+    }
+}
+
+impl Iterator for TransactionLogReader
+{
+    type Item = Box<SerializedTransaction>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        self.transaction_storage.get()
+    }
+}
+
+// ***************************** LogTailer ***************************** //
+
+// Reads the records appended to the transaction log of a FileTransactionStorage while it is written
+pub struct LogTailer
+{
+    // Directory of the transaction log
+    path: String,
+    file: File,
+    // Bytes read from the log, what are not returned as records yet
+    buffer: Vec<u8>,
+    // Position of the first not returned byte in the buffer
+    position: usize
+}
+
+impl LogTailer
+{
+    // Tail the transaction log in the directory at path (the one of the FileTransactionStorage writing it)
+    pub fn new(path: &str) -> io::Result<Self>
+    {
+        let mut file = OpenOptions::new().read(true).open(format!("{}/transactions.bin", path))?;
+        if read_log_format_version(&mut file)? == 0
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Transaction log has no format header, it is upgraded when it is opened by FileTransactionStorage"));
+        }
+        Ok(Self { path: String::from(path), file, buffer: Vec::new(), position: 0 })
+    }
+
+    // Get the identifiers of the transactions rolled back by their commands so far
+    pub fn get_failed_transaction_ids(&self) -> io::Result<Vec<usize>>
+    {
+        match std::fs::read(format!("{}/failed_transactions.bin", self.path))
+        {
+            Ok(buf) => Ok(parse_failed_transaction_ids(&buf)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e)
+        }
+    }
+
+    // Get the next record of the log, or None if no complete record was appended since the last one
+    pub fn next_record(&mut self) -> io::Result<Option<Box<SerializedTransaction>>>
+    {
+        if let Some(serialized_transaction) = self.parse_record()?
+        {
+            return Ok(Some(serialized_transaction));
+        }
+        // Returned records are dropped from the buffer before reading more
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        self.file.read_to_end(&mut self.buffer)?;
+        self.parse_record()
+    }
+
+    // Parse the record at the position of the buffer, or return None if the buffer does not contain the whole record yet
+    fn parse_record(&mut self) -> io::Result<Option<Box<SerializedTransaction>>>
+    {
+        let mut offset = self.position;
+        let Some(transaction_id) = Self::parse_usize(&self.buffer, &mut offset) else { return Ok(None); };
+        let Some(name_bytes) = Self::parse_part(&self.buffer, &mut offset) else { return Ok(None); };
+        let Some(serialized_parameters) = Self::parse_part(&self.buffer, &mut offset) else { return Ok(None); };
+        let Some(metadata_bytes) = Self::parse_part(&self.buffer, &mut offset) else { return Ok(None); };
+
+        let name = std::str::from_utf8(name_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?.to_string();
+        let metadata = bincode::deserialize(metadata_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let serialized_transaction = SerializedTransaction { transaction_id, name, serialized_parameters: Box::new(serialized_parameters.to_vec()), metadata };
+        self.position = offset;
+        Ok(Some(Box::new(serialized_transaction)))
+    }
+
+    fn parse_usize(buffer: &[u8], offset: &mut usize) -> Option<usize>
+    {
+        let bytes = buffer.get(*offset..*offset + std::mem::size_of::<usize>())?;
+        *offset += bytes.len();
+        Some(usize::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Parse a part of a record stored as its length followed by its bytes
+    fn parse_part<'a>(buffer: &'a [u8], offset: &mut usize) -> Option<&'a [u8]>
+    {
+        let length = Self::parse_usize(buffer, offset)?;
+        let bytes = buffer.get(*offset..offset.checked_add(length)?)?;
+        *offset += length;
+        Some(bytes)
+    }
+}
+
+// Parse the identifiers of failed transactions stored one after the other. A partially written identifier (like after a crash) is ignored.
+fn parse_failed_transaction_ids(buf: &[u8]) -> Vec<usize>
+{
+    buf.chunks_exact(std::mem::size_of::<usize>()).map(|chunk| usize::from_le_bytes(chunk.try_into().unwrap())).collect()
 }
 
 // ***************************** NullTransactionStorage ***************************** //
@@ -60,6 +383,14 @@ impl NullTransactionStorage
     }
 }
 
+impl Default for NullTransactionStorage
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
 impl TransactionStorage for NullTransactionStorage
 {
     fn read(&mut self, _buf: &mut [u8]) -> usize
@@ -67,32 +398,200 @@ impl TransactionStorage for NullTransactionStorage
         0
     }
 
-    fn write(&mut self, _buf: &[u8]) -> usize
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize>
     {
-        0
+        Ok(0)
+    }
+}
+
+// ***************************** MemoryTransactionStorage ***************************** //
+
+// Transaction storage keeping the log in the memory (storages created by reopen share it)
+pub struct MemoryTransactionStorage
+{
+    log: Arc<Mutex<Vec<u8>>>,
+    // Position of the next byte read from the log
+    pos: usize,
+    failed_transaction_ids: Arc<Mutex<Vec<usize>>>,
+    // The last two snapshots, the latest one first
+    snapshots: Arc<Mutex<VecDeque<Vec<u8>>>>
+}
+
+impl MemoryTransactionStorage
+{
+    pub fn new() -> Self
+    {
+        Self { log: Arc::new(Mutex::new(Vec::new())), pos: 0, failed_transaction_ids: Arc::new(Mutex::new(Vec::new())), snapshots: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    // Create a storage sharing the log and the snapshots, what reads the log from the start
+    pub fn reopen(&self) -> Self
+    {
+        Self { log: self.log.clone(), pos: 0, failed_transaction_ids: self.failed_transaction_ids.clone(), snapshots: self.snapshots.clone() }
+    }
+}
+
+impl Default for MemoryTransactionStorage
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl TransactionStorage for MemoryTransactionStorage
+{
+    fn read(&mut self, buf: &mut [u8]) -> usize
+    {
+        let log = self.log.lock().unwrap();
+        let len = buf.len().min(log.len() - self.pos);
+        buf[..len].copy_from_slice(&log[self.pos..self.pos + len]);
+        self.pos += len;
+        len
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        self.log.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate_incomplete_record(&mut self, read_len: usize) -> io::Result<()>
+    {
+        self.pos -= read_len;
+        self.log.lock().unwrap().truncate(self.pos);
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: &[u8]) -> io::Result<()>
+    {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.push_front(snapshot.to_vec());
+        snapshots.truncate(2);
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        self.snapshots.lock().unwrap().front().cloned()
+    }
+
+    fn get_previous_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        self.snapshots.lock().unwrap().get(1).cloned()
+    }
+
+    fn add_failed_transaction_id(&mut self, transaction_id: usize) -> io::Result<()>
+    {
+        self.failed_transaction_ids.lock().unwrap().push(transaction_id);
+        Ok(())
+    }
+
+    fn get_failed_transaction_ids(&mut self) -> io::Result<Vec<usize>>
+    {
+        Ok(self.failed_transaction_ids.lock().unwrap().clone())
     }
 }
 
 // ***************************** FileTransactionStorage ***************************** //
 
+// Number of processed records between two writes of the checkpoint file by default
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 1000;
+
 pub struct FileTransactionStorage
 {
     pub reader: BufReader<File>,
     pub writer: BufWriter<File>,
-    pos: usize
+    // File storing the number of processed records (if checkpoints are enabled)
+    checkpoint_file: Option<File>,
+    // Number of processed records between two writes of the checkpoint file, the last written one and the not written one
+    checkpoint_interval: usize,
+    written_checkpoint: usize,
+    pending_checkpoint: Option<usize>,
+    // File storing the identifiers of the transactions rolled back by their commands
+    failed_transactions_file: File,
+    path: String,
+    // Maximum length of the name, the parameters and the metadata of a record read during replay
+    max_record_size: Option<usize>
 }
 
 impl FileTransactionStorage
 {
     pub fn new(path: &str) -> Self
     {   
-        let file2 = OpenOptions::new().write(true).create(true).open(format!("{}/transactions.bin", path)).unwrap();     
-        let file1 = OpenOptions::new().read(true).open(format!("{}/transactions.bin", path)).unwrap();
-        let reader = BufReader::with_capacity(1000000, file1);
-        let mut writer = BufWriter::with_capacity(1000000, file2);
-        writer.seek(SeekFrom::End(0)).unwrap();
+        Self::with_buffer_capacity(path, 1000000)
+    }
+
+    // Create a storage with read and write buffers of the given capacity (panics if the log can not be opened)
+    pub fn with_buffer_capacity(path: &str, buffer_capacity: usize) -> Self
+    {
+        Self::open(path, buffer_capacity).unwrap_or_else(|error| panic!("Opening the transaction log in {} failed: {}", path, error))
+    }
+
+    // Create a storage like with_buffer_capacity, but return an error if the log can not be opened
+    pub fn open(path: &str, buffer_capacity: usize) -> io::Result<Self>
+    {
+        let mut file2 = OpenOptions::new().write(true).create(true).truncate(false).open(format!("{}/transactions.bin", path))?;
+        let mut file1 = OpenOptions::new().read(true).open(format!("{}/transactions.bin", path))?;
+        if file1.metadata()?.len() == 0
+        {
+            file2.write_all(&create_log_header())?;
+            file2.sync_data()?;
+        }
+        else if read_log_format_version(&mut file1)? == 0
+        {
+            upgrade_headerless_log(path)?;
+            file2 = OpenOptions::new().write(true).open(format!("{}/transactions.bin", path))?;
+            file1 = OpenOptions::new().read(true).open(format!("{}/transactions.bin", path))?;
+        }
+        // The reader starts at the first record
+        file1.seek(SeekFrom::Start(LOG_HEADER_LEN as u64))?;
+        let reader = BufReader::with_capacity(buffer_capacity, file1);
+        let mut writer = BufWriter::with_capacity(buffer_capacity, file2);
+        writer.seek(SeekFrom::End(0))?;
+        let failed_transactions_file = OpenOptions::new().read(true).append(true).create(true).open(format!("{}/failed_transactions.bin", path))?;
+        // A partially written identifier (like after a crash) is dropped, so the next one is appended at its place
+        let failed_transactions_len = failed_transactions_file.metadata()?.len();
+        failed_transactions_file.set_len(failed_transactions_len - failed_transactions_len % std::mem::size_of::<usize>() as u64)?;
+
+        Ok(Self { reader, writer, checkpoint_file: None, checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL, written_checkpoint: 0, pending_checkpoint: None, failed_transactions_file, path: String::from(path), max_record_size: None })
+    }
+
+    // Limit the size of the records read during replay, so a corrupted log can not exhaust the memory
+    pub fn with_max_record_size(mut self, max_record_size: usize) -> Self
+    {
+        self.max_record_size = Some(max_record_size);
+        self
+    }
 
-        Self { reader, writer, pos: 0 }
+    // Persist the number of processed records in checkpoint.bin after every 1000 records and on flush
+    pub fn with_checkpoint(mut self) -> io::Result<Self>
+    {
+        let checkpoint_file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(format!("{}/checkpoint.bin", self.path))?;
+        self.checkpoint_file = Some(checkpoint_file);
+        Ok(self)
+    }
+
+    // Enable checkpoints written after every interval processed records (a checkpoint flushes the log)
+    pub fn with_checkpoint_interval(self, checkpoint_interval: usize) -> io::Result<Self>
+    {
+        assert!(checkpoint_interval > 0, "Checkpoint interval must be positive");
+        let mut storage = self.with_checkpoint()?;
+        storage.checkpoint_interval = checkpoint_interval;
+        Ok(storage)
+    }
+
+    // Write the processed record count of the last set_checkpoint call to the checkpoint file
+    fn write_checkpoint(&mut self) -> io::Result<()>
+    {
+        let (Some(checkpoint_file), Some(processed_record_count)) = (&mut self.checkpoint_file, self.pending_checkpoint) else { return Ok(()); };
+        // Transactions can not be processed before they are written to the log
+        self.writer.flush()?;
+        checkpoint_file.seek(SeekFrom::Start(0))?;
+        checkpoint_file.write_all(&processed_record_count.to_le_bytes())?;
+        self.written_checkpoint = processed_record_count;
+        self.pending_checkpoint = None;
+        Ok(())
     }
 }
 
@@ -100,26 +599,359 @@ impl TransactionStorage for FileTransactionStorage
 {
     fn read(&mut self, buf: &mut [u8]) -> usize
     {
-        let capacity = self.reader.capacity();
-        let len = buf.len();
-        if self.pos + len <= capacity
+        // A read may return less bytes than requested, so it is repeated until the buffer is filled or the end is reached
+        let mut read_len = 0;
+        while read_len < buf.len()
         {
-            self.pos = (self.pos + len) %capacity;
-            return self.reader.read(buf).unwrap();
-        }
-        else
-        {            
-            let len1 = capacity - self.pos;   
-            let readed_len1 = self.reader.read(&mut buf[0..len1]).unwrap();
-            let readed_len2 = self.reader.read(&mut buf[len1..]).unwrap();
-            self.pos = (self.pos + len) %capacity;
-            return readed_len1 + readed_len2;
+            match self.reader.read(&mut buf[read_len..])
+            {
+                Ok(0) => break,
+                Ok(len) => read_len += len,
+                // Interrupted reads are repeated (like by Read::read_exact)
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("Reading the transaction log failed: {}", e)
+            }
         }
+        read_len
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
     {        
-        let size = self.writer.write(buf).unwrap();        
-        size
+        self.writer.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn truncate_incomplete_record(&mut self, read_len: usize) -> io::Result<()>
+    {
+        let record_position = self.reader.stream_position()? - read_len as u64;
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(record_position)?;
+        self.writer.get_ref().sync_data()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.reader.seek(SeekFrom::Start(record_position))?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.write_checkpoint()
+    }
+
+    fn set_checkpoint(&mut self, processed_record_count: usize) -> io::Result<()>
+    {
+        if self.checkpoint_file.is_none()
+        {
+            return Ok(());
+        }
+        self.pending_checkpoint = Some(processed_record_count);
+        if processed_record_count >= self.written_checkpoint + self.checkpoint_interval
+        {
+            self.write_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: &[u8]) -> io::Result<()>
+    {
+        // Transactions in the snapshot can not be missing from the log
+        self.writer.flush()?;
+        // The snapshot is written to a temporary file first, so a crash while writing it keeps the previous snapshot
+        let temporary_path = format!("{}/snapshot.bin.tmp", self.path);
+        let mut snapshot_file = File::create(&temporary_path)?;
+        snapshot_file.write_all(snapshot)?;
+        snapshot_file.sync_all()?;
+        let snapshot_path = format!("{}/snapshot.bin", self.path);
+        match std::fs::rename(&snapshot_path, format!("{}/snapshot.previous.bin", self.path))
+        {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::rename(temporary_path, snapshot_path)
+    }
+
+    fn get_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        std::fs::read(format!("{}/snapshot.bin", self.path)).ok()
+    }
+
+    fn get_previous_snapshot(&mut self) -> Option<Vec<u8>>
+    {
+        std::fs::read(format!("{}/snapshot.previous.bin", self.path)).ok()
+    }
+
+    fn add_failed_transaction_id(&mut self, transaction_id: usize) -> io::Result<()>
+    {
+        self.failed_transactions_file.write_all(&transaction_id.to_le_bytes())
+    }
+
+    fn get_failed_transaction_ids(&mut self) -> io::Result<Vec<usize>>
+    {
+        let mut buf = Vec::new();
+        self.failed_transactions_file.seek(SeekFrom::Start(0))?;
+        self.failed_transactions_file.read_to_end(&mut buf)?;
+        Ok(parse_failed_transaction_ids(&buf))
+    }
+
+    fn get_max_record_size(&self) -> Option<usize>
+    {
+        self.max_record_size
+    }
+
+    fn get_checkpoint(&mut self) -> io::Result<Option<usize>>
+    {
+        let Some(checkpoint_file) = self.checkpoint_file.as_mut() else { return Ok(None); };
+        let mut buf: [u8;8] = [0;8];
+        checkpoint_file.seek(SeekFrom::Start(0))?;
+        // An empty checkpoint file means that no transaction was processed yet
+        let checkpoint = match checkpoint_file.read_exact(&mut buf)
+        {
+            Ok(_) => usize::from_le_bytes(buf),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e)
+        };
+        self.written_checkpoint = checkpoint;
+        Ok(Some(checkpoint))
+    }
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::test_fixtures::*;
+
+    #[test]
+    fn checkpoint_is_written_after_the_interval_and_on_flush()
+    {
+        let path = create_test_directory("checkpoint_interval");
+        let mut storage = FileTransactionStorage::new(&path).with_checkpoint_interval(2).unwrap();
+        let checkpoint = || FileTransactionStorage::new(&path).with_checkpoint().unwrap().get_checkpoint().unwrap();
+
+        storage.set_checkpoint(1).unwrap();
+        assert_eq!(checkpoint(), Some(0));
+        storage.set_checkpoint(2).unwrap();
+        assert_eq!(checkpoint(), Some(2));
+        storage.set_checkpoint(3).unwrap();
+        assert_eq!(checkpoint(), Some(2));
+        storage.flush().unwrap();
+        assert_eq!(checkpoint(), Some(3));
+    }
+
+    // Write a record in the layout of the logs without a format header: name and parameters, without transaction identifier and metadata
+    fn write_headerless_record(file: &mut File, name: &str, serialized_parameters: &[u8])
+    {
+        file.write_all(&name.len().to_le_bytes()).unwrap();
+        file.write_all(name.as_bytes()).unwrap();
+        file.write_all(&serialized_parameters.len().to_le_bytes()).unwrap();
+        file.write_all(serialized_parameters).unwrap();
+    }
+
+    #[test]
+    fn log_without_a_format_header_is_read_as_format_version_0()
+    {
+        let path = create_test_directory("headerless_log");
+        let mut file = File::create(format!("{}/transactions.bin", path)).unwrap();
+        write_headerless_record(&mut file, "add_flight", &bincode::serialize(&flight("MA100", 10)).unwrap());
+        write_headerless_record(&mut file, "add_reservation", &bincode::serialize(&reservation(1, "Alice")).unwrap());
+        drop(file);
+        assert_eq!(LogTailer::new(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (query_engine, mut command_engine) = create_engine(FileTransactionStorage::new(&path));
+        assert_eq!(query_engine.query(|db| (db.flights.len(), db.reservations.len())), (1, 1));
+        command_engine.push_command(Arc::new(command_engine.get_command_definitions().add_flight.create(flight("MA200", 20)))).unwrap();
+        drop(command_engine);
+
+        // The log is rewritten with a header, so new records follow the upgraded ones
+        let records: Vec<_> = TransactionLogReader::new(Box::new(FileTransactionStorage::new(&path))).collect();
+        assert_eq!(records.iter().map(|record| (record.transaction_id, record.name.as_str())).collect::<Vec<_>>(), vec![(1, "add_flight"), (2, "add_reservation"), (3, "add_flight")]);
+        assert!(LogTailer::new(&path).is_ok());
+    }
+
+    #[test]
+    fn log_of_an_unsupported_format_version_is_refused()
+    {
+        let path = create_test_directory("future_log");
+        let mut file = File::create(format!("{}/transactions.bin", path)).unwrap();
+        file.write_all(&LOG_MAGIC).unwrap();
+        file.write_all(&(LOG_FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        drop(file);
+
+        let error = FileTransactionStorage::open(&path, 1000).err().unwrap();
+        assert!(error.to_string().contains(&format!("version {} is not supported", LOG_FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn records_are_read_back_after_the_format_header()
+    {
+        let path = create_test_directory("log_header");
+        let mut storage = FileTransactionStorage::new(&path);
+        storage.add(1, String::from("add_flight"), Box::new(vec![1, 2, 3]), &HashMap::new()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(FileTransactionStorage::new(&path))).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].transaction_id, records[0].name.as_str(), records[0].serialized_parameters.as_slice()), (1, "add_flight", &[1u8, 2, 3][..]));
+        assert_eq!(LogTailer::new(&path).unwrap().next_record().unwrap().unwrap().name, "add_flight");
+    }
+
+    #[test]
+    fn records_larger_than_a_small_buffer_are_replayed()
+    {
+        let path = create_test_directory("small_buffer");
+        let flights: Vec<Flight> = (0..20).map(|number| flight(&format!("MA{}{}", number, "0".repeat(200)), number)).collect();
+        let mut storage = FileTransactionStorage::with_buffer_capacity(&path, 64).with_max_record_size(1000);
+        for (index, flight) in flights.iter().enumerate()
+        {
+            storage.add(index + 1, String::from("add_flight"), Box::new(bincode::serialize(flight).unwrap()), &HashMap::new()).unwrap();
+        }
+        storage.flush().unwrap();
+        drop(storage);
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(FileTransactionStorage::with_buffer_capacity(&path, 64).with_max_record_size(1000))).collect();
+        let replayed_flights: Vec<Flight> = records.iter().map(|record| bincode::deserialize(&record.serialized_parameters).unwrap()).collect();
+        assert_eq!(replayed_flights, flights);
+    }
+
+    #[test]
+    #[should_panic(expected = "Record of transaction 1 is larger than the maximum record size (300 bytes, maximum is 100 bytes)")]
+    fn records_larger_than_the_maximum_record_size_are_refused()
+    {
+        let path = create_test_directory("max_record_size");
+        let mut storage = FileTransactionStorage::new(&path);
+        storage.add(1, String::from("add_flight"), Box::new(vec![0; 300]), &HashMap::new()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        TransactionLogReader::new(Box::new(FileTransactionStorage::new(&path).with_max_record_size(100))).for_each(drop);
+    }
+
+    #[test]
+    fn log_larger_than_the_read_buffer_is_read_back_completely()
+    {
+        let path = create_test_directory("large_log");
+        let mut storage = FileTransactionStorage::new(&path);
+        let parameters = |transaction_id: usize| bincode::serialize(&flight(&format!("MA{}", transaction_id), transaction_id)).unwrap();
+        for transaction_id in 1..=8000
+        {
+            // Names of different lengths, so records cross the end of the buffer at every offset
+            storage.add(transaction_id, "add_flight".repeat(1 + transaction_id % 20), Box::new(parameters(transaction_id)), &HashMap::new()).unwrap();
+        }
+        storage.flush().unwrap();
+        drop(storage);
+        assert!(std::fs::metadata(format!("{}/transactions.bin", path)).unwrap().len() > 1000000);
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(FileTransactionStorage::new(&path))).collect();
+        assert_eq!(records.len(), 8000);
+        for (index, record) in records.iter().enumerate()
+        {
+            let transaction_id = index + 1;
+            assert_eq!(record.transaction_id, transaction_id);
+            assert_eq!(record.name, "add_flight".repeat(1 + transaction_id % 20));
+            assert_eq!(*record.serialized_parameters, parameters(transaction_id));
+        }
+    }
+
+    #[test]
+    fn memory_storage_records_are_read_back_after_reopen_and_replayed_into_an_engine()
+    {
+        let mut storage = MemoryTransactionStorage::new();
+        let metadata = HashMap::from([(String::from("user"), String::from("alice"))]);
+        storage.add(1, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA100", 10)).unwrap()), &metadata).unwrap();
+        storage.add(2, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA200", 20)).unwrap()), &HashMap::new()).unwrap();
+        storage.add(3, String::from("add_reservation"), Box::new(bincode::serialize(&reservation(1, "Alice")).unwrap()), &HashMap::new()).unwrap();
+        storage.add_failed_transaction_id(2).unwrap();
+
+        let records: Vec<_> = TransactionLogReader::new(Box::new(storage.reopen())).collect();
+        assert_eq!(records.iter().map(|record| (record.transaction_id, record.name.as_str())).collect::<Vec<_>>(), vec![(1, "add_flight"), (2, "add_flight"), (3, "add_reservation")]);
+        assert_eq!(*records[0].serialized_parameters, bincode::serialize(&flight("MA100", 10)).unwrap());
+        assert_eq!(records[0].metadata, metadata);
+        assert_eq!(storage.reopen().get_failed_transaction_ids().unwrap(), vec![2]);
+
+        // The failed transaction is skipped by the replay
+        let (query_engine, _command_engine) = create_engine(storage.reopen());
+        assert_eq!(query_engine.query(|db| db.flights.iter().map(|flight| flight.flight_number.clone()).collect::<Vec<_>>()), vec!["MA100"]);
+        assert_eq!(query_engine.query(|db| db.reservations.iter().map(|reservation| (reservation.flight_id, reservation.passenger.clone())).collect::<Vec<_>>()), vec![(1, String::from("Alice"))]);
+    }
+
+
+    #[test]
+    fn replay_stops_at_a_truncated_record_and_truncates_the_log()
+    {
+        let path = create_test_directory("truncated_record");
+        let mut storage = FileTransactionStorage::new(&path);
+        for transaction_id in 1..=2
+        {
+            storage.add(transaction_id, String::from("add_flight"), Box::new(bincode::serialize(&flight(&format!("MA{}", transaction_id), 10)).unwrap()), &HashMap::new()).unwrap();
+        }
+        storage.flush().unwrap();
+        let complete_len = std::fs::metadata(format!("{}/transactions.bin", path)).unwrap().len();
+        storage.add(3, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA3", 10)).unwrap()), &HashMap::new()).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        let full_len = std::fs::metadata(format!("{}/transactions.bin", path)).unwrap().len();
+
+        // Cut the last record inside each of its parts
+        for len in [complete_len + 4, complete_len + 12, complete_len + 20, full_len - 30, full_len - 1]
+        {
+            OpenOptions::new().write(true).open(format!("{}/transactions.bin", path)).unwrap().set_len(len).unwrap();
+            let mut storage = FileTransactionStorage::new(&path);
+            let records: Vec<_> = std::iter::from_fn(|| storage.get()).collect();
+            assert_eq!(records.iter().map(|record| record.transaction_id).collect::<Vec<_>>(), vec![1, 2]);
+            assert_eq!(std::fs::metadata(format!("{}/transactions.bin", path)).unwrap().len(), complete_len);
+
+            // The next record follows the last complete one
+            storage.add(3, String::from("add_flight"), Box::new(bincode::serialize(&flight("MA3", 10)).unwrap()), &HashMap::new()).unwrap();
+            storage.flush().unwrap();
+            drop(storage);
+            let (query_engine, _command_engine) = create_engine(FileTransactionStorage::new(&path));
+            assert_eq!(query_engine.query(|db| db.flights.len()), 3);
+        }
+    }
+
+
+    #[test]
+    fn checkpoint_file_failing_to_open_is_reported()
+    {
+        let path = create_test_directory("checkpoint_error");
+        std::fs::create_dir(format!("{}/checkpoint.bin", path)).unwrap();
+
+        assert!(FileTransactionStorage::new(&path).with_checkpoint().is_err());
+        assert!(FileTransactionStorage::new(&path).with_checkpoint_interval(10).is_err());
+    }
+
+
+    #[test]
+    fn partially_written_failed_transaction_id_is_ignored_and_overwritten()
+    {
+        let path = create_test_directory("partial_failed_id");
+        let mut storage = FileTransactionStorage::new(&path);
+        storage.add_failed_transaction_id(3).unwrap();
+        storage.add_failed_transaction_id(5).unwrap();
+        drop(storage);
+        // A crash while writing the second identifier leaves only some of its bytes
+        OpenOptions::new().write(true).open(format!("{}/failed_transactions.bin", path)).unwrap().set_len(12).unwrap();
+        assert_eq!(LogTailer::new(&path).unwrap().get_failed_transaction_ids().unwrap(), vec![3]);
+
+        let mut storage = FileTransactionStorage::new(&path);
+        assert_eq!(storage.get_failed_transaction_ids().unwrap(), vec![3]);
+        storage.add_failed_transaction_id(7).unwrap();
+        assert_eq!(storage.get_failed_transaction_ids().unwrap(), vec![3, 7]);
+    }
+
+
+    #[test]
+    fn file_storage_keeps_the_previous_snapshot()
+    {
+        let path = create_test_directory("previous_snapshot");
+        let mut storage = FileTransactionStorage::new(&path);
+        assert_eq!((storage.get_snapshot(), storage.get_previous_snapshot()), (None, None));
+        storage.set_snapshot(&[1]).unwrap();
+        assert_eq!((storage.get_snapshot(), storage.get_previous_snapshot()), (Some(vec![1]), None));
+        storage.set_snapshot(&[2]).unwrap();
+        storage.set_snapshot(&[3]).unwrap();
+        assert_eq!((storage.get_snapshot(), storage.get_previous_snapshot()), (Some(vec![3]), Some(vec![2])));
+        assert!(!std::path::Path::new(&format!("{}/snapshot.bin.tmp", path)).exists());
+    }
+}
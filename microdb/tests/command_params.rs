@@ -0,0 +1,9 @@
+// Compile tests of the builders generated by #[derive(CommandParams)]
+#[test]
+fn builders_require_every_field_before_build()
+{
+    let tests = trybuild::TestCases::new();
+    tests.pass("tests/ui/command_params_complete.rs");
+    tests.compile_fail("tests/ui/command_params_missing_field.rs");
+    tests.compile_fail("tests/ui/command_params_repeated_field.rs");
+}
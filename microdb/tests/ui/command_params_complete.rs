@@ -0,0 +1,16 @@
+use microdb_derive::CommandParams;
+
+#[derive(CommandParams, Debug, PartialEq)]
+pub struct ChangeFlightScheduleParameters
+{
+    pub flight_id: usize,
+    pub day_of_week: u8,
+    pub departure: String
+}
+
+fn main()
+{
+    // Fields can be set in any order
+    let parameters = ChangeFlightScheduleParameters::builder().departure(String::from("10:15")).flight_id(1).day_of_week(3).build();
+    assert_eq!(parameters, ChangeFlightScheduleParameters { flight_id: 1, day_of_week: 3, departure: String::from("10:15") });
+}
@@ -0,0 +1,15 @@
+use microdb_derive::CommandParams;
+
+#[derive(CommandParams)]
+pub struct ChangeFlightScheduleParameters
+{
+    pub flight_id: usize,
+    pub day_of_week: u8,
+    pub departure: String
+}
+
+fn main()
+{
+    // The departure is not set
+    let _parameters = ChangeFlightScheduleParameters::builder().flight_id(1).day_of_week(3).build();
+}
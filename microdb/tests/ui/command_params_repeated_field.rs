@@ -0,0 +1,14 @@
+use microdb_derive::CommandParams;
+
+#[derive(CommandParams)]
+pub struct ChangeFlightScheduleParameters
+{
+    pub flight_id: usize,
+    pub day_of_week: u8
+}
+
+fn main()
+{
+    // The flight is set twice
+    let _parameters = ChangeFlightScheduleParameters::builder().flight_id(1).flight_id(2).day_of_week(3).build();
+}
@@ -5,14 +5,23 @@ use crate::schema::{BlogDatabase, Blogger};
 #[derive(CommandDirectory, CommandDirectoryFactory)]
 pub struct BlogCommands
 {    
-  pub create_blogger: CommandDefinition::<BlogDatabase, Box<Blogger>> 
+  pub create_blogger: CommandDefinition::<BlogDatabase, Blogger>,
+  pub like_blogger: CommandDefinition::<BlogDatabase, usize>
 }
 
 impl BlogCommands
 {
-  fn create_blogger(db: &mut BlogDatabase, blogger: &Box<Blogger>) -> Result<(), String>
+  fn create_blogger(db: &mut BlogDatabase, blogger: &Blogger) -> Result<(), String>
   {
-    db.bloggers.add((*blogger).clone());    
+    db.bloggers.add(Box::new(blogger.clone()));    
+    Ok(())
+  }
+
+  fn like_blogger(db: &mut BlogDatabase, blogger_id: &usize) -> Result<(), String>
+  {
+    let blogger = db.bloggers.get_mut(*blogger_id).ok_or_else(|| format!("Blogger {} does not exist", blogger_id))?;
+    // Only the like count is logged for rollback instead of the whole blogger
+    blogger.update_field(|blogger| &mut blogger.statistics.like_count, |like_count| *like_count += 1);
     Ok(())
   }
 }
@@ -1,11 +1,10 @@
-use std::sync::{Mutex, Arc};
-use microdb::{QueryEngine, CommandEngine};
+use microdb::{QueryEngine, CommandEngine, CommandClient};
 use crate::{schema::{BlogDatabase, Blogger, BloggerStatistics }, blog_commands::{BlogCommands}};
 
 pub struct BlogService
 {
     query_engine: QueryEngine<BlogDatabase>,
-    command_engine_mutex: Mutex<CommandEngine<BlogDatabase, BlogCommands>>
+    command_client: CommandClient<BlogDatabase, BlogCommands>
 }
 
 #[allow(dead_code)]
@@ -13,25 +12,37 @@ impl BlogService
 {
     pub fn new(engine: (QueryEngine<BlogDatabase>, CommandEngine<BlogDatabase, BlogCommands>)) -> Self
     {
-        Self { query_engine: engine.0, command_engine_mutex: Mutex::new(engine.1) }
+        Self { query_engine: engine.0, command_client: CommandClient::new(engine.1) }
     }
 
     pub fn create_blogger(&self, name: String) -> usize
     {        
-        let mut command_engine = self.command_engine_mutex.lock().unwrap();
-        let command_definitions = command_engine.get_command_definitions();
         let blogger = Blogger { name, statistics: BloggerStatistics { post_count: 0, like_count: 0 } };
-        return command_engine.push_command(Arc::new(command_definitions.create_blogger.create(Box::new(blogger))));
+        self.command_client.dispatch(|commands| &commands.create_blogger, blogger).unwrap()
+    }
+
+    pub fn like_blogger(&self, blogger_id: usize) -> usize
+    {
+        self.command_client.dispatch(|commands| &commands.like_blogger, blogger_id).unwrap()
     }
 
     pub fn get_bloggers(&self) -> Vec<(usize, Box<Blogger>)>
     {
-        self.query_engine.get_db().bloggers.iter().map(|blogger| (blogger.get_id(), (*blogger).clone())).collect()
+        self.query_engine.query(|db| db.bloggers.iter_with_ids().map(|(id, blogger)| (id, Box::new(blogger.clone()))).collect())
+    }
+
+    pub fn get_blogger_count(&self) -> usize
+    {
+        self.query_engine.query(|db| db.bloggers.len())
+    }
+
+    pub fn get_blogger_names(&self) -> Vec<(usize, String)>
+    {
+        self.query_engine.query(|db| db.bloggers.iter().map(|blogger| (blogger.get_id(), blogger.project(|blogger| blogger.name.clone()))).collect())
     }
 
     pub fn wait_for_transaction(&mut self, transaction_id: usize)
     {
-        let mut command_engine = self.command_engine_mutex.lock().unwrap();
-        command_engine.wait_for_transaction(transaction_id);
+        self.command_client.wait_for_transaction(transaction_id).unwrap();
     }
 }
\ No newline at end of file
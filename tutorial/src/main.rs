@@ -1,6 +1,7 @@
 use blog_commands::BlogCommands;
 use blog_service::BlogService;
 use microdb::prelude::*;
+use schema::BlogDatabase;
 
 mod schema;
 mod blog_commands;
@@ -12,7 +13,11 @@ fn main()
 {
     const N: usize = 1000000;    
 
-    let engine = Engine::new( BlogCommands::new(), Box::new(FileTransactionStorage::new(".")), CommandExecutionType::Asynchronous, &|_| {} );    
+    let engine = Engine::new( BlogCommands::new(), Box::new(FileTransactionStorage::new(".")), CommandExecutionType::Asynchronous, &|db: &mut BlogDatabase| {
+        // Posts can only be added for existing bloggers
+        let bloggers_table_id = db.bloggers.get_id();
+        db.posts.add_foreign_key(|post| post.user_id, bloggers_table_id);
+    } );    
 
     let mut blog_service = BlogService::new( engine );
 
@@ -32,5 +37,19 @@ fn main()
     
     println!("{} items were added in {:?}", N, start.elapsed());
 
-    println!("Number of bloggers in the database: {}", blog_service.get_bloggers().len());
+    println!("Number of bloggers in the database: {}", blog_service.get_blogger_count());
+
+    let start = std::time::Instant::now();
+
+    // Like the first blogger N times (each like logs only the like count for rollback, see BlogCommands::like_blogger)
+    let mut i = 0;
+    while i < N
+    {
+        transaction_id = blog_service.like_blogger(1);
+        i += 1;
+    }
+
+    blog_service.wait_for_transaction(transaction_id);
+
+    println!("{} likes were added in {:?}", N, start.elapsed());
 }
\ No newline at end of file